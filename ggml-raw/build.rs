@@ -7,6 +7,10 @@ fn main() {
 
     let ggml_src = ["ggml/ggml.c"];
 
+    // cc prints `rerun-if-env-changed`, which stops cargo from rebuilding when
+    // the sources change unless they are listed explicitly.
+    println!("cargo:rerun-if-changed=ggml");
+
     let mut builder = cc::Build::new();
 
     let build = builder.files(ggml_src.iter()).include("include");
@@ -2353,6 +2353,15 @@ size_t ggml_nbytes(const struct ggml_tensor * tensor) {
     return (ggml_nelements(tensor)*GGML_TYPE_SIZE[tensor->type])/GGML_BLCK_SIZE[tensor->type];
 }
 
+const char * ggml_get_name(const struct ggml_tensor * tensor) {
+    return tensor->name;
+}
+
+void ggml_set_name(struct ggml_tensor * tensor, const char * name) {
+    strncpy(tensor->name, name, sizeof(tensor->name));
+    tensor->name[sizeof(tensor->name) - 1] = '\0';
+}
+
 int ggml_blck_size(enum ggml_type type) {
     return GGML_BLCK_SIZE[type];
 }
@@ -2686,6 +2695,7 @@ struct ggml_tensor * ggml_new_tensor_impl(
         /*.perf_cycles  =*/ 0,
         /*.perf_time_us =*/ 0,
         /*.data         =*/ data == NULL ? (void *)(result + 1) : data,
+        /*.name         =*/ { 0 },
         /*.pad          =*/ { 0 },
     };
 
@@ -4153,6 +4163,17 @@ struct ggml_tensor * ggml_rope(
         int                   n_past,
         int                   n_dims,
         int                   mode) {
+    return ggml_rope_custom(ctx, a, n_past, n_dims, mode, 10000.0f, 1.0f);
+}
+
+struct ggml_tensor * ggml_rope_custom(
+        struct ggml_context * ctx,
+        struct ggml_tensor  * a,
+        int                   n_past,
+        int                   n_dims,
+        int                   mode,
+        float                 freq_base,
+        float                 freq_scale) {
     GGML_ASSERT(n_past >= 0);
     bool is_node = false;
 
@@ -4165,10 +4186,12 @@ struct ggml_tensor * ggml_rope(
     //struct ggml_tensor * result = inplace ? ggml_view_tensor(ctx, a) : ggml_dup_tensor(ctx, a);
     struct ggml_tensor * result = ggml_view_tensor(ctx, a);
 
-    struct ggml_tensor * b = ggml_new_tensor_1d(ctx, GGML_TYPE_I32, 3);
+    struct ggml_tensor * b = ggml_new_tensor_1d(ctx, GGML_TYPE_I32, 5);
     ((int32_t *) b->data)[0] = n_past;
     ((int32_t *) b->data)[1] = n_dims;
     ((int32_t *) b->data)[2] = mode;
+    memcpy((int32_t *) b->data + 3, &freq_base,  sizeof(float));
+    memcpy((int32_t *) b->data + 4, &freq_scale, sizeof(float));
 
     result->op   = GGML_OP_ROPE;
     result->grad = is_node ? ggml_dup_tensor(ctx, result) : NULL;
@@ -7283,7 +7306,7 @@ static void ggml_compute_forward_rope_f32(
         struct ggml_tensor * dst) {
     assert(params->ith == 0);
     assert(src1->type == GGML_TYPE_I32);
-    assert(ggml_nelements(src1) == 3);
+    assert(ggml_nelements(src1) == 5);
 
     if (params->type == GGML_TASK_INIT || params->type == GGML_TASK_FINALIZE) {
         return;
@@ -7293,6 +7316,11 @@ static void ggml_compute_forward_rope_f32(
     const int n_dims = ((int32_t *) src1->data)[1];
     const int mode   = ((int32_t *) src1->data)[2];
 
+    float freq_base;
+    float freq_scale;
+    memcpy(&freq_base,  (int32_t *) src1->data + 3, sizeof(float));
+    memcpy(&freq_scale, (int32_t *) src1->data + 4, sizeof(float));
+
     //const int ne0 = src0->ne[0];
     const int ne1 = src0->ne[1];
     const int ne2 = src0->ne[2];
@@ -7314,7 +7342,7 @@ static void ggml_compute_forward_rope_f32(
             const int p = (mode == 0 ? n_past + i2 : i2);
             for (int i1 = 0; i1 < ne1; i1++) {
                 for (int i0 = 0; i0 < n_dims; i0 += 2) {
-                    const double theta = pow(10000.0, ((double)-i0)/n_dims);
+                    const double theta = freq_scale*pow(freq_base, ((double)-i0)/n_dims);
 
                     const double cos_theta = cos(p*theta);
                     const double sin_theta = sin(p*theta);
@@ -7340,7 +7368,7 @@ static void ggml_compute_forward_rope_f16(
         struct ggml_tensor * dst) {
     assert(params->ith == 0);
     assert(src1->type == GGML_TYPE_I32);
-    assert(ggml_nelements(src1) == 3);
+    assert(ggml_nelements(src1) == 5);
 
     if (params->type == GGML_TASK_INIT || params->type == GGML_TASK_FINALIZE) {
         return;
@@ -7350,6 +7378,11 @@ static void ggml_compute_forward_rope_f16(
     const int n_dims = ((int32_t *) src1->data)[1];
     const int mode   = ((int32_t *) src1->data)[2];
 
+    float freq_base;
+    float freq_scale;
+    memcpy(&freq_base,  (int32_t *) src1->data + 3, sizeof(float));
+    memcpy(&freq_scale, (int32_t *) src1->data + 4, sizeof(float));
+
     //const int ne0 = src0->ne[0];
     const int ne1 = src0->ne[1];
     const int ne2 = src0->ne[2];
@@ -7370,7 +7403,7 @@ static void ggml_compute_forward_rope_f16(
             const int p = (mode == 0 ? n_past + i2 : i2);
             for (int i1 = 0; i1 < ne1; i1++) {
                 for (int i0 = 0; i0 < n_dims; i0 += 2) {
-                    const double theta = pow(10000.0, ((double)-i0)/n_dims);
+                    const double theta = freq_scale*pow(freq_base, ((double)-i0)/n_dims);
 
                     const double cos_theta = cos(p*theta);
                     const double sin_theta = sin(p*theta);
@@ -9875,9 +9908,9 @@ void ggml_graph_dump_dot(const struct ggml_cgraph * gb, const struct ggml_cgraph
 
         fprintf(fp, "  \"%p\" [ \
 style = filled; fillcolor = %s; shape = record; \
-label=\"%d [%d, %d] | <x>%s",
+label=\"%d%s%s [%d, %d] | <x>%s",
                 (void *) node, color,
-                i, node->ne[0], node->ne[1],
+                i, node->name[0] ? " " : "", node->name, node->ne[0], node->ne[1],
                 GGML_OP_SYMBOL[node->op]);
 
         if (node->grad) {
@@ -283,6 +283,9 @@ struct ggml_tensor {
     int64_t perf_time_us;
 
     void * data;
+
+    char name[32];
+
     char padding[8];
 };
 
@@ -330,6 +333,10 @@ void ggml_print_objects(const struct ggml_context * ctx);
 int    ggml_nelements(const struct ggml_tensor * tensor);
 size_t ggml_nbytes   (const struct ggml_tensor * tensor);
 
+// the name is truncated to 31 bytes
+const char * ggml_get_name(const struct ggml_tensor * tensor);
+void         ggml_set_name(struct ggml_tensor * tensor, const char * name);
+
 int    ggml_blck_size (enum ggml_type type);
 size_t ggml_type_size (enum ggml_type type); // size in bytes for all elements in a block
 float  ggml_type_sizef(enum ggml_type type); // ggml_type_size()/ggml_blck_size() as float
@@ -591,6 +598,17 @@ struct ggml_tensor * ggml_rope(
         int                   n_dims,
         int                   mode);
 
+// rotary position embedding with a custom frequency base (10000 in ggml_rope)
+// and positions multiplied by freq_scale (1 in ggml_rope)
+struct ggml_tensor * ggml_rope_custom(
+        struct ggml_context * ctx,
+        struct ggml_tensor  * a,
+        int                   n_past,
+        int                   n_dims,
+        int                   mode,
+        float                 freq_base,
+        float                 freq_scale);
+
 // padding = 1
 // TODO: we don't support extra parameters for now
 //       that's why we are hard-coding the stride, padding, and dilation
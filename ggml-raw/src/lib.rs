@@ -1,6 +1,6 @@
 #![allow(non_camel_case_types)]
 
-use std::os::raw::{c_int, c_void};
+use std::os::raw::{c_char, c_int, c_void};
 
 pub type ggml_type = c_int;
 pub const GGML_TYPE_Q4_0: ggml_type = 0;
@@ -69,6 +69,7 @@ pub struct ggml_tensor {
     pub perf_cycles: i64,
     pub perf_time_us: i64,
     pub data: *mut c_void,
+    pub name: [::std::os::raw::c_char; 32usize],
     pub padding: [::std::os::raw::c_char; 8usize],
 }
 
@@ -100,6 +101,10 @@ extern "C" {
 
     pub fn ggml_nbytes(tensor: *const ggml_tensor) -> usize;
 
+    pub fn ggml_get_name(tensor: *const ggml_tensor) -> *const c_char;
+
+    pub fn ggml_set_name(tensor: *mut ggml_tensor, name: *const c_char);
+
     pub fn ggml_blck_size(type_: ggml_type) -> c_int;
 
     pub fn ggml_type_size(type_: ggml_type) -> usize;
@@ -114,6 +119,16 @@ extern "C" {
 
     pub fn ggml_used_mem(ctx: *const ggml_context) -> usize;
 
+    /// Not part of `ggml.h`, but exported by `ggml.c`. If `data` is not null,
+    /// the tensor uses it instead of allocating its data in the context.
+    pub fn ggml_new_tensor_impl(
+        ctx: *mut ggml_context,
+        type_: ggml_type,
+        n_dims: c_int,
+        ne: *const c_int,
+        data: *mut c_void,
+    ) -> *mut ggml_tensor;
+
     pub fn ggml_new_tensor_1d(
         ctx: *mut ggml_context,
         type_: ggml_type,
@@ -225,7 +240,55 @@ extern "C" {
         mode: c_int,
     ) -> *mut ggml_tensor;
 
+    pub fn ggml_rope_custom(
+        ctx: *mut ggml_context,
+        a: *mut ggml_tensor,
+        n_past: c_int,
+        n_dims: c_int,
+        mode: c_int,
+        freq_base: f32,
+        freq_scale: f32,
+    ) -> *mut ggml_tensor;
+
     pub fn ggml_build_forward_expand(cgraph: *mut ggml_cgraph, tensor: *mut ggml_tensor);
 
     pub fn ggml_graph_compute(ctx: *mut ggml_context, cgraph: *mut ggml_cgraph);
+
+    pub fn ggml_graph_dump_dot(
+        gb: *const ggml_cgraph,
+        gf: *const ggml_cgraph,
+        filename: *const c_char,
+    );
+
+    /// Not part of `ggml.h`, but exported by `ggml.c`. `k` must be a multiple
+    /// of the block size.
+    pub fn dequantize_row_q4_0(x: *const c_void, y: *mut f32, k: c_int);
+
+    /// Not part of `ggml.h`, but exported by `ggml.c`. `k` must be a multiple
+    /// of the block size.
+    pub fn dequantize_row_q4_1(x: *const c_void, y: *mut f32, k: c_int);
+
+    pub fn ggml_cpu_has_avx() -> c_int;
+
+    pub fn ggml_cpu_has_avx2() -> c_int;
+
+    pub fn ggml_cpu_has_avx512() -> c_int;
+
+    pub fn ggml_cpu_has_fma() -> c_int;
+
+    pub fn ggml_cpu_has_neon() -> c_int;
+
+    pub fn ggml_cpu_has_arm_fma() -> c_int;
+
+    pub fn ggml_cpu_has_f16c() -> c_int;
+
+    pub fn ggml_cpu_has_fp16_va() -> c_int;
+
+    pub fn ggml_cpu_has_wasm_simd() -> c_int;
+
+    pub fn ggml_cpu_has_blas() -> c_int;
+
+    pub fn ggml_cpu_has_sse3() -> c_int;
+
+    pub fn ggml_cpu_has_vsx() -> c_int;
 }
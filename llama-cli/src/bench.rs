@@ -0,0 +1,220 @@
+//! Measures the speed of prompt evaluation and generation with a fixed,
+//! synthetic workload, so that the results are comparable across runs,
+//! builds and machines, and the speed of loading a multi-part model.
+
+use std::{
+    cell::{Cell, RefCell},
+    time::{Duration, Instant},
+};
+
+use llama_rs::{
+    EvaluateOutputRequest, Hyperparameters, InferenceParameters, InferenceSessionParameters,
+    InferenceStats, LanguageModel, Llama, LoadParameters, LoadProgress, LoadStrategy, SamplingMode,
+    StopReason, Vocabulary,
+};
+
+use crate::cli_args::Args;
+
+/// The seed used for sampling, so that the same tokens are generated.
+const SEED: u64 = 0;
+
+pub fn run(args: &Args, load_params: LoadParameters, session_params: InferenceSessionParameters) {
+    let n_prompt = args.bench_prompt_tokens;
+    let n_gen = args.bench_gen_tokens;
+    if n_prompt == 0 || n_prompt + n_gen >= args.num_ctx_tokens {
+        log::error!(
+            "The benchmark needs at least 1 prompt token, and {n_prompt} prompt tokens and \
+             {n_gen} generated tokens must fit in the context of {} tokens",
+            args.num_ctx_tokens
+        );
+        std::process::exit(1);
+    }
+
+    let hparams = RefCell::new(Hyperparameters::default());
+    let (model, vocab) = Llama::load_with_parameters(
+        &args.model_path,
+        args.num_ctx_tokens as i32,
+        load_params,
+        |progress| {
+            if let LoadProgress::HyperparametersLoaded(loaded) = progress {
+                *hparams.borrow_mut() = loaded.clone();
+            }
+        },
+    )
+    .unwrap_or_else(|err| {
+        log::error!("Could not load model: {err}");
+        std::process::exit(1);
+    });
+    let hparams = hparams.into_inner();
+    // When the model is memory-mapped, this is only resident once all of its
+    // pages have been read.
+    let model_bytes = hparams.estimated_ctx_size().unwrap_or_default();
+    let session_bytes = hparams.estimated_session_ctx_size(&session_params);
+
+    let threads = if args.bench_threads.is_empty() {
+        vec![args.num_threads]
+    } else {
+        args.bench_threads.clone()
+    };
+
+    println!("model: {}", args.model_path);
+    println!("prompt tokens: {n_prompt}, generated tokens: {n_gen}");
+    println!(
+        "estimated memory: {:.2} MB (model) + {:.2} MB (session)",
+        model_bytes as f64 / 1024.0 / 1024.0,
+        session_bytes as f64 / 1024.0 / 1024.0
+    );
+    println!();
+    println!(
+        "{:>8} | {:>18} | {:>22}",
+        "threads", "prompt (tokens/s)", "generation (tokens/s)"
+    );
+    println!("{:->8}-|-{:->18}-|-{:->22}", "", "", "");
+    for n_threads in threads {
+        let stats = run_once(&model, &vocab, args, session_params, n_threads);
+        println!(
+            "{:>8} | {:>18.2} | {:>22.2}",
+            n_threads,
+            tokens_per_second(stats.prompt_tokens, stats.feed_prompt_duration),
+            tokens_per_second(stats.predict_tokens, stats.predict_duration),
+        );
+    }
+    if let Some(peak_bytes) = peak_memory() {
+        println!();
+        println!("peak memory: {:.2} MB", peak_bytes as f64 / 1024.0 / 1024.0);
+    }
+}
+
+fn run_once(
+    model: &Llama,
+    vocab: &Vocabulary,
+    args: &Args,
+    mut session_params: InferenceSessionParameters,
+    n_threads: usize,
+) -> InferenceStats {
+    session_params.seed = Some(SEED);
+    let mut session = model.start_session(session_params);
+
+    let mut params = InferenceParameters {
+        n_threads: n_threads as i32,
+        n_batch: args.batch_size,
+        sampling_mode: SamplingMode::TopPTopK,
+        ..Default::default()
+    };
+    // Always generate the requested number of tokens.
+    params.ban_token(vocab.eos_token());
+
+    // The prompt cycles through the vocabulary, skipping the special tokens.
+    let first_token = vocab.eos_token() + 1;
+    let n_tokens = vocab.len() as i32 - first_token;
+    let prompt: Vec<_> = std::iter::once(vocab.bos_token())
+        .chain((0..).map(|i| first_token + i % n_tokens))
+        .take(args.bench_prompt_tokens)
+        .collect();
+
+    let start = Instant::now();
+    for batch in prompt.chunks(args.batch_size.max(1)) {
+        model.evaluate(
+            &mut session,
+            &params,
+            batch,
+            &mut EvaluateOutputRequest::default(),
+        );
+    }
+    let feed_prompt_duration = start.elapsed();
+
+    let start = Instant::now();
+    for _ in 0..args.bench_gen_tokens {
+        session
+            .infer_next_token(model, vocab, &params)
+            .expect("the generated tokens fit in the context");
+    }
+    let predict_duration = start.elapsed();
+
+    InferenceStats {
+        feed_prompt_duration,
+        prompt_tokens: prompt.len(),
+        predict_duration,
+        predict_tokens: args.bench_gen_tokens,
+        generated_tokens: args.bench_gen_tokens,
+        stop_reason: StopReason::MaxTokens,
+    }
+}
+
+/// Times the loading of the model with its parts read sequentially and in
+/// parallel, `args.bench_load_runs` times each, and prints the fastest time of
+/// each.
+pub fn run_load(args: &Args, load_params: LoadParameters) {
+    // Only models read into memory are loaded in parallel.
+    let load_params = LoadParameters {
+        strategy: LoadStrategy::Read,
+        ..load_params
+    };
+    let n_parts = Cell::new(1);
+    let load = |parallel_load| {
+        let start = Instant::now();
+        let result = Llama::load_with_parameters(
+            &args.model_path,
+            args.num_ctx_tokens as i32,
+            LoadParameters {
+                parallel_load,
+                ..load_params
+            },
+            |progress| {
+                if let LoadProgress::PartLoading { total_parts, .. } = progress {
+                    n_parts.set(total_parts);
+                }
+            },
+        );
+        if let Err(err) = result {
+            log::error!("Could not load model: {err}");
+            std::process::exit(1);
+        }
+        start.elapsed()
+    };
+
+    // Reads the parts into the page cache.
+    load(false);
+    if n_parts.get() == 1 {
+        log::warn!("The model has a single part, which is never loaded in parallel");
+    }
+
+    let mut sequential = Duration::MAX;
+    let mut parallel = Duration::MAX;
+    for _ in 0..args.bench_load_runs.max(1) {
+        sequential = sequential.min(load(false));
+        parallel = parallel.min(load(true));
+    }
+
+    println!("model: {} ({} parts)", args.model_path, n_parts.get());
+    println!("sequential load: {:.3} s", sequential.as_secs_f64());
+    println!(
+        "parallel load: {:.3} s ({:.2}x)",
+        parallel.as_secs_f64(),
+        sequential.as_secs_f64() / parallel.as_secs_f64()
+    );
+}
+
+/// Returns the peak resident memory of the process, in bytes.
+#[cfg(target_os = "linux")]
+fn peak_memory() -> Option<u64> {
+    // SAFETY: `usage` is only read once `getrusage` has filled it in.
+    let usage = unsafe {
+        let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+        if libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) != 0 {
+            return None;
+        }
+        usage.assume_init()
+    };
+    // On Linux, `ru_maxrss` is in kilobytes.
+    Some(usage.ru_maxrss as u64 * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peak_memory() -> Option<u64> {
+    None
+}
+
+fn tokens_per_second(tokens: usize, duration: std::time::Duration) -> f64 {
+    tokens as f64 / duration.as_secs_f64()
+}
@@ -0,0 +1,371 @@
+//! Serves a model over HTTP through an OpenAI-compatible `POST
+//! /v1/completions` endpoint, so that tools that speak the OpenAI API can use
+//! it.
+
+use std::{
+    cell::RefCell,
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::Parser;
+use llama_rs::{
+    InferenceError, InferenceParameters, InferenceSessionParameters, Llama, LoadParameters,
+    OutputToken, Penalties, StopReason, Vocabulary,
+};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Where to load the model path from
+    #[arg(long, short = 'm')]
+    model_path: String,
+
+    /// The address to listen on.
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    address: String,
+
+    /// Sets the number of threads to use
+    #[arg(long, short = 't', default_value_t = num_cpus::get_physical())]
+    num_threads: usize,
+
+    /// Sets the size of the context (in tokens). Each request gets a new
+    /// session of this size.
+    #[arg(long, default_value_t = 512)]
+    num_ctx_tokens: usize,
+
+    /// How many tokens from the prompt at a time to feed the network. Does not
+    /// affect generation.
+    #[arg(long, default_value_t = 8)]
+    batch_size: usize,
+
+    /// The temperature of the requests that don't set `temperature`.
+    #[arg(long, default_value_t = 0.80)]
+    temp: f32,
+
+    /// The top-p of the requests that don't set `top_p`.
+    #[arg(long, default_value_t = 0.95)]
+    top_p: f32,
+
+    /// The top-k of the requests that don't set `top_k`. 0 keeps all tokens.
+    #[arg(long, default_value_t = 40)]
+    top_k: usize,
+}
+
+/// The number of tokens generated when the request doesn't set `max_tokens`,
+/// as in the OpenAI API.
+const DEFAULT_MAX_TOKENS: usize = 16;
+
+/// A request to the completions endpoint. Only the fields that map to
+/// [InferenceParameters] are supported; the others are ignored. The sampling
+/// parameters that are set override the defaults of the server for this
+/// request only.
+struct CompletionRequest {
+    prompt: String,
+    max_tokens: usize,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    top_k: Option<usize>,
+    /// Makes the completion reproducible, as the `seed` of the OpenAI chat
+    /// API.
+    seed: Option<u64>,
+    frequency_penalty: Option<f32>,
+    presence_penalty: Option<f32>,
+    stop: Vec<String>,
+    stream: bool,
+}
+
+impl CompletionRequest {
+    fn parse(body: &str) -> Result<Self, String> {
+        let body: Value = serde_json::from_str(body).map_err(|err| err.to_string())?;
+        let prompt = match &body["prompt"] {
+            Value::String(prompt) => prompt.clone(),
+            Value::Null => String::new(),
+            _ => return Err("`prompt` must be a string".to_owned()),
+        };
+        let stop = match &body["stop"] {
+            Value::Null => vec![],
+            Value::String(stop) => vec![stop.clone()],
+            Value::Array(stop) => stop
+                .iter()
+                .map(|s| s.as_str().map(str::to_owned))
+                .collect::<Option<_>>()
+                .ok_or("`stop` must be a string or an array of strings")?,
+            _ => return Err("`stop` must be a string or an array of strings".to_owned()),
+        };
+        let number = |name: &str| match &body[name] {
+            Value::Null => Ok(None),
+            value => value
+                .as_f64()
+                .map(|value| Some(value as f32))
+                .ok_or(format!("`{name}` must be a number")),
+        };
+        let integer = |name: &str| match &body[name] {
+            Value::Null => Ok(None),
+            value => value
+                .as_u64()
+                .map(Some)
+                .ok_or(format!("`{name}` must be a positive integer")),
+        };
+
+        let temperature = number("temperature")?;
+        if matches!(temperature, Some(temp) if temp < 0.0) {
+            return Err("`temperature` must be at least 0".to_owned());
+        }
+        let top_p = number("top_p")?;
+        if matches!(top_p, Some(top_p) if top_p <= 0.0 || top_p > 1.0) {
+            return Err("`top_p` must be greater than 0 and at most 1".to_owned());
+        }
+
+        Ok(Self {
+            prompt,
+            max_tokens: match &body["max_tokens"] {
+                Value::Null => DEFAULT_MAX_TOKENS,
+                value => value
+                    .as_u64()
+                    .ok_or("`max_tokens` must be a positive integer")?
+                    as usize,
+            },
+            temperature,
+            top_p,
+            top_k: integer("top_k")?.map(|top_k| top_k as usize),
+            seed: integer("seed")?,
+            frequency_penalty: number("frequency_penalty")?,
+            presence_penalty: number("presence_penalty")?,
+            stop,
+            stream: body["stream"].as_bool().unwrap_or(false),
+        })
+    }
+
+    fn inference_parameters(&self, args: &Args) -> InferenceParameters {
+        let defaults = InferenceParameters::default();
+        InferenceParameters {
+            n_threads: args.num_threads as i32,
+            n_batch: args.batch_size,
+            temp: self.temperature.unwrap_or(args.temp),
+            top_p: self.top_p.unwrap_or(args.top_p),
+            top_k: self.top_k.unwrap_or(args.top_k),
+            penalties: Penalties {
+                frequency: self.frequency_penalty.unwrap_or(0.0),
+                presence: self.presence_penalty.unwrap_or(0.0),
+                ..defaults.penalties
+            },
+            stop_sequences: self.stop.clone(),
+            echo_prompt: false,
+            ..defaults
+        }
+    }
+
+    fn session_parameters(&self) -> InferenceSessionParameters {
+        InferenceSessionParameters {
+            seed: self.seed,
+            ..Default::default()
+        }
+    }
+}
+
+/// Serves the requests one at a time, on the main thread, so the other requests
+/// wait in the listener's queue. Serving them from several threads wouldn't be
+/// faster: they would have to take turns using the model, e.g. with a
+/// [llama_rs::SharedModel].
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .parse_default_env()
+        .init();
+
+    let args = Args::parse();
+    if args.temp < 0.0 || args.top_p <= 0.0 || args.top_p > 1.0 {
+        log::error!("--temp must be at least 0, and --top-p greater than 0 and at most 1");
+        std::process::exit(1);
+    }
+
+    let (model, vocab) = Llama::load_with_parameters(
+        &args.model_path,
+        args.num_ctx_tokens as i32,
+        LoadParameters::default(),
+        |_| {},
+    )
+    .unwrap_or_else(|err| {
+        log::error!("Could not load model: {err}");
+        std::process::exit(1);
+    });
+    let model_name = std::path::Path::new(&args.model_path)
+        .file_name()
+        .map_or_else(
+            || args.model_path.clone(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+
+    let server = Server::http(&args.address).unwrap_or_else(|err| {
+        log::error!("Could not listen on {}: {err}", args.address);
+        std::process::exit(1);
+    });
+    log::info!("Listening on http://{}", args.address);
+
+    for (id, request) in server.incoming_requests().enumerate() {
+        let id = format!("cmpl-{id}");
+        if let Err(err) = handle_request(&args, &model, &vocab, &model_name, &id, request) {
+            log::warn!("Could not respond to {id}: {err}");
+        }
+    }
+}
+
+fn handle_request(
+    args: &Args,
+    model: &Llama,
+    vocab: &Vocabulary,
+    model_name: &str,
+    id: &str,
+    mut request: Request,
+) -> std::io::Result<()> {
+    if request.url() != "/v1/completions" {
+        return request.respond(error_response(404, "not found"));
+    }
+    if request.method() != &Method::Post {
+        return request.respond(error_response(405, "only POST is supported"));
+    }
+
+    let mut body = String::new();
+    request.as_reader().read_to_string(&mut body)?;
+    let completion = match CompletionRequest::parse(&body) {
+        Ok(completion) => completion,
+        Err(err) => return request.respond(error_response(400, &err)),
+    };
+
+    let params = completion.inference_parameters(args);
+    let mut session = model.start_session(completion.session_parameters());
+
+    // A prompt that doesn't fit in the context window is the client's error,
+    // which must be reported before the response starts streaming.
+    match vocab.count_tokens(&completion.prompt, params.adds_bos(vocab)) {
+        Ok(n_tokens) if n_tokens >= session.n_ctx() => {
+            let message = format!(
+                "the prompt has {n_tokens} tokens, which doesn't fit in the context window of {} \
+                 tokens",
+                session.n_ctx()
+            );
+            return request.respond(error_response(400, &message));
+        }
+        Ok(_) => {}
+        Err(err) => return request.respond(error_response(400, &err.to_string())),
+    }
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let choice = |text: &str, finish_reason: Option<&str>| {
+        json!({
+            "id": id,
+            "object": "text_completion",
+            "created": created,
+            "model": model_name,
+            "choices": [{
+                "text": text,
+                "index": 0,
+                "logprobs": null,
+                "finish_reason": finish_reason,
+            }],
+        })
+    };
+
+    if completion.stream {
+        // The events are sent as they are generated, in chunks, so that the
+        // connection can be reused afterwards.
+        let mut writer = request.into_writer();
+        writer.write_all(
+            b"HTTP/1.1 200 OK\r\n\
+              Content-Type: text/event-stream\r\n\
+              Cache-Control: no-cache\r\n\
+              Transfer-Encoding: chunked\r\n\r\n",
+        )?;
+        let writer = RefCell::new(writer);
+        let send_event = |data: &str| {
+            let event = format!("data: {data}\n\n");
+            let mut writer = writer.borrow_mut();
+            write!(writer, "{:x}\r\n{event}\r\n", event.len())?;
+            writer.flush()
+        };
+
+        let res = session.inference_with_prompt(
+            model,
+            vocab,
+            &params,
+            &completion.prompt,
+            Some(completion.max_tokens),
+            |token, _| match token {
+                OutputToken::Token(text) => send_event(&choice(text, None).to_string()),
+                OutputToken::EndOfText => Ok(()),
+            },
+        );
+        let finish_reason = match res {
+            Ok(stats) => finish_reason(&stats.stop_reason),
+            Err(InferenceError::UserCallback(err)) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    err.to_string(),
+                ))
+            }
+            Err(err) => {
+                log::warn!("Could not complete {id}: {err}");
+                "error"
+            }
+        };
+        send_event(&choice("", Some(finish_reason)).to_string())?;
+        send_event("[DONE]")?;
+        let mut writer = writer.into_inner();
+        writer.write_all(b"0\r\n\r\n")?;
+        writer.flush()
+    } else {
+        let text = RefCell::new(String::new());
+        let res = session.inference_with_prompt::<std::convert::Infallible>(
+            model,
+            vocab,
+            &params,
+            &completion.prompt,
+            Some(completion.max_tokens),
+            |token, _| {
+                if let OutputToken::Token(t) = token {
+                    text.borrow_mut().push_str(t);
+                }
+                Ok(())
+            },
+        );
+        let stats = match res {
+            Ok(stats) => stats,
+            Err(err @ InferenceError::ContextFull) => {
+                return request.respond(error_response(400, &err.to_string()))
+            }
+            Err(err) => return request.respond(error_response(500, &err.to_string())),
+        };
+
+        let mut response = choice(&text.into_inner(), Some(finish_reason(&stats.stop_reason)));
+        response["usage"] = json!({
+            "prompt_tokens": stats.prompt_tokens,
+            "completion_tokens": stats.generated_tokens,
+            "total_tokens": stats.prompt_tokens + stats.generated_tokens,
+        });
+        request.respond(json_response(200, &response))
+    }
+}
+
+/// Returns why generation stopped, as reported by the OpenAI API.
+fn finish_reason(stop_reason: &StopReason) -> &'static str {
+    match stop_reason {
+        StopReason::MaxTokens | StopReason::ContextFull => "length",
+        StopReason::EndOfSequence | StopReason::StopSequence(_) | StopReason::Aborted => "stop",
+    }
+}
+
+fn json_response(status: u16, body: &Value) -> Response<std::io::Cursor<Vec<u8>>> {
+    let content_type =
+        Header::from_bytes("Content-Type", "application/json").expect("the header is valid");
+    Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(content_type)
+}
+
+fn error_response(status: u16, message: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+    json_response(status, &json!({ "error": { "message": message } }))
+}
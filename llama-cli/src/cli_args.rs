@@ -1,7 +1,7 @@
 use std::path::PathBuf;
 
 use clap::Parser;
-use llama_rs::TokenBias;
+use llama_rs::{template::PromptTemplate, ContextPolicy, ModelArchitecture, TokenBias};
 use once_cell::sync::Lazy;
 
 #[derive(Parser, Debug)]
@@ -23,13 +23,32 @@ pub struct Args {
     #[arg(long, short = 'R', default_value_t = false)]
     pub repl: bool,
 
+    /// The code before the cursor, for fill-in-the-middle generation with
+    /// code models such as Code Llama: the model generates the code between
+    /// `--in-prefix` and `--in-suffix`. Replaces the prompt and the template.
+    #[arg(long, conflicts_with_all = ["prompt", "prompt_file", "repl"])]
+    pub in_prefix: Option<String>,
+
+    /// The code after the cursor, for fill-in-the-middle generation. See
+    /// `--in-prefix`.
+    #[arg(long, conflicts_with_all = ["prompt", "prompt_file", "repl"])]
+    pub in_suffix: Option<String>,
+
     /// Sets the number of threads to use
     #[arg(long, short = 't', default_value_t = num_cpus::get_physical())]
     pub num_threads: usize,
 
-    /// Sets how many tokens to predict
-    #[arg(long, short = 'n')]
-    pub num_predict: Option<usize>,
+    /// Sets how many tokens to predict. With -1, the default, generation only
+    /// stops at the end of text token, at a stop sequence, or when the context
+    /// window is full.
+    #[arg(
+        long,
+        short = 'n',
+        default_value_t = -1,
+        value_parser = clap::value_parser!(i64).range(-1..),
+        allow_negative_numbers = true
+    )]
+    pub num_predict: i64,
 
     /// Sets the size of the context (in tokens). Allows feeding longer prompts.
     /// Note that this affects memory. TODO: Unsure how large the limit is.
@@ -41,8 +60,8 @@ pub struct Args {
     #[arg(long, default_value_t = 8)]
     pub batch_size: usize,
 
-    /// Size of the 'last N' buffer that is used for the `repeat_penalty`
-    /// option. In tokens.
+    /// Size of the 'last N' buffer that is used for the `repeat_penalty`,
+    /// `frequency_penalty` and `presence_penalty` options. In tokens.
     #[arg(long, default_value_t = 64)]
     pub repeat_last_n: usize,
 
@@ -52,16 +71,28 @@ pub struct Args {
     #[arg(long, default_value_t = 1.30)]
     pub repeat_penalty: f32,
 
-    /// Temperature
+    /// Subtracted from the logit of each token once per time it occurs in the
+    /// 'last N' buffer.
+    #[arg(long, default_value_t = 0.0)]
+    pub frequency_penalty: f32,
+
+    /// Subtracted from the logit of each token that occurs in the 'last N'
+    /// buffer.
+    #[arg(long, default_value_t = 0.0)]
+    pub presence_penalty: f32,
+
+    /// Temperature. 0 always picks the most likely token, which makes the
+    /// output deterministic.
     #[arg(long, default_value_t = 0.80)]
     pub temp: f32,
 
-    /// Top-K: The top K words by score are kept during sampling.
+    /// Top-K: The top K words by score are kept during sampling. 0 keeps all
+    /// words.
     #[arg(long, default_value_t = 40)]
     pub top_k: usize,
 
     /// Top-p: The cumulative probability after which no more words are kept
-    /// for sampling.
+    /// for sampling. 1.0 keeps all words.
     #[arg(long, default_value_t = 0.95)]
     pub top_p: f32,
 
@@ -85,15 +116,35 @@ pub struct Args {
     #[arg(long, default_value = None)]
     pub persist_session: Option<PathBuf>,
 
+    /// A directory in which the state of the model after evaluating the
+    /// prompt is cached, so that running the same prompt with the same model
+    /// again skips its evaluation. Ignored when loading a session, in REPL
+    /// mode, and with `--token-healing` or `--cfg-scale`.
+    #[arg(long, default_value = None)]
+    pub prompt_cache: Option<PathBuf>,
+
+    /// Appends the tokens of the generation to the given file as they are
+    /// generated. If the file exists, its tokens are evaluated again and the
+    /// generation continues where it stopped, instead of starting from the
+    /// prompt. The file is refused if it was written with another model.
+    #[arg(
+        long = "continue",
+        default_value = None,
+        conflicts_with_all = ["repl", "load_session", "persist_session", "save_session", "json"]
+    )]
+    pub continue_from: Option<PathBuf>,
+
     /// Specifies the seed to use during sampling. Note that, depending on
     /// hardware, the same seed may lead to different results on two separate
-    /// machines.
-    #[arg(long, default_value = None)]
-    pub seed: Option<u64>,
+    /// machines. With -1, a random seed is used and logged, so that the run
+    /// can be reproduced.
+    #[arg(long, default_value = "-1", value_parser = parse_seed, allow_negative_numbers = true)]
+    pub seed: Seed,
 
-    /// Use 16-bit floats for model memory key and value. Ignored when restoring
-    /// from the cache.
-    #[arg(long, default_value_t = false)]
+    /// Use 16-bit floats for model memory key and value. This halves the
+    /// memory used by the context window. Ignored when restoring from the
+    /// cache.
+    #[arg(long, visible_alias = "memory-f16", default_value_t = false)]
     pub float16: bool,
 
     /// A comma separated list of token biases. The list should be in the format
@@ -105,22 +156,327 @@ pub struct Args {
     #[arg(long, default_value = None, value_parser = parse_bias)]
     pub token_bias: Option<TokenBias>,
 
+    /// Prints the prompt in a different color than the generated text. Colors
+    /// are only used when the output is a terminal and `NO_COLOR` is not set.
+    #[arg(long, default_value_t = false)]
+    pub color: bool,
+
     /// Prevent the end of stream (EOS/EOD) token from being generated. This will allow the
-    /// model to generate text until it runs out of context space. Note: The --token-bias
-    /// option will override this if specified.
+    /// model to generate text until it reaches the token limit or runs out of context space.
     #[arg(long, default_value_t = false)]
     pub ignore_eos: bool,
 
+    /// Starts the prompt with the beginning-of-sentence token, even if the
+    /// architecture of the model doesn't.
+    #[arg(long, default_value_t = false, conflicts_with = "no_bos")]
+    pub add_bos: bool,
+
+    /// Doesn't start the prompt with the beginning-of-sentence token, for
+    /// fine-tunes that were trained without it.
+    #[arg(long, default_value_t = false)]
+    pub no_bos: bool,
+
+    /// If the prompt ends with a partial token (such as the beginning of a
+    /// word), remove it and make the first generated token start with it.
+    #[arg(long, default_value_t = false)]
+    pub token_healing: bool,
+
+    /// Stops generation when the generated text contains this string. Can be
+    /// specified multiple times.
+    #[arg(long = "stop", value_name = "STRING")]
+    pub stop_sequences: Vec<String>,
+
+    /// A file containing a grammar (in a subset of the GBNF format of
+    /// llama.cpp) that the generated text must match.
+    #[arg(long)]
+    pub grammar_file: Option<String>,
+
+    /// A prompt to steer generation away from, using classifier-free
+    /// guidance. This doubles the memory and time needed for inference.
+    #[arg(long)]
+    pub cfg_negative_prompt: Option<String>,
+
+    /// The strength of the classifier-free guidance. 1.0 disables guidance.
+    #[arg(long, default_value_t = 1.0)]
+    pub cfg_scale: f32,
+
+    /// The template to wrap the prompt in for chat and instruction-tuned
+    /// models: "raw", "alpaca", "vicuna", "chatml", or a custom format in which
+    /// "{system}" and "{prompt}" are replaced by the system prompt and the
+    /// prompt. In REPL mode, each input line is wrapped. Defaults to the
+    /// template of the model's architecture (see `--arch`).
+    #[arg(long, value_parser = parse_template)]
+    pub template: Option<PromptTemplate>,
+
+    /// The system prompt used by the template. Defaults to the template's own
+    /// system prompt.
+    #[arg(long)]
+    pub system_prompt: Option<String>,
+
+    /// When the context window is full, keep this many tokens from its start
+    /// and discard half of the rest, instead of stopping generation.
+    #[arg(long = "keep", value_name = "N_TOKENS")]
+    pub n_keep: Option<usize>,
+
+    /// When the context window is full, discard only as many of the oldest
+    /// tokens as needed for the next one, instead of stopping generation.
+    /// This evaluates the whole window again for every token.
+    #[arg(long, conflicts_with = "n_keep")]
+    pub sliding_window: bool,
+
+    /// Prints the prompt before the generated text. This is the default.
+    #[arg(long, overrides_with = "no_keep_prompt")]
+    pub keep_prompt: bool,
+
+    /// Only prints the generated text, without the prompt.
+    #[arg(long, overrides_with = "keep_prompt")]
+    pub no_keep_prompt: bool,
+
+    /// Load the parts of a multi-part model in parallel, using one thread per
+    /// part.
+    #[arg(long, default_value_t = false)]
+    pub parallel_load: bool,
+
+    /// Read the whole model into memory instead of memory-mapping it. Memory
+    /// mapping is only used for single-part GGJT models, and can be slower on
+    /// spinning disks or network filesystems.
+    #[arg(long, default_value_t = false)]
+    pub no_mmap: bool,
+
+    /// Hash the data of each tensor as it is loaded, and log the hash of the
+    /// whole model, to tell whether a model file is corrupted. The hashes of
+    /// the tensors are logged at trace level (`RUST_LOG=trace`).
+    #[arg(long, default_value_t = false)]
+    pub checksum: bool,
+
+    /// Skip the tensors of the model file that the model doesn't use, such
+    /// as those added by newer conversion scripts, instead of failing to
+    /// load it.
+    #[arg(long, default_value_t = false)]
+    pub skip_unknown_tensors: bool,
+
+    /// A LoRA adapter (in the ggla format of llama.cpp) to apply to the
+    /// model's weights after loading. Implies `--no-mmap`, and only works
+    /// with f32 and f16 models.
+    #[arg(long)]
+    pub lora: Option<PathBuf>,
+
+    /// The base of the frequencies of the rotary position embeddings.
+    /// Increasing it lets the model use a longer context than it was trained
+    /// with (NTK-aware scaling).
+    #[arg(long, default_value_t = 10000.0)]
+    pub rope_freq_base: f32,
+
+    /// The factor the token positions are multiplied by in the rotary
+    /// position embeddings. For example, 0.5 lets the model use twice the
+    /// context it was trained with (linear scaling).
+    #[arg(long, default_value_t = 1.0)]
+    pub rope_freq_scale: f32,
+
+    /// The number of layers to offload to the GPU. No GPU backend is
+    /// available yet, so this must be 0.
+    #[arg(long, default_value_t = 0)]
+    pub n_gpu_layers: usize,
+
+    /// The architecture of the model ("llama", "alpaca" or "vicuna"), which
+    /// picks the default `--template`. Detected from the vocabulary if not
+    /// specified, which can only tell Alpaca models apart from LLaMA.
+    #[arg(long, value_parser = parse_architecture)]
+    pub arch: Option<ModelArchitecture>,
+
+    /// Interleaves the model's memory across the NUMA nodes of multi-socket
+    /// machines, like `numactl --interleave=all` (Linux only). If the model is
+    /// memory-mapped, this only applies if it is not already in the page
+    /// cache. Unlike llama.cpp's `--numa`, the threads are not pinned to a
+    /// node and the memory is not placed by the first thread to touch it.
+    #[arg(long, default_value_t = false)]
+    pub numa: bool,
+
+    /// Runs the model once on a single token after loading it, to check that
+    /// it works before starting the generation.
+    #[arg(long, default_value_t = false)]
+    pub validate: bool,
+
+    /// Prints the model's metadata (hyperparameters, file format, vocabulary
+    /// size and estimated memory usage) and exits, without loading the
+    /// tensors.
+    #[arg(long, default_value_t = false)]
+    pub info: bool,
+
+    /// Measures the speed of prompt evaluation and generation with a
+    /// synthetic prompt and a fixed seed, prints the peak memory of the
+    /// process on Linux, and exits. The prompt options are ignored.
+    #[arg(long, default_value_t = false)]
+    pub bench: bool,
+
+    /// The number of prompt tokens evaluated by `--bench`.
+    #[arg(long, default_value_t = 128)]
+    pub bench_prompt_tokens: usize,
+
+    /// The number of tokens generated by `--bench`.
+    #[arg(long, default_value_t = 32)]
+    pub bench_gen_tokens: usize,
+
+    /// The numbers of threads to run `--bench` with, separated by commas.
+    /// Defaults to `--num-threads`.
+    #[arg(long, value_delimiter = ',')]
+    pub bench_threads: Vec<usize>,
+
+    /// Measures how long the model takes to load with its parts read one
+    /// after the other and in parallel, and exits. The model is loaded once
+    /// before, so that it is in the page cache for both.
+    #[arg(long, default_value_t = false)]
+    pub bench_load: bool,
+
+    /// The number of times each way of loading is timed by `--bench-load`.
+    #[arg(long, default_value_t = 3)]
+    pub bench_load_runs: usize,
+
+    /// Checks the dimensions and types of the model's tensors against its
+    /// hyperparameters, without loading them, and exits.
+    #[arg(long, default_value_t = false)]
+    pub verify: bool,
+
+    /// Prints the output of `--info` as JSON. When generating, prints a
+    /// single JSON object at the end with the generated text and tokens,
+    /// the timings and why generation stopped, instead of streaming the text.
+    #[arg(long, default_value_t = false)]
+    pub json: bool,
+
     /// Dumps the prompt to console and exits, first as a comma seperated list of token IDs
     /// and then as a list of comma seperated string keys and token ID values.
     #[arg(long, default_value_t = false)]
     pub dump_prompt_tokens: bool,
+
+    /// Prints the id and text of every token of the vocabulary that contains
+    /// this text, and exits.
+    #[arg(long)]
+    pub find_token: Option<String>,
+
+    /// Writes the graph that evaluates the prompt to this file in the Graphviz
+    /// DOT format, and exits. Render it with `dot -Tsvg <file> -o graph.svg`.
+    #[arg(long)]
+    pub dump_graph: Option<PathBuf>,
+
+    /// Prints each token of the prompt with its id before generating, to
+    /// debug its tokenization.
+    #[arg(long, default_value_t = false)]
+    pub verbose_prompt: bool,
+
+    /// Loads the model, prints the name, type, shape and size of each of its
+    /// tensors, and exits.
+    #[arg(long, default_value_t = false)]
+    pub list_tensors: bool,
+
+    /// Loads the model, prints the first values of the tensor with this name
+    /// (see `--list-tensors`) dequantized to f32, and their minimum, maximum
+    /// and mean, and exits. Useful to compare the weights with those loaded
+    /// by another implementation.
+    #[arg(long)]
+    pub dump_tensor: Option<String>,
+
+    /// The number of values printed by `--dump-tensor`.
+    #[arg(long, default_value_t = 16)]
+    pub dump_count: usize,
+
+    /// Prints the instruction sets that ggml's kernels were compiled to use,
+    /// and exits.
+    #[arg(long, default_value_t = false)]
+    pub print_cpu_features: bool,
+}
+
+impl Args {
+    /// Returns the policy for a full context window set by `--keep` or
+    /// `--sliding-window`.
+    pub fn context_policy(&self) -> ContextPolicy {
+        match self.n_keep {
+            Some(n_keep) => ContextPolicy::DiscardOldest { n_keep },
+            None if self.sliding_window => ContextPolicy::SlidingWindow,
+            None => ContextPolicy::Error,
+        }
+    }
+
+    /// Returns whether fill-in-the-middle generation was requested with
+    /// `--in-prefix` or `--in-suffix`.
+    pub fn fim(&self) -> bool {
+        self.in_prefix.is_some() || self.in_suffix.is_some()
+    }
+
+    /// Returns the maximum number of tokens to generate set by
+    /// `--num-predict`, or `None` if generation is unlimited.
+    pub fn num_predict(&self) -> Option<usize> {
+        usize::try_from(self.num_predict).ok()
+    }
+
+    /// Returns whether the prompt starts with the beginning-of-sentence token
+    /// as set by `--add-bos` or `--no-bos`, or `None` to follow the
+    /// architecture of the model.
+    pub fn add_bos(&self) -> Option<bool> {
+        match (self.add_bos, self.no_bos) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// The seed set with `--seed`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Seed {
+    /// A random seed, set with -1.
+    Random,
+    Fixed(u64),
+}
+impl Seed {
+    /// Returns the seed to use: the fixed one, or a new random one.
+    pub fn resolve(self) -> u64 {
+        match self {
+            Seed::Fixed(seed) => seed,
+            Seed::Random => rand::random(),
+        }
+    }
+}
+
+fn parse_seed(s: &str) -> Result<Seed, String> {
+    match s {
+        "-1" => Ok(Seed::Random),
+        _ => s
+            .parse()
+            .map(Seed::Fixed)
+            .map_err(|err| format!("the seed must be -1 or a non-negative integer: {err}")),
+    }
 }
 
 fn parse_bias(s: &str) -> Result<TokenBias, String> {
     s.parse()
 }
 
+fn parse_architecture(s: &str) -> Result<ModelArchitecture, String> {
+    s.parse()
+}
+
+fn parse_template(s: &str) -> Result<PromptTemplate, String> {
+    s.parse()
+}
+
 /// CLI args are stored in a lazy static variable so they're accessible from
 /// everywhere. Arguments are parsed on first access.
 pub static CLI_ARGS: Lazy<Args> = Lazy::new(Args::parse);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeds_are_fixed_or_random() {
+        assert_eq!(parse_seed("42"), Ok(Seed::Fixed(42)));
+        assert_eq!(parse_seed("-1"), Ok(Seed::Random));
+        assert!(parse_seed("-2").is_err());
+        assert!(parse_seed("abc").is_err());
+
+        assert_eq!(Seed::Fixed(42).resolve(), 42);
+        assert_eq!(Seed::Fixed(0).resolve(), 0);
+        // Two random seeds are equal with a probability of 2^-64.
+        assert_ne!(Seed::Random.resolve(), Seed::Random.resolve());
+    }
+}
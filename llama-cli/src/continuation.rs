@@ -0,0 +1,182 @@
+//! Writes the tokens of a generation to a file as they are generated, so that
+//! a long generation that was interrupted can be continued with
+//! `--continue`, by evaluating its tokens again. Unlike a saved session, the
+//! file is small and always up to date.
+//!
+//! The file starts with a header identifying the model, followed by one token
+//! id per line.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+use llama_rs::{
+    Detokenizer, InferenceParameters, InferenceSession, LanguageModel, Llama, StopReason,
+    Vocabulary,
+};
+
+use crate::{cli_args::CLI_ARGS, color_enabled, print_token, prompt_cache::Fnv1a, GENERATED_COLOR};
+
+const HEADER: &str = "llama-rs tokens";
+
+/// Feeds the tokens of the file at `path` to `session`, which must be new, or
+/// `prompt` if the file doesn't exist yet, then generates text and appends
+/// its tokens to the file. Exits if the file can't be used.
+pub fn generate(
+    path: &Path,
+    model: &Llama,
+    vocab: &Vocabulary,
+    params: &InferenceParameters,
+    session: &mut InferenceSession,
+    prompt: &str,
+) {
+    let color = color_enabled();
+    let header = format!("{HEADER} {:016x}", model_id(model, vocab));
+
+    let mut file = if path.exists() {
+        let tokens = read_tokens(path, &header).unwrap_or_else(|err| {
+            log::error!("Could not continue from {path:?}: {err}");
+            std::process::exit(1);
+        });
+        if tokens.last() == Some(&vocab.eos_token()) {
+            log::info!("The generation in {path:?} already ended");
+            return;
+        }
+        log::info!("Continuing the {} tokens of {path:?}", tokens.len());
+        if let Err(err) = session.feed_tokens(model, params, &tokens) {
+            log::error!("Could not evaluate the tokens of {path:?}: {err}");
+            std::process::exit(1);
+        }
+
+        // The text so far is printed like the prompt.
+        let mut detokenizer = Detokenizer::new(vocab);
+        for &token in &tokens {
+            if let Some(text) = detokenizer.push(token) {
+                print_token(text, true, color);
+            }
+        }
+        open(path, true)
+    } else {
+        let res = session.feed_prompt::<std::convert::Infallible>(
+            model,
+            vocab,
+            params,
+            prompt,
+            |token| {
+                print_token(token, true, color);
+                Ok(())
+            },
+        );
+        if let Err(err) = res {
+            log::error!("Could not feed the prompt: {err}");
+            std::process::exit(1);
+        }
+        // The prompt is saved with the generated tokens, so that it isn't
+        // needed to continue.
+        let mut file = open(path, false);
+        write_or_exit(&mut file, path, &format!("{header}\n"));
+        write_tokens(&mut file, path, session.tokens());
+        file
+    };
+
+    let mut n_written = session.tokens().len();
+    let mut stream = session.stream_text(model, vocab, params);
+    let mut n_generated = 0;
+    let max_tokens = CLI_ARGS.num_predict().unwrap_or(usize::MAX);
+    while n_generated < max_tokens {
+        let Some(chunk) = stream.next() else {
+            break;
+        };
+
+        // Several tokens can be generated before a chunk of text is complete.
+        let tokens = &stream.session().tokens()[n_written..];
+        write_tokens(&mut file, path, tokens);
+        n_written += tokens.len();
+        n_generated += tokens.len();
+
+        match chunk {
+            Ok(text) => print_token(text, false, color),
+            Err(err) => {
+                log::error!("{err}");
+                break;
+            }
+        }
+    }
+    if color {
+        print!("{GENERATED_COLOR}");
+    }
+    println!();
+
+    if stream.stop_reason() == Some(&StopReason::ContextFull) {
+        log::warn!("Context window full, stopping inference.");
+    }
+}
+
+/// Returns a hash of the hyperparameters and of the vocabulary of the model,
+/// as the tokens of another model are meaningless.
+fn model_id(model: &Llama, vocab: &Vocabulary) -> u64 {
+    let mut hash = Fnv1a::default();
+    hash.write(format!("{:?}", model.hparams()).as_bytes());
+    for id in 0..vocab.len() as i32 {
+        hash.write(vocab.token_bytes(id).unwrap_or_default());
+        hash.write(&[0]);
+    }
+    hash.0
+}
+
+/// Reads the tokens of the file at `path`, which must start with `header`.
+fn read_tokens(path: &Path, header: &str) -> Result<Vec<i32>, String> {
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let mut lines = BufReader::new(file).lines();
+
+    let first_line = lines
+        .next()
+        .transpose()
+        .map_err(|err| err.to_string())?
+        .unwrap_or_default();
+    if !first_line.starts_with(HEADER) {
+        return Err("it is not a token file".to_owned());
+    }
+    if first_line != header {
+        return Err("its tokens are those of another model".to_owned());
+    }
+
+    lines
+        .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|line| {
+            let line = line.map_err(|err| err.to_string())?;
+            line.trim()
+                .parse()
+                .map_err(|_| format!("invalid token {line:?}"))
+        })
+        .collect()
+}
+
+fn open(path: &Path, append: bool) -> File {
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .unwrap_or_else(|err| {
+            log::error!("Could not open {path:?}: {err}");
+            std::process::exit(1);
+        })
+}
+
+fn write_tokens(file: &mut File, path: &Path, tokens: &[i32]) {
+    let lines: String = tokens.iter().map(|token| format!("{token}\n")).collect();
+    write_or_exit(file, path, &lines);
+}
+
+/// Writes `text` to `file` right away, so that it is not lost if the process
+/// is killed.
+fn write_or_exit(file: &mut File, path: &Path, text: &str) {
+    if let Err(err) = file.write_all(text.as_bytes()).and_then(|()| file.flush()) {
+        log::error!("Could not write to {path:?}: {err}");
+        std::process::exit(1);
+    }
+}
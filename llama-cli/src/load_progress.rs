@@ -0,0 +1,115 @@
+//! Reports the progress of loading the model's tensors. With the `progress`
+//! feature, this is a progress bar showing the amount of data loaded, the
+//! percentage and the throughput. Otherwise, progress is logged every few
+//! tensors.
+
+use std::path::Path;
+#[cfg(feature = "progress")]
+use std::{cell::RefCell, collections::HashMap, path::PathBuf};
+
+#[cfg(feature = "progress")]
+pub struct LoadProgressReporter {
+    bar: indicatif::ProgressBar,
+    /// The number of bytes loaded so far from each part.
+    part_bytes: RefCell<HashMap<PathBuf, usize>>,
+}
+
+#[cfg(feature = "progress")]
+impl LoadProgressReporter {
+    pub fn new() -> Self {
+        let bar = indicatif::ProgressBar::hidden();
+        bar.set_style(
+            indicatif::ProgressStyle::with_template(
+                "{msg} [{bar:40}] {bytes}/{total_bytes} ({percent}%) {binary_bytes_per_sec}",
+            )
+            .unwrap()
+            .progress_chars("=> "),
+        );
+
+        Self {
+            bar,
+            part_bytes: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Shows the progress bar, with the estimated total size of the tensors.
+    pub fn start(&self, total_bytes: usize) {
+        self.bar.set_length(total_bytes as u64);
+        self.bar
+            .set_draw_target(indicatif::ProgressDrawTarget::stderr());
+    }
+
+    pub fn part_loading(&self, _file: &Path, current_part: usize, total_parts: usize) {
+        self.bar
+            .set_message(format!("part {current_part}/{total_parts}"));
+    }
+
+    pub fn tensor_loaded(
+        &self,
+        file: &Path,
+        _current_tensor: usize,
+        _tensor_count: usize,
+        bytes_loaded: usize,
+    ) {
+        let mut part_bytes = self.part_bytes.borrow_mut();
+        part_bytes.insert(file.to_owned(), bytes_loaded);
+        self.bar
+            .set_position(part_bytes.values().sum::<usize>() as u64);
+    }
+
+    /// Runs `f` with the progress bar hidden, so that it can log.
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.bar.suspend(f)
+    }
+
+    /// Removes the progress bar. If loading failed, the bar is left where it
+    /// stopped instead of being filled.
+    pub fn finish(&self, success: bool) {
+        if success {
+            // The total is only an estimate, so make sure the bar ends up full.
+            self.bar.set_length(self.bar.position());
+            self.bar.finish();
+        } else {
+            self.bar.abandon();
+        }
+    }
+}
+
+#[cfg(not(feature = "progress"))]
+pub struct LoadProgressReporter;
+
+#[cfg(not(feature = "progress"))]
+impl LoadProgressReporter {
+    pub fn new() -> Self {
+        Self
+    }
+
+    pub fn start(&self, _total_bytes: usize) {}
+
+    pub fn part_loading(&self, file: &Path, current_part: usize, total_parts: usize) {
+        log::info!(
+            "Loading model part {}/{} from '{}'\n",
+            current_part,
+            total_parts,
+            file.to_string_lossy(),
+        )
+    }
+
+    pub fn tensor_loaded(
+        &self,
+        _file: &Path,
+        current_tensor: usize,
+        tensor_count: usize,
+        _bytes_loaded: usize,
+    ) {
+        if current_tensor % 8 == 0 {
+            log::info!("Loaded tensor {current_tensor}/{tensor_count}");
+        }
+    }
+
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        f()
+    }
+
+    pub fn finish(&self, _success: bool) {}
+}
@@ -1,30 +1,129 @@
-use std::{convert::Infallible, io::Write, path::Path};
+use std::{cell::RefCell, convert::Infallible, io::Write, path::Path};
 
-use cli_args::CLI_ARGS;
+use cli_args::{Seed, CLI_ARGS};
+use is_terminal::IsTerminal;
 use llama_rs::{
-    InferenceError, InferenceParameters, InferenceSession, InferenceSessionParameters, Model,
-    ModelKVMemoryType, TokenBias, Vocabulary, EOD_TOKEN_ID,
+    grammar::Grammar,
+    template::{self, PromptTemplate},
+    ContextPolicy, GgmlType, InferenceError, InferenceParameters, InferenceSession,
+    InferenceSessionParameters, InferenceStats, Llama, LoadParameters, LoadStrategy,
+    ModelKVMemoryType, Penalties, StopReason, Vocabulary,
 };
-use rand::{thread_rng, SeedableRng};
 use rustyline::error::ReadlineError;
 
+mod bench;
 mod cli_args;
+mod continuation;
+mod load_progress;
+mod numa;
+mod prompt_cache;
+
+/// Returns the template given by `--template`, or else the default template
+/// of the model's architecture.
+fn prompt_template(model: &Llama) -> PromptTemplate {
+    CLI_ARGS
+        .template
+        .clone()
+        .unwrap_or_else(|| model.architecture().default_template())
+}
+
+/// Wraps the prompt in the template of [prompt_template].
+fn render_prompt(model: &Llama, prompt: &str) -> String {
+    prompt_template(model).render(CLI_ARGS.system_prompt.as_deref(), prompt)
+}
+
+/// The ANSI escape codes that start the colors of the prompt and of the
+/// generated text with `--color`.
+const PROMPT_COLOR: &str = "\x1b[32m";
+const GENERATED_COLOR: &str = "\x1b[0m";
+
+/// Returns whether the output is colored: `--color` was given, the output is a
+/// terminal, and `NO_COLOR` is not set (see <https://no-color.org>).
+fn color_enabled() -> bool {
+    use_color(
+        CLI_ARGS.color,
+        std::io::stdout().is_terminal(),
+        std::env::var_os("NO_COLOR").as_deref(),
+    )
+}
+
+/// Returns whether the output is colored, given the `--color` flag, whether
+/// the output is a terminal and the value of `NO_COLOR`.
+fn use_color(flag: bool, is_terminal: bool, no_color: Option<&std::ffi::OsStr>) -> bool {
+    flag && is_terminal && no_color.map_or(true, |value| value.is_empty())
+}
+
+/// Formats a token of the output, in the color of the prompt or of the
+/// generated text if `color` is set.
+fn format_token(token: impl std::fmt::Display, is_prompt: bool, color: bool) -> String {
+    if color {
+        let code = if is_prompt {
+            PROMPT_COLOR
+        } else {
+            GENERATED_COLOR
+        };
+        format!("{code}{token}")
+    } else {
+        token.to_string()
+    }
+}
+
+/// Prints a token of the output, see [format_token].
+fn print_token(token: impl std::fmt::Display, is_prompt: bool, color: bool) {
+    print!("{}", format_token(token, is_prompt, color));
+    std::io::stdout().flush().unwrap();
+}
+
+/// Prints the result of a generation as a single JSON object, for `--json`.
+fn print_generation_json(
+    text: &str,
+    session_tokens: &[i32],
+    res: &Result<InferenceStats, InferenceError>,
+) {
+    let (n_prompt, n_generated, timings, stop_reason) = match res {
+        Ok(stats) => (
+            Some(stats.prompt_tokens),
+            stats.generated_tokens,
+            serde_json::json!({
+                "feed_prompt_ms": stats.feed_prompt_duration.as_secs_f64() * 1000.0,
+                "predict_ms": stats.predict_duration.as_secs_f64() * 1000.0,
+            }),
+            match stats.stop_reason {
+                StopReason::EndOfSequence => "eos",
+                StopReason::MaxTokens => "length",
+                StopReason::StopSequence(_) => "stop_sequence",
+                StopReason::Aborted => "aborted",
+                StopReason::ContextFull => "context_full",
+            },
+        ),
+        Err(_) => (None, 0, serde_json::Value::Null, "error"),
+    };
+    let generated_tokens = &session_tokens[session_tokens.len().saturating_sub(n_generated)..];
+    let output = serde_json::json!({
+        "text": text,
+        "tokens": generated_tokens,
+        "n_prompt": n_prompt,
+        "n_generated": n_generated,
+        "timings": timings,
+        "stop_reason": stop_reason,
+    });
+    println!("{output}");
+}
 
 fn repl_mode(
     prompt: &str,
-    model: &llama_rs::Model,
+    model: &llama_rs::Llama,
     vocab: &llama_rs::Vocabulary,
     params: &InferenceParameters,
-    session_params: &InferenceSessionParameters,
     mut session: InferenceSession,
 ) {
     let mut rl = rustyline::DefaultEditor::new().unwrap();
+    let color = color_enabled();
     loop {
         let readline = rl.readline(">> ");
         match readline {
             Ok(line) => {
-                let prompt = prompt.replace("$PROMPT", &line);
-                let mut rng = thread_rng();
+                let prompt = render_prompt(model, &prompt.replace("$PROMPT", &line));
 
                 let mut sp = spinners::Spinner::new(spinners::Spinners::Dots2, "".to_string());
                 if let Err(InferenceError::ContextFull) =
@@ -39,18 +138,18 @@ fn repl_mode(
                     vocab,
                     params,
                     "",
-                    CLI_ARGS.num_predict,
-                    &mut rng,
-                    |tk| {
-                        print!("{tk}");
-                        std::io::stdout().flush().unwrap();
+                    CLI_ARGS.num_predict(),
+                    |tk, is_prompt| {
+                        print_token(tk, is_prompt, color);
                         Ok(())
                     },
                 );
                 println!();
 
-                if let Err(InferenceError::ContextFull) = res {
-                    log::error!("Reply exceeds context window length");
+                if let Ok(stats) = res {
+                    if stats.stop_reason == StopReason::ContextFull {
+                        log::error!("Reply exceeds context window length");
+                    }
                 }
             }
             Err(ReadlineError::Eof) | Err(ReadlineError::Interrupted) => {
@@ -89,6 +188,181 @@ fn dump_tokens(text: &str, vocab: &Vocabulary) -> Result<(), InferenceError> {
     Ok(())
 }
 
+fn print_model_info(
+    model_path: &str,
+    n_ctx: i32,
+    session_params: &InferenceSessionParameters,
+    json: bool,
+) {
+    let header = match Llama::load_header_only(model_path, n_ctx) {
+        Ok(header) => header,
+        Err(err) => {
+            log::error!("Could not read model metadata: {err}");
+            std::process::exit(1);
+        }
+    };
+    let hparams = &header.hparams;
+
+    let weight_type = GgmlType::try_from(hparams.f16_)
+        .map_or_else(|_| "unknown".to_owned(), |typ| typ.to_string());
+    let estimated_bytes = match hparams.estimated_ctx_size() {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            log::error!("Could not estimate the model's memory usage: {err}");
+            std::process::exit(1);
+        }
+    };
+    let estimated_session_bytes = hparams.estimated_session_ctx_size(session_params);
+
+    if json {
+        let info = serde_json::json!({
+            "file_format": header.file_format.to_string(),
+            "hyperparameters": hparams,
+            "n_ff": hparams.n_ff(),
+            "n_parts": header.part_paths.len(),
+            "weight_type": weight_type,
+            "vocabulary_size": header.vocabulary.len(),
+            "estimated_memory_bytes": estimated_bytes,
+            "estimated_session_memory_bytes": estimated_session_bytes,
+        });
+        println!("{info:#}");
+    } else {
+        println!("file format: {}", header.file_format);
+        println!("hyperparameters: {hparams:#?}");
+        println!("n_ff: {}", hparams.n_ff());
+        println!("n_parts: {}", header.part_paths.len());
+        println!("weight type: {weight_type}");
+        println!("vocabulary size: {}", header.vocabulary.len());
+        println!(
+            "estimated memory: {:.2} MB",
+            estimated_bytes as f64 / 1024.0 / 1024.0
+        );
+        println!(
+            "estimated session memory: {:.2} MB",
+            estimated_session_bytes as f64 / 1024.0 / 1024.0
+        );
+    }
+}
+
+fn verify_model(model_path: &str) {
+    let report = match Llama::verify(model_path) {
+        Ok(report) => report,
+        Err(err) => {
+            log::error!("Could not read model metadata: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    for mismatch in &report.mismatches {
+        println!("{mismatch}");
+    }
+    if report.is_ok() {
+        println!("{} tensors checked, no problems found", report.tensor_count);
+    } else {
+        println!(
+            "{} tensors checked, {} problems found",
+            report.tensor_count,
+            report.mismatches.len()
+        );
+        std::process::exit(1);
+    }
+}
+
+fn find_token(model_path: &str, n_ctx: i32, needle: &str) {
+    let header = Llama::load_header_only(model_path, n_ctx).unwrap_or_else(|err| {
+        log::error!("Could not read model metadata: {err}");
+        std::process::exit(1);
+    });
+
+    let matches = header.vocabulary.search(needle);
+    for &(id, text) in &matches {
+        println!("{id:>6} {text:?}");
+    }
+    log::info!("{} tokens contain {needle:?}", matches.len());
+}
+
+fn list_tensors(model_path: &str, n_ctx: i32, load_params: LoadParameters) {
+    let (model, _) = Llama::load_with_parameters(model_path, n_ctx, load_params, |_| {})
+        .unwrap_or_else(|err| {
+            log::error!("Could not load model: {err}");
+            std::process::exit(1);
+        });
+
+    for info in model.tensor_info() {
+        let shape: Vec<_> = info.shape.iter().map(i64::to_string).collect();
+        println!(
+            "{:<40} {:>5} {:>12} {:>12}",
+            info.name,
+            info.dtype.to_string(),
+            shape.join(" x "),
+            info.nbytes
+        );
+    }
+}
+
+fn dump_tensor(model_path: &str, n_ctx: i32, load_params: LoadParameters, name: &str) {
+    let (model, _) = Llama::load_with_parameters(model_path, n_ctx, load_params, |_| {})
+        .unwrap_or_else(|err| {
+            log::error!("Could not load model: {err}");
+            std::process::exit(1);
+        });
+
+    let info = model.tensor_info();
+    let Some(tensor) = info.iter().find(|info| info.name == name) else {
+        let mut close_matches: Vec<_> = info
+            .iter()
+            .map(|info| (edit_distance(name, &info.name), &info.name))
+            .filter(|&(distance, _)| distance <= name.len() / 3)
+            .collect();
+        close_matches.sort();
+        let close_matches: Vec<_> = close_matches
+            .iter()
+            .take(5)
+            .map(|(_, name)| name.as_str())
+            .collect();
+        match close_matches.is_empty() {
+            true => log::error!("The model has no tensor {name:?}, see --list-tensors"),
+            false => log::error!(
+                "The model has no tensor {name:?}, did you mean {}?",
+                close_matches.join(", ")
+            ),
+        }
+        std::process::exit(1);
+    };
+    let Some(values) = model.tensor_to_f32(name) else {
+        log::error!(
+            "The tensor {name:?} has type {}, which can't be converted to f32",
+            tensor.dtype
+        );
+        std::process::exit(1);
+    };
+
+    let shape: Vec<_> = tensor.shape.iter().map(i64::to_string).collect();
+    println!("{name} ({}, {})", tensor.dtype, shape.join(" x "));
+    for (i, value) in values.iter().take(CLI_ARGS.dump_count).enumerate() {
+        println!("{i:>6} {value:>14.8}");
+    }
+    let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mean = values.iter().map(|&value| f64::from(value)).sum::<f64>() / values.len() as f64;
+    println!("min = {min}, max = {max}, mean = {mean}");
+}
+
+/// Returns the Levenshtein distance between `a` and `b`, in bytes.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let mut distances: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.bytes().enumerate() {
+        let mut previous = distances[0];
+        distances[0] = i + 1;
+        for (j, cb) in b.bytes().enumerate() {
+            let substitution = previous + usize::from(ca != cb);
+            previous = distances[j + 1];
+            distances[j + 1] = substitution.min(previous + 1).min(distances[j] + 1);
+        }
+    }
+    distances[b.len()]
+}
+
 fn main() {
     env_logger::builder()
         .filter_level(log::LevelFilter::Info)
@@ -97,23 +371,14 @@ fn main() {
 
     let args = &*CLI_ARGS;
 
-    let inference_params = InferenceParameters {
-        n_threads: args.num_threads as i32,
-        n_batch: args.batch_size,
-        top_k: args.top_k,
-        top_p: args.top_p,
-        repeat_penalty: args.repeat_penalty,
-        temp: args.temp,
-        bias_tokens: args.token_bias.clone().unwrap_or_else(|| {
-            if args.ignore_eos {
-                TokenBias::new(vec![(EOD_TOKEN_ID, -1.0)])
-            } else {
-                TokenBias::default()
-            }
-        }),
-        play_back_previous_tokens: false,
-        ..Default::default()
-    };
+    if args.print_cpu_features {
+        println!("{}", llama_rs::cpu_features());
+        return;
+    }
+    log::info!("CPU features: {}", llama_rs::cpu_features());
+
+    let seed = args.seed.resolve();
+
     let inference_session_params = {
         let mem_typ = if args.float16 {
             ModelKVMemoryType::Float16
@@ -123,11 +388,125 @@ fn main() {
         InferenceSessionParameters {
             memory_k_type: mem_typ,
             memory_v_type: mem_typ,
-            repetition_penalty_last_n: args.repeat_last_n,
+            seed: Some(seed),
         }
     };
 
-    let prompt = if let Some(path) = &args.prompt_file {
+    let load_params = LoadParameters {
+        parallel_load: args.parallel_load,
+        strategy: if args.no_mmap || args.lora.is_some() {
+            LoadStrategy::Read
+        } else {
+            LoadStrategy::Mmap
+        },
+        rope_freq_base: args.rope_freq_base,
+        rope_freq_scale: args.rope_freq_scale,
+        n_gpu_layers: args.n_gpu_layers,
+        architecture: args.arch,
+        checksum: args.checksum,
+        skip_unknown_tensors: args.skip_unknown_tensors,
+    };
+
+    if args.info {
+        print_model_info(
+            &args.model_path,
+            args.num_ctx_tokens as i32,
+            &inference_session_params,
+            args.json,
+        );
+        return;
+    }
+
+    if args.verify {
+        verify_model(&args.model_path);
+        return;
+    }
+
+    if let Some(needle) = &args.find_token {
+        find_token(&args.model_path, args.num_ctx_tokens as i32, needle);
+        return;
+    }
+
+    if let Some(name) = &args.dump_tensor {
+        dump_tensor(
+            &args.model_path,
+            args.num_ctx_tokens as i32,
+            load_params,
+            name,
+        );
+        return;
+    }
+
+    if args.list_tensors {
+        list_tensors(&args.model_path, args.num_ctx_tokens as i32, load_params);
+        return;
+    }
+
+    if args.numa {
+        numa::interleave_memory();
+    }
+
+    if args.bench {
+        bench::run(args, load_params, inference_session_params);
+        return;
+    }
+
+    if args.bench_load {
+        bench::run_load(args, load_params);
+        return;
+    }
+
+    let grammar = args.grammar_file.as_ref().map(|path| {
+        let source = std::fs::read_to_string(path).unwrap_or_else(|err| {
+            log::error!("Could not read grammar file at {path}. Error {err}");
+            std::process::exit(1);
+        });
+        Grammar::parse(&source).unwrap_or_else(|err| {
+            log::error!("Could not parse grammar file at {path}: {err}");
+            std::process::exit(1);
+        })
+    });
+
+    let inference_params = InferenceParameters {
+        n_threads: args.num_threads as i32,
+        n_batch: args.batch_size,
+        top_k: args.top_k,
+        top_p: args.top_p,
+        penalties: Penalties {
+            repeat: args.repeat_penalty,
+            repeat_last_n: args.repeat_last_n,
+            frequency: args.frequency_penalty,
+            presence: args.presence_penalty,
+        },
+        temp: args.temp,
+        bias_tokens: args.token_bias.clone().unwrap_or_default(),
+        token_healing: args.token_healing,
+        stop_sequences: if args.fim() {
+            // The end of the middle, which Code Llama generates instead of
+            // the end of text token.
+            let mut stop_sequences = args.stop_sequences.clone();
+            stop_sequences.push("<EOT>".to_owned());
+            stop_sequences
+        } else {
+            args.stop_sequences.clone()
+        },
+        grammar,
+        cfg_negative_prompt: args.cfg_negative_prompt.clone(),
+        cfg_scale: args.cfg_scale,
+        context_policy: args.context_policy(),
+        echo_prompt: !args.no_keep_prompt,
+        ignore_eos: args.ignore_eos,
+        add_bos: args.add_bos(),
+        play_back_previous_tokens: false,
+        ..Default::default()
+    };
+
+    let prompt = if args.fim() {
+        template::render_fim(
+            args.in_prefix.as_deref().unwrap_or_default(),
+            args.in_suffix.as_deref().unwrap_or_default(),
+        )
+    } else if let Some(path) = &args.prompt_file {
         match std::fs::read_to_string(path) {
             Ok(mut prompt) => {
                 // Strip off the last character if it's exactly newline. Also strip off a single
@@ -149,13 +528,20 @@ fn main() {
         }
     } else if let Some(prompt) = &args.prompt {
         prompt.clone()
+    } else if matches!(&args.continue_from, Some(path) if path.exists()) {
+        // The prompt is in the file being continued.
+        String::new()
     } else {
         log::error!("No prompt or prompt file was provided. See --help");
         std::process::exit(1);
     };
 
-    let (model, vocab) =
-        llama_rs::Model::load(&args.model_path, args.num_ctx_tokens as i32, |progress| {
+    let load_progress = load_progress::LoadProgressReporter::new();
+    let load_result = llama_rs::Llama::load_with_parameters(
+        &args.model_path,
+        args.num_ctx_tokens as i32,
+        load_params,
+        |progress| {
             use llama_rs::LoadProgress;
             match progress {
                 LoadProgress::HyperparametersLoaded(hparams) => {
@@ -164,10 +550,13 @@ fn main() {
                 LoadProgress::BadToken { index } => {
                     log::info!("Warning: Bad token in vocab at index {index}")
                 }
-                LoadProgress::ContextSize { bytes } => log::info!(
-                    "ggml ctx size = {:.2} MB\n",
-                    bytes as f64 / (1024.0 * 1024.0)
-                ),
+                LoadProgress::ContextSize { bytes } => {
+                    log::info!(
+                        "ggml ctx size = {:.2} MB\n",
+                        bytes as f64 / (1024.0 * 1024.0)
+                    );
+                    load_progress.start(bytes);
+                }
                 LoadProgress::MemorySize { bytes, n_mem } => log::info!(
                     "Memory size: {} MB {}",
                     bytes as f32 / 1024.0 / 1024.0,
@@ -177,52 +566,111 @@ fn main() {
                     file,
                     current_part,
                     total_parts,
-                } => log::info!(
-                    "Loading model part {}/{} from '{}'\n",
-                    current_part,
-                    total_parts,
-                    file.to_string_lossy(),
-                ),
+                } => load_progress.part_loading(file, current_part, total_parts),
                 LoadProgress::PartTensorLoaded {
+                    file,
                     current_tensor,
                     tensor_count,
-                    ..
-                } => {
-                    if current_tensor % 8 == 0 {
-                        log::info!("Loaded tensor {current_tensor}/{tensor_count}");
-                    }
-                }
+                    bytes_loaded,
+                } => load_progress.tensor_loaded(file, current_tensor, tensor_count, bytes_loaded),
                 LoadProgress::PartLoaded {
                     file,
                     byte_size,
                     tensor_count,
-                } => {
+                } => load_progress.suspend(|| {
                     log::info!("Loading of '{}' complete", file.to_string_lossy());
                     log::info!(
                         "Model size = {:.2} MB / num tensors = {}",
                         byte_size as f64 / 1024.0 / 1024.0,
                         tensor_count
                     );
-                }
+                }),
+                // The CLI only loads models from disk.
+                LoadProgress::Downloading { .. } => {}
             }
-        })
-        .expect("Could not load model");
+        },
+    );
+    load_progress.finish(load_result.is_ok());
+    let (mut model, mut vocab) = load_result.unwrap_or_else(|err| {
+        log::error!("Could not load model: {err}");
+        std::process::exit(1);
+    });
+
+    if let Some(lora_path) = &args.lora {
+        if let Err(err) = model.apply_lora(lora_path, 1.0) {
+            log::error!("Could not apply the LoRA adapter at {lora_path:?}: {err}");
+            std::process::exit(1);
+        }
+        log::info!("Applied the LoRA adapter at {lora_path:?}");
+    }
 
     log::info!("Model fully loaded!");
 
+    if args.validate {
+        if let Err(err) = model.warmup(args.num_threads as i32) {
+            log::error!("Could not validate the model: {err}");
+            std::process::exit(1);
+        }
+        log::info!("Model validated.");
+    }
+
+    log::info!("Model architecture: {}", model.architecture());
+    let special_tokens = match args.fim() {
+        true => &template::FIM_SPECIAL_TOKENS[..],
+        false => prompt_template(&model).special_tokens(),
+    };
+    for &text in special_tokens {
+        // Code Llama's vocabulary stores the fill-in-the-middle tokens with
+        // a leading space.
+        match vocab
+            .token_id(text)
+            .or_else(|| vocab.token_id(&format!(" {text}")))
+        {
+            Some(id) => vocab.add_special_token(text, id),
+            None => log::warn!("The vocabulary has no {text} token, it is tokenized as text"),
+        }
+    }
+    // In REPL mode, the template is applied to each line instead. A
+    // fill-in-the-middle prompt is used as is.
+    let prompt = if args.repl || args.fim() {
+        prompt
+    } else {
+        render_prompt(&model, &prompt)
+    };
+
     if args.dump_prompt_tokens {
         dump_tokens(&prompt, &vocab).ok();
         return;
     }
 
-    let mut rng = if let Some(seed) = CLI_ARGS.seed {
-        rand::rngs::StdRng::seed_from_u64(seed)
-    } else {
-        rand::rngs::StdRng::from_entropy()
-    };
+    if let Some(path) = &args.dump_graph {
+        let tokens: Vec<_> = match vocab.tokenize(&prompt, inference_params.adds_bos(&vocab)) {
+            Ok(tokens) => tokens.iter().map(|&(_, id)| id).collect(),
+            Err(err) => {
+                log::error!("Could not tokenize prompt: {err}");
+                std::process::exit(1);
+            }
+        };
+        if let Err(err) = model.dump_graph_dot(&tokens, path) {
+            log::error!("Could not write the graph to {path:?}: {err}");
+            std::process::exit(1);
+        }
+        log::info!("Wrote the graph of {} tokens to {path:?}", tokens.len());
+        return;
+    }
+
+    if args.verbose_prompt {
+        match vocab.tokenize(&prompt, inference_params.adds_bos(&vocab)) {
+            Ok(tokens) => {
+                let ids: Vec<_> = tokens.iter().map(|&(_, id)| id).collect();
+                eprintln!("Prompt tokens:\n{}", vocab.describe_tokens(&ids));
+            }
+            Err(err) => log::error!("Could not tokenize prompt: {err}"),
+        }
+    }
 
     let (mut session, session_loaded) = {
-        fn load_snapshot_from_disk(model: &Model, path: &Path) -> InferenceSession {
+        fn load_snapshot_from_disk(model: &Llama, path: &Path) -> InferenceSession {
             let snapshot = snapshot::load_from_disk(path);
             match snapshot.and_then(|snapshot| model.session_from_snapshot(snapshot)) {
                 Ok(session) => {
@@ -242,18 +690,74 @@ fn main() {
             _ => (model.start_session(inference_session_params), false),
         }
     };
+    if session_loaded && args.seed == Seed::Random {
+        // Without an explicit seed, the random state saved in the snapshot is
+        // kept.
+        log::info!("Using the random state of the loaded session");
+    } else {
+        if session_loaded {
+            session.set_seed(seed);
+        }
+        // Logged so that a run with a random seed can be reproduced.
+        log::info!("seed = {seed}");
+    }
 
-    if args.repl {
-        repl_mode(
-            &prompt,
+    // Reject prompts that can't fit before spending time evaluating them.
+    if !session_loaded && args.context_policy() == ContextPolicy::Error {
+        if let Ok(n_tokens) = vocab.count_tokens(&prompt, inference_params.adds_bos(&vocab)) {
+            if n_tokens >= args.num_ctx_tokens {
+                log::error!(
+                    "The prompt has {n_tokens} tokens, which doesn't fit in the context window of {} \
+                     tokens. Use --num-ctx-tokens to increase it.",
+                    args.num_ctx_tokens
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = &args.continue_from {
+        continuation::generate(
+            path,
             &model,
             &vocab,
             &inference_params,
-            &inference_session_params,
-            session,
+            &mut session,
+            &prompt,
         );
+        return;
+    }
+
+    if args.repl {
+        repl_mode(&prompt, &model, &vocab, &inference_params, session);
     } else {
-        let inference_params = if session_loaded {
+        // The prompt cache doesn't hold the state of token healing or of
+        // classifier-free guidance.
+        let prompt_cache = args
+            .prompt_cache
+            .as_deref()
+            .filter(|_| !session_loaded && !args.token_healing && args.cfg_scale == 1.0);
+        let mut prompt = prompt.as_str();
+        let mut res = Ok(());
+        if let Some(dir) = prompt_cache {
+            res = model
+                .start_session_cached(
+                    inference_session_params,
+                    &prompt_cache::open(dir.to_owned()),
+                    &vocab,
+                    &inference_params,
+                    prompt,
+                )
+                .map(|(cached_session, restored)| {
+                    if restored {
+                        log::info!("Restored the evaluated prompt from {dir:?}");
+                    }
+                    session = cached_session;
+                });
+            prompt = "";
+        }
+
+        let inference_params = if session_loaded || prompt_cache.is_some() {
             InferenceParameters {
                 play_back_previous_tokens: true,
                 ..inference_params
@@ -262,31 +766,56 @@ fn main() {
             inference_params
         };
 
-        let res = session.inference_with_prompt::<Infallible>(
-            &model,
-            &vocab,
-            &inference_params,
-            &prompt,
-            args.num_predict,
-            &mut rng,
-            |t| {
-                print!("{t}");
-                std::io::stdout().flush().unwrap();
-
-                Ok(())
-            },
-        );
-        println!();
+        // With `--json`, the generated text is collected and printed as part
+        // of a single object at the end instead.
+        let color = !args.json && color_enabled();
+        let generated_text = RefCell::new(String::new());
+        let res = res.and_then(|()| {
+            session.inference_with_prompt::<Infallible>(
+                &model,
+                &vocab,
+                &inference_params,
+                prompt,
+                args.num_predict(),
+                |t, is_prompt| {
+                    if !args.json {
+                        print_token(t, is_prompt, color);
+                    } else if !is_prompt {
+                        generated_text.borrow_mut().push_str(&t.to_string());
+                    }
+                    Ok(())
+                },
+            )
+        });
+        if color {
+            print!("{GENERATED_COLOR}");
+        }
+        if args.json {
+            print_generation_json(&generated_text.into_inner(), session.tokens(), &res);
+        } else {
+            println!();
+        }
 
         match res {
+            Ok(stats) if stats.stop_reason == StopReason::ContextFull => {
+                log::warn!("Context window full, stopping inference.")
+            }
             Ok(_) => (),
             Err(llama_rs::InferenceError::ContextFull) => {
-                log::warn!("Context window full, stopping inference.")
+                log::error!("Prompt exceeds context window length.")
             }
             Err(llama_rs::InferenceError::TokenizationFailed) => {
                 log::error!("Failed to tokenize initial prompt.");
             }
             Err(llama_rs::InferenceError::UserCallback(_)) => unreachable!("cannot fail"),
+            Err(
+                err @ (llama_rs::InferenceError::NonFiniteLogits
+                | llama_rs::InferenceError::NonFiniteLayerOutput { .. }
+                | llama_rs::InferenceError::InvalidToken(_)
+                | llama_rs::InferenceError::ContextTooSmall { .. }),
+            ) => {
+                log::error!("{err}");
+            }
         }
 
         if let Some(session_path) = args.save_session.as_ref().or(args.persist_session.as_ref()) {
@@ -337,3 +866,29 @@ mod snapshot {
         snap.write(&mut writer)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use super::*;
+
+    #[test]
+    fn prompt_and_generated_text_have_different_colors() {
+        assert_ne!(PROMPT_COLOR, GENERATED_COLOR);
+        assert_eq!(format_token(" hello", true, true), "\x1b[32m hello");
+        assert_eq!(format_token(" world", false, true), "\x1b[0m world");
+        for is_prompt in [false, true] {
+            assert_eq!(format_token(" hello", is_prompt, false), " hello");
+        }
+    }
+
+    #[test]
+    fn color_is_only_used_in_terminals_when_asked() {
+        assert!(use_color(true, true, None));
+        assert!(use_color(true, true, Some(OsStr::new(""))));
+        assert!(!use_color(false, true, None));
+        assert!(!use_color(true, false, None));
+        assert!(!use_color(true, true, Some(OsStr::new("1"))));
+    }
+}
@@ -0,0 +1,72 @@
+//! NUMA support for multi-socket machines, where memory attached to one node
+//! is slower to access from the CPUs of the others.
+//!
+//! The model's memory is interleaved across all the nodes, like with
+//! `numactl --interleave=all`, so that the threads evaluating the model don't
+//! all compete for the memory of a single node. The threads themselves are
+//! created by ggml for each evaluation, so they can't be pinned to a node,
+//! and placing each page on the node of the thread that first touches it
+//! would need the same threads for loading and evaluation.
+
+/// Interleaves the memory allocated from now on across all the NUMA nodes.
+/// Memory-mapped models are only affected if they are not already in the
+/// page cache, so it may be necessary to drop the caches first.
+#[cfg(target_os = "linux")]
+pub fn interleave_memory() {
+    match set_interleave_policy() {
+        Ok(n_nodes) => log::info!("Interleaving memory across {n_nodes} NUMA nodes"),
+        Err(err) => log::warn!("Could not interleave memory across NUMA nodes: {err}"),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn interleave_memory() {
+    log::warn!("NUMA support is only available on Linux, ignoring --numa");
+}
+
+/// Sets the memory policy of the process to interleave across the online
+/// nodes, and returns their number.
+#[cfg(target_os = "linux")]
+fn set_interleave_policy() -> std::io::Result<usize> {
+    use std::io::{Error, ErrorKind};
+
+    // From linux/mempolicy.h
+    const MPOL_INTERLEAVE: libc::c_int = 3;
+    const BITS: usize = libc::c_ulong::BITS as usize;
+
+    let nodes = parse_node_list(std::fs::read_to_string("/sys/devices/system/node/online")?.trim())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid list of NUMA nodes"))?;
+
+    let max_node = nodes.iter().copied().max().unwrap_or(0);
+    let mut mask: Vec<libc::c_ulong> = vec![0; max_node / BITS + 1];
+    for &node in &nodes {
+        mask[node / BITS] |= 1 << (node % BITS);
+    }
+
+    // SAFETY: The mask is valid for the duration of the call, and has as
+    // many bits as specified. The kernel ignores the last bit of `maxnode`.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_set_mempolicy,
+            MPOL_INTERLEAVE,
+            mask.as_ptr(),
+            mask.len() * BITS + 1,
+        )
+    };
+    if result != 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(nodes.len())
+}
+
+/// Parses a list of nodes like `0-1,3`.
+#[cfg(target_os = "linux")]
+fn parse_node_list(list: &str) -> Option<Vec<usize>> {
+    let mut nodes = vec![];
+    for range in list.split(',') {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        nodes.extend(start.parse::<usize>().ok()?..=end.parse().ok()?);
+    }
+    Some(nodes)
+}
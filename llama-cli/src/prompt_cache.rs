@@ -0,0 +1,33 @@
+//! Opens the prompt cache of `--prompt-cache`, which skips the evaluation of
+//! a prompt that was evaluated by the same model before.
+
+use std::{fs, path::PathBuf};
+
+use llama_rs::prompt_cache::PromptCache;
+
+use crate::cli_args::CLI_ARGS;
+
+/// Returns the prompt cache in `dir` for the model given on the command line.
+/// The hyperparameters are part of the key of each entry, but the weights
+/// aren't, so the model is identified by its path and its LoRA adapters.
+pub fn open(dir: PathBuf) -> PromptCache {
+    let model_path = fs::canonicalize(&CLI_ARGS.model_path)
+        .unwrap_or_else(|_| PathBuf::from(&CLI_ARGS.model_path));
+    PromptCache::new(dir, format!("{model_path:?} {:?}", CLI_ARGS.lora))
+}
+
+/// The 64-bit FNV-1a hash, which unlike the standard library's hasher is
+/// stable across builds.
+pub(crate) struct Fnv1a(pub(crate) u64);
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+impl Fnv1a {
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x100000001b3);
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Runs the `llama-cli` binary with a tiny model, and checks its output.
+
+mod common;
+
+use std::{
+    path::Path,
+    process::{Command, Output},
+};
+
+use serde_json::Value;
+
+use common::{write_model, VOCABULARY};
+
+/// Runs `llama-cli` with the test model and `args`.
+fn command(args: &[&str]) -> Output {
+    let model_path = write_model();
+    let output = Command::new(env!("CARGO_BIN_EXE_llama-cli"))
+        .arg("--model-path")
+        .arg(&model_path)
+        .args(["--num-threads", "1", "--num-ctx-tokens", "64"])
+        .args(args)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&model_path).ok();
+    output
+}
+
+/// Runs `llama-cli` with the test model and `args`, and returns its standard
+/// output.
+fn run(args: &[&str]) -> String {
+    let output = command(args);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn json_output_describes_the_generation() {
+    let stdout = run(&[
+        "--prompt",
+        " hello",
+        "--num-predict",
+        "4",
+        "--ignore-eos",
+        "--seed",
+        "1",
+        "--json",
+    ]);
+    let output: Value = serde_json::from_str(stdout.trim()).unwrap();
+
+    assert_eq!(output["n_prompt"], 2);
+    assert_eq!(output["n_generated"], 4);
+    assert_eq!(output["stop_reason"], "length");
+    let tokens: Vec<usize> = output["tokens"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|token| token.as_u64().unwrap() as usize)
+        .collect();
+    assert_eq!(tokens.len(), 4);
+    let text: String = tokens.iter().map(|&id| VOCABULARY[id]).collect();
+    assert_eq!(output["text"], text);
+    for timing in ["feed_prompt_ms", "predict_ms"] {
+        assert!(
+            output["timings"][timing].as_f64().unwrap() >= 0.0,
+            "{timing}"
+        );
+    }
+    assert_eq!(output.as_object().unwrap().len(), 6);
+}
+
+#[test]
+fn bos_flags_override_the_architecture() {
+    let n_prompt = |flags: &[&str]| {
+        let mut args = vec![
+            "--prompt",
+            " hello",
+            "--num-predict",
+            "1",
+            "--seed",
+            "1",
+            "--json",
+        ];
+        args.extend(flags);
+        let output: Value = serde_json::from_str(run(&args).trim()).unwrap();
+        output["n_prompt"].as_u64().unwrap()
+    };
+
+    assert_eq!(n_prompt(&[]), 2);
+    assert_eq!(n_prompt(&["--add-bos"]), 2);
+    assert_eq!(n_prompt(&["--no-bos"]), 1);
+}
+
+#[test]
+fn a_negative_num_predict_generates_until_the_context_is_full() {
+    let stdout = run(&[
+        "--prompt",
+        " hello",
+        "--num-predict",
+        "-1",
+        "--ignore-eos",
+        "--seed",
+        "1",
+        "--json",
+    ]);
+    let output: Value = serde_json::from_str(stdout.trim()).unwrap();
+    assert_eq!(output["stop_reason"], "context_full");
+    // The prompt has 2 tokens, and one slot of the context is left free.
+    assert_eq!(output["n_generated"], 64 - 2 - 1);
+}
+
+/// Reads the tokens of a file written with `--continue`, after its header.
+fn read_token_file(path: &Path) -> Vec<i32> {
+    let text = std::fs::read_to_string(path).unwrap();
+    let mut lines = text.lines();
+    assert!(lines.next().unwrap().starts_with("llama-rs tokens "));
+    lines.map(|line| line.parse().unwrap()).collect()
+}
+
+#[test]
+fn continuing_a_generation_gives_the_tokens_of_an_uninterrupted_one() {
+    let dir = std::env::temp_dir();
+    let pid = std::process::id();
+    let interrupted = dir.join(format!("llama-cli-test-{pid}-interrupted.txt"));
+    let uninterrupted = dir.join(format!("llama-cli-test-{pid}-uninterrupted.txt"));
+    // Greedy sampling doesn't depend on the state of the random number
+    // generator, which isn't saved.
+    let generate = |path: &Path, n: &str| {
+        let path = path.to_str().unwrap();
+        let args = ["--prompt", " hello", "--temp", "0", "--ignore-eos"];
+        run(&[&args[..], &["--continue", path, "--num-predict", n]].concat())
+    };
+
+    generate(&uninterrupted, "8");
+    let expected = read_token_file(&uninterrupted);
+    assert_eq!(expected.len(), 2 + 8);
+
+    generate(&interrupted, "3");
+    assert_eq!(read_token_file(&interrupted), expected[..2 + 3]);
+    // The tokens so far are printed again when continuing.
+    let stdout = generate(&interrupted, "5");
+    let text: String = expected.iter().map(|&id| VOCABULARY[id as usize]).collect();
+    assert_eq!(stdout.trim_end_matches('\n'), text);
+    assert_eq!(read_token_file(&interrupted), expected);
+
+    // The tokens of another model, or another file, are refused, and left
+    // as they are.
+    let contents = std::fs::read_to_string(&interrupted).unwrap();
+    let (header, tokens) = contents.split_once('\n').unwrap();
+    let other_model = format!("{}0000000000000000\n{tokens}", &header[..header.len() - 16]);
+    for contents in [other_model, "not a token file\n".to_owned()] {
+        std::fs::write(&interrupted, &contents).unwrap();
+        let path = interrupted.to_str().unwrap();
+        let output = command(&["--continue", path, "--num-predict", "1"]);
+        assert!(!output.status.success());
+        assert_eq!(std::fs::read_to_string(&interrupted).unwrap(), contents);
+    }
+
+    std::fs::remove_file(&interrupted).ok();
+    std::fs::remove_file(&uninterrupted).ok();
+}
+
+#[test]
+fn random_seeds_are_logged_so_that_runs_can_be_reproduced() {
+    let generate = |seed: &str| {
+        let output = command(&[
+            "--prompt",
+            " hello",
+            "--num-predict",
+            "8",
+            "--ignore-eos",
+            "--seed",
+            seed,
+            "--json",
+        ]);
+        assert!(output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        let seed: u64 = stderr
+            .lines()
+            .find_map(|line| Some(line.split_once("seed = ")?.1.trim().parse().unwrap()))
+            .unwrap_or_else(|| panic!("the seed isn't logged: {stderr}"));
+        let output: Value = serde_json::from_slice(&output.stdout).unwrap();
+        (seed, output["tokens"].clone())
+    };
+
+    let (seed, tokens) = generate("-1");
+    assert_eq!(generate(&seed.to_string()), (seed, tokens));
+    assert_eq!(generate("42").0, 42);
+    assert_ne!(generate("-1").0, seed);
+}
+
+#[test]
+fn color_is_not_used_when_the_output_is_not_a_terminal() {
+    let stdout = run(&[
+        "--prompt",
+        " hello",
+        "--num-predict",
+        "4",
+        "--seed",
+        "1",
+        "--color",
+    ]);
+    assert!(stdout.starts_with("<s> hello"), "{stdout:?}");
+    assert!(!stdout.contains('\x1b'), "{stdout:?}");
+}
@@ -0,0 +1,86 @@
+//! A tiny model shared by the tests that run the binaries.
+#![allow(dead_code)]
+
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The tokens of the test model: the special tokens, then the words.
+pub const VOCABULARY: &[&str] = &[
+    "<unk>", "<s>", "</s>", " ", "a", "b", "c", "d", "e", "h", "l", "o", " he", "llo", " hello",
+    "\n", " world", "wor", "ld", ",", "!",
+];
+
+/// Writes a ggjt model with one layer and deterministic weights to a new
+/// temporary file, and returns its path.
+pub fn write_model() -> PathBuf {
+    let (n_embd, n_mult, n_head, n_layer) = (32, 32, 2, 1);
+    let n_vocab = VOCABULARY.len() as i32;
+    let n_ff = ((2 * (4 * n_embd) / 3 + n_mult - 1) / n_mult) * n_mult;
+
+    let mut tensors = vec![
+        ("tok_embeddings.weight".to_owned(), vec![n_embd, n_vocab]),
+        ("norm.weight".to_owned(), vec![n_embd]),
+        ("output.weight".to_owned(), vec![n_embd, n_vocab]),
+    ];
+    for i in 0..n_layer {
+        for (name, dims) in [
+            ("attention_norm.weight", vec![n_embd]),
+            ("attention.wq.weight", vec![n_embd, n_embd]),
+            ("attention.wk.weight", vec![n_embd, n_embd]),
+            ("attention.wv.weight", vec![n_embd, n_embd]),
+            ("attention.wo.weight", vec![n_embd, n_embd]),
+            ("ffn_norm.weight", vec![n_embd]),
+            ("feed_forward.w1.weight", vec![n_embd, n_ff]),
+            ("feed_forward.w2.weight", vec![n_ff, n_embd]),
+            ("feed_forward.w3.weight", vec![n_embd, n_ff]),
+        ] {
+            tensors.push((format!("layers.{i}.{name}"), dims));
+        }
+    }
+
+    let mut bytes = Vec::new();
+    bytes.extend(0x67676a74u32.to_le_bytes());
+    bytes.extend(1u32.to_le_bytes());
+    for value in [n_vocab, n_embd, n_mult, n_head, n_layer, n_embd / n_head, 0] {
+        bytes.extend(value.to_le_bytes());
+    }
+    for (i, token) in VOCABULARY.iter().enumerate() {
+        bytes.extend((token.len() as i32).to_le_bytes());
+        bytes.extend(token.as_bytes());
+        bytes.extend((-(i as f32)).to_le_bytes());
+    }
+
+    // The weights of the matrices come from a linear congruential generator,
+    // so that the model is the same on every run.
+    let mut state = 0u32;
+    for (name, dims) in tensors {
+        bytes.extend((dims.len() as i32).to_le_bytes());
+        bytes.extend((name.len() as i32).to_le_bytes());
+        bytes.extend(0i32.to_le_bytes());
+        for dim in &dims {
+            bytes.extend(dim.to_le_bytes());
+        }
+        bytes.extend(name.as_bytes());
+        bytes.resize((bytes.len() + 31) / 32 * 32, 0);
+        for _ in 0..dims.iter().product() {
+            let value = if dims.len() == 1 {
+                1.0
+            } else {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 8) as f32 / (1 << 24) as f32 * 0.6 - 0.3
+            };
+            bytes.extend(value.to_le_bytes());
+        }
+    }
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let path = std::env::temp_dir().join(format!(
+        "llama-cli-test-{}-{n}-model.bin",
+        std::process::id()
+    ));
+    std::fs::write(&path, bytes).unwrap();
+    path
+}
@@ -0,0 +1,250 @@
+//! Runs the `server` binary with a tiny model, and sends it requests over
+//! HTTP.
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+use common::write_model;
+
+/// The server process, which is killed when dropped.
+struct Server {
+    process: Child,
+    port: u16,
+    model_path: PathBuf,
+}
+
+impl Server {
+    fn start() -> Self {
+        let model_path = write_model();
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let process = Command::new(env!("CARGO_BIN_EXE_server"))
+            .arg("--model-path")
+            .arg(&model_path)
+            .args(["--address", &format!("127.0.0.1:{port}")])
+            .args(["--num-threads", "1", "--num-ctx-tokens", "64"])
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Self {
+            process,
+            port,
+            model_path,
+        };
+
+        let start = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(
+                start.elapsed() < Duration::from_secs(30),
+                "the server didn't start listening"
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+        server
+    }
+
+    /// Sends a request, and returns the status code and the body of the
+    /// response.
+    fn request(&self, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(("127.0.0.1", self.port)).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(60)))
+            .unwrap();
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\n\
+             Host: 127.0.0.1\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+
+        let mut reader = BufReader::new(stream);
+        let mut status_line = String::new();
+        reader.read_line(&mut status_line).unwrap();
+        let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+
+        let mut content_length = None;
+        let mut chunked = false;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').unwrap();
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => content_length = Some(value.trim().parse().unwrap()),
+                "transfer-encoding" => chunked = value.trim() == "chunked",
+                _ => {}
+            }
+        }
+
+        let mut body = Vec::new();
+        if chunked {
+            loop {
+                let mut size = String::new();
+                reader.read_line(&mut size).unwrap();
+                let size = usize::from_str_radix(size.trim_end(), 16).unwrap();
+                let mut chunk = vec![0; size + 2];
+                reader.read_exact(&mut chunk).unwrap();
+                if size == 0 {
+                    break;
+                }
+                body.extend(&chunk[..size]);
+            }
+        } else {
+            body.resize(content_length.unwrap(), 0);
+            reader.read_exact(&mut body).unwrap();
+        }
+        (status, String::from_utf8(body).unwrap())
+    }
+
+    fn complete(&self, request: Value) -> (u16, Value) {
+        let (status, body) = self.request("POST", "/v1/completions", &request.to_string());
+        (status, serde_json::from_str(&body).unwrap())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.process.kill().ok();
+        self.process.wait().ok();
+        std::fs::remove_file(&self.model_path).ok();
+    }
+}
+
+#[test]
+fn completions() {
+    let server = Server::start();
+    let request = json!({ "prompt": " hello", "max_tokens": 4, "seed": 42 });
+
+    let (status, response) = server.complete(request.clone());
+    assert_eq!(status, 200, "{response}");
+    assert_eq!(response["object"], "text_completion");
+    let choice = &response["choices"][0];
+    assert!(choice["text"].is_string());
+    assert!(["length", "stop"].contains(&choice["finish_reason"].as_str().unwrap()));
+    let usage = &response["usage"];
+    assert_eq!(usage["prompt_tokens"], 2);
+    let completion_tokens = usage["completion_tokens"].as_u64().unwrap();
+    assert!((1..=4).contains(&completion_tokens));
+    assert_eq!(usage["total_tokens"], 2 + completion_tokens);
+
+    // The same seed generates the same text.
+    let (_, again) = server.complete(request);
+    assert_eq!(again["choices"][0]["text"], choice["text"]);
+
+    // The streamed events have the same text, then the reason generation
+    // stopped, and `[DONE]`.
+    let (status, events) = server.request(
+        "POST",
+        "/v1/completions",
+        &json!({ "prompt": " hello", "max_tokens": 4, "seed": 42, "stream": true }).to_string(),
+    );
+    assert_eq!(status, 200);
+    let events: Vec<&str> = events
+        .split("\n\n")
+        .filter(|event| !event.is_empty())
+        .map(|event| event.strip_prefix("data: ").unwrap())
+        .collect();
+    let (done, events) = events.split_last().unwrap();
+    assert_eq!(*done, "[DONE]");
+    let events: Vec<Value> = events
+        .iter()
+        .map(|event| serde_json::from_str(event).unwrap())
+        .collect();
+    let (last, tokens) = events.split_last().unwrap();
+    assert_eq!(last["choices"][0]["finish_reason"], choice["finish_reason"]);
+    let text: String = tokens
+        .iter()
+        .map(|event| event["choices"][0]["text"].as_str().unwrap())
+        .collect();
+    assert_eq!(text, choice["text"].as_str().unwrap());
+}
+
+#[test]
+fn invalid_requests() {
+    let server = Server::start();
+
+    let (status, _) = server.request("GET", "/v1/models", "");
+    assert_eq!(status, 404);
+    let (status, _) = server.request("GET", "/v1/completions", "");
+    assert_eq!(status, 405);
+
+    let (status, response) = server.complete(json!({ "prompt": 4 }));
+    assert_eq!(status, 400);
+    assert!(response["error"]["message"].is_string());
+    let (status, _) = server.complete(json!({ "prompt": "", "temperature": -1.0 }));
+    assert_eq!(status, 400);
+
+    // The context window holds 64 tokens.
+    let prompt = " hello".repeat(64);
+    for stream in [false, true] {
+        let (status, response) = server.complete(json!({ "prompt": prompt, "stream": stream }));
+        assert_eq!(status, 400);
+        let message = response["error"]["message"].as_str().unwrap();
+        assert!(message.contains("context window"), "{message}");
+    }
+}
+
+#[test]
+fn sampling_parameters() {
+    let server = Server::start();
+    let request = |parameters: &Value| {
+        let mut request = json!({ "prompt": " hello", "max_tokens": 8 });
+        request
+            .as_object_mut()
+            .unwrap()
+            .extend(parameters.as_object().unwrap().clone());
+        request
+    };
+    let text = |parameters: Value| {
+        let (status, response) = server.complete(request(&parameters));
+        assert_eq!(status, 200, "{response}");
+        response["choices"][0]["text"].as_str().unwrap().to_owned()
+    };
+
+    // The same seed generates the same text, and other seeds other texts.
+    let texts: Vec<String> = (1..=4)
+        .map(|seed| text(json!({ "temperature": 1.0, "seed": seed })))
+        .collect();
+    assert_eq!(text(json!({ "temperature": 1.0, "seed": 1 })), texts[0]);
+    assert!(texts[1..].iter().any(|text| *text != texts[0]), "{texts:?}");
+
+    // A temperature of 0 is greedy, so the seed doesn't matter, unlike with
+    // a higher temperature.
+    let greedy = text(json!({ "temperature": 0.0, "seed": 1 }));
+    assert_eq!(text(json!({ "temperature": 0.0, "seed": 2 })), greedy);
+    assert!(texts.iter().any(|text| *text != greedy), "{texts:?}");
+
+    for parameters in [
+        json!({ "temperature": -0.5 }),
+        json!({ "temperature": "hot" }),
+        json!({ "top_p": 0.0 }),
+        json!({ "top_p": 1.5 }),
+        json!({ "top_k": -1 }),
+        json!({ "seed": -1 }),
+        json!({ "max_tokens": -1 }),
+    ] {
+        let (status, response) = server.complete(request(&parameters));
+        assert_eq!(status, 400, "{parameters}");
+        assert!(response["error"]["message"].is_string(), "{parameters}");
+    }
+}
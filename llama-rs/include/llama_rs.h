@@ -0,0 +1,123 @@
+#ifndef LLAMA_RS_H
+#define LLAMA_RS_H
+
+/* This file is generated by cbindgen. Do not edit it manually. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// The result of a call to the C API.
+typedef enum LlamaRsStatus {
+  // The call succeeded.
+  LlamaRsStatus_Ok = 0,
+  // A pointer was null, or a string was not valid UTF-8.
+  LlamaRsStatus_InvalidArgument = 1,
+  // The provided output buffer is too small. The required size has been
+  // written to the length out-parameter.
+  LlamaRsStatus_BufferTooSmall = 2,
+  // The text could not be tokenized.
+  LlamaRsStatus_TokenizationFailed = 3,
+  // The context window is full.
+  LlamaRsStatus_ContextFull = 4,
+  // A token id is not part of the vocabulary.
+  LlamaRsStatus_InvalidToken = 5,
+  // The library panicked.
+  LlamaRsStatus_Panic = 6,
+} LlamaRsStatus;
+
+// An opaque handle to a loaded model, its vocabulary and an inference
+// session.
+typedef struct LlamaRsContext LlamaRsContext;
+
+typedef int32_t TokenId;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Loads the model at `path` with a context window of `n_ctx` tokens.
+// Returns null if the model could not be loaded.
+//
+// # Safety
+//
+// `path` must be a valid, NUL-terminated string.
+struct LlamaRsContext *llama_rs_load(const char *path, int n_ctx);
+
+// Frees a context returned by [llama_rs_load].
+//
+// # Safety
+//
+// `ctx` must have been returned by [llama_rs_load], and must not be used
+// after this call. Passing null is allowed.
+void llama_rs_free(struct LlamaRsContext *ctx);
+
+// Seeds the random number generator used by [llama_rs_sample].
+//
+// # Safety
+//
+// `ctx` must be a valid context returned by [llama_rs_load].
+int llama_rs_set_seed(struct LlamaRsContext *ctx, uint64_t seed);
+
+// Tokenizes `text` into `tokens`, which can hold up to `n_max_tokens`
+// tokens. The number of tokens is written to `n_tokens`, even if the buffer
+// is too small.
+//
+// # Safety
+//
+// `ctx` must be a valid context returned by [llama_rs_load], `text` must be
+// a valid, NUL-terminated string, `tokens` must point to at least
+// `n_max_tokens` writable tokens and `n_tokens` must be writable.
+int llama_rs_tokenize(const struct LlamaRsContext *ctx,
+                      const char *text,
+                      bool add_bos,
+                      TokenId *tokens,
+                      uintptr_t n_max_tokens,
+                      uintptr_t *n_tokens);
+
+// Feeds `n_tokens` tokens to the model, updating the context's session.
+//
+// # Safety
+//
+// `ctx` must be a valid context returned by [llama_rs_load], and `tokens`
+// must point to at least `n_tokens` tokens.
+int llama_rs_eval(struct LlamaRsContext *ctx,
+                  const TokenId *tokens,
+                  uintptr_t n_tokens,
+                  int n_threads);
+
+// Samples the next token from the logits of the last evaluated token, and
+// writes it to `token`. The token is not fed back to the model; pass it to
+// [llama_rs_eval] to continue generating.
+//
+// # Safety
+//
+// `ctx` must be a valid context returned by [llama_rs_load], and `token`
+// must be writable.
+int llama_rs_sample(struct LlamaRsContext *ctx,
+                    uintptr_t top_k,
+                    float top_p,
+                    float temp,
+                    float repeat_penalty,
+                    TokenId *token);
+
+// Writes the text of `token` (not NUL-terminated) to `buf`, which can hold
+// up to `buf_len` bytes. The length of the text is written to `len`, even if
+// the buffer is too small.
+//
+// # Safety
+//
+// `ctx` must be a valid context returned by [llama_rs_load], `buf` must
+// point to at least `buf_len` writable bytes and `len` must be writable.
+int llama_rs_token_to_str(const struct LlamaRsContext *ctx,
+                          TokenId token,
+                          char *buf,
+                          uintptr_t buf_len,
+                          uintptr_t *len);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* LLAMA_RS_H */
@@ -0,0 +1,102 @@
+//! The backends that compute the graphs built when evaluating the model.
+//!
+//! Only the CPU backend exists for now. A GPU backend would offload the
+//! computation of the first [LoadParameters::n_gpu_layers](crate::LoadParameters::n_gpu_layers)
+//! layers, and be enabled by a feature.
+
+use crate::{ggml, LoadError};
+
+/// Computes the graphs built by the model, e.g. in
+/// [Llama::evaluate](crate::Llama::evaluate). Every computation of the model
+/// goes through its backend.
+pub(crate) trait Backend: Send + Sync {
+    /// Computes `graph`, whose intermediate tensors are allocated in `ctx`.
+    fn compute(&self, ctx: &ggml::Context, graph: &mut ggml::ComputationGraph);
+
+    /// Computes `output`, and the tensors it depends on, with `n_threads`
+    /// threads.
+    fn compute_tensor(&self, ctx: &ggml::Context, output: &ggml::Tensor, n_threads: i32) {
+        let mut graph = ggml::ComputationGraph::new(n_threads);
+        graph.build_forward_expand(output);
+        self.compute(ctx, &mut graph);
+    }
+}
+
+/// Computes the graphs on the CPU with ggml.
+pub(crate) struct Cpu;
+impl Backend for Cpu {
+    fn compute(&self, ctx: &ggml::Context, graph: &mut ggml::ComputationGraph) {
+        ctx.graph_compute(graph);
+    }
+
+    fn compute_tensor(&self, ctx: &ggml::Context, output: &ggml::Tensor, n_threads: i32) {
+        ctx.compute(output, n_threads);
+    }
+}
+
+/// Returns the backend for a model with `n_gpu_layers` layers offloaded to
+/// the GPU.
+pub(crate) fn for_gpu_layers(n_gpu_layers: usize) -> Result<Box<dyn Backend>, LoadError> {
+    if n_gpu_layers > 0 {
+        return Err(LoadError::GpuBackendUnavailable { n_gpu_layers });
+    }
+    Ok(Box::new(Cpu))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::{
+        test_model::{inference_parameters, TestModel},
+        Llama, LoadStrategy,
+    };
+
+    /// Computes the graphs on the CPU, counting them and their operations.
+    #[derive(Default)]
+    struct Counting {
+        graphs: Arc<AtomicUsize>,
+        nodes: Arc<AtomicUsize>,
+    }
+    impl Backend for Counting {
+        fn compute(&self, ctx: &ggml::Context, graph: &mut ggml::ComputationGraph) {
+            self.graphs.fetch_add(1, Ordering::Relaxed);
+            self.nodes.fetch_add(graph.n_nodes(), Ordering::Relaxed);
+            Cpu.compute(ctx, graph);
+        }
+    }
+
+    #[test]
+    fn the_model_computes_through_its_backend() {
+        let path = TestModel::default().write();
+        let (mut model, _) =
+            Llama::load_with_strategy(path, 16, LoadStrategy::Read, |_| {}).unwrap();
+        let backend = Counting::default();
+        let (graphs, nodes) = (backend.graphs.clone(), backend.nodes.clone());
+        model.backend = Box::new(backend);
+
+        let mut session = model.start_session(Default::default());
+        let params = inference_parameters();
+        session.feed_tokens(&model, &params, &[1, 270]).unwrap();
+        assert_eq!(graphs.load(Ordering::Relaxed), 1);
+        let n_eval_nodes = nodes.load(Ordering::Relaxed);
+        assert!(n_eval_nodes > 2 * model.layers.len());
+
+        // Applying a LoRA adapter is computed by the backend too.
+        let lora = crate::tests::write_lora(
+            1,
+            1,
+            &[
+                ("output.weight.loraA", [1, 64], vec![0.5; 64]),
+                ("output.weight.loraB", [1, 277], vec![0.5; 277]),
+            ],
+        );
+        model.apply_lora(lora, 1.0).unwrap();
+        assert_eq!(graphs.load(Ordering::Relaxed), 2);
+        assert!(nodes.load(Ordering::Relaxed) > n_eval_nodes);
+    }
+}
@@ -0,0 +1,278 @@
+//! A C API to load a model and run inference with it from other languages.
+//!
+//! This module is only available with the `capi` feature. To build a library
+//! that can be linked from C, run:
+//!
+//! ```text
+//! cargo rustc -p llama-rs --release --features capi --crate-type cdylib
+//! ```
+//!
+//! The matching header is `include/llama_rs.h`, and is generated with
+//! `cbindgen --config cbindgen.toml --output include/llama_rs.h` from the
+//! `llama-rs` directory.
+//!
+//! Every function returns a [LlamaRsStatus] code (or a null pointer, for
+//! [llama_rs_load]). Panics are caught before they reach the caller and are
+//! reported as [LlamaRsStatus::Panic].
+
+use std::{
+    ffi::{c_char, c_int, CStr},
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::Path,
+};
+
+use crate::{
+    EvaluateOutputRequest, InferenceParameters, InferenceSession, InferenceSessionParameters,
+    LanguageModel, Llama, Penalties, TokenId, Vocabulary,
+};
+
+/// The result of a call to the C API.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LlamaRsStatus {
+    /// The call succeeded.
+    Ok = 0,
+    /// A pointer was null, or a string was not valid UTF-8.
+    InvalidArgument = 1,
+    /// The provided output buffer is too small. The required size has been
+    /// written to the length out-parameter.
+    BufferTooSmall = 2,
+    /// The text could not be tokenized.
+    TokenizationFailed = 3,
+    /// The context window is full.
+    ContextFull = 4,
+    /// A token id is not part of the vocabulary.
+    InvalidToken = 5,
+    /// The library panicked.
+    Panic = 6,
+}
+
+/// An opaque handle to a loaded model, its vocabulary and an inference
+/// session.
+pub struct LlamaRsContext {
+    model: Llama,
+    vocab: Vocabulary,
+    session: InferenceSession,
+}
+
+/// Runs `f`, converting a panic into [LlamaRsStatus::Panic].
+fn guard(f: impl FnOnce() -> LlamaRsStatus) -> c_int {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or(LlamaRsStatus::Panic) as c_int
+}
+
+/// Loads the model at `path` with a context window of `n_ctx` tokens.
+/// Returns null if the model could not be loaded.
+///
+/// # Safety
+///
+/// `path` must be a valid, NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn llama_rs_load(path: *const c_char, n_ctx: c_int) -> *mut LlamaRsContext {
+    if path.is_null() {
+        return std::ptr::null_mut();
+    }
+    let Ok(path) = CStr::from_ptr(path).to_str() else {
+        return std::ptr::null_mut();
+    };
+
+    catch_unwind(|| {
+        let (model, vocab) = Llama::load(Path::new(path), n_ctx, |_| {}).ok()?;
+        let session = model.start_session(InferenceSessionParameters::default());
+        Some(Box::new(LlamaRsContext {
+            model,
+            vocab,
+            session,
+        }))
+    })
+    .ok()
+    .flatten()
+    .map_or(std::ptr::null_mut(), Box::into_raw)
+}
+
+/// Frees a context returned by [llama_rs_load].
+///
+/// # Safety
+///
+/// `ctx` must have been returned by [llama_rs_load], and must not be used
+/// after this call. Passing null is allowed.
+#[no_mangle]
+pub unsafe extern "C" fn llama_rs_free(ctx: *mut LlamaRsContext) {
+    if !ctx.is_null() {
+        drop(Box::from_raw(ctx));
+    }
+}
+
+/// Seeds the random number generator used by [llama_rs_sample].
+///
+/// # Safety
+///
+/// `ctx` must be a valid context returned by [llama_rs_load].
+#[no_mangle]
+pub unsafe extern "C" fn llama_rs_set_seed(ctx: *mut LlamaRsContext, seed: u64) -> c_int {
+    let Some(ctx) = ctx.as_mut() else {
+        return LlamaRsStatus::InvalidArgument as c_int;
+    };
+    guard(|| {
+        ctx.session.set_seed(seed);
+        LlamaRsStatus::Ok
+    })
+}
+
+/// Tokenizes `text` into `tokens`, which can hold up to `n_max_tokens`
+/// tokens. The number of tokens is written to `n_tokens`, even if the buffer
+/// is too small.
+///
+/// # Safety
+///
+/// `ctx` must be a valid context returned by [llama_rs_load], `text` must be
+/// a valid, NUL-terminated string, `tokens` must point to at least
+/// `n_max_tokens` writable tokens and `n_tokens` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn llama_rs_tokenize(
+    ctx: *const LlamaRsContext,
+    text: *const c_char,
+    add_bos: bool,
+    tokens: *mut TokenId,
+    n_max_tokens: usize,
+    n_tokens: *mut usize,
+) -> c_int {
+    let (Some(ctx), false, false, false) = (
+        ctx.as_ref(),
+        text.is_null(),
+        tokens.is_null(),
+        n_tokens.is_null(),
+    ) else {
+        return LlamaRsStatus::InvalidArgument as c_int;
+    };
+    let Ok(text) = CStr::from_ptr(text).to_str() else {
+        return LlamaRsStatus::InvalidArgument as c_int;
+    };
+
+    guard(|| {
+        let Ok(result) = ctx.model.tokenize(&ctx.vocab, text, add_bos) else {
+            return LlamaRsStatus::TokenizationFailed;
+        };
+        *n_tokens = result.len();
+        if result.len() > n_max_tokens {
+            return LlamaRsStatus::BufferTooSmall;
+        }
+        std::slice::from_raw_parts_mut(tokens, result.len()).copy_from_slice(&result);
+        LlamaRsStatus::Ok
+    })
+}
+
+/// Feeds `n_tokens` tokens to the model, updating the context's session.
+///
+/// # Safety
+///
+/// `ctx` must be a valid context returned by [llama_rs_load], and `tokens`
+/// must point to at least `n_tokens` tokens.
+#[no_mangle]
+pub unsafe extern "C" fn llama_rs_eval(
+    ctx: *mut LlamaRsContext,
+    tokens: *const TokenId,
+    n_tokens: usize,
+    n_threads: c_int,
+) -> c_int {
+    let (Some(ctx), false) = (ctx.as_mut(), tokens.is_null()) else {
+        return LlamaRsStatus::InvalidArgument as c_int;
+    };
+    let tokens = std::slice::from_raw_parts(tokens, n_tokens);
+
+    guard(|| {
+        if tokens.is_empty() {
+            return LlamaRsStatus::Ok;
+        }
+        if tokens.iter().any(|&t| ctx.vocab.get(t).is_none()) {
+            return LlamaRsStatus::InvalidToken;
+        }
+        if ctx.session.n_past + tokens.len() >= ctx.session.n_ctx() {
+            return LlamaRsStatus::ContextFull;
+        }
+
+        let params = InferenceParameters {
+            n_threads,
+            ..Default::default()
+        };
+        for batch in tokens.chunks(params.n_batch) {
+            ctx.model.evaluate(
+                &mut ctx.session,
+                &params,
+                batch,
+                &mut EvaluateOutputRequest::default(),
+            );
+        }
+        ctx.session.tokens.extend_from_slice(tokens);
+        LlamaRsStatus::Ok
+    })
+}
+
+/// Samples the next token from the logits of the last evaluated token, and
+/// writes it to `token`. The token is not fed back to the model; pass it to
+/// [llama_rs_eval] to continue generating.
+///
+/// # Safety
+///
+/// `ctx` must be a valid context returned by [llama_rs_load], and `token`
+/// must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn llama_rs_sample(
+    ctx: *mut LlamaRsContext,
+    top_k: usize,
+    top_p: f32,
+    temp: f32,
+    repeat_penalty: f32,
+    token: *mut TokenId,
+) -> c_int {
+    let (Some(ctx), false) = (ctx.as_mut(), token.is_null()) else {
+        return LlamaRsStatus::InvalidArgument as c_int;
+    };
+
+    guard(|| {
+        let params = InferenceParameters {
+            top_k,
+            top_p,
+            temp,
+            penalties: Penalties {
+                repeat: repeat_penalty,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        *token = ctx.session.sample(&params);
+        LlamaRsStatus::Ok
+    })
+}
+
+/// Writes the text of `token` (not NUL-terminated) to `buf`, which can hold
+/// up to `buf_len` bytes. The length of the text is written to `len`, even if
+/// the buffer is too small.
+///
+/// # Safety
+///
+/// `ctx` must be a valid context returned by [llama_rs_load], `buf` must
+/// point to at least `buf_len` writable bytes and `len` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn llama_rs_token_to_str(
+    ctx: *const LlamaRsContext,
+    token: TokenId,
+    buf: *mut c_char,
+    buf_len: usize,
+    len: *mut usize,
+) -> c_int {
+    let (Some(ctx), false, false) = (ctx.as_ref(), buf.is_null(), len.is_null()) else {
+        return LlamaRsStatus::InvalidArgument as c_int;
+    };
+
+    guard(|| {
+        let Some(text) = ctx.vocab.get(token) else {
+            return LlamaRsStatus::InvalidToken;
+        };
+        *len = text.len();
+        if text.len() > buf_len {
+            return LlamaRsStatus::BufferTooSmall;
+        }
+        std::ptr::copy_nonoverlapping(text.as_ptr(), buf as *mut u8, text.len());
+        LlamaRsStatus::Ok
+    })
+}
@@ -0,0 +1,163 @@
+//! Downloads models into a cache directory before loading them. This module
+//! is only available with the `download` feature.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{Llama, LoadError, LoadProgress, Vocabulary};
+
+impl Llama {
+    /// Loads the model at `url` like [Llama::load]. The model is downloaded
+    /// into `cache_dir` first, under a name derived from the URL, unless it
+    /// was downloaded there before.
+    ///
+    /// If `sha256` is given, as a hex string, the download is checked against
+    /// it before it is added to the cache, so that a corrupt download is never
+    /// cached. Models that are already in the cache are not checked again.
+    pub fn load_from_url(
+        url: &str,
+        cache_dir: impl AsRef<Path>,
+        n_ctx: i32,
+        sha256: Option<&str>,
+        load_progress_callback: impl Fn(LoadProgress),
+    ) -> Result<(Llama, Vocabulary), LoadError> {
+        let cache_dir = cache_dir.as_ref();
+        let path = cache_path(cache_dir, url);
+
+        if !path.exists() {
+            fs::create_dir_all(cache_dir).map_err(|source| LoadError::OpenFileFailed {
+                source,
+                path: cache_dir.to_owned(),
+            })?;
+
+            // The model is written under another name until it is complete and
+            // checked, so that an interrupted download is not loaded later.
+            let part_path = path.with_extension("part");
+            let result = download(url, &part_path, sha256, &load_progress_callback)
+                .and_then(|()| fs::rename(&part_path, &path).map_err(LoadError::from));
+            if result.is_err() {
+                fs::remove_file(&part_path).ok();
+            }
+            result?;
+        }
+
+        Llama::load(&path, n_ctx, load_progress_callback)
+    }
+}
+
+/// Returns the path of the model at `url` in `cache_dir`, which is named after
+/// the SHA-256 of the URL.
+fn cache_path(cache_dir: &Path, url: &str) -> PathBuf {
+    cache_dir.join(format!("{:x}.bin", Sha256::digest(url.as_bytes())))
+}
+
+/// Downloads `url` to `path`, checking its SHA-256 against `sha256`.
+fn download(
+    url: &str,
+    path: &Path,
+    sha256: Option<&str>,
+    load_progress_callback: &impl Fn(LoadProgress),
+) -> Result<(), LoadError> {
+    let download_failed = |source| LoadError::DownloadFailed {
+        source,
+        url: url.to_owned(),
+    };
+
+    let mut response = reqwest::blocking::get(url)
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| download_failed(io::Error::new(io::ErrorKind::Other, err)))?;
+    let total_bytes = response.content_length().map(|len| len as usize);
+
+    let mut file = File::create(path).map_err(|source| LoadError::OpenFileFailed {
+        source,
+        path: path.to_owned(),
+    })?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 20];
+    let mut bytes_downloaded = 0;
+    loop {
+        let n = match response.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(download_failed(err)),
+        };
+        file.write_all(&buf[..n])?;
+        hasher.update(&buf[..n]);
+
+        bytes_downloaded += n;
+        load_progress_callback(LoadProgress::Downloading {
+            url,
+            bytes_downloaded,
+            total_bytes,
+        });
+    }
+    file.sync_all()?;
+
+    if let Some(expected) = sha256 {
+        let actual = format!("{:x}", hasher.finalize());
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            return Err(LoadError::ChecksumMismatch {
+                url: url.to_owned(),
+                expected: expected.to_owned(),
+                actual,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_model::{temp_path, TestModel};
+
+    /// Nothing listens on this port, so that downloads fail without a network.
+    const URL: &str = "http://127.0.0.1:1/ggml-model.bin";
+
+    #[test]
+    fn models_are_cached_under_the_hash_of_their_url() {
+        let cache_dir = Path::new("cache");
+        assert_eq!(
+            cache_path(cache_dir, "https://example.com/ggml-model.bin"),
+            cache_dir.join("5074eaad672ddc1c57e9ca2b9eb8512693a3fac7568c01be47c2ea37a9fa91ed.bin")
+        );
+        assert_eq!(cache_path(cache_dir, URL), cache_path(cache_dir, URL));
+        assert_ne!(
+            cache_path(cache_dir, URL),
+            cache_path(cache_dir, "http://127.0.0.1:1/other-model.bin")
+        );
+    }
+
+    #[test]
+    fn cached_models_are_loaded_without_downloading_them() {
+        let cache_dir = temp_path("cache");
+        fs::create_dir_all(&cache_dir).unwrap();
+        fs::rename(TestModel::default().write(), cache_path(&cache_dir, URL)).unwrap();
+
+        let (_, vocab) = Llama::load_from_url(URL, &cache_dir, 8, None, |_| {}).unwrap();
+        assert_eq!(vocab.len(), TestModel::default().vocabulary().len());
+
+        fs::remove_dir_all(&cache_dir).ok();
+    }
+
+    #[test]
+    fn failed_downloads_are_not_cached() {
+        let cache_dir = temp_path("cache");
+        let result = Llama::load_from_url(URL, &cache_dir, 8, None, |_| {});
+        assert!(
+            matches!(result, Err(LoadError::DownloadFailed { ref url, .. }) if url == URL),
+            "{:?}",
+            result.err()
+        );
+        assert_eq!(fs::read_dir(&cache_dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&cache_dir).ok();
+    }
+}
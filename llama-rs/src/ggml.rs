@@ -1,15 +1,31 @@
 use std::{
-    ffi::c_void,
+    alloc::Layout,
+    ffi::{c_void, CString},
+    path::Path,
     ptr::NonNull,
     sync::{Arc, Weak},
 };
 
 pub use ggml_raw::ggml_type as Type;
 
-pub const FILE_MAGIC: i32 = 0x67676d66;
-pub const FILE_MAGIC_UNVERSIONED: i32 = 0x67676d6c;
+/// Magic constant for `ggml` files (versioned, ggmf).
+pub const FILE_MAGIC: u32 = 0x67676d66;
+/// Magic constant for `ggml` files (versioned, ggjt).
+pub const FILE_MAGIC_GGJT: u32 = 0x67676a74;
+/// Magic constant for LoRA adapter files (ggla).
+pub const FILE_MAGIC_GGLA: u32 = 0x67676c61;
+/// Magic constant for `ggml` files (unversioned).
+pub const FILE_MAGIC_UNVERSIONED: u32 = 0x67676d6c;
 
+/// The currently-supported format version for `ggmf` files.
 pub const FORMAT_VERSION: u32 = 1;
+/// The currently-supported format version for `ggjt` files.
+pub const FORMAT_VERSION_GGJT: u32 = 1;
+/// The currently-supported format version for `ggla` files.
+pub const FORMAT_VERSION_GGLA: u32 = 1;
+
+/// The alignment, in bytes, of tensor data within a `ggjt` file.
+pub const GGJT_TENSOR_ALIGNMENT: u64 = 32;
 
 pub const TYPE_Q4_0: ggml_raw::ggml_type = ggml_raw::GGML_TYPE_Q4_0;
 pub const TYPE_Q4_1: ggml_raw::ggml_type = ggml_raw::GGML_TYPE_Q4_1;
@@ -21,24 +37,129 @@ pub const TYPE_F32: ggml_raw::ggml_type = ggml_raw::GGML_TYPE_F32;
 /// ggml_init and dropping via ggml_free
 pub struct Context {
     /// An `Arc` is used to model the relation between the context and the
-    /// allocated tensors. Tensors are owned by the object, so a [`GgmlTensor`]
+    /// allocated tensors. Tensors are owned by the object, so a [`Tensor`]
     /// contains a `Weak` reference underneath and doesn't let you do anything
     /// with it if the underlying context has been deallocated.
     ptr: Arc<NonNull<ggml_raw::ggml_context>>,
+
+    /// The size of the memory pool, in bytes.
+    mem_size: usize,
+
+    /// Whether the data of new tensors is allocated in the memory pool.
+    alloc: bool,
+
+    /// The memory pool, when it is allocated on the Rust side so that it
+    /// survives [Context::reset]. Otherwise ggml owns it.
+    buffer: Option<NonNull<u8>>,
+
+    /// The memory pool supplied with [Context::init_in], which is dropped
+    /// after the context is freed.
+    caller_buffer: Option<Box<dyn AsMut<[u8]> + Send>>,
 }
 impl Context {
     pub fn init(mem_size: usize) -> Self {
+        Self::init_impl(mem_size, true)
+    }
+
+    /// Creates a context whose tensors have no data: only their metadata is
+    /// stored in the memory pool. The data of each tensor is null until it is
+    /// set with [Tensor::set_data].
+    pub fn init_no_alloc(mem_size: usize) -> Self {
+        Self::init_impl(mem_size, false)
+    }
+
+    /// Creates a context that can be emptied with [Context::reset] without
+    /// reallocating its memory pool, for temporary tensors that are rebuilt
+    /// over and over.
+    pub fn init_reusable(mem_size: usize) -> Self {
+        let layout = Self::buffer_layout(mem_size);
+        // SAFETY: The layout has a non-zero size.
+        let buffer = NonNull::new(unsafe { std::alloc::alloc(layout) })
+            .unwrap_or_else(|| std::alloc::handle_alloc_error(layout));
+        Self {
+            ptr: Self::raw_init(mem_size, buffer.as_ptr().cast()),
+            mem_size,
+            alloc: true,
+            buffer: Some(buffer),
+            caller_buffer: None,
+        }
+    }
+
+    /// Creates a context whose memory pool is `buffer`, allocated by the
+    /// caller, instead of memory allocated by ggml.
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must be valid for reads and writes of `mem_size` bytes and
+    /// aligned to [MEM_ALIGN]. It must outlive the context, and must not be
+    /// used otherwise while the context is alive.
+    pub unsafe fn init_with_buffer(mem_size: usize, buffer: *mut c_void) -> Self {
+        Self {
+            ptr: Self::raw_init(mem_size, buffer),
+            mem_size,
+            alloc: true,
+            buffer: None,
+            caller_buffer: None,
+        }
+    }
+
+    /// Creates a context whose memory pool is `buffer`, like
+    /// [Context::init_with_buffer], e.g. a `Vec<u8>` or a memory map of
+    /// huge pages. The start of the buffer is skipped up to the first address
+    /// aligned to [MEM_ALIGN]. The context owns the buffer, and drops it once
+    /// it is freed.
+    ///
+    /// The buffer is boxed, so that moving the context doesn't move the
+    /// memory pool of a buffer that stores its bytes inline.
+    pub fn init_in(mut buffer: Box<dyn AsMut<[u8]> + Send>) -> Self {
+        let bytes = buffer.as_mut().as_mut();
+        let offset = bytes.as_ptr().align_offset(MEM_ALIGN).min(bytes.len());
+        let pool = &mut bytes[offset..];
+        // SAFETY: The pool is aligned, valid for its length, and owned by the
+        // context, which frees the ggml context before dropping it.
+        let mut context = unsafe { Self::init_with_buffer(pool.len(), pool.as_mut_ptr().cast()) };
+        context.caller_buffer = Some(buffer);
+        context
+    }
+
+    fn init_impl(mem_size: usize, alloc: bool) -> Self {
+        Self {
+            // Null here means we want ggml to own this memory.
+            ptr: Self::raw_init(mem_size, std::ptr::null_mut()),
+            mem_size,
+            alloc,
+            buffer: None,
+            caller_buffer: None,
+        }
+    }
+
+    fn raw_init(mem_size: usize, mem_buffer: *mut c_void) -> Arc<NonNull<ggml_raw::ggml_context>> {
         let raw = unsafe {
             ggml_raw::ggml_init(ggml_raw::ggml_init_params {
                 mem_size,
-                // Null here means we want ggml to own this memory. We don't
-                // support passing an owned buffer from the Rust side.
-                mem_buffer: std::ptr::null_mut(),
+                mem_buffer,
             })
         };
-        Self {
-            ptr: Arc::new(NonNull::new(raw).expect("Should not be null")),
-        }
+        #[allow(clippy::arc_with_non_send_sync)]
+        Arc::new(NonNull::new(raw).expect("Should not be null"))
+    }
+
+    fn buffer_layout(mem_size: usize) -> Layout {
+        Layout::from_size_align(mem_size.max(1), MEM_ALIGN).expect("the size is valid")
+    }
+
+    /// Frees all the tensors of a context created with
+    /// [Context::init_reusable], keeping its memory pool for the next ones.
+    /// The tensors of the context can no longer be used afterwards.
+    pub fn reset(&mut self) {
+        let buffer = self
+            .buffer
+            .expect("only contexts created with init_reusable can be reset");
+        // SAFETY: The tensors only hold weak references to the old pointer,
+        // so they can't use the context once it has been replaced. The pool
+        // isn't owned by ggml, so freeing the context leaves it allocated.
+        unsafe { ggml_raw::ggml_free(self.ptr.as_ptr()) };
+        self.ptr = Self::raw_init(self.mem_size, buffer.as_ptr().cast());
     }
 
     fn new_tensor_raw(&self, raw: *mut ggml_raw::ggml_tensor) -> Tensor {
@@ -48,17 +169,45 @@ impl Context {
         }
     }
 
+    /// Creates a tensor without allocating its data, for contexts created
+    /// with [Context::init_no_alloc].
+    fn new_tensor_no_alloc(&self, typ: ggml_raw::ggml_type, ne: &[i32]) -> Tensor {
+        // ggml allocates the data if it is null, so pass a placeholder and
+        // reset it afterwards. ggml checks that the data is aligned.
+        let raw = unsafe {
+            ggml_raw::ggml_new_tensor_impl(
+                self.ptr.as_ptr(),
+                typ,
+                ne.len() as i32,
+                ne.as_ptr(),
+                MEM_ALIGN as *mut c_void,
+            )
+        };
+        // SAFETY: The tensor was just created, and nothing has read its data.
+        unsafe { (*raw).data = std::ptr::null_mut() };
+        self.new_tensor_raw(raw)
+    }
+
     pub fn new_tensor_1d(&self, typ: ggml_raw::ggml_type, ne0: i32) -> Tensor {
+        if !self.alloc {
+            return self.new_tensor_no_alloc(typ, &[ne0]);
+        }
         let raw = unsafe { ggml_raw::ggml_new_tensor_1d(self.ptr.as_ptr(), typ, ne0) };
         self.new_tensor_raw(raw)
     }
 
     pub fn new_tensor_2d(&self, typ: ggml_raw::ggml_type, ne0: i32, ne1: i32) -> Tensor {
+        if !self.alloc {
+            return self.new_tensor_no_alloc(typ, &[ne0, ne1]);
+        }
         let raw = unsafe { ggml_raw::ggml_new_tensor_2d(self.ptr.as_ptr(), typ, ne0, ne1) };
         self.new_tensor_raw(raw)
     }
 
     pub fn new_tensor_3d(&self, typ: ggml_raw::ggml_type, ne0: i32, ne1: i32, ne2: i32) -> Tensor {
+        if !self.alloc {
+            return self.new_tensor_no_alloc(typ, &[ne0, ne1, ne2]);
+        }
         let raw = unsafe { ggml_raw::ggml_new_tensor_3d(self.ptr.as_ptr(), typ, ne0, ne1, ne2) };
         self.new_tensor_raw(raw)
     }
@@ -69,96 +218,99 @@ impl Context {
     }
 
     pub fn op_get_rows(&self, a: &Tensor, b: &Tensor) -> Tensor {
-        let tensor =
-            unsafe { ggml_raw::ggml_get_rows(self.ptr.as_ptr(), a.ptr.as_ptr(), b.ptr.as_ptr()) };
+        let tensor = unsafe { ggml_raw::ggml_get_rows(self.ptr.as_ptr(), a.as_ptr(), b.as_ptr()) };
         self.new_tensor_raw(tensor)
     }
 
     pub fn op_norm(&self, a: &Tensor) -> Tensor {
-        let tensor = unsafe { ggml_raw::ggml_norm(self.ptr.as_ptr(), a.ptr.as_ptr()) };
+        let tensor = unsafe { ggml_raw::ggml_norm(self.ptr.as_ptr(), a.as_ptr()) };
         self.new_tensor_raw(tensor)
     }
 
     pub fn op_mul(&self, a: &Tensor, b: &Tensor) -> Tensor {
-        let tensor =
-            unsafe { ggml_raw::ggml_mul(self.ptr.as_ptr(), a.ptr.as_ptr(), b.ptr.as_ptr()) };
+        let tensor = unsafe { ggml_raw::ggml_mul(self.ptr.as_ptr(), a.as_ptr(), b.as_ptr()) };
         self.new_tensor_raw(tensor)
     }
 
     pub fn op_repeat(&self, a: &Tensor, b: &Tensor) -> Tensor {
-        let tensor =
-            unsafe { ggml_raw::ggml_repeat(self.ptr.as_ptr(), a.ptr.as_ptr(), b.ptr.as_ptr()) };
+        let tensor = unsafe { ggml_raw::ggml_repeat(self.ptr.as_ptr(), a.as_ptr(), b.as_ptr()) };
         self.new_tensor_raw(tensor)
     }
 
     pub fn op_mul_mat(&self, a: &Tensor, b: &Tensor) -> Tensor {
-        let tensor =
-            unsafe { ggml_raw::ggml_mul_mat(self.ptr.as_ptr(), a.ptr.as_ptr(), b.ptr.as_ptr()) };
+        let tensor = unsafe { ggml_raw::ggml_mul_mat(self.ptr.as_ptr(), a.as_ptr(), b.as_ptr()) };
         self.new_tensor_raw(tensor)
     }
 
     pub fn op_add(&self, a: &Tensor, b: &Tensor) -> Tensor {
-        let tensor =
-            unsafe { ggml_raw::ggml_add(self.ptr.as_ptr(), a.ptr.as_ptr(), b.ptr.as_ptr()) };
+        let tensor = unsafe { ggml_raw::ggml_add(self.ptr.as_ptr(), a.as_ptr(), b.as_ptr()) };
         self.new_tensor_raw(tensor)
     }
 
     pub fn op_silu(&self, a: &Tensor) -> Tensor {
-        let tensor = unsafe { ggml_raw::ggml_silu(self.ptr.as_ptr(), a.ptr.as_ptr()) };
+        let tensor = unsafe { ggml_raw::ggml_silu(self.ptr.as_ptr(), a.as_ptr()) };
         self.new_tensor_raw(tensor)
     }
 
     pub fn op_scale(&self, a: &Tensor, b: &Tensor) -> Tensor {
-        let tensor =
-            unsafe { ggml_raw::ggml_scale(self.ptr.as_ptr(), a.ptr.as_ptr(), b.ptr.as_ptr()) };
+        let tensor = unsafe { ggml_raw::ggml_scale(self.ptr.as_ptr(), a.as_ptr(), b.as_ptr()) };
         self.new_tensor_raw(tensor)
     }
 
     pub fn op_diag_mask_inf(&self, a: &Tensor, n_past: i32) -> Tensor {
-        let tensor =
-            unsafe { ggml_raw::ggml_diag_mask_inf(self.ptr.as_ptr(), a.ptr.as_ptr(), n_past) };
+        let tensor = unsafe { ggml_raw::ggml_diag_mask_inf(self.ptr.as_ptr(), a.as_ptr(), n_past) };
         self.new_tensor_raw(tensor)
     }
 
     pub fn op_soft_max(&self, a: &Tensor) -> Tensor {
-        let tensor = unsafe { ggml_raw::ggml_soft_max(self.ptr.as_ptr(), a.ptr.as_ptr()) };
+        let tensor = unsafe { ggml_raw::ggml_soft_max(self.ptr.as_ptr(), a.as_ptr()) };
         self.new_tensor_raw(tensor)
     }
 
     pub fn op_view_1d(&self, a: &Tensor, ne0: i32, offset: usize) -> Tensor {
-        let tensor =
-            unsafe { ggml_raw::ggml_view_1d(self.ptr.as_ptr(), a.ptr.as_ptr(), ne0, offset) };
+        let tensor = unsafe { ggml_raw::ggml_view_1d(self.ptr.as_ptr(), a.as_ptr(), ne0, offset) };
         self.new_tensor_raw(tensor)
     }
 
     pub fn op_cpy(&self, a: &Tensor, b: &Tensor) -> Tensor {
-        let tensor =
-            unsafe { ggml_raw::ggml_cpy(self.ptr.as_ptr(), a.ptr.as_ptr(), b.ptr.as_ptr()) };
+        let tensor = unsafe { ggml_raw::ggml_cpy(self.ptr.as_ptr(), a.as_ptr(), b.as_ptr()) };
         self.new_tensor_raw(tensor)
     }
 
     pub fn op_permute(&self, a: &Tensor, axis0: i32, axis1: i32, axis2: i32, axis3: i32) -> Tensor {
         let tensor = unsafe {
-            ggml_raw::ggml_permute(
-                self.ptr.as_ptr(),
-                a.ptr.as_ptr(),
-                axis0,
-                axis1,
-                axis2,
-                axis3,
-            )
+            ggml_raw::ggml_permute(self.ptr.as_ptr(), a.as_ptr(), axis0, axis1, axis2, axis3)
         };
         self.new_tensor_raw(tensor)
     }
     pub fn op_reshape_3d(&self, a: &Tensor, ne0: i32, ne1: i32, ne2: i32) -> Tensor {
         let tensor =
-            unsafe { ggml_raw::ggml_reshape_3d(self.ptr.as_ptr(), a.ptr.as_ptr(), ne0, ne1, ne2) };
+            unsafe { ggml_raw::ggml_reshape_3d(self.ptr.as_ptr(), a.as_ptr(), ne0, ne1, ne2) };
         self.new_tensor_raw(tensor)
     }
 
-    pub fn op_rope(&self, a: &Tensor, npast: i32, ndims: i32, mode: i32) -> Tensor {
-        let tensor =
-            unsafe { ggml_raw::ggml_rope(self.ptr.as_ptr(), a.ptr.as_ptr(), npast, ndims, mode) };
+    /// Applies rotary position embeddings. The positions are multiplied by
+    /// `freq_scale`, and `freq_base` is the base of the rotation frequencies.
+    pub fn op_rope(
+        &self,
+        a: &Tensor,
+        npast: i32,
+        ndims: i32,
+        mode: i32,
+        freq_base: f32,
+        freq_scale: f32,
+    ) -> Tensor {
+        let tensor = unsafe {
+            ggml_raw::ggml_rope_custom(
+                self.ptr.as_ptr(),
+                a.as_ptr(),
+                npast,
+                ndims,
+                mode,
+                freq_base,
+                freq_scale,
+            )
+        };
         self.new_tensor_raw(tensor)
     }
 
@@ -168,11 +320,32 @@ impl Context {
         }
     }
 
+    /// Computes `output`, and the tensors it depends on, with `n_threads`
+    /// threads. The tensors must belong to this context, which also holds
+    /// the work buffer of the computation.
+    pub fn compute(&self, output: &Tensor, n_threads: i32) {
+        let mut graph = ComputationGraph::new(n_threads);
+        graph.build_forward_expand(output);
+        self.graph_compute(&mut graph);
+    }
+
+    /// Returns the number of bytes of the memory pool used by the objects
+    /// allocated so far.
     pub fn used_mem(&self) -> usize {
         unsafe { ggml_raw::ggml_used_mem(self.ptr.as_ptr()) }
     }
+
+    /// Returns the size of the memory pool, in bytes.
+    pub fn mem_size(&self) -> usize {
+        self.mem_size
+    }
 }
 
+// SAFETY: A context is not tied to the thread that created it: its memory pool
+// is heap-allocated or a `Send` buffer, and ggml only touches its global state under its own
+// lock. The context is freed once, by whichever thread drops it.
+unsafe impl Send for Context {}
+
 impl Drop for Context {
     fn drop(&mut self) {
         // SAFETY: The only non-weak copy of ptr is no longer accessible after
@@ -180,6 +353,11 @@ impl Drop for Context {
         unsafe {
             ggml_raw::ggml_free(self.ptr.as_ptr());
         }
+        if let Some(buffer) = self.buffer {
+            // SAFETY: The buffer was allocated with this layout in
+            // init_reusable, and ggml no longer refers to it.
+            unsafe { std::alloc::dealloc(buffer.as_ptr(), Self::buffer_layout(self.mem_size)) };
+        }
     }
 }
 
@@ -190,15 +368,39 @@ pub struct Tensor {
     ctx: Weak<NonNull<ggml_raw::ggml_context>>,
 }
 
+// SAFETY: Moving a tensor only moves a pointer into its context's memory pool,
+// and the `Weak` reference to the context is atomically counted. The tensors
+// of a context are moved along with it (they are owned by the same model or
+// session), so they are never used from two threads at once.
+unsafe impl Send for Tensor {}
+
 impl Tensor {
-    /// Creates a shared copy of this tensor pointer.
-    pub fn share(&self) -> Self {
+    /// Returns another handle to the same tensor, e.g. to keep it both in a
+    /// map of tensors by name and in the struct of its layer.
+    ///
+    /// This copies the pointer to the tensor, not the tensor: the tensor and
+    /// its data are owned by its [Context], and writes through one handle are
+    /// seen through the other. Handles don't keep the context alive, as they
+    /// only hold a `Weak` reference to it, so they can't form reference
+    /// cycles. Using any handle after the context was dropped panics.
+    pub fn alias(&self) -> Self {
         Tensor {
             ptr: self.ptr,
             ctx: Weak::clone(&self.ctx),
         }
     }
 
+    /// Returns the pointer to the tensor, to pass it to ggml. In debug
+    /// builds, checks that its context wasn't dropped, which would have freed
+    /// the tensor.
+    fn as_ptr(&self) -> *mut ggml_raw::ggml_tensor {
+        debug_assert!(
+            self.ctx.strong_count() > 0,
+            "Using a tensor after the context was dropped"
+        );
+        self.ptr.as_ptr()
+    }
+
     fn with_alive_ctx<U>(&self, f: impl Fn() -> U) -> U {
         if let Some(_ctx) = self.ctx.upgrade() {
             f()
@@ -221,13 +423,66 @@ impl Tensor {
         })
     }
 
-    pub fn nelements(&self) -> i32 {
+    /// Makes the tensor use `data` as its data.
+    ///
+    /// # Safety
+    ///
+    /// `data` must point to at least [Tensor::nbytes] bytes that stay valid
+    /// for as long as the tensor is used. If ggml writes to the tensor, they
+    /// must be writable.
+    pub unsafe fn set_data(&self, data: *mut c_void) {
         self.with_alive_ctx(|| {
             // SAFETY: The with_alive_call guarantees the context is alive
-            unsafe { ggml_raw::ggml_nelements(self.ptr.as_ptr()) }
+            unsafe { (*self.ptr.as_ptr()).data = data }
         })
     }
 
+    /// Names the tensor, to tell it apart when debugging graphs. ggml keeps
+    /// the first 31 bytes of the name, up to the first NUL byte.
+    pub fn set_name(&self, name: &str) {
+        let name: Vec<u8> = name
+            .bytes()
+            .take_while(|&b| b != 0)
+            .chain(std::iter::once(0))
+            .collect();
+        self.with_alive_ctx(|| {
+            // SAFETY: The with_alive_call guarantees the context is alive, and
+            // the name is NUL-terminated.
+            unsafe { ggml_raw::ggml_set_name(self.ptr.as_ptr(), name.as_ptr().cast()) }
+        })
+    }
+
+    /// Returns the name set with [Tensor::set_name], or an empty string.
+    pub fn name(&self) -> String {
+        self.with_alive_ctx(|| {
+            // SAFETY: The with_alive_call guarantees the context is alive, and
+            // ggml keeps the name NUL-terminated.
+            let name =
+                unsafe { std::ffi::CStr::from_ptr(ggml_raw::ggml_get_name(self.ptr.as_ptr())) };
+            name.to_string_lossy().into_owned()
+        })
+    }
+
+    /// Returns the number of elements in the tensor. Unlike ggml, this does
+    /// not overflow for tensors with more than `i32::MAX` elements.
+    pub fn nelements(&self) -> i64 {
+        self.shape().iter().product()
+    }
+
+    /// Returns the number of dimensions of the tensor.
+    pub fn n_dims(&self) -> usize {
+        self.with_alive_ctx(|| {
+            // SAFETY: The with_alive_call guarantees the context is alive
+            unsafe { *self.ptr.as_ptr() }.n_dims as usize
+        })
+    }
+
+    /// Returns the number of elements in each dimension. Unused dimensions
+    /// have a size of 1.
+    pub fn shape(&self) -> [i64; 4] {
+        self.get_ne().map(i64::from)
+    }
+
     pub fn get_ne(&self) -> [i32; 4] {
         self.with_alive_ctx(|| unsafe { *self.ptr.as_ptr() }.ne)
     }
@@ -244,6 +499,63 @@ impl Tensor {
         self.with_alive_ctx(|| unsafe { ggml_raw::ggml_element_size(self.ptr.as_ptr()) })
     }
 
+    /// Returns the data of an `f32` tensor. Returns `None` for other types, or
+    /// if the tensor has no data.
+    pub fn as_f32_slice(&self) -> Option<&[f32]> {
+        let data = self.f32_data()?;
+        // SAFETY: `f32_data` checked that the data holds the tensor's
+        // elements.
+        Some(unsafe { std::slice::from_raw_parts(data, self.nelements() as usize) })
+    }
+
+    /// Returns the data of an `f32` tensor. Returns `None` for other types, or
+    /// if the tensor has no data.
+    pub fn as_f32_slice_mut(&mut self) -> Option<&mut [f32]> {
+        let data = self.f32_data()?;
+        // SAFETY: `f32_data` checked that the data holds the tensor's
+        // elements.
+        Some(unsafe { std::slice::from_raw_parts_mut(data, self.nelements() as usize) })
+    }
+
+    /// Returns a pointer to the data of the tensor if it is a contiguous `f32`
+    /// tensor with data.
+    fn f32_data(&self) -> Option<*mut f32> {
+        let data = self.data() as *mut f32;
+        let contiguous = self.nbytes() == self.nelements() as usize * std::mem::size_of::<f32>();
+        (self.get_type() == TYPE_F32 && contiguous && !data.is_null()).then_some(data)
+    }
+
+    /// Returns a copy of the tensor's data converted to `f32`, dequantizing
+    /// it if needed. Returns `None` for integer tensors.
+    pub fn to_f32_vec(&self) -> Option<Vec<f32>> {
+        self.with_alive_ctx(|| {
+            let n = self.nelements() as usize;
+            let data = self.data();
+            let mut values = vec![0.0; n];
+            // SAFETY: The with_alive_call guarantees the context is alive, and
+            // the data holds `n` contiguous elements of the tensor's type.
+            unsafe {
+                match self.get_type() {
+                    TYPE_F32 => values.copy_from_slice(self.as_f32_slice()?),
+                    TYPE_F16 => {
+                        let halves = std::slice::from_raw_parts(data as *const u16, n);
+                        for (value, &half) in values.iter_mut().zip(halves) {
+                            *value = fp16_to_fp32(half);
+                        }
+                    }
+                    TYPE_Q4_0 => {
+                        ggml_raw::dequantize_row_q4_0(data, values.as_mut_ptr(), n.try_into().ok()?)
+                    }
+                    TYPE_Q4_1 => {
+                        ggml_raw::dequantize_row_q4_1(data, values.as_mut_ptr(), n.try_into().ok()?)
+                    }
+                    _ => return None,
+                }
+            }
+            Some(values)
+        })
+    }
+
     pub unsafe fn write_data(&self, src: &[u8]) {
         std::ptr::copy_nonoverlapping(src.as_ptr(), self.data() as *mut u8, src.len())
     }
@@ -259,6 +571,34 @@ impl Tensor {
     }
 }
 
+impl std::fmt::Debug for Tensor {
+    /// Formats the tensor as its name, if it has one, type, shape and size,
+    /// e.g. `output q4_0 [4096 x 32000] 73728000 bytes`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.ctx.upgrade().is_none() {
+            return write!(f, "<tensor of a dropped context>");
+        }
+
+        let name = self.name();
+        if !name.is_empty() {
+            write!(f, "{name} ")?;
+        }
+
+        let shape = self.shape();
+        let dims: Vec<String> = shape[..self.n_dims().clamp(1, 4)]
+            .iter()
+            .map(|ne| ne.to_string())
+            .collect();
+        write!(
+            f,
+            "{} [{}] {} bytes",
+            type_name(self.get_type()),
+            dims.join(" x "),
+            self.nbytes()
+        )
+    }
+}
+
 pub struct ComputationGraph {
     inner: ggml_raw::ggml_cgraph,
 }
@@ -276,18 +616,471 @@ impl ComputationGraph {
     }
 
     pub fn build_forward_expand(&mut self, tensor: &Tensor) {
-        unsafe { ggml_raw::ggml_build_forward_expand(&mut self.inner, tensor.ptr.as_ptr()) }
+        unsafe { ggml_raw::ggml_build_forward_expand(&mut self.inner, tensor.as_ptr()) }
+    }
+
+    /// Returns the number of operations of the graph.
+    #[cfg(test)]
+    pub fn n_nodes(&self) -> usize {
+        self.inner.n_nodes as usize
+    }
+
+    /// Writes the graph to `path` in the Graphviz DOT format, with a node for
+    /// each operation labelled with its index, name, shape and operation, and
+    /// a node for each input labelled with its index and shape.
+    ///
+    /// The tensors of the graph must still be alive.
+    pub fn dump_dot(&self, path: &Path) -> std::io::Result<()> {
+        let invalid_path =
+            || std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid graph dump path");
+        let c_path =
+            CString::new(path.to_str().ok_or_else(invalid_path)?).map_err(|_| invalid_path())?;
+        // ggml aborts if it can't open the file, so check that it can first.
+        std::fs::File::create(path)?;
+        // SAFETY: The graph has no gradients, so it is not looked up in the
+        // forward graph, and the path is NUL-terminated.
+        unsafe { ggml_raw::ggml_graph_dump_dot(&self.inner, &self.inner, c_path.as_ptr()) };
+        Ok(())
     }
 }
 
-pub fn type_size(t: Type) -> usize {
-    unsafe { ggml_raw::ggml_type_size(t) }
+/// Converts the bits of an IEEE 754 half-precision float to an `f32`. ggml's
+/// own conversion is not used, as its `ggml_fp16_t` is not a `u16` on all
+/// platforms.
+pub fn fp16_to_fp32(half: u16) -> f32 {
+    let sign = u32::from(half >> 15) << 31;
+    let exponent = u32::from(half >> 10) & 0x1f;
+    let mantissa = u32::from(half) & 0x3ff;
+    match exponent {
+        // Zero, or a subnormal number, which is a normal number as an `f32`.
+        0 => {
+            let magnitude = mantissa as f32 * 2f32.powi(-24);
+            f32::from_bits(sign | magnitude.to_bits())
+        }
+        // Infinity or NaN.
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (mantissa << 13)),
+        _ => f32::from_bits(sign | ((exponent + 127 - 15) << 23) | (mantissa << 13)),
+    }
 }
 
-pub fn type_sizef(x: ggml_raw::ggml_type) -> f64 {
-    (unsafe { ggml_raw::ggml_type_sizef(x) }) as f64
+/// Converts an `f32` to the bits of the nearest IEEE 754 half-precision
+/// float, rounding ties to even like ggml.
+pub fn fp32_to_fp16(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        // Infinity, or NaN, which stays a NaN.
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+
+    // Rounds `value >> shift` to the nearest integer, ties to even. Rounding
+    // up can carry into the exponent, which is still the right result.
+    let round = |value: u32, shift: u32| {
+        let (half, rest) = (value >> shift, value & ((1 << shift) - 1));
+        let halfway = 1 << (shift - 1);
+        half + u32::from(rest > halfway || (rest == halfway && half & 1 == 1))
+    };
+    let exponent = exponent - 127 + 15;
+    let half = if exponent >= 0x1f {
+        // Too large, so infinite.
+        0x7c00
+    } else if exponent > 0 {
+        round(((exponent as u32) << 23) | mantissa, 13)
+    } else if exponent >= -10 {
+        // A subnormal number, in units of 2^-24.
+        round(mantissa | 0x80_0000, (14 - exponent) as u32)
+    } else {
+        // Too small, so zero.
+        0
+    };
+    sign | half as u16
+}
+
+/// The alignment of the data of each tensor in a context.
+pub const MEM_ALIGN: usize = 16;
+
+/// The size of the `ggml_object` header that precedes each tensor in a
+/// context: two `size_t`s, a pointer and 8 bytes of padding.
+const OBJECT_SIZE: usize = 3 * std::mem::size_of::<usize>() + 8;
+
+/// Returns the number of bytes used by each tensor in a context, in addition
+/// to its data.
+pub fn tensor_overhead() -> usize {
+    OBJECT_SIZE + std::mem::size_of::<ggml_raw::ggml_tensor>()
+}
+
+/// Returns the name of the type, as used by ggml.
+pub fn type_name(t: Type) -> &'static str {
+    match t {
+        TYPE_Q4_0 => "q4_0",
+        TYPE_Q4_1 => "q4_1",
+        ggml_raw::GGML_TYPE_I8 => "i8",
+        ggml_raw::GGML_TYPE_I16 => "i16",
+        TYPE_I32 => "i32",
+        TYPE_F16 => "f16",
+        TYPE_F32 => "f32",
+        _ => "unknown",
+    }
+}
+
+pub fn type_size(t: Type) -> usize {
+    unsafe { ggml_raw::ggml_type_size(t) }
 }
 
 pub fn blck_size(t: Type) -> i32 {
     unsafe { ggml_raw::ggml_blck_size(t) }
 }
+
+/// Defines a function that returns whether ggml was compiled with kernels
+/// for an instruction set.
+macro_rules! cpu_has {
+    ($($name:ident => $raw:ident),* $(,)?) => {
+        $(
+            pub fn $name() -> bool {
+                unsafe { ggml_raw::$raw() != 0 }
+            }
+        )*
+    };
+}
+
+cpu_has! {
+    cpu_has_avx => ggml_cpu_has_avx,
+    cpu_has_avx2 => ggml_cpu_has_avx2,
+    cpu_has_avx512 => ggml_cpu_has_avx512,
+    cpu_has_fma => ggml_cpu_has_fma,
+    cpu_has_neon => ggml_cpu_has_neon,
+    cpu_has_arm_fma => ggml_cpu_has_arm_fma,
+    cpu_has_f16c => ggml_cpu_has_f16c,
+    cpu_has_fp16_va => ggml_cpu_has_fp16_va,
+    cpu_has_wasm_simd => ggml_cpu_has_wasm_simd,
+    cpu_has_blas => ggml_cpu_has_blas,
+    cpu_has_sse3 => ggml_cpu_has_sse3,
+    cpu_has_vsx => ggml_cpu_has_vsx,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fp32_to_fp16_inverts_fp16_to_fp32() {
+        for half in 0..=u16::MAX {
+            let x = fp16_to_fp32(half);
+            if x.is_nan() {
+                assert!(fp16_to_fp32(fp32_to_fp16(x)).is_nan());
+            } else {
+                assert_eq!(fp32_to_fp16(x), half, "{x}");
+            }
+        }
+    }
+
+    #[test]
+    fn fp32_to_fp16_rounds_to_nearest_even() {
+        // 1 + 2^-11 is halfway between 1 and the next half-precision float.
+        assert_eq!(fp32_to_fp16(1.0 + 2f32.powi(-11)), 0x3c00);
+        assert_eq!(fp32_to_fp16(1.0 + 3.0 * 2f32.powi(-11)), 0x3c02);
+        assert_eq!(fp32_to_fp16(1.0 + 1.5 * 2f32.powi(-11)), 0x3c01);
+        assert_eq!(fp32_to_fp16(65520.0), 0x7c00);
+        assert_eq!(fp32_to_fp16(-1e9), 0xfc00);
+        assert_eq!(fp32_to_fp16(2f32.powi(-25)), 0);
+        assert_eq!(fp32_to_fp16(3.0 * 2f32.powi(-26)), 1);
+    }
+
+    #[test]
+    fn context_in_a_vec_allocates_its_tensors_there() {
+        let buffer = vec![0u8; 64 * 1024 + MEM_ALIGN];
+        let range = buffer.as_ptr_range();
+        let context = Context::init_in(Box::new(buffer));
+        assert!(context.mem_size() > 64 * 1024);
+
+        let tensor = context.new_tensor_1d(TYPE_F32, 4);
+        let data = tensor.data() as *const u8;
+        assert!(range.contains(&data));
+        assert!(range.contains(&data.wrapping_add(tensor.nbytes() - 1)));
+
+        let values: Vec<u8> = [1.0f32, 2.0, 3.0, 4.0]
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect();
+        let mut read = vec![0; values.len()];
+        // SAFETY: The tensor holds as many bytes as `values`.
+        unsafe {
+            tensor.write_data(&values);
+            tensor.read_data(0, &mut read);
+        }
+        assert_eq!(read, values);
+    }
+
+    /// Packs 32 4-bit values into a block of 16 bytes, two per byte with the
+    /// first in the low bits, as ggml does.
+    fn pack_nibbles(quants: impl Fn(usize) -> u8) -> Vec<u8> {
+        (0..16)
+            .map(|j| quants(2 * j) | quants(2 * j + 1) << 4)
+            .collect()
+    }
+
+    /// Returns a tensor of `typ` and `ne0` elements with the data `bytes`.
+    fn tensor_with_data(context: &Context, typ: Type, ne0: i32, bytes: &[u8]) -> Tensor {
+        let tensor = context.new_tensor_1d(typ, ne0);
+        assert_eq!(tensor.nbytes(), bytes.len());
+        // SAFETY: The tensor holds as many bytes as `bytes`.
+        unsafe { tensor.write_data(bytes) };
+        tensor
+    }
+
+    #[test]
+    fn to_f32_vec_dequantizes_the_data() {
+        let context = Context::init(1024 * 1024);
+
+        let halves = [0x3c00, 0xc000, 0x3555, 0x7bff, 0x0001, 0x8000];
+        let bytes: Vec<u8> = halves.iter().flat_map(|h: &u16| h.to_le_bytes()).collect();
+        let tensor = tensor_with_data(&context, TYPE_F16, 6, &bytes);
+        let expected = [1.0, -2.0, 0.333_251_95, 65504.0, 2f32.powi(-24), -0.0];
+        let values = tensor.to_f32_vec().unwrap();
+        assert_eq!(values, expected);
+        assert!(values[5].is_sign_negative());
+        let round_trip: Vec<u16> = values.iter().map(|&x| fp32_to_fp16(x)).collect();
+        assert_eq!(round_trip, halves);
+
+        // Q4_0 blocks are a scale `d` and 32 quants `q`, for `(q - 8) d`.
+        let mut bytes = Vec::new();
+        for (d, quant) in [(0.5f32, 3), (-2.0, 5)] {
+            bytes.extend(d.to_le_bytes());
+            bytes.extend(pack_nibbles(|l| ((l * quant) % 16) as u8));
+        }
+        let tensor = tensor_with_data(&context, TYPE_Q4_0, 64, &bytes);
+        let values = tensor.to_f32_vec().unwrap();
+        assert_eq!(values[..4], [-4.0, -2.5, -1.0, 0.5]);
+        assert_eq!(values[32..36], [16.0, 6.0, -4.0, -14.0]);
+        for (i, value) in values.iter().enumerate() {
+            let (d, quant) = [(0.5, 3), (-2.0, 5)][i / 32];
+            let q = ((i % 32) * quant % 16) as f32;
+            assert_eq!(*value, (q - 8.0) * d, "{i}");
+        }
+
+        // Q4_1 blocks are a scale `d`, a minimum `m` and 32 quants `q`, for
+        // `q d + m`.
+        let mut bytes = Vec::new();
+        bytes.extend(0.25f32.to_le_bytes());
+        bytes.extend((-1.0f32).to_le_bytes());
+        bytes.extend(pack_nibbles(|l| (15 - l % 16) as u8));
+        let tensor = tensor_with_data(&context, TYPE_Q4_1, 32, &bytes);
+        let values = tensor.to_f32_vec().unwrap();
+        assert_eq!(values[..3], [2.75, 2.5, 2.25]);
+        for (i, value) in values.iter().enumerate() {
+            assert_eq!(*value, (15 - i % 16) as f32 * 0.25 - 1.0, "{i}");
+        }
+
+        let tensor = context.new_tensor_1d(TYPE_I32, 4);
+        assert_eq!(tensor.to_f32_vec(), None);
+    }
+
+    #[test]
+    fn compute_evaluates_the_graph_of_a_tensor() {
+        let context = Context::init(1024 * 1024);
+        let mut a = context.new_tensor_1d(TYPE_F32, 4);
+        let mut b = context.new_tensor_1d(TYPE_F32, 4);
+        a.as_f32_slice_mut()
+            .unwrap()
+            .copy_from_slice(&[1.0, 2.0, 3.0, 4.0]);
+        b.as_f32_slice_mut()
+            .unwrap()
+            .copy_from_slice(&[10.0, 20.0, 30.0, 40.0]);
+
+        let sum = context.op_add(&a, &b);
+        context.compute(&sum, 1);
+        assert_eq!(sum.as_f32_slice().unwrap(), [11.0, 22.0, 33.0, 44.0]);
+    }
+
+    #[test]
+    fn reset_reuses_the_memory_pool_for_new_tensors() {
+        let mut context = Context::init_reusable(1024 * 1024);
+        let mut first_data = None;
+        let mut old_tensors: Vec<Tensor> = Vec::new();
+        for i in 0..3 {
+            let mut a = context.new_tensor_1d(TYPE_F32, 4);
+            let mut b = context.new_tensor_1d(TYPE_F32, 4);
+            a.as_f32_slice_mut().unwrap().fill(i as f32);
+            b.as_f32_slice_mut().unwrap().fill(1.0);
+            let sum = context.op_add(&a, &b);
+            context.compute(&sum, 1);
+            assert_eq!(sum.as_f32_slice().unwrap(), [i as f32 + 1.0; 4]);
+
+            // Each evaluation starts from the beginning of the same pool.
+            assert_eq!(*first_data.get_or_insert(a.data()), a.data());
+            let used_mem = context.used_mem();
+            assert!(old_tensors
+                .iter()
+                .all(|tensor| tensor.ctx.upgrade().is_none()));
+            old_tensors.extend([a, b, sum]);
+
+            context.reset();
+            assert!(old_tensors
+                .iter()
+                .all(|tensor| tensor.ctx.upgrade().is_none()));
+            let tensor = context.new_tensor_1d(TYPE_F32, 4);
+            assert!(context.used_mem() < used_mem);
+            drop(tensor);
+            context.reset();
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Using a tensor after the context was dropped")]
+    fn tensors_are_unusable_after_a_reset() {
+        let mut context = Context::init_reusable(1024 * 1024);
+        let tensor = context.new_tensor_1d(TYPE_F32, 4);
+        context.reset();
+        tensor.nbytes();
+    }
+
+    #[test]
+    fn rope_frequencies_can_be_customized() {
+        // 8 dimensions of 2 heads, for 3 tokens.
+        let rope = |n_past, freq: Option<(f32, f32)>| {
+            let context = Context::init(1024 * 1024);
+            let mut x = context.new_tensor_3d(TYPE_F32, 8, 2, 3);
+            for (i, value) in x.as_f32_slice_mut().unwrap().iter_mut().enumerate() {
+                *value = (i % 8) as f32 / 4.0 - 1.0;
+            }
+            let output = match freq {
+                Some((freq_base, freq_scale)) => {
+                    context.op_rope(&x, n_past, 8, 0, freq_base, freq_scale)
+                }
+                None => context.new_tensor_raw(unsafe {
+                    ggml_raw::ggml_rope(context.ptr.as_ptr(), x.as_ptr(), n_past, 8, 0)
+                }),
+            };
+            context.compute(&output, 1);
+            output.as_f32_slice().unwrap().to_vec()
+        };
+
+        let default = rope(2, None);
+        assert_eq!(rope(2, Some((10000.0, 1.0))), default);
+        assert_ne!(rope(2, Some((20000.0, 1.0))), default);
+        assert_ne!(rope(2, Some((10000.0, 0.5))), default);
+        // Halving the scale halves the positions: the first token is rotated
+        // as if it came after one token instead of two.
+        assert_eq!(rope(2, Some((10000.0, 0.5)))[..16], rope(1, None)[..16]);
+    }
+
+    #[test]
+    fn aliases_share_the_tensor() {
+        let context = Context::init(1024 * 1024);
+        let tensor = context.new_tensor_1d(TYPE_F32, 4);
+        let mut alias = tensor.alias();
+        assert_eq!(alias.data(), tensor.data());
+
+        alias
+            .as_f32_slice_mut()
+            .unwrap()
+            .copy_from_slice(&[1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(tensor.as_f32_slice().unwrap(), [1.0, 2.0, 3.0, 4.0]);
+        alias.set_name("shared");
+        assert_eq!(tensor.name(), "shared");
+
+        // The context frees the tensor once, whichever handle is dropped
+        // first.
+        drop(tensor);
+        assert_eq!(alias.as_f32_slice().unwrap(), [1.0, 2.0, 3.0, 4.0]);
+        drop(alias);
+        drop(context);
+    }
+
+    #[test]
+    fn tensor_names_are_truncated_to_31_bytes() {
+        let context = Context::init(1024 * 1024);
+        let mut a = context.new_tensor_1d(TYPE_F32, 4);
+        let b = context.new_tensor_1d(TYPE_F32, 4);
+        assert_eq!(a.name(), "");
+        a.as_f32_slice_mut()
+            .unwrap()
+            .copy_from_slice(&[1.0, 2.0, 3.0, 4.0]);
+        b.set_name("b");
+
+        a.set_name("layers.0.attention.wq.weight");
+        assert_eq!(a.name(), "layers.0.attention.wq.weight");
+        let long_name = "layers.31.feed_forward.w3.weight.transposed";
+        a.set_name(long_name);
+        assert_eq!(a.name(), long_name[..31]);
+        a.set_name("before\0after");
+        assert_eq!(a.name(), "before");
+
+        // The name is stored in the tensor, so a long one must not overwrite
+        // what follows it.
+        assert_eq!(a.as_f32_slice().unwrap(), [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(b.name(), "b");
+    }
+
+    #[test]
+    #[should_panic(expected = "Using a tensor after the context was dropped")]
+    fn aliases_are_unusable_after_the_context_is_dropped() {
+        let context = Context::init(1024 * 1024);
+        let alias = context.new_tensor_1d(TYPE_F32, 4).alias();
+        drop(context);
+        alias.nbytes();
+    }
+
+    #[test]
+    fn graphs_are_dumped_in_the_dot_format() {
+        let context = Context::init(1024 * 1024);
+        let x = context.new_tensor_2d(TYPE_F32, 4, 3);
+        let w = context.new_tensor_2d(TYPE_F32, 4, 5);
+        x.set_name("x");
+        w.set_name("w");
+        let product = context.op_mul_mat(&w, &x);
+        product.set_name("product");
+        let output = context.op_silu(&product);
+        output.set_name("output");
+        let mut graph = ComputationGraph::new(1);
+        graph.build_forward_expand(&output);
+
+        let path = crate::test_model::temp_path("graph.dot");
+        graph.dump_dot(&path).unwrap();
+        let dot = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(dot.starts_with("digraph G {\n"), "{dot}");
+        assert!(dot.ends_with("}\n"), "{dot}");
+        // The operations are labelled with their name, shape and operation,
+        // and the inputs with their shape.
+        for label in [
+            "0 product [5, 3] | <x>X*Y",
+            "1 output [5, 3] | <x>silu(x)",
+            "<x>CONST 0 [4, 5]",
+            "<x>CONST 1 [4, 3]",
+        ] {
+            assert!(dot.contains(&format!("label=\"{label}\"")), "{dot}");
+        }
+        assert_eq!(dot.matches(" -> ").count(), 3, "{dot}");
+    }
+
+    #[test]
+    fn tensor_overhead_is_what_ggml_uses() {
+        let context = Context::init(1024 * 1024);
+        let shapes = [
+            (TYPE_F32, 1, 1),
+            (TYPE_F32, 7, 3),
+            (TYPE_F16, 5, 1),
+            (TYPE_Q4_0, 64, 2),
+            (TYPE_Q4_1, 32, 3),
+            (TYPE_I32, 5, 1),
+        ];
+        // ggml can't report the memory used by an empty context, so the
+        // total is checked after each tensor.
+        let mut expected = 0;
+        for (typ, ne0, ne1) in shapes {
+            let tensor = context.new_tensor_2d(typ, ne0, ne1);
+            expected +=
+                tensor_overhead() + (tensor.nbytes() + MEM_ALIGN - 1) / MEM_ALIGN * MEM_ALIGN;
+            assert_eq!(
+                context.used_mem(),
+                expected,
+                "{} tensor of {ne0}x{ne1}",
+                type_name(typ)
+            );
+        }
+    }
+}
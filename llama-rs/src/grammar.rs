@@ -0,0 +1,685 @@
+//! Grammar-constrained decoding, using a subset of the GBNF format of
+//! llama.cpp.
+//!
+//! A grammar is a list of rules of the form `name ::= expression`, and the
+//! generated text must match the rule named `root`. Expressions can contain:
+//!
+//! - string literals, e.g. `"yes"`, with the escapes `\n`, `\r`, `\t`, `\\`,
+//!   `\"` and `\xHH`;
+//! - character classes, e.g. `[a-z0-9]` or `[^"]`, and `.` for any character;
+//! - references to other rules, and groups in parentheses;
+//! - alternation with `|`;
+//! - repetition with the `*`, `+` and `?` suffixes.
+//!
+//! Comments start with `#`. Left-recursive rules are not supported.
+//!
+//! ```
+//! use llama_rs::grammar::Grammar;
+//!
+//! let grammar: Grammar = r#"root ::= "yes" | "no""#.parse().unwrap();
+//! ```
+
+use std::{collections::HashMap, str::FromStr};
+
+use thiserror::Error;
+
+use crate::{TokenId, Vocabulary};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+/// Errors encountered while parsing a grammar.
+pub enum GrammarError {
+    #[error("expected {expected} at byte {position}")]
+    Expected {
+        expected: &'static str,
+        position: usize,
+    },
+    #[error("invalid escape sequence at byte {position}")]
+    InvalidEscape { position: usize },
+    #[error("rule {name:?} is defined more than once")]
+    DuplicateRule { name: String },
+    #[error("rule {name:?} is used but never defined")]
+    UndefinedRule { name: String },
+    #[error("the grammar has no root rule")]
+    MissingRoot,
+    #[error("rule {name:?} is left-recursive")]
+    LeftRecursion { name: String },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Element {
+    /// Matches a single character in (or, if negated, not in) the inclusive
+    /// ranges.
+    Char {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    /// Matches the rule with the given id.
+    Rule(usize),
+}
+impl Element {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Element::Char { ranges, negated } => {
+                ranges.iter().any(|&(lo, hi)| (lo..=hi).contains(&c)) != *negated
+            }
+            Element::Rule(_) => false,
+        }
+    }
+}
+
+/// A parsed grammar. See the [module documentation](self) for the syntax.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Grammar {
+    /// The alternatives of each rule, indexed by rule id.
+    rules: Vec<Vec<Vec<Element>>>,
+    /// The name of each rule. Rules generated for groups and repetitions are
+    /// named after the rule they appear in.
+    names: Vec<String>,
+    root: usize,
+}
+
+/// A position in the grammar: the next element to match in an alternative of
+/// a rule.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Position {
+    rule: usize,
+    alt: usize,
+    elem: usize,
+}
+
+/// The positions of the rules being matched, from the outermost to the
+/// innermost. The innermost position always points at a character element,
+/// unless the stack is empty, in which case the grammar has been matched
+/// completely.
+type Stack = Vec<Position>;
+
+impl Grammar {
+    /// Parses a grammar.
+    pub fn parse(source: &str) -> Result<Self, GrammarError> {
+        Parser::new(source).parse()
+    }
+
+    fn alternative(&self, pos: Position) -> &[Element] {
+        &self.rules[pos.rule][pos.alt]
+    }
+
+    /// Returns the stacks at the start of the root rule.
+    fn initial_stacks(&self) -> Vec<Stack> {
+        let mut stacks = vec![];
+        for alt in 0..self.rules[self.root].len() {
+            self.expand(
+                vec![Position {
+                    rule: self.root,
+                    alt,
+                    elem: 0,
+                }],
+                &mut stacks,
+            );
+        }
+        stacks.sort();
+        stacks.dedup();
+        stacks
+    }
+
+    /// Resolves the rule references and finished rules at the top of the
+    /// stack, until it points at a character element.
+    fn expand(&self, mut stack: Stack, out: &mut Vec<Stack>) {
+        let Some(&top) = stack.last() else {
+            out.push(stack);
+            return;
+        };
+
+        match self.alternative(top).get(top.elem) {
+            None => {
+                stack.pop();
+                self.expand(stack, out);
+            }
+            Some(Element::Char { .. }) => out.push(stack),
+            Some(&Element::Rule(rule)) => {
+                // Continue after the reference once the rule is matched. If
+                // that is the end of the alternative, there is no need to
+                // come back to it.
+                let parent = stack.last_mut().unwrap();
+                parent.elem += 1;
+                if parent.elem == self.alternative(*parent).len() {
+                    stack.pop();
+                }
+
+                for alt in 0..self.rules[rule].len() {
+                    let mut stack = stack.clone();
+                    stack.push(Position { rule, alt, elem: 0 });
+                    self.expand(stack, out);
+                }
+            }
+        }
+    }
+
+    /// Returns the stacks after matching `c`.
+    fn advance(&self, stacks: &[Stack], c: char) -> Vec<Stack> {
+        let mut out = vec![];
+        for stack in stacks {
+            let Some(&top) = stack.last() else {
+                continue;
+            };
+            if !self.alternative(top)[top.elem].matches(c) {
+                continue;
+            }
+
+            let mut stack = stack.clone();
+            stack.last_mut().unwrap().elem += 1;
+            self.expand(stack, &mut out);
+        }
+        out.sort();
+        out.dedup();
+        out
+    }
+
+    /// Returns an error if a rule can reference itself without matching any
+    /// character first, which would make matching loop forever.
+    fn check_left_recursion(&self) -> Result<(), GrammarError> {
+        // Find the rules that can match the empty string.
+        let mut nullable = vec![false; self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (rule, alts) in self.rules.iter().enumerate() {
+                if nullable[rule] {
+                    continue;
+                }
+                let is_nullable = alts.iter().any(|alt| {
+                    alt.iter()
+                        .all(|e| matches!(e, Element::Rule(r) if nullable[*r]))
+                });
+                if is_nullable {
+                    nullable[rule] = true;
+                    changed = true;
+                }
+            }
+        }
+
+        // The rules that can be referenced before any character is matched.
+        let leftmost: Vec<Vec<usize>> = self
+            .rules
+            .iter()
+            .map(|alts| {
+                let mut refs = vec![];
+                for alt in alts {
+                    for element in alt {
+                        match element {
+                            Element::Rule(r) => {
+                                refs.push(*r);
+                                if !nullable[*r] {
+                                    break;
+                                }
+                            }
+                            Element::Char { .. } => break,
+                        }
+                    }
+                }
+                refs
+            })
+            .collect();
+
+        for start in 0..self.rules.len() {
+            let mut visited = vec![false; self.rules.len()];
+            let mut pending = leftmost[start].clone();
+            while let Some(rule) = pending.pop() {
+                if rule == start {
+                    return Err(GrammarError::LeftRecursion {
+                        name: self.names[start].clone(),
+                    });
+                }
+                if !std::mem::replace(&mut visited[rule], true) {
+                    pending.extend_from_slice(&leftmost[rule]);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Grammar {
+    type Err = GrammarError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    position: usize,
+    rules: Vec<Vec<Vec<Element>>>,
+    names: Vec<String>,
+    defined: Vec<bool>,
+    ids: HashMap<String, usize>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            position: 0,
+            rules: vec![],
+            names: vec![],
+            defined: vec![],
+            ids: HashMap::new(),
+        }
+    }
+
+    fn parse(mut self) -> Result<Grammar, GrammarError> {
+        self.skip_whitespace();
+        while !self.rest().is_empty() {
+            self.parse_rule()?;
+            self.skip_whitespace();
+        }
+
+        if let Some(rule) = self.defined.iter().position(|defined| !defined) {
+            return Err(GrammarError::UndefinedRule {
+                name: self.names[rule].clone(),
+            });
+        }
+        let root = *self.ids.get("root").ok_or(GrammarError::MissingRoot)?;
+
+        let grammar = Grammar {
+            rules: self.rules,
+            names: self.names,
+            root,
+        };
+        grammar.check_left_recursion()?;
+        Ok(grammar)
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.source[self.position..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        Some(c)
+    }
+
+    fn expect(&mut self, token: &'static str) -> Result<(), GrammarError> {
+        if self.rest().starts_with(token) {
+            self.position += token.len();
+            Ok(())
+        } else {
+            Err(self.expected(token))
+        }
+    }
+
+    fn expected(&self, expected: &'static str) -> GrammarError {
+        GrammarError::Expected {
+            expected,
+            position: self.position,
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.position += rest.len() - trimmed.len();
+            if !trimmed.starts_with('#') {
+                break;
+            }
+            self.position += trimmed.find('\n').unwrap_or(trimmed.len());
+        }
+    }
+
+    fn parse_name(&mut self) -> Option<&'a str> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+            .unwrap_or(rest.len());
+        (len > 0).then(|| {
+            self.position += len;
+            &rest[..len]
+        })
+    }
+
+    /// Returns whether the input continues with the definition of a rule.
+    fn at_rule_definition(&mut self) -> bool {
+        let start = self.position;
+        let is_definition = self.parse_name().is_some() && {
+            self.skip_whitespace();
+            self.rest().starts_with("::=")
+        };
+        self.position = start;
+        is_definition
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        let id = self.new_rule(name.to_owned());
+        self.ids.insert(name.to_owned(), id);
+        id
+    }
+
+    fn new_rule(&mut self, name: String) -> usize {
+        self.rules.push(vec![]);
+        self.names.push(name);
+        self.defined.push(false);
+        self.rules.len() - 1
+    }
+
+    fn define_rule(&mut self, id: usize, alternatives: Vec<Vec<Element>>) {
+        self.rules[id] = alternatives;
+        self.defined[id] = true;
+    }
+
+    fn parse_rule(&mut self) -> Result<(), GrammarError> {
+        let name = self
+            .parse_name()
+            .ok_or_else(|| self.expected("rule name"))?;
+        let id = self.rule_id(name);
+        if self.defined[id] {
+            return Err(GrammarError::DuplicateRule {
+                name: name.to_owned(),
+            });
+        }
+        // Mark the rule as defined, so that a duplicate definition is caught
+        // even if the rule references itself.
+        self.defined[id] = true;
+
+        self.skip_whitespace();
+        self.expect("::=")?;
+        let alternatives = self.parse_alternatives(name)?;
+        self.define_rule(id, alternatives);
+        Ok(())
+    }
+
+    fn parse_alternatives(&mut self, rule_name: &str) -> Result<Vec<Vec<Element>>, GrammarError> {
+        let mut alternatives = vec![self.parse_sequence(rule_name)?];
+        while self.peek() == Some('|') {
+            self.next();
+            alternatives.push(self.parse_sequence(rule_name)?);
+        }
+        Ok(alternatives)
+    }
+
+    fn parse_sequence(&mut self, rule_name: &str) -> Result<Vec<Element>, GrammarError> {
+        let mut sequence = vec![];
+        loop {
+            self.skip_whitespace();
+            // The index of the first element of the last item, which the
+            // repetition operators apply to.
+            let item_start = sequence.len();
+            match self.peek() {
+                Some('"') => {
+                    self.next();
+                    while self.peek() != Some('"') {
+                        let c = self.parse_char()?;
+                        sequence.push(Element::Char {
+                            ranges: vec![(c, c)],
+                            negated: false,
+                        });
+                    }
+                    self.next();
+                }
+                Some('[') => {
+                    self.next();
+                    let negated = self.peek() == Some('^');
+                    if negated {
+                        self.next();
+                    }
+                    let mut ranges = vec![];
+                    while self.peek() != Some(']') {
+                        let lo = self.parse_char()?;
+                        let hi = if self.rest().starts_with('-') && !self.rest().starts_with("-]") {
+                            self.next();
+                            self.parse_char()?
+                        } else {
+                            lo
+                        };
+                        ranges.push((lo, hi));
+                    }
+                    self.next();
+                    sequence.push(Element::Char { ranges, negated });
+                }
+                Some('.') => {
+                    self.next();
+                    sequence.push(Element::Char {
+                        ranges: vec![],
+                        negated: true,
+                    });
+                }
+                Some('(') => {
+                    self.next();
+                    let alternatives = self.parse_alternatives(rule_name)?;
+                    self.expect(")")?;
+                    let id = self.new_rule(rule_name.to_owned());
+                    self.define_rule(id, alternatives);
+                    sequence.push(Element::Rule(id));
+                }
+                Some(c) if c.is_ascii_alphanumeric() || c == '-' || c == '_' => {
+                    if self.at_rule_definition() {
+                        break;
+                    }
+                    let name = self.parse_name().unwrap();
+                    let id = self.rule_id(name);
+                    sequence.push(Element::Rule(id));
+                }
+                _ => break,
+            }
+
+            if let Some(op @ ('*' | '+' | '?')) = self.peek() {
+                self.next();
+                let item = sequence.split_off(item_start);
+                let id = self.new_rule(rule_name.to_owned());
+                let repeated = [item.clone(), vec![Element::Rule(id)]].concat();
+                let alternatives = match op {
+                    // id ::= item id | ""
+                    '*' => vec![repeated, vec![]],
+                    // id ::= item id | item
+                    '+' => vec![repeated, item],
+                    // id ::= item | ""
+                    _ => vec![item, vec![]],
+                };
+                self.define_rule(id, alternatives);
+                sequence.push(Element::Rule(id));
+            }
+        }
+        Ok(sequence)
+    }
+
+    /// Parses a single, possibly escaped, character of a literal or a
+    /// character class.
+    fn parse_char(&mut self) -> Result<char, GrammarError> {
+        let position = self.position;
+        match self.next() {
+            None => Err(self.expected("closing delimiter")),
+            Some('\\') => match self.next() {
+                Some('n') => Ok('\n'),
+                Some('r') => Ok('\r'),
+                Some('t') => Ok('\t'),
+                Some(c @ ('\\' | '"' | '[' | ']' | '-')) => Ok(c),
+                Some('x') => self
+                    .rest()
+                    .get(..2)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .map(|byte| {
+                        self.position += 2;
+                        char::from(byte)
+                    })
+                    .ok_or(GrammarError::InvalidEscape { position }),
+                _ => Err(GrammarError::InvalidEscape { position }),
+            },
+            Some(c) => Ok(c),
+        }
+    }
+}
+
+/// Restricts sampling to the tokens that keep the generated text matching a
+/// [Grammar].
+#[derive(Clone, Debug)]
+pub struct GrammarSampler {
+    grammar: Grammar,
+    stacks: Vec<Stack>,
+    tokens: Vec<String>,
+    eos_token: TokenId,
+}
+
+impl GrammarSampler {
+    /// Creates a sampler at the start of the grammar. The vocabulary is used
+    /// to look up the text of the tokens.
+    pub fn new(grammar: &Grammar, vocab: &Vocabulary) -> Self {
+        Self {
+            grammar: grammar.clone(),
+            stacks: grammar.initial_stacks(),
+            tokens: vocab.id_to_token.clone(),
+            eos_token: vocab.eos_token(),
+        }
+    }
+
+    /// Returns whether the text so far matches the grammar completely, i.e.
+    /// generation can end here.
+    pub fn is_complete(&self) -> bool {
+        self.stacks.iter().any(|stack| stack.is_empty())
+    }
+
+    /// Returns whether the token can come next. The end of text token is
+    /// only allowed once the grammar is complete.
+    pub fn allows(&self, token: TokenId) -> bool {
+        if token == self.eos_token {
+            return self.is_complete();
+        }
+        let Some(text) = self.tokens.get(token as usize).filter(|t| !t.is_empty()) else {
+            return false;
+        };
+
+        let mut stacks = self.stacks.clone();
+        for c in text.chars() {
+            stacks = self.grammar.advance(&stacks, c);
+            if stacks.is_empty() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Sets the logits of the tokens that can't come next to negative
+    /// infinity. If no token is allowed, e.g. because a token that doesn't
+    /// match the grammar was accepted, only the end of text token is.
+    pub fn apply(&self, logits: &mut [f32]) {
+        let mut any_allowed = false;
+        for (id, logit) in logits.iter_mut().enumerate() {
+            if self.allows(id as TokenId) {
+                any_allowed = true;
+            } else {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+
+        if !any_allowed {
+            if let Some(logit) = logits.get_mut(self.eos_token as usize) {
+                *logit = 0.0;
+            }
+        }
+    }
+
+    /// Advances the grammar past the token.
+    pub fn accept(&mut self, token: TokenId) {
+        if token == self.eos_token {
+            return;
+        }
+        let Some(text) = self.tokens.get(token as usize) else {
+            self.stacks.clear();
+            return;
+        };
+        for c in text.chars() {
+            self.stacks = self.grammar.advance(&self.stacks, c);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns whether the whole of `text` matches the grammar.
+    fn matches(grammar: &str, text: &str) -> bool {
+        let grammar = Grammar::parse(grammar).unwrap();
+        let mut stacks = grammar.initial_stacks();
+        for c in text.chars() {
+            stacks = grammar.advance(&stacks, c);
+        }
+        stacks.iter().any(|stack| stack.is_empty())
+    }
+
+    #[test]
+    fn literals_and_alternation() {
+        let grammar = r#"root ::= "yes" | "no""#;
+        assert!(matches(grammar, "yes"));
+        assert!(matches(grammar, "no"));
+        assert!(!matches(grammar, "ye"));
+        assert!(!matches(grammar, "yesno"));
+        assert!(!matches(grammar, ""));
+
+        assert!(matches(r#"root ::= "a\n\"\x41""#, "a\n\"A"));
+    }
+
+    #[test]
+    fn character_classes() {
+        let grammar = r#"root ::= [a-c0-9] [^"] ."#;
+        assert!(matches(grammar, "b7x"));
+        assert!(matches(grammar, "5a\u{e9}"));
+        assert!(!matches(grammar, "d7x"));
+        assert!(!matches(grammar, "b\"x"));
+    }
+
+    #[test]
+    fn repetition_and_groups() {
+        let grammar = r#"
+            # A number, and optional words after it.
+            root ::= digit+ (" " word)* "!"?
+            digit ::= [0-9]
+            word ::= [a-z]+
+        "#;
+        assert!(matches(grammar, "42"));
+        assert!(matches(grammar, "42 is the answer!"));
+        assert!(!matches(grammar, " is"));
+        assert!(!matches(grammar, "42 is  the"));
+        assert!(!matches(grammar, "42!!"));
+    }
+
+    #[test]
+    fn invalid_grammars() {
+        let error = |source| Grammar::parse(source).unwrap_err();
+        assert_eq!(error(r#"other ::= "a""#), GrammarError::MissingRoot);
+        assert_eq!(
+            error("root ::= word"),
+            GrammarError::UndefinedRule {
+                name: "word".to_owned()
+            }
+        );
+        assert_eq!(
+            error(r#"root ::= "a" root ::= "b""#),
+            GrammarError::DuplicateRule {
+                name: "root".to_owned()
+            }
+        );
+        assert_eq!(
+            error(r#"root ::= root "a" | "b""#),
+            GrammarError::LeftRecursion {
+                name: "root".to_owned()
+            }
+        );
+        assert!(matches!(
+            error(r#"root ::= "a"#),
+            GrammarError::Expected { .. }
+        ));
+        assert!(matches!(
+            error(r#"root ::= "\q""#),
+            GrammarError::InvalidEscape { .. }
+        ));
+    }
+}
@@ -1,32 +1,101 @@
+mod backend;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "download")]
+mod download;
 mod ggml;
+pub mod grammar;
+pub mod pipeline;
+pub mod prompt_cache;
+pub mod sampling;
+pub mod template;
+#[cfg(test)]
+mod test_model;
+#[cfg(test)]
+mod tests;
 
 use core::slice;
 use std::{
-    collections::HashMap,
+    borrow::BorrowMut,
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    ffi::c_void,
     fmt::Display,
-    io::{BufRead, Read, Seek, SeekFrom},
+    fs::File,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    ops::Deref,
     path::{Path, PathBuf},
     str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
     time,
 };
 
 use thiserror::Error;
 
-use partial_sort::PartialSort;
-use rand::{distributions::WeightedIndex, prelude::Distribution};
+use grammar::{Grammar, GrammarSampler};
+use memmap2::Mmap;
+use rand::SeedableRng;
+use xxhash_rust::xxh3::Xxh3;
 
+pub const UNK_TOKEN_ID: TokenId = 0; // Hardcoded (for now?)
+pub const BOS_TOKEN_ID: TokenId = 1; // Hardcoded (for now?)
 pub const EOD_TOKEN_ID: TokenId = 2; // Hardcoded (for now?)
 
-#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+/// The hyperparameters of the model.
+///
+/// Apart from `n_ctx`, which is provided by the user, the fields are in the
+/// same order as they are stored in the model file.
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Hyperparameters {
-    n_vocab: i32,
-    n_ctx: i32,
-    n_embd: i32,
-    n_mult: i32,
-    n_head: i32,
-    n_layer: i32,
-    n_rot: i32,
-    f16_: i32,
+    pub n_vocab: i32,
+    pub n_ctx: i32,
+    pub n_embd: i32,
+    pub n_mult: i32,
+    pub n_head: i32,
+    pub n_layer: i32,
+    pub n_rot: i32,
+    pub f16_: i32,
+}
+impl Hyperparameters {
+    /// The size of the hidden layer of the feed-forward network.
+    pub fn n_ff(&self) -> i32 {
+        ((2 * (4 * self.n_embd) / 3 + self.n_mult - 1) / self.n_mult) * self.n_mult
+    }
+
+    /// Serializes the hyperparameters to a JSON string.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    /// Deserializes the hyperparameters from a JSON string.
+    #[cfg(feature = "serde")]
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    /// Estimates the number of bytes of memory needed to hold the tensors of
+    /// a model with these hyperparameters.
+    pub fn estimated_ctx_size(&self) -> Result<u64, LoadError> {
+        Ok(estimate_ctx_size(
+            self,
+            weight_type(self.f16_)?,
+            self.n_ff(),
+            1,
+        ))
+    }
+
+    /// Estimates the number of bytes of memory needed to hold the key/value
+    /// memory of an inference session, which depends on the types chosen in
+    /// `params`.
+    pub fn estimated_session_ctx_size(&self, params: &InferenceSessionParameters) -> u64 {
+        estimate_session_ctx_size(self, params)
+    }
 }
 
 struct Layer {
@@ -46,11 +115,137 @@ struct Layer {
     w3: ggml::Tensor,
 }
 
+/// The container format of a model file, as identified by its magic number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FileFormat {
+    /// `ggml`: the original unversioned format. Does not store token scores.
+    Ggml,
+    /// `ggmf`: versioned format that adds token scores to the vocabulary.
+    Ggmf,
+    /// `ggjt`: versioned format that additionally aligns the tensor data to
+    /// [ggml::GGJT_TENSOR_ALIGNMENT] bytes.
+    Ggjt,
+}
+impl FileFormat {
+    /// Whether this format stores a score for each vocabulary token.
+    pub fn has_token_scores(self) -> bool {
+        !matches!(self, FileFormat::Ggml)
+    }
+
+    /// The alignment of the tensor data within the file, if any.
+    pub fn tensor_alignment(self) -> Option<u64> {
+        match self {
+            FileFormat::Ggjt => Some(ggml::GGJT_TENSOR_ALIGNMENT),
+            FileFormat::Ggml | FileFormat::Ggmf => None,
+        }
+    }
+
+    /// Returns the offset at which the data of a tensor starts, given the
+    /// offset right after its name: the next multiple of the alignment, or
+    /// the same offset if the format doesn't align the data.
+    pub fn tensor_data_offset(self, position: u64) -> u64 {
+        match self.tensor_alignment() {
+            Some(alignment) => position + (alignment - position % alignment) % alignment,
+            None => position,
+        }
+    }
+}
+impl Display for FileFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                FileFormat::Ggml => "ggml",
+                FileFormat::Ggmf => "ggmf",
+                FileFormat::Ggjt => "ggjt",
+            }
+        )
+    }
+}
+
+/// The family of a model. The models of the LLaMA family share the same
+/// file format, tensors and special tokens, but are fine-tuned to expect
+/// different prompt formats.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ModelArchitecture {
+    /// The original LLaMA, or a model that can't be told apart from it.
+    #[default]
+    Llama,
+    /// Stanford Alpaca, and the models fine-tuned on its dataset.
+    Alpaca,
+    /// Vicuna v1.1.
+    Vicuna,
+}
+impl ModelArchitecture {
+    /// Guesses the architecture of a model from its vocabulary. Fine-tunes
+    /// that kept the vocabulary of LLaMA, such as Vicuna, can't be detected,
+    /// so [ModelArchitecture::Llama] is returned when in doubt.
+    pub fn detect(vocab: &Vocabulary) -> Self {
+        // Alpaca's training code adds a padding token to LLaMA's 32000.
+        if vocab.len() == 32001 && vocab.id_to_token[32000] == "[PAD]" {
+            ModelArchitecture::Alpaca
+        } else {
+            ModelArchitecture::Llama
+        }
+    }
+
+    /// The prompt template the model was fine-tuned with.
+    pub fn default_template(self) -> template::PromptTemplate {
+        match self {
+            ModelArchitecture::Llama => template::PromptTemplate::Raw,
+            ModelArchitecture::Alpaca => template::PromptTemplate::Alpaca,
+            ModelArchitecture::Vicuna => template::PromptTemplate::Vicuna,
+        }
+    }
+
+    /// Whether prompts start with the beginning-of-sentence token, as the
+    /// model was trained with. See [Vocabulary::should_add_bos].
+    pub fn add_bos(self) -> bool {
+        match self {
+            ModelArchitecture::Llama | ModelArchitecture::Alpaca | ModelArchitecture::Vicuna => {
+                true
+            }
+        }
+    }
+}
+impl Display for ModelArchitecture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ModelArchitecture::Llama => "llama",
+                ModelArchitecture::Alpaca => "alpaca",
+                ModelArchitecture::Vicuna => "vicuna",
+            }
+        )
+    }
+}
+impl FromStr for ModelArchitecture {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "llama" => Ok(ModelArchitecture::Llama),
+            "alpaca" => Ok(ModelArchitecture::Alpaca),
+            "vicuna" => Ok(ModelArchitecture::Vicuna),
+            _ => Err(format!(
+                "unknown architecture {s:?}, expected llama, alpaca or vicuna"
+            )),
+        }
+    }
+}
+
 /// The weights for the LLaMA model. All the mutable state is split into a
 /// separate struct `InferenceSession`.
-pub struct Model {
+pub struct Llama {
     hparams: Hyperparameters,
 
+    file_format: FileFormat,
+
+    architecture: ModelArchitecture,
+
     tok_embeddings: ggml::Tensor,
 
     norm: ggml::Tensor,
@@ -60,10 +255,136 @@ pub struct Model {
 
     tensors: HashMap<String, ggml::Tensor>,
 
+    /// The parameters of the rotary position embeddings, see
+    /// [LoadParameters].
+    rope_freq_base: f32,
+    rope_freq_scale: f32,
+
+    /// The special tokens of the vocabulary the model was loaded with.
+    bos_token: TokenId,
+    eos_token: TokenId,
+
+    /// Computes the graphs built by [Llama::evaluate] and
+    /// [Llama::apply_lora].
+    backend: Box<dyn backend::Backend>,
+
+    // Must be kept alive for the model. When the model file is memory-mapped,
+    // the tensor data points into this mapping.
+    mmap: Option<Mmap>,
+
+    /// The hash of the tensor data, if [LoadParameters::checksum] was set.
+    checksum: Option<u64>,
+
     // Must be kept alive for the model
     _context: ggml::Context,
 }
 
+/// The forward pass of a model, which is all that the generation loop of
+/// [InferenceSession] needs to know about the model. This lets other model
+/// families reuse it; [Llama] is the only implementation for now.
+pub trait LanguageModel {
+    /// Returns the hyperparameters of the model.
+    fn hparams(&self) -> &Hyperparameters;
+
+    /// Returns the size of the context window, in tokens.
+    fn n_ctx(&self) -> usize {
+        self.hparams().n_ctx as usize
+    }
+
+    /// Returns the number of tokens in the vocabulary of the model.
+    fn n_vocab(&self) -> usize {
+        self.hparams().n_vocab as usize
+    }
+
+    /// Evaluates the transformer.
+    ///
+    /// The provided `output_request` struct lets you specify which additional
+    /// data you are interested in fetching from the transformer. Setting a
+    /// field to a `Some` value will clear and fill the provided vector with
+    /// data. The provided vector will be resized to the exact output size.
+    ///
+    /// Implementations evaluate `input_tokens` after the
+    /// [InferenceSession::n_past] tokens already in the session, then call
+    /// [InferenceSession::advance] with the logits of the last one.
+    /// Implementations that don't use the memory of the session keep their
+    /// own state for each session.
+    fn evaluate(
+        &self,
+        session: &mut InferenceSession,
+        params: &InferenceParameters,
+        input_tokens: &[TokenId],
+        output_request: &mut EvaluateOutputRequest,
+    );
+
+    /// Discards the `n_discard` tokens of `session` that follow its first
+    /// `n_keep` tokens, and moves the tokens after them into their place,
+    /// for [ContextPolicy::SlidingWindow].
+    ///
+    /// By default, the moved tokens are evaluated again, which costs as much
+    /// as evaluating them the first time. [Llama] moves their keys and values
+    /// in the memory of the session instead.
+    fn discard_tokens(
+        &self,
+        session: &mut InferenceSession,
+        params: &InferenceParameters,
+        n_keep: usize,
+        n_discard: usize,
+    ) where
+        Self: Sized,
+    {
+        session.evaluate_again(self, params, n_keep, n_discard);
+    }
+
+    fn tokenize(
+        &self,
+        vocab: &Vocabulary,
+        text: &str,
+        bos: bool,
+    ) -> Result<Vec<TokenId>, InferenceError> {
+        Ok(vocab
+            .tokenize(text, bos)?
+            .iter()
+            .map(|(_, tid)| *tid)
+            .collect::<Vec<TokenId>>())
+    }
+}
+
+/// The previous name of [Llama], from before [LanguageModel] was added.
+#[deprecated(note = "renamed to `Llama`")]
+pub type Model = Llama;
+
+/// A [Llama] model that can be shared between threads, which take turns using
+/// it. [Llama] is `Send`, but not `Sync`: each evaluation builds ggml graphs
+/// that point to its tensors, which ggml doesn't support doing from several
+/// threads at once.
+#[derive(Clone)]
+pub struct SharedModel(Arc<Mutex<Llama>>);
+impl SharedModel {
+    pub fn new(model: Llama) -> Self {
+        Self(Arc::new(Mutex::new(model)))
+    }
+
+    /// Waits until no other thread uses the model, and locks it for this one.
+    pub fn lock(&self) -> impl Deref<Target = Llama> + '_ {
+        // A panic while the model was locked doesn't leave it inconsistent:
+        // the lock only gives shared access to it, and evaluating doesn't
+        // modify it.
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// The models and sessions must be movable to other threads, and shared ones
+// usable from several.
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send::<Llama>();
+    assert_send::<InferenceSession>();
+    assert_send::<BatchSession>();
+    assert_send_sync::<SharedModel>();
+    assert_send_sync::<Vocabulary>();
+};
+
 /// An inference session represents the state of the text generation. This holds
 /// the full context window, as long as several additional parameters used
 /// during sampling.
@@ -77,25 +398,313 @@ pub struct InferenceSession {
     memory_k: ggml::Tensor,
     memory_v: ggml::Tensor,
 
+    /// How many tokens the memory can hold. Matches the model's context
+    /// window unless the session was resized with [Llama::resize_context].
+    n_ctx: usize,
+
     /// How many tokens have been fed into the model's working memory so far.
     n_past: usize,
 
-    /// How much memory is required per token for the temporary context used
-    /// during inference.
-    mem_per_token: usize,
+    /// The context holding the temporary tensors of the evaluations. It is
+    /// allocated on the first evaluation and reset for the next ones.
+    scratch: Option<ggml::Context>,
 
     /// All tokens generated by this inference session
     tokens: Vec<TokenId>,
 
     /// The logits that were last predicted by the network. Zeroed out otherwise.
     last_logits: Vec<f32>,
+
+    /// The running maximum surprise estimate used by Mirostat sampling. Set
+    /// on first use.
+    mirostat_mu: Option<f32>,
+
+    /// The random number generator used for sampling.
+    rng: InferenceRng,
+
+    /// The tokens the next sampled token is restricted to, when the last
+    /// token of the prompt was removed by token healing.
+    token_healing_candidates: Option<Vec<TokenId>>,
+
+    /// Constrains the text generated after the prompt to a grammar, if one
+    /// was specified in the [InferenceParameters].
+    grammar: Option<GrammarSampler>,
+
+    /// The session that is fed the negative prompt instead of the prompt,
+    /// when classifier-free guidance is used.
+    guidance: Option<Box<InferenceSession>>,
+
+    /// Set by an [AbortHandle] to stop the generation in progress.
+    abort: Arc<AtomicBool>,
+
+    /// The end-of-sentence token, which [InferenceParameters::ignore_eos]
+    /// keeps from being sampled.
+    eos_token: TokenId,
 }
 impl InferenceSession {
-    fn repetition_penalty_tokens(&self) -> &[TokenId] {
-        &self.tokens[self
-            .tokens
-            .len()
-            .saturating_sub(self.params.repetition_penalty_last_n)..]
+    /// Starts a new session for a model with the given hyperparameters,
+    /// whose end-of-sentence token is [EOD_TOKEN_ID]. Use
+    /// [Llama::start_session] for a [Llama] model, which uses the special
+    /// tokens of its vocabulary.
+    pub fn new(hparams: &Hyperparameters, params: InferenceSessionParameters) -> Self {
+        let Hyperparameters {
+            n_ctx,
+            n_embd,
+            n_layer,
+            n_vocab,
+            ..
+        } = *hparams;
+
+        let ctx_size = estimate_session_ctx_size(hparams, &params);
+
+        let session_ctx = ggml::Context::init(ctx_size as usize);
+
+        // Initialize key + value memory tensors
+        let n_mem = n_layer * n_ctx;
+        let n_elements = n_embd * n_mem;
+        let memory_k = session_ctx.new_tensor_1d(params.memory_k_type.into(), n_elements);
+        let memory_v = session_ctx.new_tensor_1d(params.memory_v_type.into(), n_elements);
+        debug_assert!(session_ctx.used_mem() <= session_ctx.mem_size());
+
+        InferenceSession {
+            _session_ctx: session_ctx,
+            params,
+            memory_k,
+            memory_v,
+            n_ctx: n_ctx as usize,
+            n_past: 0,
+            scratch: None,
+            tokens: vec![],
+            last_logits: vec![0.0; n_vocab as usize],
+            mirostat_mu: None,
+            token_healing_candidates: None,
+            grammar: None,
+            guidance: None,
+            abort: Arc::default(),
+            eos_token: EOD_TOKEN_ID,
+            rng: match params.seed {
+                Some(seed) => InferenceRng::seed_from_u64(seed),
+                None => InferenceRng::from_entropy(),
+            },
+        }
+    }
+
+    /// Reallocates the memory to hold `hparams.n_ctx` tokens, and copies the
+    /// keys and values of the tokens fed so far to it. The caller makes sure
+    /// that they fit.
+    fn resize_memory(&mut self, hparams: &Hyperparameters) {
+        let n_ctx = hparams.n_ctx as usize;
+        debug_assert!(self.n_past <= n_ctx);
+
+        let session_ctx =
+            ggml::Context::init(estimate_session_ctx_size(hparams, &self.params) as usize);
+        let n_elements = hparams.n_embd * hparams.n_layer * hparams.n_ctx;
+        let memory_k = session_ctx.new_tensor_1d(self.params.memory_k_type.into(), n_elements);
+        let memory_v = session_ctx.new_tensor_1d(self.params.memory_v_type.into(), n_elements);
+
+        // The memory of each layer starts at a multiple of the context
+        // window, so the tokens of each layer are copied separately.
+        for (old, new) in [(&self.memory_k, &memory_k), (&self.memory_v, &memory_v)] {
+            let token_size = old.element_size() * hparams.n_embd as usize;
+            for il in 0..hparams.n_layer as usize {
+                // SAFETY: both tensors hold `n_layer` layers of at least
+                // `n_past` tokens, and are in different contexts.
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        (old.data() as *const u8).add(il * self.n_ctx * token_size),
+                        (new.data() as *mut u8).add(il * n_ctx * token_size),
+                        self.n_past * token_size,
+                    );
+                }
+            }
+        }
+
+        self.memory_k = memory_k;
+        self.memory_v = memory_v;
+        self._session_ctx = session_ctx;
+        self.n_ctx = n_ctx;
+    }
+
+    /// Discards the `n_discard` tokens after the first `n_keep` ones, and
+    /// moves the keys and values of the tokens after them into their place.
+    /// The keys in the memory were rotated by the rotary position embeddings
+    /// of their position, so they are rotated back by `n_discard` positions.
+    fn shift_memory(
+        &mut self,
+        hparams: &Hyperparameters,
+        rope_freq_base: f32,
+        rope_freq_scale: f32,
+        n_keep: usize,
+        n_discard: usize,
+    ) {
+        let n_embd = hparams.n_embd as usize;
+        let head_dim = n_embd / hparams.n_head as usize;
+        let n_rot = hparams.n_rot as usize;
+        let n_moved = self.n_past - n_keep - n_discard;
+
+        // The rotation of each pair of dimensions of a head, computed like
+        // ggml does for a position of `-n_discard`.
+        let rotations: Vec<(f64, f64)> = (0..n_rot)
+            .step_by(2)
+            .map(|i0| {
+                let theta = f64::from(rope_freq_scale)
+                    * f64::from(rope_freq_base).powf(-(i0 as f64) / n_rot as f64);
+                let angle = -(n_discard as f64) * theta;
+                (angle.cos(), angle.sin())
+            })
+            .collect();
+
+        for (memory, is_key) in [(&self.memory_k, true), (&self.memory_v, false)] {
+            let token_size = memory.element_size() * n_embd;
+            // SAFETY: The tensor's data is `nbytes` long, and only the
+            // session uses it.
+            let data = unsafe {
+                std::slice::from_raw_parts_mut(memory.data() as *mut u8, memory.nbytes())
+            };
+            for il in 0..hparams.n_layer as usize {
+                let dst = (il * self.n_ctx + n_keep) * token_size;
+                let src = dst + n_discard * token_size;
+                data.copy_within(src..src + n_moved * token_size, dst);
+                if is_key {
+                    let keys = &mut data[dst..dst + n_moved * token_size];
+                    rotate_keys(keys, memory.element_size(), head_dim, &rotations);
+                }
+            }
+        }
+
+        self.tokens.drain(n_keep..n_keep + n_discard);
+        self.n_past -= n_discard;
+    }
+
+    /// Reseeds the random number generator used for sampling.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = InferenceRng::seed_from_u64(seed);
+    }
+
+    /// Returns how many times each of the last `last_n` tokens occurs among
+    /// them.
+    fn recent_token_counts(&self, last_n: usize) -> HashMap<TokenId, usize> {
+        let mut counts = HashMap::new();
+        for &id in &self.tokens[self.tokens.len().saturating_sub(last_n)..] {
+            *counts.entry(id).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Samples the next token from the session's last logits, using the
+    /// strategy selected by `params.sampling_mode` and the session's random
+    /// number generator.
+    pub fn sample(&mut self, params: &InferenceParameters) -> TokenId {
+        // A temperature of zero is greedy, and doesn't use the random number
+        // generator.
+        let sampling_mode = match params.temp <= 0.0 {
+            true => SamplingMode::Greedy,
+            false => params.sampling_mode,
+        };
+        let token = match sampling_mode {
+            SamplingMode::Greedy => sampling::sample_greedy(&self.adjusted_logits(params)),
+            SamplingMode::TopPTopK => {
+                let logits = self.adjusted_logits(params);
+                sampling::sample_top_p_top_k(&logits, params.top_k, params.top_p, &mut self.rng)
+            }
+            SamplingMode::MirostatV2 { tau, eta } => {
+                let mut logits = self.adjusted_logits(params);
+                let mu = self.mirostat_mu.get_or_insert(2.0 * tau);
+                sampling::sample_mirostat_v2(&mut logits, tau, eta, mu, &mut self.rng)
+            }
+            SamplingMode::TailFree { z } => {
+                let mut logits = self.adjusted_logits(params);
+                sampling::sample_tail_free(&mut logits, z, &mut self.rng)
+            }
+            SamplingMode::Typical { p } => {
+                let mut logits = self.adjusted_logits(params);
+                sampling::sample_typical(&mut logits, p, &mut self.rng)
+            }
+        };
+
+        // Token healing only constrains the first token after the prompt.
+        self.token_healing_candidates = None;
+
+        token
+    }
+
+    /// Returns the last logits, adjusted by the token biases, the penalties
+    /// and the temperature. A temperature of zero or below, which is greedy,
+    /// leaves the logits unscaled instead of making them infinite.
+    fn adjusted_logits(&self, params: &InferenceParameters) -> Vec<f32> {
+        let scale = match params.temp > 0.0 {
+            true => 1.0 / params.temp,
+            false => 1.0,
+        };
+        let penalties = &params.penalties;
+        let recent_token_counts = self.recent_token_counts(penalties.repeat_last_n);
+
+        let guided_logits: Vec<f32>;
+        let last_logits = match &self.guidance {
+            Some(guidance) => {
+                guided_logits = self
+                    .last_logits
+                    .iter()
+                    .zip(&guidance.last_logits)
+                    .map(|(&pos, &neg)| params.cfg_scale * (pos - neg) + neg)
+                    .collect();
+                &guided_logits
+            }
+            None => &self.last_logits,
+        };
+
+        let mut logits: Vec<f32> = last_logits
+            .iter()
+            .enumerate()
+            .map(|(i, &logit)| {
+                let tid = i as TokenId;
+                let logit = logit + params.logit_bias.get(&tid).copied().unwrap_or(0.0);
+
+                // repetition penalty from CTRL paper (https://arxiv.org/abs/1909.05858)
+                // credit https://github.com/facebookresearch/llama/compare/main...shawwn:llama:main
+                if let Some(logit_override) = params.bias_tokens.get(tid) {
+                    logit_override
+                } else if let Some(&count) = recent_token_counts.get(&tid) {
+                    // if score < 0 then repetition penalty has to multiplied to reduce the previous token probability
+                    let logit = if logit < 0.0 {
+                        logit * scale * penalties.repeat
+                    } else {
+                        logit * scale / penalties.repeat
+                    };
+                    logit - (count as f32 * penalties.frequency + penalties.presence) * scale
+                } else {
+                    logit * scale
+                }
+            })
+            .collect();
+
+        if let Some(candidates) = &self.token_healing_candidates {
+            let mut healed = vec![f32::NEG_INFINITY; logits.len()];
+            for &id in candidates {
+                healed[id as usize] = logits[id as usize];
+            }
+            logits = healed;
+        }
+
+        if let Some(grammar) = &self.grammar {
+            grammar.apply(&mut logits);
+        }
+
+        // The end of text is still allowed when nothing else is, i.e. when the
+        // grammar is complete.
+        let eos = self.eos_token as usize;
+        if params.ignore_eos
+            && logits
+                .iter()
+                .enumerate()
+                .any(|(id, &logit)| id != eos && logit > f32::NEG_INFINITY)
+        {
+            if let Some(logit) = logits.get_mut(eos) {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+
+        logits
     }
 }
 
@@ -117,10 +726,13 @@ pub struct InferenceSnapshotRef<'a> {
     /// The contents of the 'value' memory tensor
     #[serde(with = "serde_bytes")]
     pub memory_v: &'a [u8],
+    /// The state of the random number generator used for sampling, so that
+    /// a restored session samples the same tokens as the original would have.
+    pub rng: InferenceRng,
 }
 
 /// A serializable snapshot of the inference process. Can be restored by calling
-/// `Model::restore_from_snapshot`.
+/// `Llama::restore_from_snapshot`.
 #[derive(serde::Deserialize, Clone, PartialEq)]
 // Keep in sync with [InferenceSession] and [InferenceSnapshotRef]
 pub struct InferenceSnapshot {
@@ -138,6 +750,8 @@ pub struct InferenceSnapshot {
     /// The contents of the 'value' memory tensor
     #[serde(with = "serde_bytes")]
     pub memory_v: Vec<u8>,
+    /// The state of the random number generator used for sampling.
+    pub rng: InferenceRng,
 }
 
 // Allowed types for the model memory K/V tensors.
@@ -157,59 +771,594 @@ impl From<ModelKVMemoryType> for i32 {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
-// Parameters for an inference session.
-pub struct InferenceSessionParameters {
-    pub repetition_penalty_last_n: usize,
-    pub memory_k_type: ModelKVMemoryType,
-    pub memory_v_type: ModelKVMemoryType,
+/// The type of the elements of a model's weights, as stored in the `ftype`
+/// field of each tensor and in the `f16_` hyperparameter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GgmlType {
+    F32,
+    F16,
+    Q4_0,
+    Q4_1,
 }
 
-impl Default for InferenceSessionParameters {
-    fn default() -> Self {
-        Self {
-            repetition_penalty_last_n: 512,
-            memory_k_type: ModelKVMemoryType::Float32,
-            memory_v_type: ModelKVMemoryType::Float32,
+impl GgmlType {
+    /// The number of bytes used by a block of [Self::block_size] elements.
+    pub fn size(self) -> usize {
+        ggml::type_size(self.raw())
+    }
+
+    /// The number of elements that are stored together. Quantized types
+    /// store their elements in blocks that share a scale factor.
+    pub fn block_size(self) -> usize {
+        ggml::blck_size(self.raw()) as usize
+    }
+
+    /// The average number of bytes used by an element.
+    fn sizef(self) -> f64 {
+        self.size() as f64 / self.block_size() as f64
+    }
+
+    fn raw(self) -> ggml::Type {
+        match self {
+            GgmlType::F32 => ggml::TYPE_F32,
+            GgmlType::F16 => ggml::TYPE_F16,
+            GgmlType::Q4_0 => ggml::TYPE_Q4_0,
+            GgmlType::Q4_1 => ggml::TYPE_Q4_1,
+        }
+    }
+
+    fn from_raw(typ: ggml::Type) -> Option<Self> {
+        match typ {
+            ggml::TYPE_F32 => Some(GgmlType::F32),
+            ggml::TYPE_F16 => Some(GgmlType::F16),
+            ggml::TYPE_Q4_0 => Some(GgmlType::Q4_0),
+            ggml::TYPE_Q4_1 => Some(GgmlType::Q4_1),
+            _ => None,
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
-/// The parameters that drive text generation.
-pub struct InferenceParameters {
-    pub n_threads: i32,
-    pub n_batch: usize,
-    pub top_k: usize,
-    pub top_p: f32,
-    pub repeat_penalty: f32,
-    pub temp: f32,
-    pub bias_tokens: TokenBias,
-    pub play_back_previous_tokens: bool,
-    pub increased_determinism: bool,
+impl TryFrom<i32> for GgmlType {
+    /// The `ftype` that doesn't name a supported type.
+    type Error = i32;
+
+    fn try_from(ftype: i32) -> Result<Self, Self::Error> {
+        match ftype {
+            0 => Ok(GgmlType::F32),
+            1 => Ok(GgmlType::F16),
+            2 => Ok(GgmlType::Q4_0),
+            3 => Ok(GgmlType::Q4_1),
+            invalid => Err(invalid),
+        }
+    }
 }
 
-impl Default for InferenceParameters {
-    fn default() -> Self {
-        Self {
-            n_threads: 8,
-            n_batch: 8,
-            top_k: 40,
-            top_p: 0.95,
-            repeat_penalty: 1.30,
-            temp: 0.80,
-            bias_tokens: TokenBias::default(),
-            play_back_previous_tokens: false,
-            increased_determinism: true,
+impl From<GgmlType> for i32 {
+    /// Returns the `ftype` that the type is stored as in model files.
+    fn from(value: GgmlType) -> Self {
+        match value {
+            GgmlType::F32 => 0,
+            GgmlType::F16 => 1,
+            GgmlType::Q4_0 => 2,
+            GgmlType::Q4_1 => 3,
         }
     }
 }
 
-pub struct InferenceStats {
-    pub feed_prompt_duration: std::time::Duration,
-    pub prompt_tokens: usize,
+impl From<ModelKVMemoryType> for GgmlType {
+    fn from(value: ModelKVMemoryType) -> Self {
+        match value {
+            ModelKVMemoryType::Float16 => GgmlType::F16,
+            ModelKVMemoryType::Float32 => GgmlType::F32,
+        }
+    }
+}
+
+impl Display for GgmlType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", ggml::type_name(self.raw()))
+    }
+}
+
+/// Describes one of the tensors of a loaded [Llama].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TensorInfo {
+    /// The name of the tensor, such as `layers.0.attention.wq.weight`.
+    pub name: String,
+    /// The number of elements in each dimension.
+    pub shape: Vec<i64>,
+    /// The type of the elements.
+    pub dtype: GgmlType,
+    /// The size of the tensor's data, in bytes.
+    pub nbytes: usize,
+}
+
+/// The instruction sets that ggml's kernels were compiled to use. ggml picks
+/// its kernels when it is built, not at runtime, so these only depend on the
+/// build: a model runs much slower if, for instance, AVX2 is supported by the
+/// CPU but not listed here.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuFeatures {
+    pub avx: bool,
+    pub avx2: bool,
+    pub avx512: bool,
+    pub fma: bool,
+    pub neon: bool,
+    pub arm_fma: bool,
+    pub f16c: bool,
+    pub fp16_va: bool,
+    pub wasm_simd: bool,
+    pub blas: bool,
+    pub sse3: bool,
+    pub vsx: bool,
+}
+
+impl Display for CpuFeatures {
+    /// Formats the features as llama.cpp's system info, e.g.
+    /// `AVX = 1 | AVX2 = 0 | ...`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let features = [
+            ("AVX", self.avx),
+            ("AVX2", self.avx2),
+            ("AVX512", self.avx512),
+            ("FMA", self.fma),
+            ("NEON", self.neon),
+            ("ARM_FMA", self.arm_fma),
+            ("F16C", self.f16c),
+            ("FP16_VA", self.fp16_va),
+            ("WASM_SIMD", self.wasm_simd),
+            ("BLAS", self.blas),
+            ("SSE3", self.sse3),
+            ("VSX", self.vsx),
+        ];
+        for (i, (name, enabled)) in features.into_iter().enumerate() {
+            if i > 0 {
+                write!(f, " | ")?;
+            }
+            write!(f, "{name} = {}", enabled as u8)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the instruction sets that ggml's kernels use in this build.
+pub fn cpu_features() -> CpuFeatures {
+    CpuFeatures {
+        avx: ggml::cpu_has_avx(),
+        avx2: ggml::cpu_has_avx2(),
+        avx512: ggml::cpu_has_avx512(),
+        fma: ggml::cpu_has_fma(),
+        neon: ggml::cpu_has_neon(),
+        arm_fma: ggml::cpu_has_arm_fma(),
+        f16c: ggml::cpu_has_f16c(),
+        fp16_va: ggml::cpu_has_fp16_va(),
+        wasm_simd: ggml::cpu_has_wasm_simd(),
+        blas: ggml::cpu_has_blas(),
+        sse3: ggml::cpu_has_sse3(),
+        vsx: ggml::cpu_has_vsx(),
+    }
+}
+
+/// The random number generator used by inference sessions. Unlike
+/// [rand::rngs::StdRng], its output for a given seed is the same on every
+/// platform and across releases of `rand`.
+pub type InferenceRng = rand_chacha::ChaCha8Rng;
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+// Parameters for an inference session.
+pub struct InferenceSessionParameters {
+    pub memory_k_type: ModelKVMemoryType,
+    pub memory_v_type: ModelKVMemoryType,
+    /// The seed for the session's random number generator. The same seed,
+    /// parameters and prompt always produce the same output. If `None`, the
+    /// generator is seeded from system entropy.
+    #[serde(skip)]
+    pub seed: Option<u64>,
+}
+
+impl Default for InferenceSessionParameters {
+    fn default() -> Self {
+        Self {
+            memory_k_type: ModelKVMemoryType::Float32,
+            memory_v_type: ModelKVMemoryType::Float32,
+            seed: None,
+        }
+    }
+}
+
+/// What happens when the context window is full and more tokens have to be
+/// evaluated. The tokens after the discarded ones have to be moved to their
+/// new positions in the memory, by evaluating them again or by shifting the
+/// memory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ContextPolicy {
+    /// [InferenceError::ContextFull] is returned.
+    #[default]
+    Error,
+    /// The first `n_keep` tokens (e.g. a system prompt) are kept, the older
+    /// half of the remaining tokens is discarded, and the rest is evaluated
+    /// again. This costs the evaluation of about `(n_ctx - n_keep) / 2`
+    /// tokens, once every `(n_ctx - n_keep) / 2` tokens.
+    DiscardOldest { n_keep: usize },
+    /// Only as many of the oldest tokens as needed are discarded, after the
+    /// beginning-of-sentence token, so that the most recent tokens fill the
+    /// window. The tokens after them are moved into their place with
+    /// [LanguageModel::discard_tokens]: for a [Llama], this moves their keys
+    /// and values in the memory without evaluating anything, which costs
+    /// about as much as copying the memory once per new token. Unlike with
+    /// [ContextPolicy::DiscardOldest], the moved tokens still reflect the
+    /// discarded tokens they attended to when they were evaluated.
+    SlidingWindow,
+}
+
+/// Rotates each pair of dimensions of each head of `keys`, whose elements are
+/// `f32`s or `f16`s, by the angle whose cosine and sine are given for that
+/// pair. The dimensions without a rotation are left as they are.
+fn rotate_keys(keys: &mut [u8], element_size: usize, head_dim: usize, rotations: &[(f64, f64)]) {
+    let read = |bytes: &[u8]| match element_size {
+        2 => ggml::fp16_to_fp32(u16::from_ne_bytes([bytes[0], bytes[1]])),
+        _ => f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+    };
+    let write = |bytes: &mut [u8], value: f64| match element_size {
+        2 => bytes.copy_from_slice(&ggml::fp32_to_fp16(value as f32).to_ne_bytes()),
+        _ => bytes.copy_from_slice(&(value as f32).to_ne_bytes()),
+    };
+    for head in keys.chunks_exact_mut(head_dim * element_size) {
+        for (pair, &(cos, sin)) in head.chunks_exact_mut(2 * element_size).zip(rotations) {
+            let (x0, x1) = pair.split_at_mut(element_size);
+            let (v0, v1) = (f64::from(read(x0)), f64::from(read(x1)));
+            write(x0, v0 * cos - v1 * sin);
+            write(x1, v0 * sin + v1 * cos);
+        }
+    }
+}
+
+/// Returns how many of the `n_past` tokens in a context window of `n_ctx`
+/// tokens are kept at its start, and how many of the most recent ones are
+/// retained after them, so that `n_tokens` more tokens fit as set by
+/// `policy`. The others are discarded.
+fn context_shift(
+    policy: ContextPolicy,
+    n_ctx: usize,
+    n_past: usize,
+    n_tokens: usize,
+) -> Result<(usize, usize), InferenceError> {
+    let (n_keep, n_retained) = match policy {
+        ContextPolicy::Error => return Err(InferenceError::ContextFull),
+        ContextPolicy::DiscardOldest { n_keep } => {
+            let n_keep = n_keep.max(1).min(n_past);
+            (n_keep, (n_past - n_keep) / 2)
+        }
+        ContextPolicy::SlidingWindow => {
+            // One slot of the window is always left free.
+            let n_keep = n_past.min(1);
+            let n_retained = n_ctx.saturating_sub(1 + n_keep + n_tokens);
+            (n_keep, n_retained.min(n_past - n_keep))
+        }
+    };
+    if n_keep + n_retained + n_tokens >= n_ctx {
+        return Err(InferenceError::ContextFull);
+    }
+    Ok((n_keep, n_retained))
+}
+
+/// The strategy used to pick the next token.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplingMode {
+    /// Always pick the most likely token.
+    Greedy,
+    /// Sample from the `top_k` most likely tokens, further restricted to the
+    /// smallest set whose cumulative probability exceeds `top_p`.
+    TopPTopK,
+    /// Mirostat v2 sampling, which targets a constant surprise `tau` (in
+    /// bits), adjusting its truncation with the learning rate `eta`.
+    MirostatV2 { tau: f32, eta: f32 },
+    /// Tail-free sampling, which cuts off the tail of the distribution where
+    /// the probabilities flatten out. `z` is between 0.0 and 1.0; lower values
+    /// keep fewer tokens.
+    TailFree { z: f32 },
+    /// Locally typical sampling, which keeps the tokens whose surprise is
+    /// closest to the entropy of the distribution, up to a cumulative
+    /// probability of `p`.
+    Typical { p: f32 },
+}
+
+/// Penalties that make the model less likely to repeat the tokens it has
+/// seen recently.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Penalties {
+    /// The repetition penalty from the CTRL paper: the logits of recent
+    /// tokens are divided by it if positive, and multiplied by it if
+    /// negative. 1.0 disables it.
+    pub repeat: f32,
+    /// How many of the last tokens are penalized.
+    pub repeat_last_n: usize,
+    /// Subtracted from the logit of recent tokens once per occurrence, as in
+    /// the OpenAI API. 0.0 disables it.
+    pub frequency: f32,
+    /// Subtracted from the logit of recent tokens once, however often they
+    /// occur, as in the OpenAI API. 0.0 disables it.
+    pub presence: f32,
+}
+
+impl Default for Penalties {
+    fn default() -> Self {
+        Self {
+            repeat: 1.30,
+            repeat_last_n: 512,
+            frequency: 0.0,
+            presence: 0.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// The parameters that drive text generation.
+pub struct InferenceParameters {
+    pub n_threads: i32,
+    pub n_batch: usize,
+    /// The number of most likely tokens to sample from. 0 keeps all tokens.
+    pub top_k: usize,
+    /// The cumulative probability of the most likely tokens to sample from.
+    /// 1.0 keeps all tokens.
+    pub top_p: f32,
+    pub penalties: Penalties,
+    /// The temperature the logits are divided by. A temperature of 0.0 (or
+    /// below) always picks the most likely token, as with
+    /// [SamplingMode::Greedy], whatever the `sampling_mode`.
+    pub temp: f32,
+    pub sampling_mode: SamplingMode,
+    pub bias_tokens: TokenBias,
+    /// Biases that are added to the logit of each token before sampling. A
+    /// bias of `f32::NEG_INFINITY` prevents the token from being sampled.
+    pub logit_bias: HashMap<TokenId, f32>,
+    /// Enables token healing: if the prompt ends with a token that is the
+    /// beginning of longer tokens (e.g. a partial word), that token is removed
+    /// from the prompt, and the first generated token must start with its
+    /// text. This lets the model pick a better tokenization of the end of the
+    /// prompt.
+    pub token_healing: bool,
+    /// Generation stops as soon as the generated text contains one of these
+    /// strings. The stop sequence itself is not passed to the callback.
+    pub stop_sequences: Vec<String>,
+    /// If set, the text generated after the prompt must match this grammar.
+    /// Generation ends once the grammar is complete and nothing more can
+    /// follow.
+    pub grammar: Option<Grammar>,
+    /// If set, classifier-free guidance is used to steer generation away
+    /// from this prompt: a second session is fed this prompt instead of the
+    /// actual prompt, and the logits of both sessions are combined as
+    /// `cfg_scale * (logits - negative_logits) + negative_logits`.
+    ///
+    /// As every token is evaluated by both sessions, this doubles the memory
+    /// used by the session and the computation time.
+    pub cfg_negative_prompt: Option<String>,
+    /// The strength of the classifier-free guidance. A scale of 1.0 disables
+    /// guidance, and higher values steer further away from
+    /// [InferenceParameters::cfg_negative_prompt].
+    pub cfg_scale: f32,
+    /// What happens when the context window fills up.
+    pub context_policy: ContextPolicy,
+    /// The number of tokens kept when the context window fills up, which is
+    /// [ContextPolicy::DiscardOldest] if
+    /// [InferenceParameters::context_policy] is [ContextPolicy::Error].
+    #[deprecated(note = "use `context_policy: ContextPolicy::DiscardOldest { n_keep }` instead")]
+    pub n_keep: Option<usize>,
+    /// Whether [InferenceSession::inference_with_prompt] passes the prompt,
+    /// and the tokens played back with
+    /// [InferenceParameters::play_back_previous_tokens], to its callback
+    /// before the generated text.
+    pub echo_prompt: bool,
+    /// Prevents the end-of-text token from being sampled, so that generation
+    /// goes on until the token limit is reached or the context window is
+    /// full. A complete [InferenceParameters::grammar] still ends it.
+    pub ignore_eos: bool,
+    /// Whether the first prompt of a session starts with the
+    /// beginning-of-sentence token. Defaults to
+    /// [Vocabulary::should_add_bos] if `None`.
+    pub add_bos: Option<bool>,
+    pub play_back_previous_tokens: bool,
+    pub increased_determinism: bool,
+}
+
+impl InferenceParameters {
+    /// Returns whether the first prompt of a session starts with the
+    /// beginning-of-sentence token of `vocab`, see
+    /// [InferenceParameters::add_bos].
+    pub fn adds_bos(&self, vocab: &Vocabulary) -> bool {
+        self.add_bos.unwrap_or_else(|| vocab.should_add_bos())
+    }
+
+    /// Prevents the given token from ever being sampled.
+    pub fn ban_token(&mut self, id: TokenId) {
+        self.logit_bias.insert(id, f32::NEG_INFINITY);
+    }
+
+    /// Returns the context policy, taking the deprecated
+    /// [InferenceParameters::n_keep] into account.
+    #[allow(deprecated)]
+    fn resolved_context_policy(&self) -> ContextPolicy {
+        match (self.context_policy, self.n_keep) {
+            (ContextPolicy::Error, Some(n_keep)) => ContextPolicy::DiscardOldest { n_keep },
+            (policy, _) => policy,
+        }
+    }
+}
+
+impl Default for InferenceParameters {
+    #[allow(deprecated)]
+    fn default() -> Self {
+        Self {
+            n_threads: 8,
+            n_batch: 8,
+            top_k: 40,
+            top_p: 0.95,
+            penalties: Penalties::default(),
+            temp: 0.80,
+            sampling_mode: SamplingMode::TopPTopK,
+            bias_tokens: TokenBias::default(),
+            logit_bias: HashMap::new(),
+            token_healing: false,
+            stop_sequences: vec![],
+            grammar: None,
+            cfg_negative_prompt: None,
+            cfg_scale: 1.0,
+            context_policy: ContextPolicy::Error,
+            n_keep: None,
+            echo_prompt: true,
+            ignore_eos: false,
+            add_bos: None,
+            play_back_previous_tokens: false,
+            increased_determinism: true,
+        }
+    }
+}
+
+/// Several independent sequences that are generated together. Each step
+/// samples a token for every unfinished sequence, and evaluates all of them in
+/// a single graph, which shares the cost of reading the model's weights.
+///
+/// Each sequence is an [InferenceSession] with its own memory, whose prompt
+/// can be fed with [InferenceSession::feed_prompt] through
+/// [BatchSession::session_mut]. The sequences don't affect each other, so
+/// they generate the same tokens as they would in separate sessions.
+pub struct BatchSession {
+    sessions: Vec<InferenceSession>,
+    finished: Vec<bool>,
+}
+impl BatchSession {
+    /// Returns the number of sequences.
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Returns whether there are no sequences.
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Returns the session of the sequence at `index`.
+    pub fn session(&self, index: usize) -> &InferenceSession {
+        &self.sessions[index]
+    }
+
+    /// Returns the session of the sequence at `index`, to feed its prompt or
+    /// change its seed.
+    pub fn session_mut(&mut self, index: usize) -> &mut InferenceSession {
+        &mut self.sessions[index]
+    }
+
+    /// Returns whether the sequence at `index` has generated the end of text
+    /// token, after which it is no longer generated.
+    pub fn is_finished(&self, index: usize) -> bool {
+        self.finished[index]
+    }
+
+    /// Samples the next token of every unfinished sequence, and evaluates
+    /// them together. Returns the tokens in the order of the sequences, with
+    /// `None` for the sequences that were already finished.
+    pub fn infer_next_tokens<'v>(
+        &mut self,
+        model: &Llama,
+        vocab: &'v Vocabulary,
+        params: &InferenceParameters,
+    ) -> Result<Vec<Option<OutputToken<'v>>>, InferenceError> {
+        let mut next_tokens = vec![None; self.sessions.len()];
+        for (session, (next_token, &finished)) in self
+            .sessions
+            .iter_mut()
+            .zip(next_tokens.iter_mut().zip(&self.finished))
+        {
+            if finished {
+                continue;
+            }
+            session.make_room(model, params, 1)?;
+            let token = session.sample(params);
+            session.accept_token(model, params, token)?;
+            *next_token = Some([token]);
+        }
+
+        let mut sequences: Vec<(&mut InferenceSession, &[TokenId])> = self
+            .sessions
+            .iter_mut()
+            .zip(&next_tokens)
+            .filter_map(|(session, token)| Some((session, token.as_ref()?.as_slice())))
+            .collect();
+        if !sequences.is_empty() {
+            model.evaluate_sequences(
+                &mut sequences,
+                params,
+                &mut EvaluateOutputRequest::default(),
+            );
+        }
+
+        let mut output = Vec::with_capacity(self.sessions.len());
+        for ((session, finished), next_token) in self
+            .sessions
+            .iter_mut()
+            .zip(&mut self.finished)
+            .zip(next_tokens)
+        {
+            let Some([token]) = next_token else {
+                output.push(None);
+                continue;
+            };
+            session.tokens.push(token);
+            output.push(Some(if token == vocab.eos_token() {
+                *finished = true;
+                OutputToken::EndOfText
+            } else {
+                OutputToken::Token(&vocab.id_to_token[token as usize])
+            }));
+        }
+        Ok(output)
+    }
+}
+
+/// Stops the generation of an [InferenceSession], usually from another
+/// thread. Obtained with [InferenceSession::abort_handle], and cheap to clone.
+///
+/// The request is checked between tokens, not while a token is evaluated,
+/// so generation stops at most one token's evaluation time after
+/// [AbortHandle::abort] is called. The text generated so far is still
+/// passed to the callback, and [InferenceSession::inference_with_prompt]
+/// returns successfully.
+#[derive(Clone, Debug)]
+pub struct AbortHandle(Arc<AtomicBool>);
+impl AbortHandle {
+    /// Requests the generation in progress to stop. If no generation is in
+    /// progress, the next one stops before generating any token.
+    pub fn abort(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Why generation stopped, as returned by [InferenceSession::inference_with_prompt],
+/// [Llama::complete] and [TextStream::stop_reason].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The model generated the end of text token.
+    EndOfSequence,
+    /// The maximum number of tokens was generated.
+    MaxTokens,
+    /// The text matched this one of [InferenceParameters::stop_sequences].
+    StopSequence(String),
+    /// Generation was stopped with [AbortHandle::abort].
+    Aborted,
+    /// There was no room left in the context window for the next token.
+    /// Running out of room while feeding the prompt is an error instead.
+    ContextFull,
+}
+
+pub struct InferenceStats {
+    pub feed_prompt_duration: std::time::Duration,
+    pub prompt_tokens: usize,
     pub predict_duration: std::time::Duration,
     pub predict_tokens: usize,
+    /// The number of tokens generated by this call, which unlike
+    /// `predict_tokens` doesn't count the tokens of the session before it.
+    pub generated_tokens: usize,
+    pub stop_reason: StopReason,
 }
 
 impl Default for InferenceStats {
@@ -219,6 +1368,8 @@ impl Default for InferenceStats {
             prompt_tokens: 0,
             predict_duration: std::time::Duration::from_secs(0),
             predict_tokens: 0,
+            generated_tokens: 0,
+            stop_reason: StopReason::MaxTokens,
         }
     }
 }
@@ -237,7 +1388,7 @@ impl Display for InferenceStats {
     }
 }
 
-type TokenId = i32;
+pub type TokenId = i32;
 type Token = String;
 type TokenScore = f32;
 
@@ -252,8 +1403,42 @@ pub struct Vocabulary {
     /// Maps a token to a token id
     token_to_id: HashMap<Token, TokenId>,
 
+    /// The bytes of the tokens that are not valid UTF-8 on their own, e.g.
+    /// part of a multi-byte character. These are replaced by U+FFFD in
+    /// `id_to_token`.
+    invalid_token_bytes: HashMap<TokenId, Vec<u8>>,
+
     /// The longest token in this vocabulary
     max_token_length: usize,
+
+    /// The tokens added with [Vocabulary::add_special_token], by text.
+    special_tokens: HashMap<Token, TokenId>,
+
+    /// Whether prompts start with the beginning-of-sentence token.
+    add_bos: bool,
+
+    /// The ids of the special tokens, see [Vocabulary::bos_token].
+    bos_token: TokenId,
+    eos_token: TokenId,
+    unk_token: TokenId,
+}
+
+impl std::fmt::Debug for Vocabulary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Printing every token is not useful, so just show the first few.
+        const SAMPLE_SIZE: usize = 8;
+        f.debug_struct("Vocabulary")
+            .field("len", &self.len())
+            .field("max_token_length", &self.max_token_length)
+            .field("sample", &&self.id_to_token[..self.len().min(SAMPLE_SIZE)])
+            .finish_non_exhaustive()
+    }
+}
+
+impl Display for Vocabulary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "vocabulary of {} tokens", self.len())
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -263,7 +1448,7 @@ pub enum OutputToken<'a> {
 }
 impl<'a> OutputToken<'a> {
     fn from_id(vocab: &'a Vocabulary, id: TokenId) -> Self {
-        if id == 2 {
+        if id == vocab.eos_token() {
             Self::EndOfText
         } else {
             Self::Token(&vocab.id_to_token[id as usize])
@@ -333,6 +1518,160 @@ impl std::fmt::Display for TokenBias {
     }
 }
 
+/// The metadata of a model, as read by [Llama::load_header_only].
+#[derive(Debug)]
+pub struct ModelHeader {
+    pub file_format: FileFormat,
+    pub hparams: Hyperparameters,
+    pub vocabulary: Vocabulary,
+    /// The paths of all the parts the model is split into, in order.
+    pub part_paths: Vec<PathBuf>,
+    /// The offset of the first tensor in each part, right after the header.
+    pub tensors_offset: u64,
+}
+
+/// The result of [Llama::verify].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// The number of tensors that were checked, across all the parts.
+    pub tensor_count: usize,
+    /// The problems found in the model files. Empty if they look valid.
+    pub mismatches: Vec<TensorMismatch>,
+}
+impl VerifyReport {
+    /// Returns whether no problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// A problem found by [Llama::verify].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TensorMismatch {
+    /// The tensor is not part of the model.
+    Unknown { tensor_name: String, path: PathBuf },
+    /// The tensor is part of the model, but is not in the file.
+    Missing { tensor_name: String, path: PathBuf },
+    /// The dimensions of the tensor don't match the hyperparameters.
+    WrongShape {
+        tensor_name: String,
+        path: PathBuf,
+        expected: Vec<i32>,
+        actual: Vec<i32>,
+    },
+    /// The type of the tensor doesn't match the hyperparameters.
+    WrongType {
+        tensor_name: String,
+        path: PathBuf,
+        expected: i32,
+        actual: i32,
+    },
+    /// The file ends in the middle of the tensor at `offset`.
+    Truncated { path: PathBuf, offset: u64 },
+    /// The metadata of the tensor at `offset` is invalid. The rest of the
+    /// file can't be checked.
+    Corrupted { path: PathBuf, offset: u64 },
+}
+impl Display for TensorMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TensorMismatch::Unknown { tensor_name, path } => {
+                write!(f, "unknown tensor {tensor_name:?} in {path:?}")
+            }
+            TensorMismatch::Missing { tensor_name, path } => {
+                write!(f, "missing tensor {tensor_name:?} in {path:?}")
+            }
+            TensorMismatch::WrongShape {
+                tensor_name,
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "tensor {tensor_name:?} in {path:?} has shape {actual:?}, expected {expected:?}"
+            ),
+            TensorMismatch::WrongType {
+                tensor_name,
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "tensor {tensor_name:?} in {path:?} has type {actual}, expected {expected}"
+            ),
+            TensorMismatch::Truncated { path, offset } => {
+                write!(f, "{path:?} ends in the tensor at offset {offset}")
+            }
+            TensorMismatch::Corrupted { path, offset } => {
+                write!(f, "invalid tensor metadata in {path:?} at offset {offset}")
+            }
+        }
+    }
+}
+
+/// Parameters that control how a model is loaded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoadParameters {
+    /// Load each part of a multi-part model on its own thread.
+    pub parallel_load: bool,
+    /// How the tensor data is loaded from the model file.
+    pub strategy: LoadStrategy,
+    /// The base of the frequencies of the rotary position embeddings.
+    /// Increasing it (NTK-aware scaling) lets the model use a longer context
+    /// than it was trained with.
+    pub rope_freq_base: f32,
+    /// The factor the positions are multiplied by in the rotary position
+    /// embeddings. Setting it to `trained context / context` (linear
+    /// scaling) lets the model use a longer context than it was trained with.
+    pub rope_freq_scale: f32,
+    /// The number of layers to offload to the GPU. No GPU backend is
+    /// available yet, so loading fails if this is not 0.
+    pub n_gpu_layers: usize,
+    /// The architecture of the model. Detected from the vocabulary if `None`.
+    pub architecture: Option<ModelArchitecture>,
+    /// Hash the data of each tensor with XXH3 as it is loaded, to tell
+    /// whether two copies of a model file are identical. The hash of each
+    /// tensor is logged at trace level, and the hash of the whole model at
+    /// info level; it is also returned by [Llama::checksum].
+    ///
+    /// With [LoadStrategy::Mmap], this reads the whole file during loading.
+    pub checksum: bool,
+    /// Skip the tensors the model doesn't use, such as those added by newer
+    /// conversion scripts, with a warning, instead of failing with
+    /// [LoadError::UnknownTensor].
+    pub skip_unknown_tensors: bool,
+}
+impl Default for LoadParameters {
+    fn default() -> Self {
+        Self {
+            parallel_load: false,
+            strategy: LoadStrategy::default(),
+            rope_freq_base: 10000.0,
+            rope_freq_scale: 1.0,
+            n_gpu_layers: 0,
+            architecture: None,
+            checksum: false,
+            skip_unknown_tensors: false,
+        }
+    }
+}
+
+/// How the tensor data is loaded from the model file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LoadStrategy {
+    /// Map the model file into memory and use the tensor data in place,
+    /// without copying it. The data is only read from disk when it is first
+    /// used, and can be shared with other processes using the same model.
+    ///
+    /// This is only supported for single-part GGJT files; other files are
+    /// loaded with [LoadStrategy::Read]. Memory mapping can be slower than
+    /// reading the file on spinning disks or network filesystems.
+    #[default]
+    Mmap,
+    /// Read the whole model file into memory allocated for the model.
+    Read,
+}
+
 /// Each variant represents a step within the process of loading the model.
 /// These can be used to report progress to the user.
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
@@ -357,12 +1696,22 @@ pub enum LoadProgress<'a> {
         file: &'a Path,
         current_tensor: usize,
         tensor_count: usize,
+        /// The number of bytes loaded from this part so far.
+        bytes_loaded: usize,
     },
     PartLoaded {
         file: &'a Path,
         byte_size: usize,
         tensor_count: usize,
     },
+    /// Reported by `Llama::load_from_url`, of the `download` feature, while
+    /// the model is downloaded.
+    Downloading {
+        url: &'a str,
+        bytes_downloaded: usize,
+        /// The size of the model, if the server sent it.
+        total_bytes: Option<usize>,
+    },
 }
 
 #[derive(Error, Debug)]
@@ -372,6 +1721,16 @@ pub enum LoadError {
         source: std::io::Error,
         path: PathBuf,
     },
+    #[error("the file {path:?} does not exist")]
+    FileNotFound { path: PathBuf },
+    #[error(
+        "{path:?} is a directory, not a model file; pass the path of the model's .bin file in it"
+    )]
+    IsADirectory { path: PathBuf },
+    #[error(
+        "permission denied when opening {path:?}; check that it is readable by the current user"
+    )]
+    PermissionDenied { path: PathBuf },
     #[error("no parent path for {path:?}")]
     NoParentPath { path: PathBuf },
     #[error("unable to read exactly {bytes} bytes")]
@@ -381,6 +1740,13 @@ pub enum LoadError {
     },
     #[error("non-specific I/O error")]
     IO(#[from] std::io::Error),
+    #[error("failed reading {what} at offset {offset:#x} in {path:?}")]
+    ReadFailed {
+        source: std::io::Error,
+        what: String,
+        path: PathBuf,
+        offset: u64,
+    },
 
     #[error("could not convert bytes to a UTF-8 string")]
     InvalidUtf8(#[from] std::string::FromUtf8Error),
@@ -389,18 +1755,100 @@ pub enum LoadError {
 
     #[error("unversioned magic number, regenerate your ggml models")]
     UnversionedMagic,
-    #[error("invalid magic number for {path:?}")]
-    InvalidMagic { path: PathBuf },
-    #[error("invalid file format version {value}")]
-    InvalidFormatVersion { value: u32 },
+    #[error("invalid magic number {magic:#x} for {path:?}")]
+    InvalidMagic { path: PathBuf, magic: u32 },
+    #[error("invalid file format version {value} for {format} file")]
+    InvalidFormatVersion { format: FileFormat, value: u32 },
     #[error("invalid value {value} for `f16` in hyperparameters")]
     HyperparametersF16Invalid { value: i32 },
+    /// A size in the hyperparameters is zero or negative, so the file is
+    /// corrupt, or the context size passed to the loader is.
+    #[error("invalid value {value} for `{name}` in the hyperparameters of {path:?}, it must be positive")]
+    InvalidHyperparameters {
+        name: &'static str,
+        value: i32,
+        path: PathBuf,
+    },
+    /// The number of tokens in the vocabulary, or of rows in the embeddings,
+    /// doesn't match the `n_vocab` hyperparameter. This is usually caused by
+    /// a bug in the conversion of the model.
+    #[error("{path:?} has a vocabulary of {actual} tokens, but its hyperparameters say {n_vocab}")]
+    VocabMismatch {
+        n_vocab: i32,
+        actual: usize,
+        path: PathBuf,
+    },
     #[error("unknown tensor `{tensor_name}` in {path:?}")]
     UnknownTensor { tensor_name: String, path: PathBuf },
     #[error("the tensor `{tensor_name}` has the wrong size in {path:?}")]
     TensorWrongSize { tensor_name: String, path: PathBuf },
+    #[error(
+        "the tensor `{tensor_name}` can't be split evenly between the {n_parts} parts of {path:?}"
+    )]
+    UnevenSplit {
+        tensor_name: String,
+        n_parts: usize,
+        path: PathBuf,
+    },
+    /// The tensor's type doesn't match the one implied by the
+    /// hyperparameters. Models that mix quantized and unquantized weights are
+    /// not supported, as the memory for the tensors is allocated before they
+    /// are read.
+    #[error("the tensor `{tensor_name}` in {path:?} has ftype {ftype}, but the model's tensors should have ftype {expected_ftype}")]
+    TensorWrongType {
+        tensor_name: String,
+        path: PathBuf,
+        ftype: i32,
+        expected_ftype: i32,
+    },
+    /// Quantized types store their elements in blocks, so a tensor of such a
+    /// type with a partial block is corrupt.
+    #[error("the tensor `{tensor_name}` in {path:?} has {nelements} elements, which is not a multiple of the block size {block_size} of its type")]
+    BlockSizeMismatch {
+        tensor_name: String,
+        path: PathBuf,
+        nelements: i64,
+        block_size: usize,
+    },
+    /// The file ends in the middle of the data of a tensor, which usually
+    /// means that it was not fully downloaded or copied.
+    #[error("{path:?} is truncated: it ends within the data of the tensor `{tensor_name}`, which starts at offset {offset:#x} and should be {expected} bytes long")]
+    TruncatedFile {
+        tensor_name: String,
+        path: PathBuf,
+        offset: u64,
+        expected: usize,
+    },
+    #[error("the tensor `{tensor_name}` is missing from {path:?}")]
+    MissingTensor { tensor_name: String, path: PathBuf },
     #[error("invalid ftype {ftype} in {path:?}")]
     InvalidFtype { ftype: i32, path: PathBuf },
+    #[error("invalid LoRA adapter format version {value} for {path:?}")]
+    InvalidLoraVersion { value: u32, path: PathBuf },
+    #[error("invalid LoRA adapter rank {rank} in {path:?}")]
+    InvalidLoraRank { rank: i32, path: PathBuf },
+    /// Memory-mapped weights are read-only, so the model must be loaded with
+    /// [LoadStrategy::Read] to apply a LoRA adapter.
+    #[error("LoRA adapters cannot be applied to a memory-mapped model")]
+    LoraMemoryMapped,
+    /// Memory-mapped weights are read-only, so the model must be loaded with
+    /// [LoadStrategy::Read] to load tensors into it.
+    #[error("tensors cannot be loaded into a memory-mapped model")]
+    TensorsMemoryMapped,
+    #[error("LoRA adapters cannot be applied to the quantized tensor `{tensor_name}`")]
+    LoraQuantizedTensor { tensor_name: String },
+    #[error("the buffer passed to Llama::load_with_buffer holds {size} bytes once aligned, but the model needs {required}")]
+    ContextBufferTooSmall { size: usize, required: usize },
+    #[error("cannot offload {n_gpu_layers} layers: GPU backend not compiled")]
+    GpuBackendUnavailable { n_gpu_layers: usize },
+    #[error("could not download {url}")]
+    DownloadFailed { source: std::io::Error, url: String },
+    #[error("the SHA-256 of {url} is {actual}, but {expected} was expected")]
+    ChecksumMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
 }
 
 #[derive(Error, Debug)]
@@ -421,6 +1869,14 @@ pub enum InferenceError {
     ContextFull,
     #[error("the user-specified callback returned an error")]
     UserCallback(Box<dyn std::error::Error>),
+    #[error("the model produced non-finite logits")]
+    NonFiniteLogits,
+    #[error("the output of layer {layer} is not finite")]
+    NonFiniteLayerOutput { layer: usize },
+    #[error("the token {0} is not in the vocabulary")]
+    InvalidToken(TokenId),
+    #[error("a context window of {n_ctx} tokens can't hold the {n_past} tokens of the session")]
+    ContextTooSmall { n_ctx: usize, n_past: usize },
 }
 
 /// Used in a call to `evaluate` to request information from the transformer.
@@ -432,700 +1888,2294 @@ pub struct EvaluateOutputRequest {
     /// Returns the embeddings for the provided batch of tokens
     /// Output shape is n_batch * n_embd
     pub embeddings: Option<Vec<f32>>,
+    /// Returns the hidden states (the residual stream) after each layer for
+    /// the provided batch of tokens, one vector per layer.
+    /// Output shape is n_layer * (n_batch * n_embd)
+    pub hidden_states: Option<Vec<Vec<f32>>>,
 }
 
 /// NOTE: The original code relies in promotion rules and automatic cast between
 /// int to float. What we do instead is use this macro to convert every term of
 /// the multiplication to f64, which should have enough precision bits to hold
-/// the final value, then cast to usize. I have observed a discrepancy between
-/// the ctx_size found using this code, and the one in llama.cpp. The number for
-/// rust ends up being slightly lower, but no "out of memory" errors are
-/// reported by ggml.
+/// the final value, then cast to usize.
 macro_rules! mulf {
     ($term:expr, $($terms:expr),*) => {
         (($term as f64) $(* ($terms as f64))*) as u64
     };
 }
 
-impl Model {
-    pub fn load(
-        path: impl AsRef<Path>,
-        n_ctx: i32,
-        load_progress_callback: impl Fn(LoadProgress),
-    ) -> Result<(Model, Vocabulary), LoadError> {
-        use std::fs::File;
-        use std::io::BufReader;
+fn read_bytes<const N: usize>(reader: &mut impl BufRead) -> Result<[u8; N], LoadError> {
+    let mut bytes = [0u8; N];
+    reader
+        .read_exact(&mut bytes)
+        .map_err(|e| LoadError::ReadExactFailed {
+            source: e,
+            bytes: N,
+        })?;
+    Ok(bytes)
+}
 
-        let main_path = path.as_ref();
+fn read_i32(reader: &mut impl BufRead) -> Result<i32, LoadError> {
+    Ok(i32::from_le_bytes(read_bytes::<4>(reader)?))
+}
 
-        let mut reader =
-            BufReader::new(
-                File::open(main_path).map_err(|e| LoadError::OpenFileFailed {
-                    source: e,
-                    path: main_path.to_owned(),
-                })?,
-            );
+fn read_u32(reader: &mut impl BufRead) -> Result<u32, LoadError> {
+    Ok(u32::from_le_bytes(read_bytes::<4>(reader)?))
+}
 
-        fn read_bytes<const N: usize>(reader: &mut impl BufRead) -> Result<[u8; N], LoadError> {
-            let mut bytes = [0u8; N];
-            reader
-                .read_exact(&mut bytes)
-                .map_err(|e| LoadError::ReadExactFailed {
-                    source: e,
-                    bytes: N,
-                })?;
-            Ok(bytes)
-        }
+fn read_f32(reader: &mut impl BufRead) -> Result<f32, LoadError> {
+    Ok(f32::from_le_bytes(read_bytes::<4>(reader)?))
+}
 
-        fn read_i32(reader: &mut impl BufRead) -> Result<i32, LoadError> {
-            Ok(i32::from_le_bytes(read_bytes::<4>(reader)?))
-        }
+fn read_bytes_with_len(reader: &mut impl BufRead, len: usize) -> Result<Vec<u8>, LoadError> {
+    let mut buf = vec![0; len];
+    reader
+        .read_exact(&mut buf)
+        .map_err(|e| LoadError::ReadExactFailed {
+            source: e,
+            bytes: buf.len(),
+        })?;
+    Ok(buf)
+}
 
-        fn read_u32(reader: &mut impl BufRead) -> Result<u32, LoadError> {
-            Ok(u32::from_le_bytes(read_bytes::<4>(reader)?))
-        }
+/// A matrix of a LoRA adapter, with its data read into memory.
+struct LoraTensor {
+    typ: GgmlType,
+    ne: [i32; 2],
+    data: Vec<u8>,
+}
 
-        fn read_f32(reader: &mut impl BufRead) -> Result<f32, LoadError> {
-            Ok(f32::from_le_bytes(read_bytes::<4>(reader)?))
+/// Reads the next tensor of a LoRA adapter file, and returns it with its name.
+fn read_lora_tensor(
+    reader: &mut BufReader<File>,
+    path: &Path,
+) -> Result<(String, LoraTensor), LoadError> {
+    let n_dims = read_i32(reader)?;
+    let length = read_i32(reader)?;
+    let ftype = read_i32(reader)?;
+
+    let mut dims_valid = n_dims == 2;
+    let mut ne = [1i32, 1i32];
+    for i in 0..n_dims {
+        let dim = read_i32(reader)?;
+        match ne.get_mut(i as usize) {
+            Some(ne_i) if dim >= 0 => *ne_i = dim,
+            _ => dims_valid = false,
         }
+    }
 
-        /// Helper function. Reads a string from the buffer and returns it.
-        fn read_string(reader: &mut BufReader<File>, len: usize) -> Result<String, LoadError> {
-            let mut buf = vec![0; len];
-            reader
-                .read_exact(&mut buf)
-                .map_err(|e| LoadError::ReadExactFailed {
-                    source: e,
-                    bytes: buf.len(),
-                })?;
-            let s = String::from_utf8(buf)?;
-            Ok(s)
+    let tensor_name = read_string(reader, length as usize)?;
+    if !dims_valid {
+        return Err(LoadError::TensorWrongSize {
+            tensor_name,
+            path: path.to_owned(),
+        });
+    }
+    let typ = match GgmlType::try_from(ftype) {
+        Ok(typ @ (GgmlType::F32 | GgmlType::F16)) => typ,
+        _ => {
+            return Err(LoadError::InvalidFtype {
+                ftype,
+                path: path.to_owned(),
+            })
         }
+    };
 
-        // Verify magic
-        let is_legacy_model: bool = match read_i32(&mut reader)? {
-            ggml::FILE_MAGIC => false,
-            ggml::FILE_MAGIC_UNVERSIONED => true,
-            _ => {
-                return Err(LoadError::InvalidMagic {
-                    path: main_path.to_owned(),
-                })
-            }
-        };
-
-        // Load format version
-        if !is_legacy_model {
-            #[allow(unused_variables)]
-            let version: u32 = match read_u32(&mut reader)? {
-                ggml::FORMAT_VERSION => ggml::FORMAT_VERSION,
-                version => return Err(LoadError::InvalidFormatVersion { value: version }),
-            };
-        }
+    // As in ggjt files, the tensor data starts at the next aligned offset.
+    let position = reader.stream_position()?;
+    let padding = (ggml::GGJT_TENSOR_ALIGNMENT - position % ggml::GGJT_TENSOR_ALIGNMENT)
+        % ggml::GGJT_TENSOR_ALIGNMENT;
+    reader.seek_relative(padding as i64)?;
 
-        // =================
-        // Load hyper params
-        // =================
+    let nbytes = ne[0] as usize * ne[1] as usize * typ.size();
+    let data = read_bytes_with_len(reader, nbytes)?;
+    Ok((tensor_name, LoraTensor { typ, ne, data }))
+}
 
-        // NOTE: Field order matters! Data is laid out in the file exactly
-        // in this order.
-        let hparams = Hyperparameters {
-            n_vocab: read_i32(&mut reader)?,
-            n_ctx,
-            n_embd: read_i32(&mut reader)?,
-            n_mult: read_i32(&mut reader)?,
-            n_head: read_i32(&mut reader)?,
-            n_layer: read_i32(&mut reader)?,
-            n_rot: read_i32(&mut reader)?,
-            f16_: read_i32(&mut reader)?,
+/// Computes `weight += scaling * B A` in place, with `backend`.
+fn apply_lora_tensor(
+    backend: &dyn backend::Backend,
+    weight: &ggml::Tensor,
+    a: &LoraTensor,
+    b: &LoraTensor,
+    scaling: f32,
+    n_threads: i32,
+) {
+    let ne = weight.get_ne();
+    let n_weight = ne[0] as usize * ne[1] as usize;
+    let n_lora = (a.ne[0] * a.ne[1] + b.ne[0] * b.ne[1]) as usize;
+    // The inputs, a conversion of B to f32 and a work buffer for the
+    // multiplication, and up to four intermediate results the size of the
+    // weight.
+    let ctx_size = a.data.len()
+        + b.data.len()
+        + 2 * n_lora * ggml::type_size(ggml::TYPE_F32)
+        + 4 * n_weight * ggml::type_size(ggml::TYPE_F32)
+        + 16 * (ggml::tensor_overhead() + ggml::MEM_ALIGN);
+    let ctx = ggml::Context::init(ctx_size);
+
+    let new_tensor = |tensor: &LoraTensor| {
+        let t = ctx.new_tensor_2d(tensor.typ.raw(), tensor.ne[0], tensor.ne[1]);
+        // SAFETY: the tensor was allocated with exactly this size.
+        unsafe {
+            std::slice::from_raw_parts_mut(t.data() as *mut u8, t.nbytes())
+                .copy_from_slice(&tensor.data)
         };
+        t
+    };
+    let a_t = new_tensor(a);
+    let mut b_t = new_tensor(b);
+    // ggml only multiplies by f32 matrices.
+    if b.typ != GgmlType::F32 {
+        b_t = ctx.op_cpy(&b_t, &ctx.new_tensor_2d(ggml::TYPE_F32, b.ne[0], b.ne[1]));
+    }
 
-        let n_ff =
-            ((2 * (4 * hparams.n_embd) / 3 + hparams.n_mult - 1) / hparams.n_mult) * hparams.n_mult;
+    let ba = ctx.op_mul_mat(&a_t, &b_t);
+    let ba = ctx.op_scale(&ba, &ctx.new_f32(scaling));
 
-        load_progress_callback(LoadProgress::HyperparametersLoaded(&hparams));
+    // ggml only adds f32 matrices, so f16 weights are converted back and forth.
+    let weight_f32 = if weight.get_type() == ggml::TYPE_F32 {
+        weight.alias()
+    } else {
+        ctx.op_cpy(weight, &ctx.new_tensor_2d(ggml::TYPE_F32, ne[0], ne[1]))
+    };
+    let sum = ctx.op_add(&weight_f32, &ba);
 
-        // ===============
-        // Load vocabulary
-        // ===============
-        let vocab = {
-            let mut id_to_token = vec![];
-            let mut id_to_token_score = vec![];
-            let mut token_to_id = HashMap::new();
-            let mut max_token_length = 0;
+    backend.compute_tensor(&ctx, &ctx.op_cpy(&sum, weight), n_threads);
+}
 
-            for i in 0..hparams.n_vocab {
-                let len = read_i32(&mut reader)?;
-                if let Ok(word) = read_string(&mut reader, len as usize) {
-                    max_token_length = max_token_length.max(word.len());
-                    id_to_token.push(word.clone());
-                    token_to_id.insert(word, i);
-                } else {
-                    load_progress_callback(LoadProgress::BadToken {
-                        index: i.try_into()?,
-                    });
-                    id_to_token.push("�".to_string());
-                }
+/// Helper function. Reads a string from the buffer and returns it.
+fn read_string(reader: &mut impl BufRead, len: usize) -> Result<String, LoadError> {
+    let s = String::from_utf8(read_bytes_with_len(reader, len)?)?;
+    Ok(s)
+}
 
-                // Token score, currently unused
-                if !is_legacy_model {
-                    if let Ok(score) = read_f32(&mut reader) {
-                        id_to_token_score.push(score);
-                    }
-                } else {
-                    // Legacy model, set empty score
-                    id_to_token_score.push(0.);
-                }
-            }
+/// Returns how the tensor is split across the parts of a multi-part model.
+fn tensor_split_type(tensor_name: &str) -> usize {
+    // split_type = 0: split by columns
+    // split_type = 1: split by rows
+    //
+    // split_type = 0:
+    // regex:
+    //   - tok_embeddings.*
+    //   - layers.*.attention.wo.weight
+    //   - layers.*.feed_forward.w2.weight
+
+    // split_type = 1:
+    // regex:
+    //   - output.*
+    //   - layers.*.attention.wq.weight
+    //   - layers.*.attention.wk.weight
+    //   - layers.*.attention.wv.weight
+    //   - layers.*.feed_forward.w1.weight
+    //   - layers.*.feed_forward.w3.weight
+    #[allow(clippy::if_same_then_else)]
+    if tensor_name.contains("tok_embeddings") {
+        0
+    } else if tensor_name.contains("layers") {
+        if tensor_name.contains("attention.wo.weight") {
+            0
+        } else if tensor_name.contains("feed_forward.w2.weight") {
+            0
+        } else {
+            1
+        }
+    } else if tensor_name.contains("output") {
+        1
+    } else {
+        0
+    }
+}
 
-            Vocabulary {
-                id_to_token,
-                id_to_token_score,
-                token_to_id,
-                max_token_length,
-            }
-        };
+/// Returns the dimensions and file type of every tensor of a model with the
+/// given hyperparameters, as stored in a single-part file.
+fn expected_tensors(hparams: &Hyperparameters) -> HashMap<String, (Vec<i32>, i32)> {
+    let n_embd = hparams.n_embd;
+    let n_ff = hparams.n_ff();
+    let weight = |ne: &[i32]| (ne.to_vec(), hparams.f16_);
+    let norm = || (vec![n_embd], 0);
+
+    let mut tensors = HashMap::from([
+        (
+            "tok_embeddings.weight".to_owned(),
+            weight(&[n_embd, hparams.n_vocab]),
+        ),
+        ("norm.weight".to_owned(), norm()),
+        (
+            "output.weight".to_owned(),
+            weight(&[n_embd, hparams.n_vocab]),
+        ),
+    ]);
+    for i in 0..hparams.n_layer {
+        let layer_tensors = [
+            ("attention_norm.weight", norm()),
+            ("attention.wq.weight", weight(&[n_embd, n_embd])),
+            ("attention.wk.weight", weight(&[n_embd, n_embd])),
+            ("attention.wv.weight", weight(&[n_embd, n_embd])),
+            ("attention.wo.weight", weight(&[n_embd, n_embd])),
+            ("ffn_norm.weight", norm()),
+            ("feed_forward.w1.weight", weight(&[n_embd, n_ff])),
+            ("feed_forward.w2.weight", weight(&[n_ff, n_embd])),
+            ("feed_forward.w3.weight", weight(&[n_embd, n_ff])),
+        ];
+        for (name, tensor) in layer_tensors {
+            tensors.insert(format!("layers.{i}.{name}"), tensor);
+        }
+    }
+    tensors
+}
 
-        // for the big tensors, we have the option to store the data in 16-bit
-        // floats or quantized in order to save memory and also to speed up the
-        // computation
-        let wtype = match hparams.f16_ {
-            0 => ggml::TYPE_F32,
-            1 => ggml::TYPE_F16,
-            2 => ggml::TYPE_Q4_0,
-            3 => ggml::TYPE_Q4_1,
-            invalid => return Err(LoadError::HyperparametersF16Invalid { value: invalid }),
+/// Checks the tensors stored in the part file at `part_path` against the
+/// `expected` ones, adding the problems to the `report`. Returns the names
+/// of the tensors found, or `None` if the file could not be read completely.
+fn verify_part(
+    expected: &HashMap<String, (Vec<i32>, i32)>,
+    file_format: FileFormat,
+    part_path: &Path,
+    n_parts: i32,
+    file_offset: u64,
+    report: &mut VerifyReport,
+) -> Result<Option<HashSet<String>>, LoadError> {
+    // Longer names are assumed to come from a corrupted length.
+    const MAX_NAME_LENGTH: i32 = 1 << 16;
+
+    let file = open_file(part_path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(file_offset))?;
+
+    let mut found = HashSet::new();
+    loop {
+        if reader.fill_buf()?.is_empty() {
+            return Ok(Some(found));
+        }
+
+        let offset = reader.stream_position()?;
+        let metadata = (|| {
+            let n_dims = read_i32(&mut reader)?;
+            let length = read_i32(&mut reader)?;
+            let ftype = read_i32(&mut reader)?;
+            if !(1..=2).contains(&n_dims) || !(0..=MAX_NAME_LENGTH).contains(&length) {
+                return Ok(None);
+            }
+            let ne = (0..n_dims)
+                .map(|_| read_i32(&mut reader))
+                .collect::<Result<Vec<_>, _>>()?;
+            let tensor_name = read_string(&mut reader, length as usize)?;
+            Ok(Some((ftype, ne, tensor_name)))
+        })();
+        let (ftype, ne, tensor_name) = match metadata {
+            Ok(Some(metadata)) => metadata,
+            Ok(None) => {
+                report.mismatches.push(TensorMismatch::Corrupted {
+                    path: part_path.to_owned(),
+                    offset,
+                });
+                return Ok(None);
+            }
+            Err(LoadError::ReadExactFailed { .. }) => {
+                report.mismatches.push(TensorMismatch::Truncated {
+                    path: part_path.to_owned(),
+                    offset,
+                });
+                return Ok(None);
+            }
+            Err(err) => return Err(err),
+        };
+        let tensor_name = normalize_tensor_name(&tensor_name).unwrap_or(tensor_name);
+        report.tensor_count += 1;
+
+        match expected.get(&tensor_name) {
+            None => report.mismatches.push(TensorMismatch::Unknown {
+                tensor_name: tensor_name.clone(),
+                path: part_path.to_owned(),
+            }),
+            Some((expected_ne, expected_ftype)) => {
+                let mut expected_ne = expected_ne.clone();
+                if expected_ne.len() == 2 {
+                    expected_ne[tensor_split_type(&tensor_name)] /= n_parts;
+                }
+                if ne != expected_ne {
+                    report.mismatches.push(TensorMismatch::WrongShape {
+                        tensor_name: tensor_name.clone(),
+                        path: part_path.to_owned(),
+                        expected: expected_ne,
+                        actual: ne.clone(),
+                    });
+                }
+                if ftype != *expected_ftype {
+                    report.mismatches.push(TensorMismatch::WrongType {
+                        tensor_name: tensor_name.clone(),
+                        path: part_path.to_owned(),
+                        expected: *expected_ftype,
+                        actual: ftype,
+                    });
+                }
+            }
+        }
+        found.insert(tensor_name);
+
+        // Skip the data, which requires knowing its size.
+        let data_size = GgmlType::try_from(ftype).ok().and_then(|typ| {
+            let nelements = ne
+                .iter()
+                .try_fold(1u64, |acc, &n| acc.checked_mul(u64::try_from(n).ok()?))?;
+            Some(nelements * typ.size() as u64 / typ.block_size() as u64)
+        });
+        let Some(data_size) = data_size else {
+            report.mismatches.push(TensorMismatch::Corrupted {
+                path: part_path.to_owned(),
+                offset,
+            });
+            return Ok(None);
         };
 
-        let n_embd = hparams.n_embd;
-        let n_layer = hparams.n_layer;
-        let n_vocab = hparams.n_vocab;
+        let data_start = file_format.tensor_data_offset(reader.stream_position()?);
+        match data_start.checked_add(data_size) {
+            Some(data_end) if data_end <= file_len => {
+                reader.seek(SeekFrom::Start(data_end))?;
+            }
+            _ => {
+                report.mismatches.push(TensorMismatch::Truncated {
+                    path: part_path.to_owned(),
+                    offset,
+                });
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// Loads the tensors stored in the part file at `part_path` into `tensors`.
+/// Returns the number of bytes and the number of tensors that were loaded.
+///
+/// If `mmap` is set, it must be the mapping of `part_path`, and the tensors
+/// are made to point to their data in it instead of reading it.
+///
+/// `on_tensor_loaded` is called with the running tensor and byte counts after
+/// each tensor is loaded. If `checksums` is set, the name and the hash of the
+/// data of each tensor are pushed to it. Tensors that are not in `tensors` are
+/// an error, unless `skip_unknown` is set.
+#[allow(clippy::too_many_arguments)]
+fn load_part(
+    tensors: &HashMap<String, ggml::Tensor>,
+    file_format: FileFormat,
+    part_path: &Path,
+    part_id: usize,
+    n_parts: usize,
+    file_offset: u64,
+    mmap: Option<&Mmap>,
+    skip_unknown: bool,
+    checksums: Option<&mut Vec<(String, u64)>>,
+    on_tensor_loaded: impl FnMut(usize, usize),
+) -> Result<(usize, usize), LoadError> {
+    let mut part_reader = BufReader::new(open_file(part_path)?);
+
+    // Skip metadata
+    part_reader
+        .seek(SeekFrom::Start(file_offset))
+        .map_err(io_context(
+            || "the tensors".to_owned(),
+            part_path,
+            file_offset,
+        ))?;
+
+    load_tensors(
+        &mut part_reader,
+        tensors,
+        file_format,
+        part_path,
+        part_id,
+        n_parts,
+        mmap,
+        &HashSet::new(),
+        skip_unknown,
+        checksums,
+        on_tensor_loaded,
+    )
+}
+
+/// Loads the tensors that follow the position of `part_reader` into
+/// `tensors`, like [load_part], except for those named in `skip`, whose data
+/// is seeked past, as is the data of unknown tensors if `skip_unknown` is set.
+/// `part_path` is only used in errors.
+#[allow(clippy::too_many_arguments)]
+fn load_tensors<R: Read + Seek>(
+    part_reader: &mut BufReader<R>,
+    tensors: &HashMap<String, ggml::Tensor>,
+    file_format: FileFormat,
+    part_path: &Path,
+    part_id: usize,
+    n_parts: usize,
+    mmap: Option<&Mmap>,
+    skip: &HashSet<String>,
+    skip_unknown: bool,
+    mut checksums: Option<&mut Vec<(String, u64)>>,
+    mut on_tensor_loaded: impl FnMut(usize, usize),
+) -> Result<(usize, usize), LoadError> {
+    let mut total_size = 0;
+    let mut n_tensors: usize = 0;
+
+    // Load weights
+    loop {
+        let offset = part_reader.stream_position()?;
+
+        // NOTE: Implementation from #![feature(buf_read_has_data_left)]
+        let is_eof = part_reader
+            .fill_buf()
+            .map(|b| b.is_empty())
+            .map_err(io_context(
+                || "a tensor header".to_owned(),
+                part_path,
+                offset,
+            ))?;
+
+        if is_eof {
+            break;
+        }
+
+        // The dimensions come straight from the file, so they are validated
+        // once the tensor name is known and can be reported.
+        //
+        // The size of tensors that are skipped is computed from all of their
+        // dimensions, as they may have more than those of the model.
+        let (n_dims, ftype, ne, dims_valid, skipped_nelements, tensor_name) = (|| {
+            let n_dims = read_i32(part_reader)?;
+            let length = read_i32(part_reader)?;
+            let ftype = read_i32(part_reader)?;
+
+            let mut dims_valid = (1..=2).contains(&n_dims);
+            let mut ne = [1i32, 1i32];
+            let mut skipped_nelements = (1..=4).contains(&n_dims).then_some(1i64);
+            for i in 0..n_dims {
+                let dim = read_i32(part_reader)?;
+                match ne.get_mut(i as usize) {
+                    Some(ne_i) => *ne_i = dim,
+                    None => dims_valid = false,
+                }
+                skipped_nelements = skipped_nelements
+                    .filter(|_| dim >= 0)
+                    .and_then(|n| n.checked_mul(i64::from(dim)));
+            }
+
+            let tensor_name = read_string(part_reader, length as usize)?;
+            Ok::<_, LoadError>((
+                n_dims,
+                ftype,
+                ne,
+                dims_valid,
+                skipped_nelements,
+                tensor_name,
+            ))
+        })()
+        .map_err(io_context(
+            || "a tensor header".to_owned(),
+            part_path,
+            offset,
+        ))?;
+        let nelements = (ne[0] >= 0 && ne[1] >= 0).then(|| i64::from(ne[0]) * i64::from(ne[1]));
+        let tensor_name = normalize_tensor_name(&tensor_name).unwrap_or(tensor_name);
+
+        let unknown = !tensors.contains_key(&tensor_name);
+        if unknown && skip_unknown {
+            log::warn!(
+                "Skipping the tensor {tensor_name} of {part_path:?}, which the model doesn't use"
+            );
+        }
+        if skip.contains(&tensor_name) || (unknown && skip_unknown) {
+            let Some(nelements) = skipped_nelements else {
+                return Err(LoadError::TensorWrongSize {
+                    tensor_name,
+                    path: part_path.to_owned(),
+                });
+            };
+            let typ = GgmlType::try_from(ftype).map_err(|ftype| LoadError::InvalidFtype {
+                ftype,
+                path: part_path.to_owned(),
+            })?;
+            check_block_size(&tensor_name, nelements, typ, part_path)?;
+            let data_offset = file_format.tensor_data_offset(part_reader.stream_position()?);
+            let data_size = nelements as u64 * typ.size() as u64 / typ.block_size() as u64;
+            part_reader
+                .seek(SeekFrom::Start(data_offset + data_size))
+                .map_err(io_context(
+                    || format!("the data of the tensor `{tensor_name}`"),
+                    part_path,
+                    data_offset,
+                ))?;
+            log::trace!("Skipped tensor {tensor_name}");
+            continue;
+        }
+
+        log::trace!(
+            "Loading tensor {tensor_name} ({} x {}, ftype {ftype})",
+            ne[0],
+            ne[1]
+        );
+
+        let Some(tensor) = tensors.get(&tensor_name) else {
+            return Err(LoadError::UnknownTensor {
+                tensor_name,
+                path: part_path.to_owned(),
+            });
+        };
+
+        let Some(nelements) = nelements.filter(|_| dims_valid) else {
+            return Err(LoadError::TensorWrongSize {
+                tensor_name,
+                path: part_path.to_owned(),
+            });
+        };
+        // An invalid ftype is reported once the expected type is known.
+        if let Ok(typ) = GgmlType::try_from(ftype) {
+            check_block_size(&tensor_name, nelements, typ, part_path)?;
+        }
+
+        // In ggjt files, the tensor data starts at the next aligned offset.
+        let position = part_reader.stream_position()?;
+        let data_offset = file_format.tensor_data_offset(position);
+        if data_offset != position {
+            part_reader
+                .seek_relative((data_offset - position) as i64)
+                .map_err(io_context(
+                    || format!("the data of the tensor `{tensor_name}`"),
+                    part_path,
+                    data_offset,
+                ))?;
+        }
+
+        let split_type = tensor_split_type(&tensor_name);
+
+        // The embeddings have a row per token, so a vocabulary of the wrong
+        // size shows up here first.
+        if ["tok_embeddings.weight", "output.weight"].contains(&tensor_name.as_str()) {
+            let n_rows = match split_type {
+                1 => i64::from(ne[1]) * i64::try_from(n_parts)?,
+                _ => i64::from(ne[1]),
+            };
+            if n_rows != i64::from(tensor.get_ne()[1]) {
+                return Err(LoadError::VocabMismatch {
+                    n_vocab: tensor.get_ne()[1],
+                    actual: n_rows.try_into()?,
+                    path: part_path.to_owned(),
+                });
+            }
+        }
+
+        if n_dims == 1 {
+            if tensor.nelements() != nelements {
+                return Err(LoadError::TensorWrongSize {
+                    tensor_name,
+                    path: part_path.to_owned(),
+                });
+            }
+        } else if tensor.nelements() / i64::try_from(n_parts)? != nelements {
+            return Err(LoadError::TensorWrongSize {
+                tensor_name,
+                path: part_path.to_owned(),
+            });
+        }
+
+        if n_dims == 1 {
+            if tensor.get_ne()[0] != ne[0] || tensor.get_ne()[1] != ne[1] {
+                return Err(LoadError::TensorWrongSize {
+                    tensor_name,
+                    path: part_path.to_owned(),
+                });
+            }
+        } else if split_type == 0 {
+            if tensor.get_ne()[0] / i32::try_from(n_parts)? != ne[0] || tensor.get_ne()[1] != ne[1]
+            {
+                return Err(LoadError::TensorWrongSize {
+                    tensor_name,
+                    path: part_path.to_owned(),
+                });
+            }
+        } else if tensor.get_ne()[0] != ne[0]
+            || tensor.get_ne()[1] / i32::try_from(n_parts)? != ne[1]
+        {
+            return Err(LoadError::TensorWrongSize {
+                tensor_name,
+                path: part_path.to_owned(),
+            });
+        }
+
+        let expected_typ = GgmlType::from_raw(tensor.get_type())
+            .expect("tensors are only created with these types");
+        let typ = match GgmlType::try_from(ftype) {
+            Ok(typ) if typ != expected_typ => {
+                return Err(LoadError::TensorWrongType {
+                    tensor_name,
+                    path: part_path.to_owned(),
+                    ftype,
+                    expected_ftype: expected_typ.into(),
+                });
+            }
+            Ok(typ) => typ,
+            Err(ftype) => {
+                return Err(LoadError::InvalidFtype {
+                    ftype,
+                    path: part_path.to_owned(),
+                })
+            }
+        };
+        if matches!(typ, GgmlType::Q4_0 | GgmlType::Q4_1) && ne[0] % 64 != 0 {
+            return Err(LoadError::TensorWrongSize {
+                tensor_name,
+                path: part_path.to_owned(),
+            });
+        }
+        let bpe = typ.size();
+        let mut hasher = checksums.is_some().then(Xxh3::new);
+
+        if n_dims == 1 || n_parts == 1 {
+            if (nelements as usize * bpe) / typ.block_size() != tensor.nbytes() {
+                return Err(LoadError::TensorWrongSize {
+                    tensor_name,
+                    path: part_path.to_owned(),
+                });
+            }
+
+            let data = tensor.data();
+
+            if let Some(mmap) = mmap {
+                let offset = data_offset as usize;
+                let Some(tensor_data) = mmap.get(offset..offset + tensor.nbytes()) else {
+                    return Err(LoadError::TruncatedFile {
+                        tensor_name,
+                        path: part_path.to_owned(),
+                        offset: data_offset,
+                        expected: tensor.nbytes(),
+                    });
+                };
+                if let Some(hasher) = &mut hasher {
+                    hasher.update(tensor_data);
+                }
+                // SAFETY: the slice covers exactly the tensor's data, and the
+                // mapping is kept alive by the model. ggml never writes to the
+                // model's tensors.
+                unsafe { tensor.set_data(tensor_data.as_ptr() as *mut c_void) };
+                part_reader
+                    .seek(SeekFrom::Current(tensor.nbytes() as i64))
+                    .map_err(io_context(
+                        || format!("the data of the tensor `{tensor_name}`"),
+                        part_path,
+                        data_offset,
+                    ))?;
+            } else if part_id == 0 {
+                let mut tensor = tensor.alias();
+                let expected = tensor.nbytes();
+                let buf = match tensor.as_f32_slice_mut() {
+                    Some(values) => bytemuck::cast_slice_mut(values),
+                    // SAFETY: the slice covers exactly the tensor's data.
+                    None => unsafe { std::slice::from_raw_parts_mut(data as *mut u8, expected) },
+                };
+                read_tensor_data(
+                    part_reader,
+                    buf,
+                    &tensor_name,
+                    part_path,
+                    data_offset,
+                    expected,
+                )?;
+                if let Some(hasher) = &mut hasher {
+                    hasher.update(buf);
+                }
+            } else {
+                // Only the copy in the first part is loaded.
+                hasher = None;
+                part_reader
+                    .seek(SeekFrom::Current(tensor.nbytes() as i64))
+                    .map_err(io_context(
+                        || format!("the data of the tensor `{tensor_name}`"),
+                        part_path,
+                        data_offset,
+                    ))?;
+            }
+
+            total_size += tensor.nbytes();
+        } else {
+            if (nelements as usize * bpe) / typ.block_size() != tensor.nbytes() / n_parts {
+                return Err(LoadError::TensorWrongSize {
+                    tensor_name,
+                    path: part_path.to_owned(),
+                });
+            }
+
+            if split_type == 0 {
+                let np0 = ne[0];
+                let row_size = tensor.get_ne()[0] as usize / typ.block_size() * bpe;
+
+                assert_eq!(row_size, tensor.get_nb()[1]);
+
+                for i1 in 0..ne[1] {
+                    let offset_row = i1 as usize * row_size;
+                    let offset = offset_row + ((part_id * np0 as usize) / typ.block_size()) * bpe;
+                    check_tensor_bounds(
+                        tensor,
+                        offset,
+                        row_size / n_parts,
+                        &tensor_name,
+                        part_path,
+                    )?;
+                    // SAFETY: the range was checked to be within the tensor's data.
+                    unsafe {
+                        let ptr = tensor.data().add(offset);
+                        let slice =
+                            std::slice::from_raw_parts_mut(ptr as *mut u8, row_size / n_parts);
+                        read_tensor_data(
+                            part_reader,
+                            slice,
+                            &tensor_name,
+                            part_path,
+                            data_offset,
+                            tensor.nbytes() / n_parts,
+                        )?;
+                        if let Some(hasher) = &mut hasher {
+                            hasher.update(slice);
+                        }
+                    }
+                }
+            } else {
+                let np1 = ne[1];
+                let row_size = tensor.get_ne()[0] as usize / typ.block_size() * bpe;
+
+                for i1 in 0..ne[1] {
+                    let offset_row = (i1 as usize + part_id * np1 as usize) * row_size;
+                    check_tensor_bounds(tensor, offset_row, row_size, &tensor_name, part_path)?;
+                    // SAFETY: the range was checked to be within the tensor's data.
+                    unsafe {
+                        let ptr = tensor.data().add(offset_row);
+                        let slice = std::slice::from_raw_parts_mut(ptr as *mut u8, row_size);
+                        read_tensor_data(
+                            part_reader,
+                            slice,
+                            &tensor_name,
+                            part_path,
+                            data_offset,
+                            tensor.nbytes() / n_parts,
+                        )?;
+                        if let Some(hasher) = &mut hasher {
+                            hasher.update(slice);
+                        }
+                    }
+                }
+            }
 
-        let ctx_size = {
-            // Use 64-bit math to prevent overflow.
-            let n_embd = n_embd as u64;
-            let n_layer = n_layer as u64;
-            let n_vocab = n_vocab as u64;
-            let n_ff = n_ff as u64;
+            total_size += tensor.nbytes() / n_parts;
+        }
 
-            let mut ctx_size: u64 = 0;
+        if let (Some(checksums), Some(hasher)) = (checksums.as_deref_mut(), hasher) {
+            let hash = hasher.digest();
+            log::trace!("{tensor_name}: {hash:016x}");
+            checksums.push((tensor_name, hash));
+        }
 
-            ctx_size += mulf!(n_embd, n_vocab, ggml::type_sizef(wtype)); // tok_embeddings
+        n_tensors += 1;
+        on_tensor_loaded(n_tensors, total_size);
+    }
 
-            ctx_size += mulf!(n_embd, ggml::type_sizef(ggml::TYPE_F32)); // norm
+    Ok((total_size, n_tensors))
+}
 
-            ctx_size += mulf!(n_embd, n_vocab, ggml::type_sizef(wtype)); // output
+/// Aliases for the names of the tensors that are not part of a layer, as
+/// `(alias, canonical name)` pairs.
+const TENSOR_NAME_ALIASES: &[(&str, &str)] = &[
+    ("model.embed_tokens.weight", "tok_embeddings.weight"),
+    ("model.norm.weight", "norm.weight"),
+    ("lm_head.weight", "output.weight"),
+];
+
+/// Aliases for the names of the tensors of a layer, without the layer prefix,
+/// as `(alias, canonical name)` pairs.
+const LAYER_TENSOR_NAME_ALIASES: &[(&str, &str)] = &[
+    ("input_layernorm.weight", "attention_norm.weight"),
+    ("self_attn.q_proj.weight", "attention.wq.weight"),
+    ("self_attn.k_proj.weight", "attention.wk.weight"),
+    ("self_attn.v_proj.weight", "attention.wv.weight"),
+    ("self_attn.o_proj.weight", "attention.wo.weight"),
+    ("post_attention_layernorm.weight", "ffn_norm.weight"),
+    ("mlp.gate_proj.weight", "feed_forward.w1.weight"),
+    ("mlp.down_proj.weight", "feed_forward.w2.weight"),
+    ("mlp.up_proj.weight", "feed_forward.w3.weight"),
+];
+
+/// Maps a tensor name used by other converters (such as the Hugging Face
+/// `model.layers.0.self_attn.q_proj.weight`) to the name used by the loader
+/// (`layers.0.attention.wq.weight`). Returns `None` if `raw` is not a known
+/// alias.
+fn normalize_tensor_name(raw: &str) -> Option<String> {
+    if let Some((_, name)) = TENSOR_NAME_ALIASES.iter().find(|(alias, _)| *alias == raw) {
+        return Some(name.to_string());
+    }
 
-            ctx_size += mulf!(n_layer, n_embd, ggml::type_sizef(ggml::TYPE_F32)); // attention_norm
+    let (layer, suffix) = raw.strip_prefix("model.layers.")?.split_once('.')?;
+    let layer: usize = layer.parse().ok()?;
+    let (_, name) = LAYER_TENSOR_NAME_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == suffix)?;
+    Some(format!("layers.{layer}.{name}"))
+}
 
-            ctx_size += mulf!(n_layer, n_embd, n_embd, ggml::type_sizef(wtype)); // wq
-            ctx_size += mulf!(n_layer, n_embd, n_embd, ggml::type_sizef(wtype)); // wk
-            ctx_size += mulf!(n_layer, n_embd, n_embd, ggml::type_sizef(wtype)); // wv
-            ctx_size += mulf!(n_layer, n_embd, n_embd, ggml::type_sizef(wtype)); // wo
+/// Checks that writing `len` bytes at `offset` into the tensor's data stays in
+/// bounds. The offsets are derived from the file, so they can't be trusted.
+fn check_tensor_bounds(
+    tensor: &ggml::Tensor,
+    offset: usize,
+    len: usize,
+    tensor_name: &str,
+    part_path: &Path,
+) -> Result<(), LoadError> {
+    match offset.checked_add(len) {
+        Some(end) if end <= tensor.nbytes() => Ok(()),
+        _ => Err(LoadError::TensorWrongSize {
+            tensor_name: tensor_name.to_owned(),
+            path: part_path.to_owned(),
+        }),
+    }
+}
 
-            ctx_size += mulf!(n_layer, n_embd, ggml::type_sizef(ggml::TYPE_F32)); // ffn_norm
+/// Reads (part of) the data of the tensor `tensor_name`, which starts at
+/// `data_offset`, into `buf`. If the file ends first, the error names the
+/// tensor, where its data starts and the `expected` size of its data in this
+/// file.
+fn read_tensor_data(
+    reader: &mut impl Read,
+    buf: &mut [u8],
+    tensor_name: &str,
+    part_path: &Path,
+    data_offset: u64,
+    expected: usize,
+) -> Result<(), LoadError> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        std::io::ErrorKind::UnexpectedEof => LoadError::TruncatedFile {
+            tensor_name: tensor_name.to_owned(),
+            path: part_path.to_owned(),
+            offset: data_offset,
+            expected,
+        },
+        _ => io_context(
+            || format!("the data of the tensor `{tensor_name}`"),
+            part_path,
+            data_offset,
+        )(err),
+    })
+}
 
-            ctx_size += mulf!(n_layer, n_ff, n_embd, ggml::type_sizef(wtype)); // w1
-            ctx_size += mulf!(n_layer, n_ff, n_embd, ggml::type_sizef(wtype)); // w2
-            ctx_size += mulf!(n_layer, n_ff, n_embd, ggml::type_sizef(wtype)); // w3
+/// Opens the file at `path` for reading, with a specific error for the common
+/// reasons it can't be read: it doesn't exist, it is a directory (which
+/// opens, but can't be read), or it isn't readable.
+fn open_file(path: &Path) -> Result<File, LoadError> {
+    let path_buf = || path.to_owned();
+    let file = File::open(path).map_err(|source| match source.kind() {
+        std::io::ErrorKind::NotFound => LoadError::FileNotFound { path: path_buf() },
+        std::io::ErrorKind::PermissionDenied => LoadError::PermissionDenied { path: path_buf() },
+        _ => LoadError::OpenFileFailed {
+            source,
+            path: path_buf(),
+        },
+    })?;
+    if matches!(file.metadata(), Ok(metadata) if metadata.is_dir()) {
+        return Err(LoadError::IsADirectory { path: path_buf() });
+    }
+    Ok(file)
+}
 
-            ctx_size += (5 + 10 * n_layer) * 256; // object overhead
+/// Returns a function that adds what was being read, and where, to I/O
+/// errors, which don't say on their own. Other errors are returned as is.
+fn io_context<'a, E: Into<LoadError>>(
+    what: impl FnOnce() -> String + 'a,
+    path: &'a Path,
+    offset: u64,
+) -> impl FnOnce(E) -> LoadError + 'a {
+    move |err| match err.into() {
+        LoadError::IO(source) | LoadError::ReadExactFailed { source, .. } => {
+            LoadError::ReadFailed {
+                source,
+                what: what(),
+                path: path.to_owned(),
+                offset,
+            }
+        }
+        err => err,
+    }
+}
+
+/// Reads the metadata at the start of a model file: the file format, the
+/// hyperparameters and the vocabulary.
+fn read_header(
+    reader: &mut impl BufRead,
+    path: &Path,
+    n_ctx: i32,
+    load_progress_callback: &impl Fn(LoadProgress),
+) -> Result<(FileFormat, Hyperparameters, Vocabulary), LoadError> {
+    // Verify magic
+    let file_format = match read_u32(reader)? {
+        ggml::FILE_MAGIC => FileFormat::Ggmf,
+        ggml::FILE_MAGIC_GGJT => FileFormat::Ggjt,
+        ggml::FILE_MAGIC_UNVERSIONED => FileFormat::Ggml,
+        magic => {
+            return Err(LoadError::InvalidMagic {
+                path: path.to_owned(),
+                magic,
+            })
+        }
+    };
 
-            load_progress_callback(LoadProgress::ContextSize {
-                bytes: ctx_size.try_into()?,
+    // Load format version
+    let expected_version = match file_format {
+        FileFormat::Ggml => None,
+        FileFormat::Ggmf => Some(ggml::FORMAT_VERSION),
+        FileFormat::Ggjt => Some(ggml::FORMAT_VERSION_GGJT),
+    };
+    if let Some(expected_version) = expected_version {
+        let version = read_u32(reader)?;
+        if version != expected_version {
+            return Err(LoadError::InvalidFormatVersion {
+                format: file_format,
+                value: version,
             });
+        }
+    }
+
+    // =================
+    // Load hyper params
+    // =================
+
+    // NOTE: Field order matters! Data is laid out in the file exactly
+    // in this order.
+    let hparams = Hyperparameters {
+        n_vocab: read_i32(reader)?,
+        n_ctx,
+        n_embd: read_i32(reader)?,
+        n_mult: read_i32(reader)?,
+        n_head: read_i32(reader)?,
+        n_layer: read_i32(reader)?,
+        n_rot: read_i32(reader)?,
+        f16_: read_i32(reader)?,
+    };
+
+    // The sizes are used to allocate and divide, so a zero or negative one
+    // would only fail later in a confusing way, or not at all.
+    let sizes = [
+        ("n_vocab", hparams.n_vocab),
+        ("n_ctx", hparams.n_ctx),
+        ("n_embd", hparams.n_embd),
+        ("n_mult", hparams.n_mult),
+        ("n_head", hparams.n_head),
+        ("n_layer", hparams.n_layer),
+    ];
+    if let Some(&(name, value)) = sizes.iter().find(|(_, value)| *value <= 0) {
+        return Err(LoadError::InvalidHyperparameters {
+            name,
+            value,
+            path: path.to_owned(),
+        });
+    }
+
+    load_progress_callback(LoadProgress::HyperparametersLoaded(&hparams));
+
+    // ===============
+    // Load vocabulary
+    // ===============
+    let vocab = {
+        let mut id_to_token = vec![];
+        let mut id_to_token_score = vec![];
+        let mut token_to_id = HashMap::new();
+        let mut invalid_token_bytes = HashMap::new();
+        let mut max_token_length = 0;
+
+        for i in 0..hparams.n_vocab {
+            let len = read_i32(reader)?;
+            match String::from_utf8(read_bytes_with_len(reader, len as usize)?) {
+                Ok(word) => {
+                    max_token_length = max_token_length.max(word.len());
+                    id_to_token.push(word.clone());
+                    token_to_id.insert(word, i);
+                }
+                Err(err) => {
+                    load_progress_callback(LoadProgress::BadToken {
+                        index: i.try_into()?,
+                    });
+                    id_to_token.push("�".to_string());
+                    invalid_token_bytes.insert(i, err.into_bytes());
+                }
+            }
+
+            // Token score, currently unused
+            if file_format.has_token_scores() {
+                if let Ok(score) = read_f32(reader) {
+                    id_to_token_score.push(score);
+                }
+            } else {
+                // Legacy model, set empty score
+                id_to_token_score.push(0.);
+            }
+        }
+
+        // The converted LLaMA models have no text for the beginning and end
+        // of sentence tokens, but other vocabularies name them.
+        let special_token = |text: &str, default| token_to_id.get(text).copied().unwrap_or(default);
+        let bos_token = special_token("<s>", BOS_TOKEN_ID);
+        let eos_token = special_token("</s>", EOD_TOKEN_ID);
+        let unk_token = special_token("<unk>", UNK_TOKEN_ID);
+
+        Vocabulary {
+            id_to_token,
+            id_to_token_score,
+            token_to_id,
+            invalid_token_bytes,
+            max_token_length,
+            special_tokens: HashMap::new(),
+            add_bos: true,
+            bos_token,
+            eos_token,
+            unk_token,
+        }
+    };
+    if vocab.len() as i64 != i64::from(hparams.n_vocab) {
+        return Err(LoadError::VocabMismatch {
+            n_vocab: hparams.n_vocab,
+            actual: vocab.len(),
+            path: path.to_owned(),
+        });
+    }
+
+    Ok((file_format, hparams, vocab))
+}
+
+/// Checks that a tensor of type `typ` with `nelements` elements is made of
+/// whole blocks, as the size of its data is computed per block.
+fn check_block_size(
+    tensor_name: &str,
+    nelements: i64,
+    typ: GgmlType,
+    path: &Path,
+) -> Result<(), LoadError> {
+    let block_size = typ.block_size();
+    if nelements % block_size as i64 != 0 {
+        return Err(LoadError::BlockSizeMismatch {
+            tensor_name: tensor_name.to_owned(),
+            path: path.to_owned(),
+            nelements,
+            block_size,
+        });
+    }
+    Ok(())
+}
+
+/// Returns the log-probability of `token` in the softmax of `logits`,
+/// computed in `f64` so that long sums stay precise.
+fn log_softmax(logits: &[f32], token: TokenId) -> f64 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max) as f64;
+    let sum: f64 = logits.iter().map(|&l| (l as f64 - max).exp()).sum();
+    logits[token as usize] as f64 - max - sum.ln()
+}
+
+/// Returns the byte offset of the earliest stop sequence found in `text`,
+/// and that stop sequence.
+fn find_stop_sequence<'s>(text: &str, stop_sequences: &'s [String]) -> Option<(usize, &'s str)> {
+    stop_sequences
+        .iter()
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| Some((text.find(s.as_str())?, s.as_str())))
+        .min_by_key(|&(offset, _)| offset)
+}
+
+/// Returns the length of the longest suffix of `text` that is the beginning
+/// of a stop sequence, i.e. that could still turn into a stop sequence once
+/// more text is generated.
+fn partial_stop_sequence_len(text: &str, stop_sequences: &[String]) -> usize {
+    stop_sequences
+        .iter()
+        .filter_map(|s| {
+            (1..s.len())
+                .rev()
+                .find(|&len| s.is_char_boundary(len) && text.ends_with(&s[..len]))
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Returns the paths of all the parts of the model whose first part is at
+/// `main_path`, in order.
+fn find_part_paths(main_path: &Path) -> Result<Vec<PathBuf>, LoadError> {
+    // Parts are siblings of the main file with a numeric suffix appended to
+    // the file name, e.g. `ggml-model-f16.bin`, `ggml-model-f16.bin.1`, ...
+    let main_filename = main_path
+        .file_name()
+        .ok_or_else(|| LoadError::NoParentPath {
+            path: main_path.to_owned(),
+        })?
+        .to_owned();
+
+    let mut paths = vec![main_path.to_owned()];
+    for i in 1.. {
+        let mut part_filename = main_filename.clone();
+        part_filename.push(format!(".{i}"));
+        let part_path = main_path.with_file_name(part_filename);
+        if !part_path.is_file() {
+            break;
+        }
+        paths.push(part_path);
+    }
+    Ok(paths)
+}
+
+/// Allows the model tensors to be shared with the threads that load the model
+/// parts in parallel.
+struct SharedTensors<'a>(&'a HashMap<String, ggml::Tensor>);
+// SAFETY: The tensor handles themselves are only read from, and the `Weak`
+// context reference uses atomic reference counting. The context outlives the
+// threads, as they are scoped to the loading call.
+unsafe impl Send for SharedTensors<'_> {}
+// SAFETY: The tensor data is written to by every thread, but never in
+// overlapping regions. Tensors that are not split are only written by the
+// first part. Split tensors are written by each part into the range of
+// columns (split_type = 0) or rows (split_type = 1) given by its `part_id`,
+// which are disjoint because [check_even_split] checked that the split
+// dimension is a multiple of the number of parts, in whole blocks, before the
+// threads were started.
+unsafe impl Sync for SharedTensors<'_> {}
+impl<'a> SharedTensors<'a> {
+    // Accessed through a method so that closures capture the whole wrapper,
+    // and not just the inner reference.
+    fn get(&self) -> &'a HashMap<String, ggml::Tensor> {
+        self.0
+    }
+}
 
-            ctx_size
+/// Checks that the split dimension of each of `tensors` is a multiple of
+/// `n_parts`, in whole blocks, so that every part writes a range of the same
+/// size into its own slice of the data. Otherwise the last blocks written by
+/// a part would overlap the first ones of the next.
+fn check_even_split(
+    tensors: &HashMap<String, ggml::Tensor>,
+    n_parts: usize,
+    path: &Path,
+) -> Result<(), LoadError> {
+    for (tensor_name, tensor) in tensors {
+        if tensor.n_dims() == 1 {
+            continue;
+        }
+        let split_type = tensor_split_type(tensor_name);
+        let granularity = match split_type {
+            0 => GgmlType::from_raw(tensor.get_type())
+                .expect("tensors are only created with these types")
+                .block_size(),
+            _ => 1,
         };
+        if tensor.get_ne()[split_type] as usize % (n_parts * granularity) != 0 {
+            return Err(LoadError::UnevenSplit {
+                tensor_name: tensor_name.clone(),
+                n_parts,
+                path: path.to_owned(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Returns the type of the big tensors for the given value of `f16_` in the
+/// hyperparameters.
+fn weight_type(f16_: i32) -> Result<GgmlType, LoadError> {
+    GgmlType::try_from(f16_).map_err(|value| LoadError::HyperparametersF16Invalid { value })
+}
+
+/// Estimates the size in bytes of the ggml context needed to load a model with
+/// the given hyperparameters, where the big tensors are of type `wtype` and
+/// the feed-forward layers have `n_ff` units, from `n_parts` files.
+///
+/// The tensors of every part are loaded into one tensor of the context, so
+/// the size doesn't depend on `n_parts`, which must be at least 1. This is the
+/// size when the tensor data is read into the context: a memory-mapped model
+/// only needs ggml's bookkeeping.
+pub fn estimate_ctx_size(
+    hparams: &Hyperparameters,
+    wtype: GgmlType,
+    n_ff: i32,
+    n_parts: i32,
+) -> u64 {
+    assert!(n_parts >= 1, "a model has at least one part");
+    model_ctx_size(hparams, wtype, n_ff, true)
+}
+
+/// Returns the size of the context holding the tensors of a model, with or
+/// without their data.
+fn model_ctx_size(hparams: &Hyperparameters, wtype: GgmlType, n_ff: i32, tensor_data: bool) -> u64 {
+    // Use 64-bit math to prevent overflow.
+    let n_embd = hparams.n_embd as u64;
+    let n_layer = hparams.n_layer as u64;
+    let n_vocab = hparams.n_vocab as u64;
+    let n_ff = n_ff as u64;
+
+    // Without the tensor data, only ggml's bookkeeping is stored.
+    let tensor_ctx_size = |typ, n_elements| tensor_ctx_size(typ, n_elements * tensor_data as u64);
+
+    let mut ctx_size: u64 = 0;
+
+    ctx_size += tensor_ctx_size(wtype, n_embd * n_vocab); // tok_embeddings
 
-        // Initialize the context
-        let context = ggml::Context::init(ctx_size as usize);
+    ctx_size += tensor_ctx_size(GgmlType::F32, n_embd); // norm
 
-        let model = {
+    ctx_size += tensor_ctx_size(wtype, n_embd * n_vocab); // output
+
+    ctx_size += n_layer * tensor_ctx_size(GgmlType::F32, n_embd); // attention_norm
+
+    ctx_size += n_layer * tensor_ctx_size(wtype, n_embd * n_embd); // wq
+    ctx_size += n_layer * tensor_ctx_size(wtype, n_embd * n_embd); // wk
+    ctx_size += n_layer * tensor_ctx_size(wtype, n_embd * n_embd); // wv
+    ctx_size += n_layer * tensor_ctx_size(wtype, n_embd * n_embd); // wo
+
+    ctx_size += n_layer * tensor_ctx_size(GgmlType::F32, n_embd); // ffn_norm
+
+    ctx_size += n_layer * tensor_ctx_size(wtype, n_ff * n_embd); // w1
+    ctx_size += n_layer * tensor_ctx_size(wtype, n_ff * n_embd); // w2
+    ctx_size += n_layer * tensor_ctx_size(wtype, n_ff * n_embd); // w3
+
+    ctx_size
+}
+
+/// Returns the number of bytes used in a ggml context by a tensor with
+/// `n_elements` elements of type `typ`, including ggml's bookkeeping.
+fn tensor_ctx_size(typ: GgmlType, n_elements: u64) -> u64 {
+    let align = ggml::MEM_ALIGN as u64;
+    let data_size = mulf!(n_elements, typ.sizef());
+    let data_size = (data_size + align - 1) / align * align;
+
+    data_size + ggml::tensor_overhead() as u64
+}
+
+fn estimate_session_ctx_size(
+    hparams: &Hyperparameters,
+    params: &InferenceSessionParameters,
+) -> u64 {
+    let n_ctx = hparams.n_ctx as u64;
+    let n_embd = hparams.n_embd as u64;
+    let n_layer = hparams.n_layer as u64;
+
+    let mut ctx_size: u64 = 0;
+
+    ctx_size += tensor_ctx_size(params.memory_k_type.into(), n_ctx * n_layer * n_embd); // memory_k
+    ctx_size += tensor_ctx_size(params.memory_v_type.into(), n_ctx * n_layer * n_embd); // memory_v
+
+    ctx_size
+}
+
+/// Returns an upper bound of the size of the temporary ggml context used to
+/// evaluate `n` tokens split between `n_seq` sequences with `params`, in
+/// sessions with a context window of at most `hparams.n_ctx` tokens.
+///
+/// This mirrors the tensors created by [Llama::build_graph] and the work
+/// buffer allocated by ggml to compute it. Each tensor takes
+/// [ggml::tensor_overhead] bytes and its data rounded up to
+/// [ggml::MEM_ALIGN], so counting [ggml::MEM_ALIGN] more bytes per tensor
+/// covers the rounding, whatever the sizes of the sequences. The attention of
+/// each sequence covers at most `n_ctx` tokens, which bounds the size of the
+/// attention scores.
+fn estimate_eval_ctx_size(
+    hparams: &Hyperparameters,
+    n: usize,
+    n_seq: usize,
+    params: &InferenceParameters,
+) -> usize {
+    let n = n as u64;
+    let n_seq = n_seq.max(1) as u64;
+    let n_ctx = hparams.n_ctx as u64;
+    let n_embd = hparams.n_embd as u64;
+    let n_head = hparams.n_head as u64;
+    let n_layer = hparams.n_layer as u64;
+    let n_vocab = hparams.n_vocab as u64;
+    let n_ff = hparams.n_ff() as u64;
+    let n_threads = params.n_threads.max(1) as u64;
+    let f32_size = std::mem::size_of::<f32>() as u64;
+
+    // The number of f32 elements of each layer, and of its tensors: the
+    // norms, projections, feed-forward and residuals of all the tokens,
+    // then, for each sequence, its queries, attention scores and output,
+    // the parameters of its ropes, scale and mask, and the copy of its
+    // values with increased determinism.
+    let mut layer_elements = 14 * n_embd * n + 4 * n_ff * n;
+    let mut layer_tensors = 17;
+    layer_elements += 3 * n_embd * n + n_head * n_ctx * n + 12 * n_seq;
+    layer_tensors += 30 * n_seq;
+    if params.increased_determinism {
+        layer_elements += n_embd * n_ctx * n_seq;
+        layer_tensors += 2 * n_seq;
+    }
+    if n_seq > 1 {
+        layer_tensors += 2 * n_seq + 1;
+    }
+
+    // The token ids, the embeddings, the output norm and the logits.
+    let elements = n_layer * layer_elements + n + 4 * n_embd * n + n_vocab * n;
+    let tensors = n_layer * layer_tensors + 6;
+
+    // The work buffer of the matrix multiplications, where the other operand
+    // is converted to f16 or quantized, the output of the multiplications by
+    // the transposed values is accumulated by each thread, and the weights
+    // are converted to f32 for BLAS.
+    let mut work_size = (2 * n * n_ff.max(n_embd)).max(f32_size * n_embd * n * n_threads);
+    if ggml::cpu_has_blas() {
+        work_size = work_size.max(f32_size * n_vocab.max(n_ff).max(n_ctx) * n_embd);
+    }
+    let work_size = work_size + 128 * (n_threads - 1);
+
+    let tensor_size = (ggml::tensor_overhead() + ggml::MEM_ALIGN) as u64;
+    (f32_size * elements + work_size + (tensors + 1) * tensor_size) as usize
+}
+
+impl Llama {
+    pub fn load(
+        path: impl AsRef<Path>,
+        n_ctx: i32,
+        load_progress_callback: impl Fn(LoadProgress),
+    ) -> Result<(Llama, Vocabulary), LoadError> {
+        Self::load_with_parameters(
+            path,
+            n_ctx,
+            LoadParameters::default(),
+            load_progress_callback,
+        )
+    }
+
+    /// Loads the model like [Llama::load], using the given strategy to load
+    /// the tensor data.
+    pub fn load_with_strategy(
+        path: impl AsRef<Path>,
+        n_ctx: i32,
+        strategy: LoadStrategy,
+        load_progress_callback: impl Fn(LoadProgress),
+    ) -> Result<(Llama, Vocabulary), LoadError> {
+        Self::load_with_parameters(
+            path,
+            n_ctx,
+            LoadParameters {
+                strategy,
+                ..Default::default()
+            },
+            load_progress_callback,
+        )
+    }
+
+    /// Loads the model like [Llama::load], but with additional parameters
+    /// controlling how the model is loaded.
+    pub fn load_with_parameters(
+        path: impl AsRef<Path>,
+        n_ctx: i32,
+        params: LoadParameters,
+        load_progress_callback: impl Fn(LoadProgress),
+    ) -> Result<(Llama, Vocabulary), LoadError> {
+        Self::load_impl(path, n_ctx, params, None, load_progress_callback)
+    }
+
+    /// Loads the model like [Llama::load_with_parameters], allocating its
+    /// tensors in `buffer` instead of memory allocated by ggml, e.g. to use
+    /// a preallocated huge-page region.
+    ///
+    /// The buffer must hold the number of bytes reported by
+    /// [LoadProgress::ContextSize], plus up to 15 bytes to align its start to
+    /// 16 bytes. The model owns it, and drops it with its tensors. It is
+    /// unused when the model is memory-mapped, as the tensor data is then in
+    /// the mapping.
+    pub fn load_with_buffer(
+        path: impl AsRef<Path>,
+        n_ctx: i32,
+        params: LoadParameters,
+        buffer: impl AsMut<[u8]> + Send + 'static,
+        load_progress_callback: impl Fn(LoadProgress),
+    ) -> Result<(Llama, Vocabulary), LoadError> {
+        Self::load_impl(
+            path,
+            n_ctx,
+            params,
+            Some(Box::new(buffer)),
+            load_progress_callback,
+        )
+    }
+
+    fn load_impl(
+        path: impl AsRef<Path>,
+        n_ctx: i32,
+        params: LoadParameters,
+        buffer: Option<Box<dyn AsMut<[u8]> + Send>>,
+        load_progress_callback: impl Fn(LoadProgress),
+    ) -> Result<(Llama, Vocabulary), LoadError> {
+        let main_path = path.as_ref();
+        let backend = backend::for_gpu_layers(params.n_gpu_layers)?;
+
+        let mut reader = BufReader::new(open_file(main_path)?);
+
+        let (file_format, hparams, mut vocab) =
+            read_header(&mut reader, main_path, n_ctx, &load_progress_callback)
+                .map_err(io_context(|| "the header".to_owned(), main_path, 0))?;
+        let n_ff = hparams.n_ff();
+        let architecture = params
+            .architecture
+            .unwrap_or_else(|| ModelArchitecture::detect(&vocab));
+        vocab.add_bos = architecture.add_bos();
+
+        // for the big tensors, we have the option to store the data in 16-bit
+        // floats or quantized in order to save memory and also to speed up the
+        // computation
+        let wtype = weight_type(hparams.f16_)?;
+
+        let n_embd = hparams.n_embd;
+        let n_layer = hparams.n_layer;
+        let n_vocab = hparams.n_vocab;
+
+        let paths = find_part_paths(main_path)?;
+        let n_parts = paths.len();
+
+        // Memory-map the file if we can use the tensor data in place.
+        let mmap = match params.strategy {
+            LoadStrategy::Mmap if file_format == FileFormat::Ggjt && n_parts == 1 => {
+                // SAFETY: the file must not be modified while the model is
+                // loaded, as for any memory-mapped file.
+                Some(unsafe { Mmap::map(reader.get_ref())? })
+            }
+            _ => None,
+        };
+        log::debug!(
+            "Loading {n_parts} part(s) of {file_format:?} model, memory-mapped: {}",
+            mmap.is_some()
+        );
+
+        let ctx_size = model_ctx_size(&hparams, wtype, n_ff, mmap.is_none());
+        load_progress_callback(LoadProgress::ContextSize {
+            bytes: ctx_size.try_into()?,
+        });
+
+        // Initialize the context. When the file is memory-mapped, the tensor
+        // data is not stored in the context.
+        let context = match buffer {
+            _ if mmap.is_some() => ggml::Context::init_no_alloc(ctx_size as usize),
+            Some(buffer) => {
+                let context = ggml::Context::init_in(buffer);
+                if (context.mem_size() as u64) < ctx_size {
+                    return Err(LoadError::ContextBufferTooSmall {
+                        size: context.mem_size(),
+                        required: ctx_size as usize,
+                    });
+                }
+                context
+            }
+            None => ggml::Context::init(ctx_size as usize),
+        };
+
+        let mut model = {
             let mut tensors = HashMap::new();
 
-            let tok_embeddings = context.new_tensor_2d(wtype, n_embd, n_vocab);
+            let tok_embeddings = context.new_tensor_2d(wtype.raw(), n_embd, n_vocab);
             let norm = context.new_tensor_1d(ggml::TYPE_F32, n_embd);
-            let output = context.new_tensor_2d(wtype, n_embd, n_vocab);
+            let output = context.new_tensor_2d(wtype.raw(), n_embd, n_vocab);
 
-            tensors.insert("tok_embeddings.weight".to_owned(), tok_embeddings.share());
-            tensors.insert("norm.weight".to_owned(), norm.share());
-            tensors.insert("output.weight".to_owned(), output.share());
+            tensors.insert("tok_embeddings.weight".to_owned(), tok_embeddings.alias());
+            tensors.insert("norm.weight".to_owned(), norm.alias());
+            tensors.insert("output.weight".to_owned(), output.alias());
 
             let mut layers = Vec::new();
             for i in 0..n_layer {
                 let layer = Layer {
                     attention_norm: context.new_tensor_1d(ggml::TYPE_F32, n_embd),
-                    wq: context.new_tensor_2d(wtype, n_embd, n_embd),
-                    wk: context.new_tensor_2d(wtype, n_embd, n_embd),
-                    wv: context.new_tensor_2d(wtype, n_embd, n_embd),
-                    wo: context.new_tensor_2d(wtype, n_embd, n_embd),
+                    wq: context.new_tensor_2d(wtype.raw(), n_embd, n_embd),
+                    wk: context.new_tensor_2d(wtype.raw(), n_embd, n_embd),
+                    wv: context.new_tensor_2d(wtype.raw(), n_embd, n_embd),
+                    wo: context.new_tensor_2d(wtype.raw(), n_embd, n_embd),
                     ffn_norm: context.new_tensor_1d(ggml::TYPE_F32, n_embd),
-                    w1: context.new_tensor_2d(wtype, n_embd, n_ff),
-                    w2: context.new_tensor_2d(wtype, n_ff, n_embd),
-                    w3: context.new_tensor_2d(wtype, n_embd, n_ff),
+                    w1: context.new_tensor_2d(wtype.raw(), n_embd, n_ff),
+                    w2: context.new_tensor_2d(wtype.raw(), n_ff, n_embd),
+                    w3: context.new_tensor_2d(wtype.raw(), n_embd, n_ff),
                 };
 
                 tensors.insert(
                     format!("layers.{i}.attention_norm.weight"),
-                    layer.attention_norm.share(),
+                    layer.attention_norm.alias(),
                 );
 
-                tensors.insert(format!("layers.{i}.attention.wq.weight"), layer.wq.share());
-                tensors.insert(format!("layers.{i}.attention.wk.weight"), layer.wk.share());
-                tensors.insert(format!("layers.{i}.attention.wv.weight"), layer.wv.share());
-                tensors.insert(format!("layers.{i}.attention.wo.weight"), layer.wo.share());
+                tensors.insert(format!("layers.{i}.attention.wq.weight"), layer.wq.alias());
+                tensors.insert(format!("layers.{i}.attention.wk.weight"), layer.wk.alias());
+                tensors.insert(format!("layers.{i}.attention.wv.weight"), layer.wv.alias());
+                tensors.insert(format!("layers.{i}.attention.wo.weight"), layer.wo.alias());
 
                 tensors.insert(
                     format!("layers.{i}.ffn_norm.weight"),
-                    layer.ffn_norm.share(),
+                    layer.ffn_norm.alias(),
                 );
 
                 tensors.insert(
                     format!("layers.{i}.feed_forward.w1.weight"),
-                    layer.w1.share(),
+                    layer.w1.alias(),
                 );
                 tensors.insert(
                     format!("layers.{i}.feed_forward.w2.weight"),
-                    layer.w2.share(),
+                    layer.w2.alias(),
                 );
                 tensors.insert(
                     format!("layers.{i}.feed_forward.w3.weight"),
-                    layer.w3.share(),
+                    layer.w3.alias(),
                 );
 
                 layers.push(layer);
             }
 
-            Model {
+            debug_assert!(
+                context.used_mem() <= context.mem_size(),
+                "the tensors use more memory than the estimated context size"
+            );
+
+            Llama {
                 hparams,
+                file_format,
+                architecture,
                 tok_embeddings,
                 norm,
                 output,
                 layers,
                 tensors,
+                mmap,
+                checksum: None,
+                rope_freq_base: params.rope_freq_base,
+                rope_freq_scale: params.rope_freq_scale,
+                bos_token: vocab.bos_token(),
+                eos_token: vocab.eos_token(),
+                backend,
                 _context: context,
             }
         };
 
+        if n_parts > 1 {
+            check_even_split(&model.tensors, n_parts, main_path)?;
+        }
+
         // Close the file, but keep its offset. That way we know how to skip the
         // metadata when loading the parts.
         let file_offset = reader.stream_position()?;
         drop(reader);
 
-        let paths = {
-            let main_filename = main_path.file_name().and_then(|p| p.to_str());
+        let tensor_count = model.tensors.len();
 
-            let mut paths: Vec<PathBuf> =
-                std::fs::read_dir(main_path.parent().ok_or_else(|| LoadError::NoParentPath {
-                    path: main_path.to_owned(),
-                })?)?
-                .filter_map(Result::ok)
-                .map(|de| de.path())
-                .filter(|p| {
-                    p.file_name()
-                        .and_then(|p| p.to_str())
-                        .zip(main_filename)
-                        .map(|(part_filename, main_filename)| {
-                            part_filename.starts_with(main_filename)
-                        })
-                        .unwrap_or(false)
-                })
-                .collect();
-            paths.sort();
-            paths
-        };
+        // The hashes of the tensors of each part, if they are computed.
+        let mut part_checksums = vec![Vec::new(); n_parts];
+        let mut part_checksums = params.checksum.then_some(&mut part_checksums[..]);
 
-        let n_parts = paths.len();
+        if params.parallel_load && n_parts > 1 {
+            for (i, part_path) in paths.iter().enumerate() {
+                load_progress_callback(LoadProgress::PartLoading {
+                    file: part_path,
+                    current_part: i + 1,
+                    total_parts: n_parts,
+                });
+            }
 
-        for (i, part_path) in paths.into_iter().enumerate() {
-            let part_id = i;
+            let results = std::thread::scope(|s| {
+                let (tx, rx) = std::sync::mpsc::channel();
+                let mut checksums = part_checksums.as_deref_mut().map(|c| c.iter_mut());
+                let handles: Vec<_> = paths
+                    .iter()
+                    .enumerate()
+                    .map(|(part_id, part_path)| {
+                        let tx = tx.clone();
+                        let tensors = SharedTensors(&model.tensors);
+                        let checksums = checksums.as_mut().and_then(|c| c.next());
+                        s.spawn(move || {
+                            load_part(
+                                tensors.get(),
+                                file_format,
+                                part_path,
+                                part_id,
+                                n_parts,
+                                file_offset,
+                                None,
+                                params.skip_unknown_tensors,
+                                checksums,
+                                |current_tensor, bytes_loaded| {
+                                    // The receiver outlives all the senders.
+                                    tx.send((part_id, current_tensor, bytes_loaded)).ok();
+                                },
+                            )
+                        })
+                    })
+                    .collect();
+                drop(tx);
+
+                // Progress is reported from this thread, so the callback does
+                // not need to be thread-safe.
+                for (part_id, current_tensor, bytes_loaded) in rx {
+                    load_progress_callback(LoadProgress::PartTensorLoaded {
+                        file: &paths[part_id],
+                        current_tensor,
+                        tensor_count,
+                        bytes_loaded,
+                    });
+                }
 
-            load_progress_callback(LoadProgress::PartLoading {
-                file: &part_path,
-                current_part: i + 1,
-                total_parts: n_parts,
+                handles
+                    .into_iter()
+                    .map(|h| h.join().expect("part loading thread panicked"))
+                    .collect::<Vec<_>>()
             });
 
-            let mut part_reader = BufReader::new(File::open(&part_path)?);
-
-            // Skip metadata
-            part_reader.seek(SeekFrom::Start(file_offset))?;
+            for (part_path, result) in paths.iter().zip(results) {
+                let (byte_size, n_tensors) = result?;
+                load_progress_callback(LoadProgress::PartLoaded {
+                    file: part_path,
+                    byte_size,
+                    tensor_count: n_tensors,
+                });
+            }
+        } else {
+            for (i, part_path) in paths.iter().enumerate() {
+                load_progress_callback(LoadProgress::PartLoading {
+                    file: part_path,
+                    current_part: i + 1,
+                    total_parts: n_parts,
+                });
 
-            let mut total_size = 0;
-            let mut n_tensors = 0;
+                let (byte_size, n_tensors) = load_part(
+                    &model.tensors,
+                    file_format,
+                    part_path,
+                    i,
+                    n_parts,
+                    file_offset,
+                    model.mmap.as_ref(),
+                    params.skip_unknown_tensors,
+                    part_checksums.as_deref_mut().map(|c| &mut c[i]),
+                    |current_tensor, bytes_loaded| {
+                        load_progress_callback(LoadProgress::PartTensorLoaded {
+                            file: part_path,
+                            current_tensor,
+                            tensor_count,
+                            bytes_loaded,
+                        })
+                    },
+                )?;
 
-            // Load weights
-            loop {
-                // NOTE: Implementation from #![feature(buf_read_has_data_left)]
-                let is_eof = part_reader.fill_buf().map(|b| b.is_empty())?;
+                load_progress_callback(LoadProgress::PartLoaded {
+                    file: part_path,
+                    byte_size,
+                    tensor_count: n_tensors,
+                });
+            }
+        }
 
-                if is_eof {
-                    break;
-                }
+        // The tensors of a memory-mapped model have no data until they are
+        // found in the file.
+        if model.mmap.is_some() {
+            if let Some(tensor_name) = model
+                .tensors
+                .iter()
+                .find_map(|(name, tensor)| tensor.data().is_null().then(|| name.clone()))
+            {
+                return Err(LoadError::MissingTensor {
+                    tensor_name,
+                    path: main_path.to_owned(),
+                });
+            }
+        }
 
-                let n_dims = read_i32(&mut part_reader)?;
-                let length = read_i32(&mut part_reader)?;
-                let ftype = read_i32(&mut part_reader)?;
+        if let Some(part_checksums) = part_checksums {
+            let mut hasher = Xxh3::new();
+            for (tensor_name, hash) in part_checksums.iter().flatten() {
+                hasher.update(tensor_name.as_bytes());
+                hasher.update(&hash.to_le_bytes());
+            }
+            let checksum = hasher.digest();
+            log::info!("Model checksum: {checksum:016x}");
+            model.checksum = Some(checksum);
+        }
 
-                let mut nelements = 1;
-                let mut ne = [1i32, 1i32];
-                for i in 0..n_dims {
-                    ne[i as usize] = read_i32(&mut part_reader)?;
-                    nelements *= ne[i as usize];
-                }
+        Ok((model, vocab))
+    }
 
-                let tensor_name = read_string(&mut part_reader, length as usize)?;
+    /// Reads only the metadata of the model at `path`, without allocating
+    /// memory for or reading any of the tensors.
+    pub fn load_header_only(path: impl AsRef<Path>, n_ctx: i32) -> Result<ModelHeader, LoadError> {
+        let main_path = path.as_ref();
 
-                let Some(tensor) = model.tensors.get(&tensor_name)
-                    else {
-                        return Err(LoadError::UnknownTensor { tensor_name, path: part_path });
-                    };
+        let mut reader = BufReader::new(open_file(main_path)?);
 
-                // split_type = 0: split by columns
-                // split_type = 1: split by rows
-                //
-                // split_type = 0:
-                // regex:
-                //   - tok_embeddings.*
-                //   - layers.*.attention.wo.weight
-                //   - layers.*.feed_forward.w2.weight
-
-                // split_type = 1:
-                // regex:
-                //   - output.*
-                //   - layers.*.attention.wq.weight
-                //   - layers.*.attention.wk.weight
-                //   - layers.*.attention.wv.weight
-                //   - layers.*.feed_forward.w1.weight
-                //   - layers.*.feed_forward.w3.weight
-                #[allow(clippy::if_same_then_else)]
-                let split_type = if tensor_name.contains("tok_embeddings") {
-                    0
-                } else if tensor_name.contains("layers") {
-                    if tensor_name.contains("attention.wo.weight") {
-                        0
-                    } else if tensor_name.contains("feed_forward.w2.weight") {
-                        0
-                    } else {
-                        1
-                    }
-                } else if tensor_name.contains("output") {
-                    1
-                } else {
-                    0
-                };
+        let (file_format, hparams, mut vocabulary) =
+            read_header(&mut reader, main_path, n_ctx, &|_| {})?;
+        vocabulary.add_bos = ModelArchitecture::detect(&vocabulary).add_bos();
+        let tensors_offset = reader.stream_position()?;
+        let part_paths = find_part_paths(main_path)?;
 
-                if n_dims == 1 {
-                    if tensor.nelements() != nelements {
-                        return Err(LoadError::TensorWrongSize {
-                            tensor_name,
-                            path: part_path,
-                        });
-                    }
-                } else if tensor.nelements() / i32::try_from(n_parts)? != nelements {
-                    return Err(LoadError::TensorWrongSize {
-                        tensor_name,
-                        path: part_path,
-                    });
-                }
+        Ok(ModelHeader {
+            file_format,
+            hparams,
+            vocabulary,
+            part_paths,
+            tensors_offset,
+        })
+    }
 
-                if n_dims == 1 {
-                    if tensor.get_ne()[0] != ne[0] || tensor.get_ne()[1] != ne[1] {
-                        return Err(LoadError::TensorWrongSize {
-                            tensor_name,
-                            path: part_path,
-                        });
-                    }
-                } else if split_type == 0 {
-                    if tensor.get_ne()[0] / i32::try_from(n_parts)? != ne[0]
-                        || tensor.get_ne()[1] != ne[1]
-                    {
-                        return Err(LoadError::TensorWrongSize {
-                            tensor_name,
-                            path: part_path,
-                        });
-                    }
-                } else if tensor.get_ne()[0] != ne[0]
-                    || tensor.get_ne()[1] / i32::try_from(n_parts)? != ne[1]
-                {
-                    return Err(LoadError::TensorWrongSize {
-                        tensor_name,
-                        path: part_path,
-                    });
-                }
+    /// Checks the dimensions and types of all the tensors of the model at
+    /// `path` against the ones implied by its hyperparameters, without
+    /// allocating memory for or reading the tensor data. This can detect
+    /// corrupted or truncated files without loading them.
+    ///
+    /// Errors are only returned if the header can't be read; problems with
+    /// the tensors are listed in the report.
+    pub fn verify(path: impl AsRef<Path>) -> Result<VerifyReport, LoadError> {
+        let main_path = path.as_ref();
 
-                let bpe = match ftype {
-                    0 => ggml::type_size(ggml::TYPE_F32),
-                    1 => ggml::type_size(ggml::TYPE_F16),
-                    2 => {
-                        assert_eq!(ne[0] % 64, 0);
-                        ggml::type_size(ggml::TYPE_Q4_0)
-                    }
-                    3 => {
-                        assert_eq!(ne[0] % 64, 0);
-                        ggml::type_size(ggml::TYPE_Q4_1)
-                    }
-                    _ => {
-                        return Err(LoadError::InvalidFtype {
-                            ftype,
-                            path: part_path,
-                        })
-                    }
-                };
+        let mut reader = BufReader::new(open_file(main_path)?);
 
-                if n_dims == 1 || n_parts == 1 {
-                    if (nelements as usize * bpe) / ggml::blck_size(tensor.get_type()) as usize
-                        != tensor.nbytes()
-                    {
-                        return Err(LoadError::TensorWrongSize {
-                            tensor_name,
-                            path: part_path,
-                        });
-                    }
+        // The context size doesn't matter, as no memory is allocated, but
+        // the header is rejected if it isn't positive.
+        let (file_format, hparams, _) = read_header(&mut reader, main_path, 1, &|_| {})?;
+        let file_offset = reader.stream_position()?;
+        drop(reader);
 
-                    let data = tensor.data();
+        let expected = expected_tensors(&hparams);
+        let part_paths = find_part_paths(main_path)?;
+        let n_parts = i32::try_from(part_paths.len())?;
+
+        let mut report = VerifyReport::default();
+        for part_path in &part_paths {
+            let found = verify_part(
+                &expected,
+                file_format,
+                part_path,
+                n_parts,
+                file_offset,
+                &mut report,
+            )?;
+
+            // Only report missing tensors if the whole file could be read.
+            if let Some(found) = found {
+                let mut missing: Vec<_> = expected
+                    .keys()
+                    .filter(|name| !found.contains(*name))
+                    .collect();
+                missing.sort();
+                report
+                    .mismatches
+                    .extend(missing.into_iter().map(|name| TensorMismatch::Missing {
+                        tensor_name: name.clone(),
+                        path: part_path.to_owned(),
+                    }));
+            }
+        }
 
-                    if part_id == 0 {
-                        // SAFETY: yolo, same as original code
-                        let slice = unsafe {
-                            std::slice::from_raw_parts_mut(data as *mut u8, tensor.nbytes())
-                        };
-                        part_reader.read_exact(slice)?;
-                    } else {
-                        part_reader.seek(SeekFrom::Current(tensor.nbytes() as i64))?;
-                    }
+        Ok(report)
+    }
 
-                    total_size += tensor.nbytes();
-                } else {
-                    if (nelements as usize * bpe) / ggml::blck_size(tensor.get_type()) as usize
-                        != tensor.nbytes() / n_parts
-                    {
-                        return Err(LoadError::TensorWrongSize {
-                            tensor_name,
-                            path: part_path,
-                        });
-                    }
+    /// Applies the LoRA adapter at `lora_path` to the model's weights. For
+    /// each weight `W` the adapter has low-rank matrices `A` and `B` for,
+    /// this computes `W += scale * alpha / r * B A`, where the rank `r` and
+    /// `alpha` are stored in the adapter.
+    ///
+    /// The adapter must be in the `ggla` format of llama.cpp's
+    /// `convert-lora-to-ggml.py`. Only `f32` and `f16` weights can be
+    /// modified, and the model must be loaded with [LoadStrategy::Read].
+    pub fn apply_lora(&mut self, lora_path: impl AsRef<Path>, scale: f32) -> Result<(), LoadError> {
+        let path = lora_path.as_ref();
+        if self.mmap.is_some() {
+            return Err(LoadError::LoraMemoryMapped);
+        }
 
-                    if split_type == 0 {
-                        let np0 = ne[0];
-                        let row_size = (tensor.get_ne()[0] / ggml::blck_size(tensor.get_type()))
-                            as usize
-                            * ggml::type_size(tensor.get_type());
-
-                        assert_eq!(row_size, tensor.get_nb()[1]);
-
-                        for i1 in 0..ne[1] {
-                            let offset_row = i1 as usize * row_size;
-                            let offset = offset_row
-                                + ((part_id * np0 as usize)
-                                    / ggml::blck_size(tensor.get_type()) as usize)
-                                    * ggml::type_size(tensor.get_type());
-                            // SAFETY: yolo, same as original code
-                            unsafe {
-                                let ptr = tensor.data().add(offset);
-                                let slice = std::slice::from_raw_parts_mut(
-                                    ptr as *mut u8,
-                                    row_size / n_parts,
-                                );
-                                part_reader.read_exact(slice)?;
-                            }
-                        }
-                    } else {
-                        let np1 = ne[1];
-                        let row_size = (tensor.get_ne()[0] / ggml::blck_size(tensor.get_type()))
-                            as usize
-                            * ggml::type_size(tensor.get_type());
-
-                        for i1 in 0..ne[1] {
-                            let offset_row = (i1 as usize + part_id * np1 as usize) * row_size;
-                            // SAFETY: yolo, same as original code
-                            unsafe {
-                                let ptr = tensor.data().add(offset_row);
-                                let slice =
-                                    std::slice::from_raw_parts_mut(ptr as *mut u8, row_size);
-                                part_reader.read_exact(slice)?;
-                            }
-                        }
-                    }
+        let mut reader = BufReader::new(open_file(path)?);
 
-                    total_size += tensor.nbytes() / n_parts;
-                }
+        let magic = read_u32(&mut reader)?;
+        if magic != ggml::FILE_MAGIC_GGLA {
+            return Err(LoadError::InvalidMagic {
+                path: path.to_owned(),
+                magic,
+            });
+        }
+        let version = read_u32(&mut reader)?;
+        if version != ggml::FORMAT_VERSION_GGLA {
+            return Err(LoadError::InvalidLoraVersion {
+                value: version,
+                path: path.to_owned(),
+            });
+        }
+        let rank = read_i32(&mut reader)?;
+        let alpha = read_i32(&mut reader)?;
+        if rank <= 0 {
+            return Err(LoadError::InvalidLoraRank {
+                rank,
+                path: path.to_owned(),
+            });
+        }
+        let scaling = scale * alpha as f32 / rank as f32;
+
+        // The A and B matrices of each weight, by the name of the weight.
+        let mut adapters: HashMap<String, [Option<LoraTensor>; 2]> = HashMap::new();
+        while !reader.fill_buf()?.is_empty() {
+            let (tensor_name, tensor) = read_lora_tensor(&mut reader, path)?;
+            let (weight_name, index) = if let Some(name) = tensor_name.strip_suffix(".loraA") {
+                (name, 0)
+            } else if let Some(name) = tensor_name.strip_suffix(".loraB") {
+                (name, 1)
+            } else {
+                return Err(LoadError::UnknownTensor {
+                    tensor_name,
+                    path: path.to_owned(),
+                });
+            };
+            adapters.entry(weight_name.to_owned()).or_default()[index] = Some(tensor);
+        }
 
-                n_tensors += 1;
-                load_progress_callback(LoadProgress::PartTensorLoaded {
-                    file: &part_path,
-                    current_tensor: n_tensors.try_into()?,
-                    tensor_count: model.tensors.len(),
+        // Every adapter is checked before any weight is modified, so that an
+        // invalid file leaves the model unchanged.
+        let mut validated = Vec::with_capacity(adapters.len());
+        for (weight_name, [a, b]) in adapters {
+            let Some(weight) = self.tensors.get(&weight_name) else {
+                return Err(LoadError::UnknownTensor {
+                    tensor_name: weight_name,
+                    path: path.to_owned(),
+                });
+            };
+            let (Some(a), Some(b)) = (a, b) else {
+                return Err(LoadError::MissingTensor {
+                    tensor_name: weight_name,
+                    path: path.to_owned(),
+                });
+            };
+            if ![ggml::TYPE_F32, ggml::TYPE_F16].contains(&weight.get_type()) {
+                return Err(LoadError::LoraQuantizedTensor {
+                    tensor_name: weight_name,
+                });
+            }
+            // A is stored transposed, so that both matrices have `rank`
+            // elements per row.
+            let ne = weight.get_ne();
+            if a.ne != [rank, ne[0]] || b.ne != [rank, ne[1]] {
+                return Err(LoadError::TensorWrongSize {
+                    tensor_name: weight_name,
+                    path: path.to_owned(),
                 });
             }
 
-            load_progress_callback(LoadProgress::PartLoaded {
-                file: &part_path,
-                byte_size: total_size,
-                tensor_count: n_tensors.try_into()?,
-            });
+            validated.push((weight_name, weight, a, b));
         }
 
-        Ok((model, vocab))
+        let n_threads = std::thread::available_parallelism().map_or(1, |n| n.get()) as i32;
+        for (weight_name, weight, a, b) in validated {
+            log::trace!("Applying LoRA adapter to {weight_name}");
+            apply_lora_tensor(self.backend.as_ref(), weight, &a, &b, scaling, n_threads);
+        }
+
+        Ok(())
+    }
+
+    /// Returns a copy of the data of the tensor called `name` (such as
+    /// `layers.0.attention.wq.weight`), dequantized to `f32`, or `None` if
+    /// the model has no such tensor.
+    pub fn tensor_to_f32(&self, name: &str) -> Option<Vec<f32>> {
+        self.tensors.get(name)?.to_f32_vec()
+    }
+
+    /// Loads the tensors stored in `reader`, from its current position to its
+    /// end, into the model's tensors, in the model's file format. The tensors
+    /// named in `skip` are seeked past and keep their current data, so that
+    /// only the missing tensors of an interrupted load, or only some layers,
+    /// can be read.
+    ///
+    /// The model must be loaded with [LoadStrategy::Read], and the tensors
+    /// must be stored whole, as in a single-part model.
+    pub fn load_tensors_into(
+        &mut self,
+        reader: impl Read + Seek,
+        skip: &HashSet<String>,
+    ) -> Result<(), LoadError> {
+        if self.mmap.is_some() {
+            return Err(LoadError::TensorsMemoryMapped);
+        }
+
+        load_tensors(
+            &mut BufReader::new(reader),
+            &self.tensors,
+            self.file_format,
+            Path::new("<reader>"),
+            0,
+            1,
+            None,
+            skip,
+            false,
+            None,
+            |_, _| {},
+        )?;
+        Ok(())
+    }
+
+    /// Returns the hash of the model's tensor data, if it was loaded with
+    /// [LoadParameters::checksum]. Loading the same file always gives the
+    /// same hash, whatever the [LoadStrategy].
+    pub fn checksum(&self) -> Option<u64> {
+        self.checksum
+    }
+
+    /// Describes the model's tensors, sorted by name.
+    pub fn tensor_info(&self) -> Vec<TensorInfo> {
+        let mut info: Vec<_> = self
+            .tensors
+            .iter()
+            .map(|(name, tensor)| TensorInfo {
+                name: name.clone(),
+                shape: tensor.shape()[..tensor.n_dims()].to_vec(),
+                dtype: GgmlType::from_raw(tensor.get_type())
+                    .expect("tensors are only created with these types"),
+                nbytes: tensor.nbytes(),
+            })
+            .collect();
+        info.sort_by(|a, b| a.name.cmp(&b.name));
+        info
+    }
+
+    /// Returns the container format of the file this model was loaded from.
+    pub fn file_format(&self) -> FileFormat {
+        self.file_format
+    }
+
+    /// Returns the architecture of the model, as detected from its vocabulary
+    /// or set in the [LoadParameters].
+    pub fn architecture(&self) -> ModelArchitecture {
+        self.architecture
     }
 
     /// Starts a new `InferenceSession` for this model.
     pub fn start_session(&self, params: InferenceSessionParameters) -> InferenceSession {
-        let Hyperparameters {
-            n_ctx,
-            n_embd,
-            n_layer,
-            n_vocab,
-            ..
-        } = self.hparams;
+        InferenceSession {
+            eos_token: self.eos_token,
+            ..InferenceSession::new(&self.hparams, params)
+        }
+    }
 
-        let ctx_size = {
-            let mut ctx_size = 0;
-            ctx_size += mulf!(
-                n_ctx,
-                n_layer,
-                n_embd,
-                ggml::type_sizef(params.memory_k_type.into())
-            ); // memory_k
-            ctx_size += mulf!(
-                n_ctx,
-                n_layer,
-                n_embd,
-                ggml::type_sizef(params.memory_v_type.into())
-            ); // memory_v
-            ctx_size += (5 + 10 * n_layer as u64) * 256; // object overhead
-            ctx_size
-        };
+    /// Starts a [BatchSession] of `n_seq` sequences, which all use the given
+    /// parameters.
+    pub fn start_batch_session(
+        &self,
+        params: InferenceSessionParameters,
+        n_seq: usize,
+    ) -> BatchSession {
+        BatchSession {
+            sessions: (0..n_seq).map(|_| self.start_session(params)).collect(),
+            finished: vec![false; n_seq],
+        }
+    }
 
-        let session_ctx = ggml::Context::init(ctx_size as usize);
+    /// Changes the size of the context window of `session` to `n_ctx`
+    /// tokens, reallocating its memory and keeping the tokens it was fed.
+    /// This lets a session outgrow the context window the model was loaded
+    /// with, without reloading the model. The model and its other sessions
+    /// are unchanged.
+    ///
+    /// The memory of a session takes `2 * n_layer * n_ctx * n_embd` elements
+    /// of [InferenceSessionParameters::memory_k_type], e.g. 1 GiB for a 7B
+    /// model with a context window of 2048 tokens at f16, and both the old and
+    /// the new memory are allocated while they are copied. The temporary
+    /// memory used for each evaluation also grows with the context window.
+    ///
+    /// Returns [InferenceError::ContextTooSmall], and leaves the session
+    /// unchanged, if `n_ctx` can't hold the tokens the session was fed, or
+    /// those of its classifier-free guidance session.
+    pub fn resize_context(
+        &self,
+        session: &mut InferenceSession,
+        n_ctx: usize,
+    ) -> Result<(), InferenceError> {
+        let n_past = session
+            .n_past
+            .max(session.guidance.as_ref().map_or(0, |g| g.n_past));
+        if n_ctx == 0 || n_past > n_ctx {
+            return Err(InferenceError::ContextTooSmall { n_ctx, n_past });
+        }
 
-        // Initialize key + value memory tensors
-        let n_mem = n_layer * n_ctx;
-        let n_elements = n_embd * n_mem;
-        let memory_k = session_ctx.new_tensor_1d(params.memory_k_type.into(), n_elements);
-        let memory_v = session_ctx.new_tensor_1d(params.memory_v_type.into(), n_elements);
+        let hparams = Hyperparameters {
+            n_ctx: n_ctx as i32,
+            ..self.hparams
+        };
+        session.resize_memory(&hparams);
+        if let Some(guidance) = &mut session.guidance {
+            guidance.resize_memory(&hparams);
+        }
+        Ok(())
+    }
 
-        InferenceSession {
-            _session_ctx: session_ctx,
+    /// Generates a completion for `prompt` in a new session, and returns the
+    /// generated text, without the prompt, and why generation stopped.
+    ///
+    /// Generation stops at the end of text token, after
+    /// `maximum_token_count` tokens, when one of `params.stop_sequences`
+    /// is generated, or when the context window is full. For more control
+    /// over the generation, use [InferenceSession::inference_with_prompt].
+    ///
+    /// ```no_run
+    /// # use llama_rs::{InferenceParameters, Llama};
+    /// let (model, vocab) = Llama::load("ggml-model-q4_0.bin", 512, |_| {})?;
+    /// let (completion, stop_reason) = model.complete(
+    ///     &vocab,
+    ///     &InferenceParameters::default(),
+    ///     "The capital of France is",
+    ///     Some(16),
+    /// )?;
+    /// println!("{completion} ({stop_reason:?})");
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn complete(
+        &self,
+        vocab: &Vocabulary,
+        params: &InferenceParameters,
+        prompt: &str,
+        maximum_token_count: Option<usize>,
+    ) -> Result<(String, StopReason), InferenceError> {
+        self.complete_with_session_parameters(
+            vocab,
             params,
-            memory_k,
-            memory_v,
-            n_past: 0,
-            mem_per_token: 0,
-            tokens: vec![],
-            last_logits: vec![0.0; n_vocab as usize],
-        }
+            InferenceSessionParameters::default(),
+            prompt,
+            maximum_token_count,
+        )
     }
 
-    pub fn sample_top_p_top_k(
+    /// Like [Llama::complete], but uses the given parameters for the
+    /// session. Set `session_params.seed` to get reproducible completions.
+    pub fn complete_with_session_parameters(
         &self,
-        session: &InferenceSession,
+        vocab: &Vocabulary,
         params: &InferenceParameters,
-        rng: &mut impl rand::Rng,
-    ) -> TokenId {
-        let logits = &session.last_logits;
-        let n_logits = logits.len();
-        let mut logits_id = Vec::<(f32, TokenId)>::with_capacity(n_logits);
+        session_params: InferenceSessionParameters,
+        prompt: &str,
+        maximum_token_count: Option<usize>,
+    ) -> Result<(String, StopReason), InferenceError> {
+        let mut session = self.start_session(session_params);
 
-        {
-            let scale = 1.0 / params.temp;
-            for (i, &logit) in logits.iter().enumerate() {
-                let tid = i as TokenId;
+        // Feed the prompt separately, so that it is not part of the output.
+        session.feed_prompt::<Infallible>(self, vocab, params, prompt, |_| Ok(()))?;
 
-                // repetition penalty from CTRL paper (https://arxiv.org/abs/1909.05858)
-                // credit https://github.com/facebookresearch/llama/compare/main...shawwn:llama:main
-                let val = if let Some(logit_override) = params.bias_tokens.get(tid) {
-                    logit_override
-                } else if session
-                    .repetition_penalty_tokens()
-                    .contains(&(i as TokenId))
-                {
-                    // if score < 0 then repetition penalty has to multiplied to reduce the previous token probability
-                    if logits[i] < 0.0 {
-                        logit * scale * params.repeat_penalty
-                    } else {
-                        logit * scale / params.repeat_penalty
-                    }
-                } else {
-                    logit * scale
-                };
-                logits_id.push((val, tid));
-            }
-        }
+        let params = InferenceParameters {
+            play_back_previous_tokens: false,
+            ..params.clone()
+        };
+        let completion = RefCell::new(String::new());
+        let stats = session.inference_with_prompt::<Infallible>(
+            self,
+            vocab,
+            &params,
+            "",
+            maximum_token_count,
+            |t, _| {
+                if let OutputToken::Token(t) = t {
+                    completion.borrow_mut().push_str(t);
+                }
+                Ok(())
+            },
+        )?;
 
-        // find the top K tokens
-        {
-            logits_id.partial_sort(params.top_k, |a, b| {
-                // Sort descending
-                b.0.total_cmp(&a.0)
-            });
-            logits_id.truncate(params.top_k);
-        }
+        Ok((completion.into_inner(), stats.stop_reason))
+    }
 
-        let maxl = logits_id
-            .iter()
-            .map(|x| x.0)
-            .max_by(f32::total_cmp)
-            .unwrap();
+    /// Runs the model on the beginning of sentence token in a throwaway
+    /// session and discards the result. This checks that a full forward pass
+    /// works, and that it produces finite logits, before starting a long
+    /// generation. It also pages in the model's weights. If the output of a
+    /// layer is not finite, the first such layer is reported.
+    ///
+    /// Note that ggml aborts the process on some errors, such as tensors with
+    /// mismatched shapes, instead of returning an error. The loader checks
+    /// the shape of every tensor, so these only happen with a broken graph.
+    pub fn warmup(&self, n_threads: i32) -> Result<(), InferenceError> {
+        let mut session = self.start_session(InferenceSessionParameters {
+            memory_k_type: ModelKVMemoryType::Float16,
+            memory_v_type: ModelKVMemoryType::Float16,
+            ..Default::default()
+        });
+        let params = InferenceParameters {
+            n_threads,
+            ..Default::default()
+        };
+        let mut output_request = EvaluateOutputRequest {
+            hidden_states: Some(Vec::new()),
+            ..Default::default()
+        };
+        self.evaluate(
+            &mut session,
+            &params,
+            &[self.bos_token],
+            &mut output_request,
+        );
 
-        // compute probs for the top K tokens
-        let mut probs: Vec<f32> = logits_id
+        let hidden_states = output_request.hidden_states.unwrap_or_default();
+        if let Some(layer) = hidden_states
             .iter()
-            .copied()
-            .map(|(k, _)| (k - maxl).exp())
-            .collect();
-        let sum: f32 = probs.iter().copied().sum();
+            .position(|hidden_state| !hidden_state.iter().all(|x| x.is_finite()))
+        {
+            Err(InferenceError::NonFiniteLayerOutput { layer })
+        } else if session.last_logits.iter().all(|l| l.is_finite()) {
+            Ok(())
+        } else {
+            Err(InferenceError::NonFiniteLogits)
+        }
+    }
 
-        // Normalize the probs
-        for p in probs.iter_mut() {
-            *p /= sum;
+    /// Returns the log-likelihood of `continuation` after `prefix`, i.e. the
+    /// sum of the log-probabilities the model assigns to each of its tokens,
+    /// in a throwaway session. Comparing the scores of several continuations
+    /// of the same prefix answers e.g. multiple-choice questions; dividing by
+    /// the number of tokens gives the mean log-likelihood, which doesn't
+    /// favor short continuations.
+    ///
+    /// The prefix must not be empty, as the first token of the continuation
+    /// is predicted from the last token of the prefix; it usually starts with
+    /// the beginning of sentence token.
+    pub fn score(
+        &self,
+        prefix: &[TokenId],
+        continuation: &[TokenId],
+        n_threads: i32,
+    ) -> Result<f32, InferenceError> {
+        assert!(!prefix.is_empty(), "the prefix must not be empty");
+        let n_vocab = self.hparams.n_vocab as usize;
+        if let Some(&token) = prefix
+            .iter()
+            .chain(continuation)
+            .find(|&&token| usize::try_from(token).map_or(true, |id| id >= n_vocab))
+        {
+            return Err(InferenceError::InvalidToken(token));
+        }
+        if prefix.len() + continuation.len() > self.n_ctx() {
+            return Err(InferenceError::ContextFull);
         }
+        let Some((_, predicted_from)) = continuation.split_last() else {
+            return Ok(0.0);
+        };
 
-        // Top p sampling
-        if params.top_p < 1.0 {
-            let mut cumsum = 0.0;
-            for i in 0..probs.len() {
-                cumsum += probs[i];
-                if cumsum >= params.top_p {
-                    probs.truncate(i + 1);
-                    logits_id.truncate(i + 1);
-                    break;
-                }
-            }
+        let mut session = self.start_session(InferenceSessionParameters {
+            memory_k_type: ModelKVMemoryType::Float16,
+            memory_v_type: ModelKVMemoryType::Float16,
+            ..Default::default()
+        });
+        let params = InferenceParameters {
+            n_threads,
+            ..Default::default()
+        };
 
-            cumsum = 1.0 / cumsum;
-            for p in probs.iter_mut() {
-                *p *= cumsum;
+        // The logits at each position predict the token at the next one, so
+        // the last token of the continuation is not evaluated, and only the
+        // logits from the last token of the prefix on are used.
+        let input: Vec<TokenId> = prefix.iter().chain(predicted_from).copied().collect();
+        let first_scored = prefix.len() - 1;
+        let mut log_likelihood = 0.0;
+        let mut position = 0;
+        for batch in input.chunks(params.n_batch) {
+            let mut output_request = EvaluateOutputRequest {
+                all_logits: (position + batch.len() > first_scored).then(Vec::new),
+                ..Default::default()
+            };
+            self.evaluate(&mut session, &params, batch, &mut output_request);
+
+            for (i, logits) in output_request
+                .all_logits
+                .unwrap_or_default()
+                .chunks_exact(n_vocab)
+                .enumerate()
+                .skip(first_scored.saturating_sub(position))
+            {
+                let token = continuation[position + i - first_scored];
+                log_likelihood += log_softmax(logits, token);
             }
+            position += batch.len();
         }
 
-        let dist = WeightedIndex::new(&probs).expect("WeightedIndex error");
-        let idx = dist.sample(rng);
-
-        logits_id[idx].1
+        Ok(log_likelihood as f32)
     }
 
-    /// Evaluates the transformer.
+    /// Evaluates `tokens` after the tokens already in `session`, like
+    /// [LanguageModel::evaluate], and returns the logits of each token with the
+    /// hidden states after `layer`, or after each layer if `layer` is `None`.
     ///
-    /// The provided `output_request` struct lets you specify which additional
-    /// data you are interested in fetching from the transformer. Setting a
-    /// field to a `Some` value will clear and fill the provided vector with
-    /// data. The provided vector will be resized to the exact output size.
-    pub fn evaluate(
+    /// The hidden states are the residual stream, before the final norm. There
+    /// is one vector per returned layer, holding `n_embd` values for each
+    /// token, one token after the other.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `layer` is not less than the number of layers.
+    pub fn evaluate_with_hidden_states(
         &self,
         session: &mut InferenceSession,
         params: &InferenceParameters,
+        tokens: &[TokenId],
+        layer: Option<usize>,
+    ) -> (Vec<f32>, Vec<Vec<f32>>) {
+        let n_layer = self.hparams.n_layer as usize;
+        if let Some(layer) = layer {
+            assert!(layer < n_layer, "the model only has {n_layer} layers");
+        }
+
+        let mut output_request = EvaluateOutputRequest {
+            all_logits: Some(Vec::new()),
+            hidden_states: Some(Vec::new()),
+            ..Default::default()
+        };
+        self.evaluate(session, params, tokens, &mut output_request);
+
+        let logits = output_request.all_logits.unwrap_or_default();
+        let mut hidden_states = output_request.hidden_states.unwrap_or_default();
+        if let Some(layer) = layer {
+            hidden_states = vec![hidden_states.swap_remove(layer)];
+        }
+        (logits, hidden_states)
+    }
+
+    /// Builds the graph that evaluates `tokens` at the start of a new session
+    /// and writes it to `path` in the Graphviz DOT format, without computing
+    /// it. Each node is labelled with the name, shape and operation of its
+    /// tensor, which helps to find where the shapes of a graph go wrong.
+    pub fn dump_graph_dot(
+        &self,
+        tokens: &[TokenId],
+        path: impl AsRef<Path>,
+    ) -> std::io::Result<()> {
+        if tokens.len() > self.hparams.n_ctx as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the tokens don't fit in the context window",
+            ));
+        }
+
+        let mut session = self.start_session(InferenceSessionParameters {
+            memory_k_type: ModelKVMemoryType::Float16,
+            memory_v_type: ModelKVMemoryType::Float16,
+            ..Default::default()
+        });
+        let params = InferenceParameters::default();
+        let ctx0 = ggml::Context::init(estimate_eval_ctx_size(
+            &self.hparams,
+            tokens.len(),
+            1,
+            &params,
+        ));
+        let (gf, ..) = self.build_graph(&ctx0, &[(&mut session, tokens)], tokens, &params);
+        gf.dump_dot(path.as_ref())
+    }
+
+    /// Builds the graph that evaluates the tokens of `sequences`, which are
+    /// `input_tokens`, in `ctx0`. Returns the graph with its logits and
+    /// embeddings tensors, and the output tensor of each layer.
+    fn build_graph(
+        &self,
+        ctx0: &ggml::Context,
+        sequences: &[(&mut InferenceSession, &[TokenId])],
         input_tokens: &[TokenId],
-        output_request: &mut EvaluateOutputRequest,
+        params: &InferenceParameters,
+    ) -> (
+        ggml::ComputationGraph,
+        ggml::Tensor,
+        ggml::Tensor,
+        Vec<ggml::Tensor>,
     ) {
         let n = input_tokens.len();
-        let n_past = session.n_past as i32;
-        let n_threads = params.n_threads;
         let increased_determinism = params.increased_determinism;
 
         let Hyperparameters {
-            n_vocab,
-            n_ctx,
+            n_vocab: _,
+            n_ctx: _,
             n_embd,
             n_mult: _,
             n_head,
@@ -1134,45 +4184,17 @@ impl Model {
             f16_: _,
         } = self.hparams;
 
-        // For the first run, we need to guess a maximum buffer size so we can measure
-        // the actual memory consumption of the temporary ggml context.
-        let mut buf_size = 1024 * 1024 * 1024;
-        if session.mem_per_token > 0 && session.mem_per_token * n > buf_size {
-            // add 10% to account for ggml object overhead
-            buf_size = (1.1f64 * session.mem_per_token as f64 * n as f64) as usize;
-        };
-        let ctx0 = ggml::Context::init(buf_size);
-
-        let mut gf = ggml::ComputationGraph::new(n_threads);
+        let mut gf = ggml::ComputationGraph::new(params.n_threads);
 
         let embd = ctx0.new_tensor_1d(ggml::TYPE_I32, n as i32);
+        embd.set_name("embd");
         unsafe { embd.write_data(bytemuck::cast_slice(input_tokens)) };
 
         let mut input_layer = ctx0.op_get_rows(&self.tok_embeddings, &embd);
-
-        // Defined here to avoid repetition and creating a binding inside nested loops.
-        // See the call site below for more context.
-        let vtrans_fun = |il: usize| -> ggml::Tensor {
-            ctx0.op_permute(
-                &ctx0.op_reshape_3d(
-                    &ctx0.op_view_1d(
-                        &session.memory_v,
-                        (n_past + n as i32) * n_embd,
-                        il * n_ctx as usize * session.memory_v.element_size() * n_embd as usize,
-                    ),
-                    n_embd / n_head,
-                    n_head,
-                    n_past + n as i32,
-                ),
-                1,
-                2,
-                0,
-                3,
-            )
-        };
+        let mut layer_outputs = Vec::with_capacity(n_layer as usize);
 
         for il in 0..n_layer as usize {
-            let input_self_attention = input_layer.share();
+            let input_self_attention = input_layer.alias();
             let mut current: ggml::Tensor;
 
             // norm
@@ -1188,118 +4210,183 @@ impl Model {
 
             // self-attention
             {
-                let q_current = ctx0.op_mul_mat(&self.layers[il].wq, &current);
-                let k_current = ctx0.op_mul_mat(&self.layers[il].wk, &current);
-                let v_current = ctx0.op_mul_mat(&self.layers[il].wv, &current);
-
-                // store key and value to memory
-                if n >= 1 {
-                    let k = ctx0.op_view_1d(
-                        &session.memory_k,
-                        n as i32 * n_embd,
-                        (session.memory_k.element_size() * n_embd as usize)
-                            * (il * n_ctx as usize + n_past as usize),
-                    );
-
-                    let v = ctx0.op_view_1d(
-                        &session.memory_v,
-                        n as i32 * n_embd,
-                        (session.memory_v.element_size() * n_embd as usize)
-                            * (il * n_ctx as usize + n_past as usize),
-                    );
-
-                    gf.build_forward_expand(&ctx0.op_cpy(&k_current, &k));
-                    gf.build_forward_expand(&ctx0.op_cpy(&v_current, &v));
-                }
+                let q_current_all = ctx0.op_mul_mat(&self.layers[il].wq, &current);
+                let k_current_all = ctx0.op_mul_mat(&self.layers[il].wk, &current);
+                let v_current_all = ctx0.op_mul_mat(&self.layers[il].wv, &current);
+                q_current_all.set_name(&format!("Qcur-{il}"));
+                k_current_all.set_name(&format!("Kcur-{il}"));
+                v_current_all.set_name(&format!("Vcur-{il}"));
+
+                // With several sequences, the attention output of each one is
+                // copied to its columns of this tensor.
+                let merged = (sequences.len() > 1)
+                    .then(|| ctx0.new_tensor_2d(ggml::TYPE_F32, n_embd, n as i32));
+
+                let mut offset = 0;
+                for (session, tokens) in sequences.iter() {
+                    let n = tokens.len();
+                    let n_past = session.n_past as i32;
+                    let n_ctx = session.n_ctx;
+
+                    // The columns of this sequence's tokens.
+                    let columns = |t: &ggml::Tensor| {
+                        ctx0.op_view_1d(
+                            t,
+                            n as i32 * n_embd,
+                            offset * n_embd as usize * std::mem::size_of::<f32>(),
+                        )
+                    };
+                    let q_current = columns(&q_current_all);
+                    let k_current = columns(&k_current_all);
+                    let v_current = columns(&v_current_all);
+
+                    // store key and value to memory
+                    if n >= 1 {
+                        let k = ctx0.op_view_1d(
+                            &session.memory_k,
+                            n as i32 * n_embd,
+                            (session.memory_k.element_size() * n_embd as usize)
+                                * (il * n_ctx + n_past as usize),
+                        );
+
+                        let v = ctx0.op_view_1d(
+                            &session.memory_v,
+                            n as i32 * n_embd,
+                            (session.memory_v.element_size() * n_embd as usize)
+                                * (il * n_ctx + n_past as usize),
+                        );
+
+                        gf.build_forward_expand(&ctx0.op_cpy(&k_current, &k));
+                        gf.build_forward_expand(&ctx0.op_cpy(&v_current, &v));
+                    }
 
-                // Q = Qcur.contiguous().view(n_embd/n_head, n_head, N).permute(0, 2, 1, 3)
-                let q = ctx0.op_permute(
-                    &ctx0.op_rope(
-                        &ctx0.op_cpy(
-                            &q_current,
-                            &ctx0.new_tensor_3d(ggml::TYPE_F32, n_embd / n_head, n_head, n as i32),
+                    // Q = Qcur.contiguous().view(n_embd/n_head, n_head, N).permute(0, 2, 1, 3)
+                    let q = ctx0.op_permute(
+                        &ctx0.op_rope(
+                            &ctx0.op_cpy(
+                                &q_current,
+                                &ctx0.new_tensor_3d(
+                                    ggml::TYPE_F32,
+                                    n_embd / n_head,
+                                    n_head,
+                                    n as i32,
+                                ),
+                            ),
+                            n_past,
+                            n_rot,
+                            0,
+                            self.rope_freq_base,
+                            self.rope_freq_scale,
                         ),
-                        n_past,
-                        n_rot,
                         0,
-                    ),
-                    0,
-                    2,
-                    1,
-                    3,
-                );
+                        2,
+                        1,
+                        3,
+                    );
 
-                // K = Kmem.view(n_embd/n_head, n_head, n_past + N).permute(0, 2, 1, 3)
-                let k = ctx0.op_permute(
-                    &ctx0.op_rope(
-                        &ctx0.op_reshape_3d(
-                            &ctx0.op_view_1d(
-                                &session.memory_k,
-                                (n_past + n as i32) * n_embd,
-                                il * n_ctx as usize
-                                    * session.memory_k.element_size()
-                                    * n_embd as usize,
+                    // K = Kmem.view(n_embd/n_head, n_head, n_past + N).permute(0, 2, 1, 3)
+                    let k = ctx0.op_permute(
+                        &ctx0.op_rope(
+                            &ctx0.op_reshape_3d(
+                                &ctx0.op_view_1d(
+                                    &session.memory_k,
+                                    (n_past + n as i32) * n_embd,
+                                    il * n_ctx * session.memory_k.element_size() * n_embd as usize,
+                                ),
+                                n_embd / n_head,
+                                n_head,
+                                n_past + n as i32,
                             ),
-                            n_embd / n_head,
-                            n_head,
-                            n_past + n as i32,
+                            n_past,
+                            n_rot,
+                            1,
+                            self.rope_freq_base,
+                            self.rope_freq_scale,
                         ),
-                        n_past,
-                        n_rot,
+                        0,
+                        2,
                         1,
-                    ),
-                    0,
-                    2,
-                    1,
-                    3,
-                );
-
-                // K * Q
-                let k_q = ctx0.op_mul_mat(&k, &q);
+                        3,
+                    );
 
-                // KQ_scaled = KQ / sqrt(n_embd/n_head)
-                let k_q_scaled = ctx0.op_scale(
-                    &k_q,
-                    &ctx0.new_f32(1.0 / f32::sqrt(n_embd as f32 / n_head as f32)),
-                );
+                    q.set_name(&format!("Q-{il}"));
+                    k.set_name(&format!("K-{il}"));
 
-                // KQ_masked = mask_past(KQ_scaled)
-                let k_q_masked = ctx0.op_diag_mask_inf(&k_q_scaled, n_past);
+                    // K * Q
+                    let k_q = ctx0.op_mul_mat(&k, &q);
+                    k_q.set_name(&format!("KQ-{il}"));
 
-                // KQ = soft_max(KQ_masked)
-                let k_q_soft_max = ctx0.op_soft_max(&k_q_masked);
+                    // KQ_scaled = KQ / sqrt(n_embd/n_head)
+                    let k_q_scaled = ctx0.op_scale(
+                        &k_q,
+                        &ctx0.new_f32(1.0 / f32::sqrt(n_embd as f32 / n_head as f32)),
+                    );
 
-                // V_trans = Vmem.view(n_embd/n_head, n_head, n_past + N).permute(1, 2, 0, 3).contiguous()
-                let v_transposed = {
-                    if !increased_determinism {
-                        vtrans_fun(il)
-                    } else {
-                        ctx0.op_cpy(
-                            &vtrans_fun(il),
-                            &ctx0.new_tensor_3d(
-                                ggml::TYPE_F32,
-                                n_past + n as i32,
+                    // KQ_masked = mask_past(KQ_scaled)
+                    let k_q_masked = ctx0.op_diag_mask_inf(&k_q_scaled, n_past);
+
+                    // KQ = soft_max(KQ_masked)
+                    let k_q_soft_max = ctx0.op_soft_max(&k_q_masked);
+                    k_q_soft_max.set_name(&format!("attn_scores-{il}"));
+
+                    // V_trans = Vmem.view(n_embd/n_head, n_head, n_past + N).permute(1, 2, 0, 3).contiguous()
+                    let v_transposed = {
+                        let v_transposed = ctx0.op_permute(
+                            &ctx0.op_reshape_3d(
+                                &ctx0.op_view_1d(
+                                    &session.memory_v,
+                                    (n_past + n as i32) * n_embd,
+                                    il * n_ctx * session.memory_v.element_size() * n_embd as usize,
+                                ),
                                 n_embd / n_head,
                                 n_head,
+                                n_past + n as i32,
                             ),
-                        )
-                    }
-                };
+                            1,
+                            2,
+                            0,
+                            3,
+                        );
+                        if !increased_determinism {
+                            v_transposed
+                        } else {
+                            ctx0.op_cpy(
+                                &v_transposed,
+                                &ctx0.new_tensor_3d(
+                                    ggml::TYPE_F32,
+                                    n_past + n as i32,
+                                    n_embd / n_head,
+                                    n_head,
+                                ),
+                            )
+                        }
+                    };
 
-                // KQV = transpose(V) * KQ_soft_max
-                let k_q_v = ctx0.op_mul_mat(&v_transposed, &k_q_soft_max);
+                    // KQV = transpose(V) * KQ_soft_max
+                    let k_q_v = ctx0.op_mul_mat(&v_transposed, &k_q_soft_max);
+                    k_q_v.set_name(&format!("KQV-{il}"));
 
-                // KQV_merged = KQV.permute(0, 2, 1, 3)
-                let k_q_v_merged = ctx0.op_permute(&k_q_v, 0, 2, 1, 3);
+                    // KQV_merged = KQV.permute(0, 2, 1, 3)
+                    let k_q_v_merged = ctx0.op_permute(&k_q_v, 0, 2, 1, 3);
 
-                // cur = KQV_merged.contiguous().view(n_embd, N)
-                current = ctx0.op_cpy(
-                    &k_q_v_merged,
-                    &ctx0.new_tensor_2d(ggml::TYPE_F32, n_embd, n as i32),
-                );
+                    // cur = KQV_merged.contiguous().view(n_embd, N)
+                    current = ctx0.op_cpy(
+                        &k_q_v_merged,
+                        &ctx0.new_tensor_2d(ggml::TYPE_F32, n_embd, n as i32),
+                    );
+                    if let Some(merged) = &merged {
+                        gf.build_forward_expand(&ctx0.op_cpy(&current, &columns(merged)));
+                    }
+
+                    offset += n;
+                }
+                if let Some(merged) = merged {
+                    current = merged;
+                }
 
                 // projection (no bias)
                 current = ctx0.op_mul_mat(&self.layers[il].wo, &current);
+                current.set_name(&format!("attn_out-{il}"));
             }
 
             let input_feed_forward = ctx0.op_add(&current, &input_self_attention);
@@ -1327,9 +4414,12 @@ impl Model {
                 current = ctx0.op_mul(&current, &tmp);
 
                 current = ctx0.op_mul_mat(&self.layers[il].w2, &current);
+                current.set_name(&format!("ffn_out-{il}"));
             }
 
             current = ctx0.op_add(&current, &input_feed_forward);
+            current.set_name(&format!("layer_out-{il}"));
+            layer_outputs.push(current.alias());
 
             // input for next layer
             input_layer = current;
@@ -1344,38 +4434,102 @@ impl Model {
 
             // inpL = norm*inpL
             input_layer = ctx0.op_mul(&ctx0.op_repeat(&self.norm, &input_layer), &input_layer);
-            embeddings_tensor = input_layer.share();
+            embeddings_tensor = input_layer.alias();
+            embeddings_tensor.set_name("embeddings");
         }
 
         // lm_head
         {
             input_layer = ctx0.op_mul_mat(&self.output, &input_layer);
+            input_layer.set_name("logits");
         }
 
         // logits -> probs
         // inpL = ctx0.op_soft_max(&inpL);
 
-        // run the computation
         gf.build_forward_expand(&input_layer);
-        ctx0.graph_compute(&mut gf);
 
-        // return result for just the last token
-        // SAFETY: yolo
-        assert_eq!(session.last_logits.len(), n_vocab as usize);
-        unsafe {
-            input_layer.read_data(
-                n_vocab as usize * (n - 1) * std::mem::size_of::<f32>(),
-                bytemuck::cast_slice_mut(&mut session.last_logits),
-            )
+        (gf, input_layer, embeddings_tensor, layer_outputs)
+    }
+
+    /// Evaluates the tokens of several sessions in a single graph. The
+    /// multiplications by the weights are shared by all the tokens, while
+    /// attention is computed separately for each session, over its own
+    /// memory. `output_request` receives the outputs for all the tokens, in
+    /// order.
+    fn evaluate_sequences(
+        &self,
+        sequences: &mut [(&mut InferenceSession, &[TokenId])],
+        params: &InferenceParameters,
+        output_request: &mut EvaluateOutputRequest,
+    ) {
+        let input_tokens: Vec<TokenId> = sequences
+            .iter()
+            .flat_map(|(_, tokens)| tokens.iter().copied())
+            .collect();
+        let n = input_tokens.len();
+        let Hyperparameters {
+            n_vocab, n_embd, ..
+        } = self.hparams;
+
+        // The sessions can have different context windows, if some were
+        // resized, and the largest one bounds the size of the attention.
+        let hparams = Hyperparameters {
+            n_ctx: sequences
+                .iter()
+                .map(|(session, _)| session.n_ctx as i32)
+                .max()
+                .unwrap_or(self.hparams.n_ctx),
+            ..self.hparams
+        };
+
+        // The temporary tensors go in the scratch context of the first
+        // session, which is only reallocated if it is too small for this batch.
+        let n_seq = sequences.len();
+        let buf_size = estimate_eval_ctx_size(&hparams, n, n_seq, params);
+        let ctx0 = match sequences.first_mut().and_then(|(s, _)| s.scratch.take()) {
+            Some(mut scratch) if scratch.mem_size() >= buf_size => {
+                scratch.reset();
+                scratch
+            }
+            _ => ggml::Context::init_reusable(estimate_eval_ctx_size(
+                &hparams,
+                n.max(params.n_batch),
+                n_seq,
+                params,
+            )),
         };
 
+        let (mut gf, input_layer, embeddings_tensor, layer_outputs) =
+            self.build_graph(&ctx0, sequences, &input_tokens, params);
+
+        // run the computation
+        self.backend.compute(&ctx0, &mut gf);
+
+        // return result for just the last token of each sequence
+        let mut offset = 0;
+        for (session, tokens) in sequences.iter_mut() {
+            offset += tokens.len();
+            if tokens.is_empty() {
+                continue;
+            }
+            // SAFETY: yolo
+            assert_eq!(session.last_logits.len(), n_vocab as usize);
+            unsafe {
+                input_layer.read_data(
+                    n_vocab as usize * (offset - 1) * std::mem::size_of::<f32>(),
+                    bytemuck::cast_slice_mut(&mut session.last_logits),
+                )
+            };
+        }
+
         // Extract logits
         if let Some(all_logits) = &mut output_request.all_logits {
             all_logits.resize(n_vocab as usize * n, 0.0);
             // SAFETY: Tensor data can be read (properly aligned, initialized,
             // data will not be mutated or otherwise aliased during the copy),
             // and we're not reading past the end of the tensor data.
-            assert_eq!(input_layer.nelements(), n_vocab * n as i32);
+            assert_eq!(input_layer.nelements(), i64::from(n_vocab) * n as i64);
             unsafe {
                 input_layer.read_data(0, bytemuck::cast_slice_mut(all_logits));
             }
@@ -1385,32 +4539,40 @@ impl Model {
         if let Some(embeddings) = &mut output_request.embeddings {
             embeddings.resize(n_embd as usize * n, 0.0);
             // SAFETY: Same rationale as for the "Extract logits" section applies.
-            assert_eq!(embeddings_tensor.nelements(), n_embd * n as i32);
+            assert_eq!(embeddings_tensor.nelements(), i64::from(n_embd) * n as i64);
             unsafe {
                 embeddings_tensor.read_data(0, bytemuck::cast_slice_mut(embeddings));
             }
         }
 
-        // Adjust the required memory per token if we didn't know that already
-        if session.mem_per_token == 0 {
-            session.mem_per_token = ctx0.used_mem() / n;
+        // Extract hidden states
+        if let Some(hidden_states) = &mut output_request.hidden_states {
+            hidden_states.resize(layer_outputs.len(), Vec::new());
+            for (hidden_state, layer_output) in hidden_states.iter_mut().zip(&layer_outputs) {
+                hidden_state.resize(n_embd as usize * n, 0.0);
+                // SAFETY: Same rationale as for the "Extract logits" section applies.
+                assert_eq!(layer_output.nelements(), i64::from(n_embd) * n as i64);
+                unsafe {
+                    layer_output.read_data(0, bytemuck::cast_slice_mut(hidden_state));
+                }
+            }
         }
 
-        // Adjust n_past to new length.
-        session.n_past += input_tokens.len();
-    }
+        // The scratch context can be larger than this batch needs, so this
+        // checks the estimate itself, and not just what ggml allocated.
+        debug_assert!(
+            ctx0.used_mem() <= buf_size,
+            "evaluating {n} tokens used {} bytes, but {buf_size} bytes were estimated",
+            ctx0.used_mem()
+        );
+        if let Some((session, _)) = sequences.first_mut() {
+            session.scratch = Some(ctx0);
+        }
 
-    pub fn tokenize(
-        &self,
-        vocab: &Vocabulary,
-        text: &str,
-        bos: bool,
-    ) -> Result<Vec<TokenId>, InferenceError> {
-        Ok(vocab
-            .tokenize(text, bos)?
-            .iter()
-            .map(|(_, tid)| *tid)
-            .collect::<Vec<TokenId>>())
+        for (session, tokens) in sequences.iter_mut() {
+            // Adjust n_past to new length.
+            session.n_past += tokens.len();
+        }
     }
 
     /// Hydrates a previously obtained InferenceSnapshot for this model
@@ -1440,28 +4602,118 @@ impl Model {
         session.n_past = snapshot.npast;
         session.tokens = snapshot.tokens;
         session.last_logits = snapshot.last_logits;
+        session.rng = snapshot.rng;
 
         Ok(session)
     }
 }
 
+impl LanguageModel for Llama {
+    fn hparams(&self) -> &Hyperparameters {
+        &self.hparams
+    }
+
+    fn evaluate(
+        &self,
+        session: &mut InferenceSession,
+        params: &InferenceParameters,
+        input_tokens: &[TokenId],
+        output_request: &mut EvaluateOutputRequest,
+    ) {
+        self.evaluate_sequences(&mut [(session, input_tokens)], params, output_request);
+    }
+
+    fn discard_tokens(
+        &self,
+        session: &mut InferenceSession,
+        _params: &InferenceParameters,
+        n_keep: usize,
+        n_discard: usize,
+    ) {
+        session.shift_memory(
+            &self.hparams,
+            self.rope_freq_base,
+            self.rope_freq_scale,
+            n_keep,
+            n_discard,
+        );
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Llama {
+    /// Loads the model like [Llama::load], on tokio's blocking thread pool so
+    /// that the executor is not blocked while the model is read.
+    pub async fn load_async(
+        path: impl AsRef<Path>,
+        n_ctx: i32,
+    ) -> Result<(Llama, Vocabulary), LoadError> {
+        let path = path.as_ref().to_owned();
+        let result = tokio::task::spawn_blocking(move || Llama::load(path, n_ctx, |_| {})).await;
+
+        match result {
+            Ok(loaded) => loaded,
+            Err(err) => std::panic::resume_unwind(err.into_panic()),
+        }
+    }
+}
+
 impl InferenceSession {
     pub fn feed_prompt<E: std::error::Error + 'static>(
         &mut self,
-        model: &Model,
+        model: &impl LanguageModel,
         vocab: &Vocabulary,
         params: &InferenceParameters,
         prompt: &str,
         callback: impl Fn(OutputToken) -> Result<(), E>,
     ) -> Result<(), InferenceError> {
-        let beginning_of_sentence = self.n_past == 0;
-        let prompt_tokens = model.tokenize(vocab, prompt, beginning_of_sentence)?;
+        self.grammar = params
+            .grammar
+            .as_ref()
+            .map(|grammar| GrammarSampler::new(grammar, vocab));
+
+        let beginning_of_sentence = self.n_past == 0 && params.adds_bos(vocab);
+        let mut prompt_tokens = model.tokenize(vocab, prompt, beginning_of_sentence)?;
+
+        if !prompt_tokens.is_empty() {
+            self.token_healing_candidates = None;
+        }
+        if params.token_healing {
+            if let Some(&last) = prompt_tokens.last().filter(|&&t| t != vocab.bos_token()) {
+                let prefix = &vocab.id_to_token[last as usize];
+                let candidates: Vec<TokenId> = (0..vocab.id_to_token.len() as TokenId)
+                    .filter(|&id| vocab.id_to_token[id as usize].starts_with(prefix.as_str()))
+                    .collect();
+                // Only heal the token if it can be extended.
+                if candidates.len() > 1 {
+                    prompt_tokens.pop();
+                    self.token_healing_candidates = Some(candidates);
+                }
+            }
+        }
 
-        if self.n_past + prompt_tokens.len() >= model.hparams.n_ctx as usize {
+        if params.resolved_context_policy() == ContextPolicy::Error
+            && self.n_past + prompt_tokens.len() >= self.n_ctx
+        {
             return Err(InferenceError::ContextFull);
         }
 
+        // The guidance session is fed the negative prompt in place of the
+        // first prompt, and the same tokens as this session afterwards.
+        match (&mut self.guidance, &params.cfg_negative_prompt) {
+            (Some(guidance), _) => guidance.feed_tokens(model, params, &prompt_tokens)?,
+            (None, Some(negative_prompt)) if params.cfg_scale != 1.0 => {
+                let mut guidance = InferenceSession::new(model.hparams(), self.params);
+                let negative_tokens =
+                    model.tokenize(vocab, negative_prompt, params.adds_bos(vocab))?;
+                guidance.feed_tokens(model, params, &negative_tokens)?;
+                self.guidance = Some(Box::new(guidance));
+            }
+            _ => {}
+        }
+
         for batch in prompt_tokens.chunks(8) {
+            self.make_room(model, params, batch.len())?;
             model.evaluate(self, params, batch, &mut EvaluateOutputRequest::default());
             for &tk in batch {
                 // NOTE: No string ever tokenizes to the end of sentence. So we
@@ -1478,37 +4730,181 @@ impl InferenceSession {
         Ok(())
     }
 
-    pub fn infer_next_token<'v>(
+    pub fn infer_next_token<'v>(
+        &mut self,
+        model: &impl LanguageModel,
+        vocab: &'v Vocabulary,
+        params: &InferenceParameters,
+    ) -> Result<OutputToken<'v>, InferenceError> {
+        self.make_room(model, params, 1)?;
+
+        // First, sample the next token, using the stored last_logits;
+        let next_token = self.sample(params);
+
+        // Then, evaluate the network again to compute the new last_logits
+        self.set_next_token(model, params, next_token)?;
+
+        // Return the next token
+        Ok(if next_token == vocab.eos_token() {
+            OutputToken::EndOfText
+        } else {
+            OutputToken::Token(&vocab.id_to_token[next_token as usize])
+        })
+    }
+
+    /// Returns the logits predicted for the next token by the last
+    /// evaluation, with one entry per token in the vocabulary (`n_vocab`).
+    ///
+    /// These are only meaningful after at least one token has been evaluated,
+    /// e.g. by [InferenceSession::feed_prompt]; before that, they are all
+    /// zero. Together with [InferenceSession::set_next_token], this can be
+    /// used to implement custom sampling outside of this crate.
+    pub fn last_logits(&self) -> &[f32] {
+        &self.last_logits
+    }
+
+    /// Returns how many tokens were evaluated in the session, i.e. the
+    /// position of the next token.
+    pub fn n_past(&self) -> usize {
+        self.n_past
+    }
+
+    /// Records that `n_tokens` more tokens were evaluated, the last of which
+    /// has the given logits. This is for implementations of
+    /// [LanguageModel::evaluate] outside of this crate.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are not as many logits as tokens in the vocabulary.
+    pub fn advance(&mut self, n_tokens: usize, last_logits: &[f32]) {
+        self.last_logits.copy_from_slice(last_logits);
+        self.n_past += n_tokens;
+    }
+
+    /// Appends the given token to the session and evaluates it, updating
+    /// [InferenceSession::last_logits]. Use this to feed back a token that was
+    /// sampled externally. Returns [InferenceError::InvalidToken] if the token
+    /// is not in the vocabulary.
+    pub fn set_next_token(
+        &mut self,
+        model: &impl LanguageModel,
+        params: &InferenceParameters,
+        token: TokenId,
+    ) -> Result<(), InferenceError> {
+        self.accept_token(model, params, token)?;
+
+        // Update the tokens for this session
+        self.tokens.push(token);
+
+        model.evaluate(
+            self,
+            params,
+            &[token],
+            &mut EvaluateOutputRequest::default(),
+        );
+
+        Ok(())
+    }
+
+    /// Checks the token and updates the sampling state for it, before it is
+    /// appended to the session and evaluated.
+    fn accept_token(
+        &mut self,
+        model: &impl LanguageModel,
+        params: &InferenceParameters,
+        token: TokenId,
+    ) -> Result<(), InferenceError> {
+        if !(0..model.n_vocab() as TokenId).contains(&token) {
+            return Err(InferenceError::InvalidToken(token));
+        }
+        self.make_room(model, params, 1)?;
+
+        // Token healing only constrains the first token after the prompt.
+        self.token_healing_candidates = None;
+        if let Some(grammar) = &mut self.grammar {
+            grammar.accept(token);
+        }
+        if let Some(guidance) = &mut self.guidance {
+            guidance.set_next_token(model, params, token)?;
+        }
+        Ok(())
+    }
+
+    /// Evaluates the tokens without reporting them, making room in the context
+    /// window as needed. Together with [InferenceSession::tokens], this can
+    /// be used to restore a session from its tokens, without a snapshot.
+    /// Returns [InferenceError::InvalidToken] if a token is not in the
+    /// vocabulary.
+    pub fn feed_tokens(
+        &mut self,
+        model: &impl LanguageModel,
+        params: &InferenceParameters,
+        tokens: &[TokenId],
+    ) -> Result<(), InferenceError> {
+        let n_vocab = model.n_vocab();
+        if let Some(&token) = tokens
+            .iter()
+            .find(|&&token| usize::try_from(token).map_or(true, |id| id >= n_vocab))
+        {
+            return Err(InferenceError::InvalidToken(token));
+        }
+
+        for batch in tokens.chunks(params.n_batch.max(1)) {
+            self.make_room(model, params, batch.len())?;
+            model.evaluate(self, params, batch, &mut EvaluateOutputRequest::default());
+            self.tokens.extend_from_slice(batch);
+        }
+        Ok(())
+    }
+
+    /// Makes sure that `n_tokens` more tokens fit in the context window,
+    /// shifting it as set by [InferenceParameters::context_policy]. The
+    /// beginning-of-sentence token is always kept.
+    fn make_room(
         &mut self,
-        model: &Model,
-        vocab: &'v Vocabulary,
+        model: &impl LanguageModel,
         params: &InferenceParameters,
-        rng: &mut impl rand::Rng,
-    ) -> Result<OutputToken<'v>, InferenceError> {
-        if self.n_past + 1 >= model.hparams.n_ctx as usize {
-            return Err(InferenceError::ContextFull);
+        n_tokens: usize,
+    ) -> Result<(), InferenceError> {
+        let n_ctx = self.n_ctx;
+        if self.n_past + n_tokens < n_ctx {
+            return Ok(());
         }
 
-        // First, sample the next token, using the stored last_logits;
-        let next_token = model.sample_top_p_top_k(self, params, rng);
-
-        // Update the tokens for this session
-        self.tokens.push(next_token);
-
-        // Then, evaluate the network again to compute the new last_logits
-        model.evaluate(
-            self,
-            params,
-            &[next_token],
-            &mut EvaluateOutputRequest::default(),
-        );
+        // The session tokens and the memory are in sync, unless the session
+        // was restored from an inconsistent snapshot.
+        let n_past = self.n_past.min(self.tokens.len());
+        let policy = params.resolved_context_policy();
+        let (n_keep, n_retained) = context_shift(policy, n_ctx, n_past, n_tokens)?;
+        let n_discard = n_past - n_keep - n_retained;
+        self.n_past = n_past;
+        self.tokens.truncate(n_past);
+        log::debug!("Context full, discarding {n_discard} tokens");
+
+        match policy {
+            ContextPolicy::SlidingWindow => model.discard_tokens(self, params, n_keep, n_discard),
+            _ => self.evaluate_again(model, params, n_keep, n_discard),
+        }
+        Ok(())
+    }
 
-        // Return the next token
-        Ok(if next_token as TokenId == EOD_TOKEN_ID {
-            OutputToken::EndOfText
-        } else {
-            OutputToken::Token(&vocab.id_to_token[next_token as usize])
-        })
+    /// Discards the `n_discard` tokens after the first `n_keep` ones, and
+    /// overwrites them in the memory by evaluating the tokens after them
+    /// again, right after the kept ones.
+    fn evaluate_again(
+        &mut self,
+        model: &impl LanguageModel,
+        params: &InferenceParameters,
+        n_keep: usize,
+        n_discard: usize,
+    ) {
+        let retained = self.tokens[n_keep + n_discard..self.n_past].to_vec();
+        self.tokens.truncate(n_keep);
+        self.n_past = n_keep;
+        for batch in retained.chunks(params.n_batch.max(1)) {
+            model.evaluate(self, params, batch, &mut EvaluateOutputRequest::default());
+            self.tokens.extend_from_slice(batch);
+        }
     }
 
     // todo: see if we can reduce the arguments here somehow - consolidate model and vocab maybe?
@@ -1516,24 +4912,33 @@ impl InferenceSession {
     ///
     /// Note that this will "play back" all existing tokens in the session. If this is not desired
     /// behaviour, consider implementing your own inference loop to customize the behavior.
+    ///
+    /// The second argument of the callback is `true` for the tokens of the prompt, which are only
+    /// passed to it if [InferenceParameters::echo_prompt] is set, and `false` for generated text.
+    ///
+    /// At most `maximum_token_count` tokens are generated. With `None`, generation only stops at
+    /// the end of text token, at a stop sequence, when aborted, or when the context window is full.
+    ///
+    /// The returned statistics include the [StopReason]. Running out of room in the context
+    /// window while generating stops generation with [StopReason::ContextFull], whereas running
+    /// out of room while feeding the prompt is an [InferenceError::ContextFull].
     #[allow(clippy::too_many_arguments)]
     pub fn inference_with_prompt<E: std::error::Error + 'static>(
         &mut self,
-        model: &Model,
+        model: &impl LanguageModel,
         vocab: &Vocabulary,
         params: &InferenceParameters,
         prompt: &str,
         maximum_token_count: Option<usize>,
-        rng: &mut impl rand::Rng,
-        callback: impl Fn(OutputToken) -> Result<(), E>,
+        callback: impl Fn(OutputToken, bool) -> Result<(), E>,
     ) -> Result<InferenceStats, InferenceError> {
         let maximum_token_count = maximum_token_count.unwrap_or(usize::MAX);
-        if params.play_back_previous_tokens {
+        if params.play_back_previous_tokens && params.echo_prompt {
             // "Play back" the existing tokens, so that loading from an inference snapshot works
             // as expected.
             for token_id in &self.tokens {
                 let token = OutputToken::from_id(vocab, *token_id);
-                if let Err(e) = callback(token) {
+                if let Err(e) = callback(token, true) {
                     return Err(InferenceError::UserCallback(Box::new(e)));
                 }
             }
@@ -1545,7 +4950,12 @@ impl InferenceSession {
 
         // Feed the initial prompt through the transformer, to update its
         // context window with new data.
-        self.feed_prompt(model, vocab, params, prompt, |tk| callback(tk))?;
+        self.feed_prompt(model, vocab, params, prompt, |token| {
+            match params.echo_prompt {
+                true => callback(token, true),
+                false => Ok(()),
+            }
+        })?;
         stats.feed_prompt_duration = start_at.elapsed().unwrap();
         stats.prompt_tokens = self.n_past;
 
@@ -1553,26 +4963,196 @@ impl InferenceSession {
         // `infer_next_token`. We generate tokens until the model returns an
         // EndOfText token, or we run out of space in the context window,
         // or we reach the specified limit.
+        //
+        // Text that could be the beginning of a stop sequence is held back
+        // until we know whether the sequence is completed or not, as are
+        // incomplete UTF-8 characters.
+        let emit = |text: &str| -> Result<(), InferenceError> {
+            if text.is_empty() {
+                return Ok(());
+            }
+            callback(OutputToken::Token(text), false)
+                .map_err(|e| InferenceError::UserCallback(Box::new(e)))
+        };
+        let mut pending = String::new();
+        let mut detokenizer = Detokenizer::new(vocab);
         let mut tokens_processed = 0;
         while tokens_processed < maximum_token_count {
-            let token = self.infer_next_token(model, vocab, params, rng)?;
-
-            if let Err(e) = callback(token) {
-                return Err(InferenceError::UserCallback(Box::new(e)));
+            if self.abort.swap(false, Ordering::Relaxed) {
+                stats.stop_reason = StopReason::Aborted;
+                break;
             }
 
+            let token = match self.infer_next_token(model, vocab, params) {
+                Ok(token) => token,
+                Err(InferenceError::ContextFull) => {
+                    stats.stop_reason = StopReason::ContextFull;
+                    break;
+                }
+                Err(err) => return Err(err),
+            };
             tokens_processed += 1;
 
-            if let OutputToken::EndOfText = token {
-                break;
+            match token {
+                OutputToken::Token(_) => {
+                    let id = *self.tokens.last().expect("a token was just generated");
+                    let Some(text) = detokenizer.push(id) else {
+                        continue;
+                    };
+                    pending.push_str(&text);
+
+                    if let Some((stop_at, stop_sequence)) =
+                        find_stop_sequence(&pending, &params.stop_sequences)
+                    {
+                        emit(&pending[..stop_at])?;
+                        pending.clear();
+                        // Discard the text after the stop sequence.
+                        detokenizer.flush();
+                        stats.stop_reason = StopReason::StopSequence(stop_sequence.to_owned());
+                        break;
+                    }
+
+                    let held_back = partial_stop_sequence_len(&pending, &params.stop_sequences);
+                    emit(&pending[..pending.len() - held_back])?;
+                    pending.drain(..pending.len() - held_back);
+                }
+                OutputToken::EndOfText => {
+                    pending.extend(detokenizer.flush());
+                    emit(&pending)?;
+                    pending.clear();
+
+                    if let Err(e) = callback(token, false) {
+                        return Err(InferenceError::UserCallback(Box::new(e)));
+                    }
+
+                    stats.stop_reason = StopReason::EndOfSequence;
+                    break;
+                }
             }
         }
+        pending.extend(detokenizer.flush());
+        emit(&pending)?;
         stats.predict_duration = start_at.elapsed().unwrap();
         stats.predict_tokens = self.n_past;
+        stats.generated_tokens = tokens_processed;
+
+        Ok(stats)
+    }
+
+    /// Feeds `prompt` to the session, then writes the generated text to `out`
+    /// as it is generated, flushing it after each token. As with
+    /// [InferenceSession::inference_with_prompt], incomplete UTF-8 characters
+    /// and possible beginnings of stop sequences are held back; unlike it,
+    /// the prompt is not written.
+    ///
+    /// Generation stops at the first write error, which is returned as an
+    /// [InferenceError::UserCallback] holding the [std::io::Error].
+    ///
+    /// ```no_run
+    /// # use llama_rs::{InferenceParameters, InferenceSessionParameters, Llama};
+    /// let (model, vocab) = Llama::load("ggml-model-q4_0.bin", 512, |_| {})?;
+    /// let mut session = model.start_session(InferenceSessionParameters::default());
+    /// session.inference_to_writer(
+    ///     &model,
+    ///     &vocab,
+    ///     &InferenceParameters::default(),
+    ///     "The capital of France is",
+    ///     Some(16),
+    ///     &mut std::io::stdout(),
+    /// )?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn inference_to_writer(
+        &mut self,
+        model: &impl LanguageModel,
+        vocab: &Vocabulary,
+        params: &InferenceParameters,
+        prompt: &str,
+        maximum_token_count: Option<usize>,
+        out: &mut dyn std::io::Write,
+    ) -> Result<InferenceStats, InferenceError> {
+        let start_at = time::SystemTime::now();
+        self.feed_prompt::<Infallible>(model, vocab, params, prompt, |_| Ok(()))?;
+        let feed_prompt_duration = start_at.elapsed().unwrap();
 
+        let params = InferenceParameters {
+            play_back_previous_tokens: false,
+            ..params.clone()
+        };
+        let out = RefCell::new(out);
+        let mut stats = self.inference_with_prompt(
+            model,
+            vocab,
+            &params,
+            "",
+            maximum_token_count,
+            |token, _| {
+                if let OutputToken::Token(text) = token {
+                    let mut out = out.borrow_mut();
+                    out.write_all(text.as_bytes())?;
+                    out.flush()?;
+                }
+                Ok::<(), std::io::Error>(())
+            },
+        )?;
+        stats.feed_prompt_duration = feed_prompt_duration;
         Ok(stats)
     }
 
+    /// Returns an iterator over the text generated by the session, after its
+    /// prompt was fed with [InferenceSession::feed_prompt]. Each item is a
+    /// chunk of complete UTF-8 characters, usually the text of one token.
+    ///
+    /// As with [InferenceSession::inference_with_prompt], possible
+    /// beginnings of stop sequences are held back, and generation ends at the
+    /// end of text token, at a stop sequence, when the context window is
+    /// full, or when the session is aborted; see [TextStream::stop_reason].
+    /// The text that is still held back is returned before the iterator ends,
+    /// or before an error, which is always the last item.
+    ///
+    /// ```no_run
+    /// # use llama_rs::{InferenceParameters, InferenceSessionParameters, Llama};
+    /// let (model, vocab) = Llama::load("ggml-model-q4_0.bin", 512, |_| {})?;
+    /// let params = InferenceParameters::default();
+    /// let mut session = model.start_session(InferenceSessionParameters::default());
+    /// session.feed_prompt::<std::convert::Infallible>(
+    ///     &model,
+    ///     &vocab,
+    ///     &params,
+    ///     "The capital of France is",
+    ///     |_| Ok(()),
+    /// )?;
+    /// for chunk in session.stream_text(&model, &vocab, &params).take(16) {
+    ///     print!("{}", chunk?);
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn stream_text<'a, M: LanguageModel>(
+        &'a mut self,
+        model: &'a M,
+        vocab: &'a Vocabulary,
+        params: &'a InferenceParameters,
+    ) -> TextStream<'a, M> {
+        TextStream::new(self, model, vocab, params)
+    }
+
+    /// Returns how many tokens the context window of the session holds.
+    pub fn n_ctx(&self) -> usize {
+        self.n_ctx
+    }
+
+    /// Returns the tokens of the session so far: the prompts and the
+    /// generated tokens.
+    pub fn tokens(&self) -> &[TokenId] {
+        &self.tokens
+    }
+
+    /// Returns a handle that can be used to stop [InferenceSession::inference_with_prompt]
+    /// from another thread.
+    pub fn abort_handle(&self) -> AbortHandle {
+        AbortHandle(self.abort.clone())
+    }
+
     /// Obtains a serializable snapshot of the current inference status. This
     /// can be used to cache the state of the model and store them into a file.
     ///
@@ -1596,6 +5176,7 @@ impl InferenceSession {
             logits: self.last_logits.clone(),
             memory_k,
             memory_v,
+            rng: self.rng.clone(),
         }
     }
 }
@@ -1613,53 +5194,225 @@ impl InferenceSnapshot {
 }
 
 impl Vocabulary {
-    // SentencePiece implementation after https://guillaume-be.github.io/2020-05-30/sentence_piece
+    /// Returns the number of tokens in the vocabulary.
+    pub fn len(&self) -> usize {
+        self.id_to_token.len()
+    }
+
+    /// Returns whether the vocabulary has no tokens.
+    pub fn is_empty(&self) -> bool {
+        self.id_to_token.is_empty()
+    }
+
+    /// Returns the bytes of the token with the given id, if it is part of the
+    /// vocabulary. Unlike [Vocabulary::get], this works for tokens that are
+    /// not valid UTF-8 on their own.
+    pub fn token_bytes(&self, id: TokenId) -> Option<&[u8]> {
+        match self.invalid_token_bytes.get(&id) {
+            Some(bytes) => Some(bytes),
+            None => self.get(id).map(str::as_bytes),
+        }
+    }
+
+    /// Returns the id of the beginning-of-sentence token: the token `<s>`
+    /// if the vocabulary has one, and [BOS_TOKEN_ID] otherwise, as in the
+    /// LLaMA models.
+    pub fn bos_token(&self) -> TokenId {
+        self.bos_token
+    }
+
+    /// Returns the id of the end-of-sentence token: the token `</s>` if the
+    /// vocabulary has one, and [EOD_TOKEN_ID] otherwise.
+    pub fn eos_token(&self) -> TokenId {
+        self.eos_token
+    }
+
+    /// Returns whether prompts start with the beginning-of-sentence token, as
+    /// the model was trained with. This depends on the
+    /// [ModelArchitecture] the model was loaded as, and can be overridden
+    /// with [Vocabulary::set_add_bos] or [InferenceParameters::add_bos].
+    pub fn should_add_bos(&self) -> bool {
+        self.add_bos
+    }
+
+    /// Sets whether prompts start with the beginning-of-sentence token, for
+    /// fine-tunes that were trained differently from their architecture.
+    pub fn set_add_bos(&mut self, add_bos: bool) {
+        self.add_bos = add_bos;
+    }
+
+    /// Returns the id of the token for unknown text: the token `<unk>` if
+    /// the vocabulary has one, and [UNK_TOKEN_ID] otherwise.
+    pub fn unk_token(&self) -> TokenId {
+        self.unk_token
+    }
+
+    /// Returns the token with the given id, if it is part of the vocabulary.
+    pub fn get(&self, id: TokenId) -> Option<&str> {
+        usize::try_from(id)
+            .ok()
+            .and_then(|id| self.id_to_token.get(id))
+            .map(String::as_str)
+    }
+
+    /// Describes each of `tokens` on its own line as its id and its text,
+    /// e.g. `15043 -> " Hello"`, to debug the tokenization of a prompt.
+    /// Tokens that are not valid UTF-8 are shown as bytes.
+    pub fn describe_tokens(&self, tokens: &[TokenId]) -> String {
+        tokens
+            .iter()
+            .map(|&id| match self.token_bytes(id) {
+                Some(bytes) => match std::str::from_utf8(bytes) {
+                    Ok(text) => format!("{id} -> {text:?}"),
+                    Err(_) => format!("{id} -> {bytes:?}"),
+                },
+                None => format!("{id} -> <unknown>"),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Returns the id of the token whose text is `text`, if there is one.
+    pub fn token_id(&self, text: &str) -> Option<TokenId> {
+        self.token_to_id.get(text).copied()
+    }
+
+    /// Returns the id and text of every token whose text contains `needle`,
+    /// by id, e.g. to check whether there is a token for `" Paris"`. This
+    /// goes through the whole vocabulary.
+    pub fn search(&self, needle: &str) -> Vec<(TokenId, &str)> {
+        self.id_to_token
+            .iter()
+            .enumerate()
+            .filter(|(_, token)| token.contains(needle))
+            .map(|(id, token)| (id as TokenId, token.as_str()))
+            .collect()
+    }
+
+    /// Registers `text` as a special token, such as ChatML's `<|im_start|>`:
+    /// wherever it appears in the text, [Vocabulary::tokenize] maps it to the
+    /// single token `id`, instead of tokenizing it like the text around it.
+    /// The text of the token `id` becomes `text`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` is not part of the vocabulary.
+    pub fn add_special_token(&mut self, text: &str, id: TokenId) {
+        let token = usize::try_from(id)
+            .ok()
+            .and_then(|id| self.id_to_token.get_mut(id))
+            .expect("special tokens must be part of the vocabulary");
+        if self.token_to_id.get(token.as_str()) == Some(&id) {
+            self.token_to_id.remove(token.as_str());
+        }
+        *token = text.to_owned();
+        self.invalid_token_bytes.remove(&id);
+        self.token_to_id.insert(text.to_owned(), id);
+        self.special_tokens.insert(text.to_owned(), id);
+        self.max_token_length = self.max_token_length.max(text.len());
+    }
+
+    /// Tokenizes `text`, preceded by the beginning-of-sentence token if `bos`
+    /// is set. The special tokens are extracted first, and the text between
+    /// them is tokenized on its own.
     pub fn tokenize<'a>(
         &'a self,
         text: &str,
         bos: bool,
     ) -> Result<Vec<(&'a str, TokenId)>, InferenceError> {
+        let mut res = vec![];
+        if bos {
+            res.push(("", self.bos_token()));
+        }
+
+        let mut rest = text;
+        while let Some((start, special, id)) = self.find_special_token(rest) {
+            res.extend(self.tokenize_piece(&rest[..start])?);
+            res.push((self.id_to_token[id as usize].as_str(), id));
+            rest = &rest[start + special.len()..];
+        }
+        res.extend(self.tokenize_piece(rest)?);
+
+        Ok(res)
+    }
+
+    /// Returns the position, text and id of the first special token in
+    /// `text`, preferring the longest one if several start there.
+    fn find_special_token<'t>(&self, text: &'t str) -> Option<(usize, &'t str, TokenId)> {
+        self.special_tokens
+            .iter()
+            .filter_map(|(special, &id)| {
+                let start = text.find(special.as_str())?;
+                Some((start, &text[start..start + special.len()], id))
+            })
+            .min_by_key(|&(start, special, _)| (start, std::cmp::Reverse(special.len())))
+    }
+
+    // SentencePiece implementation after https://guillaume-be.github.io/2020-05-30/sentence_piece
+    fn tokenize_piece<'a>(&'a self, text: &str) -> Result<Vec<(&'a str, TokenId)>, InferenceError> {
         let len = text.len();
 
-        let mut score = vec![0usize; len + 1];
-        let mut prev = vec![TokenId::default(); len + 1];
+        // The best tokenization of the text up to each byte, if there is
+        // one: its score, where its last token starts, and that token, or
+        // `None` if the last character is tokenized as its bytes.
+        let mut best: Vec<Option<(usize, usize, Option<TokenId>)>> = vec![None; len + 1];
+        best[0] = Some((0, 0, None));
 
         for i in 0..len {
+            let Some((score, _, _)) = best[i] else {
+                continue;
+            };
             let max_len = (len - i).min(self.max_token_length);
             for sub_len in 1..=max_len {
                 let sub = &text.as_bytes()[i..i + sub_len];
-                let Ok(sub) = std::str::from_utf8(sub) else { continue; };
+                let Ok(sub) = std::str::from_utf8(sub) else {
+                    continue;
+                };
                 let token = self.token_to_id.get(sub);
 
                 if let Some(token) = token {
                     let token_score = sub.len() * sub.len();
-                    let local_score = score[i] + token_score;
+                    let local_score = score + token_score;
                     let next = i + sub_len;
 
-                    if score[next] < local_score {
-                        score[next] = local_score;
-                        prev[next] = *token;
+                    if best[next].map_or(true, |(best_score, _, _)| best_score < local_score) {
+                        best[next] = Some((local_score, i, Some(*token)));
                     }
                 }
             }
+
+            // Byte fallback: a character that no token covers is tokenized
+            // as the tokens of its bytes, as SentencePiece does, e.g. for
+            // emoji.
+            if let Some(c) = text[i..].chars().next() {
+                let next = i + c.len_utf8();
+                if best[next].is_none()
+                    && text[i..next].bytes().all(|b| self.byte_token(b).is_some())
+                {
+                    best[next] = Some((score, i, None));
+                }
+            }
         }
 
         // Backward pass
         let mut res = vec![];
         let mut i = len;
         while i > 0 {
-            let token_id = prev[i];
-            if token_id == 0 {
+            let Some((_, start, token_id)) = best[i] else {
                 return Err(InferenceError::TokenizationFailed);
+            };
+            match token_id {
+                Some(token_id) => {
+                    res.push((self.id_to_token[token_id as usize].as_str(), token_id))
+                }
+                None => {
+                    for byte in text[start..i].bytes().rev() {
+                        let token_id = self.byte_token(byte).expect("checked in the forward pass");
+                        res.push((self.id_to_token[token_id as usize].as_str(), token_id));
+                    }
+                }
             }
-            let token = self.id_to_token[token_id as usize].as_str();
-            res.push((token, token_id));
-            i -= token.len();
-        }
-
-        if bos {
-            // TODO: replace with vocab.bos
-            res.push(("", 1));
+            i = start;
         }
 
         // Pieces are in reverse order so correct that
@@ -1667,4 +5420,413 @@ impl Vocabulary {
 
         Ok(res)
     }
+
+    /// Returns the token of a single byte, used to tokenize the characters
+    /// that have no token. It is either `<0xNN>`, as in SentencePiece
+    /// vocabularies, or the byte itself.
+    fn byte_token(&self, byte: u8) -> Option<TokenId> {
+        if let Some(&id) = self.token_to_id.get(&format!("<0x{byte:02X}>")) {
+            return Some(id);
+        }
+        if byte.is_ascii() {
+            return self.token_to_id.get(&(byte as char).to_string()).copied();
+        }
+        self.invalid_token_bytes
+            .iter()
+            .find(|(_, bytes)| bytes.as_slice() == [byte])
+            .map(|(&id, _)| id)
+    }
+
+    /// Returns the length of the text that the token `id`, whose text is
+    /// `token`, was tokenized from: byte tokens stand for a single byte.
+    fn token_text_len(&self, token: &str, id: TokenId) -> usize {
+        match self.invalid_token_bytes.get(&id) {
+            Some(bytes) => bytes.len(),
+            None if Self::is_byte_token(token) => 1,
+            None => token.len(),
+        }
+    }
+
+    /// Returns whether `token` is a SentencePiece byte token, `<0xNN>`.
+    fn is_byte_token(token: &str) -> bool {
+        token.len() == 6
+            && token.starts_with("<0x")
+            && token.ends_with('>')
+            && token[3..5].bytes().all(|b| b.is_ascii_hexdigit())
+    }
+
+    /// Returns the number of tokens `text` is tokenized into, including the
+    /// beginning-of-sentence token if `bos` is set.
+    pub fn count_tokens(&self, text: &str, bos: bool) -> Result<usize, InferenceError> {
+        // TODO: count the tokens without collecting them.
+        Ok(self.tokenize(text, bos)?.len())
+    }
+
+    /// Tokenizes `new_text`, which is appended to `existing_text` that has
+    /// already been tokenized (e.g. the previous turns of a conversation),
+    /// without tokenizing all of `existing_text` again.
+    ///
+    /// A token can span the join of the two texts, so the end of
+    /// `existing_text` is tokenized again along with `new_text`. If the join
+    /// falls on a token boundary, the tokens after it are returned, which
+    /// are the same as when tokenizing the whole text in most cases. If a
+    /// token spans the join, the tokens of `existing_text` would have to
+    /// change, so `new_text` is tokenized on its own instead.
+    pub fn tokenize_append(
+        &self,
+        existing_text: &str,
+        new_text: &str,
+    ) -> Result<Vec<TokenId>, InferenceError> {
+        if new_text.is_empty() {
+            return Ok(vec![]);
+        }
+
+        // No token is longer than `max_token_length`, so only the end of the
+        // existing text can be merged with the new text.
+        let mut window_start = existing_text.len().saturating_sub(self.max_token_length);
+        while !existing_text.is_char_boundary(window_start) {
+            window_start -= 1;
+        }
+        let tail = &existing_text[window_start..];
+
+        if let Ok(tokens) = self.tokenize(&format!("{tail}{new_text}"), false) {
+            let mut offset = 0;
+            for (i, &(token, id)) in tokens.iter().enumerate() {
+                if offset == tail.len() {
+                    return Ok(tokens[i..].iter().map(|&(_, id)| id).collect());
+                }
+                if offset > tail.len() {
+                    break;
+                }
+                offset += self.token_text_len(token, id);
+            }
+        }
+
+        Ok(self
+            .tokenize(new_text, false)?
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect())
+    }
+
+    /// Tokenizes the text read from `reader` as it is read, yielding the same
+    /// tokens as [Vocabulary::tokenize] on the whole text, without holding
+    /// all of it in memory. The text is tokenized in pieces that end where
+    /// no token of the vocabulary can span the join.
+    ///
+    /// Text that is not valid UTF-8 is reported as an
+    /// [std::io::ErrorKind::InvalidData] error, as are tokenization failures.
+    pub fn tokenize_reader<'a>(
+        &'a self,
+        reader: impl BufRead + 'a,
+        bos: bool,
+    ) -> impl Iterator<Item = std::io::Result<TokenId>> + 'a {
+        ReaderTokenizer {
+            vocab: self,
+            reader,
+            text: String::new(),
+            incomplete_char: vec![],
+            checked: 0,
+            tokens: VecDeque::new(),
+            bos,
+            done: false,
+        }
+    }
+
+    /// Whether a token of the vocabulary contains the text on both sides of
+    /// `position`, so that the text can't be tokenized separately on each
+    /// side.
+    fn can_span(&self, text: &str, position: usize) -> bool {
+        let start = position.saturating_sub(self.max_token_length.saturating_sub(1));
+        (start..position).any(|i| {
+            (position + 1..=(i + self.max_token_length).min(text.len())).any(|j| {
+                text.get(i..j)
+                    .map_or(false, |sub| self.token_to_id.contains_key(sub))
+            })
+        })
+    }
+}
+
+/// The iterator returned by [Vocabulary::tokenize_reader].
+struct ReaderTokenizer<'a, R> {
+    vocab: &'a Vocabulary,
+    reader: R,
+    /// The text that was read but not tokenized yet.
+    text: String,
+    /// The bytes of a UTF-8 character that was not fully read yet.
+    incomplete_char: Vec<u8>,
+    /// The positions in `text` below this one have already been checked and
+    /// can't be split at.
+    checked: usize,
+    tokens: VecDeque<TokenId>,
+    bos: bool,
+    done: bool,
+}
+
+impl<R: BufRead> ReaderTokenizer<'_, R> {
+    /// Reads the next chunk of text, and tokenizes as much of the text as can
+    /// be tokenized without knowing what follows.
+    fn read_chunk(&mut self) -> std::io::Result<()> {
+        let chunk = self.reader.fill_buf()?;
+        if chunk.is_empty() {
+            self.done = true;
+            if !self.incomplete_char.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "the text ends with an incomplete UTF-8 character",
+                ));
+            }
+            return self.tokenize_text(self.text.len());
+        }
+
+        self.incomplete_char.extend_from_slice(chunk);
+        let len = chunk.len();
+        self.reader.consume(len);
+        let valid_len = match std::str::from_utf8(&self.incomplete_char) {
+            Ok(_) => self.incomplete_char.len(),
+            Err(err) if err.error_len().is_none() => err.valid_up_to(),
+            Err(err) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err)),
+        };
+        let rest = self.incomplete_char.split_off(valid_len);
+        let valid = std::mem::replace(&mut self.incomplete_char, rest);
+        self.text
+            .push_str(std::str::from_utf8(&valid).expect("the bytes were validated"));
+
+        // A token can only span a position if it starts at most
+        // `max_token_length` bytes before it, so the split must leave enough
+        // text after it to check.
+        let vocab = self.vocab;
+        let last = self
+            .text
+            .len()
+            .saturating_sub(vocab.max_token_length.saturating_sub(1));
+        let split = (self.checked.max(1)..=last)
+            .rev()
+            .find(|&i| self.text.is_char_boundary(i) && !vocab.can_span(&self.text, i));
+        match split {
+            Some(split) => {
+                self.tokenize_text(split)?;
+                self.checked = last + 1 - split;
+            }
+            None => self.checked = self.checked.max(last + 1),
+        }
+        Ok(())
+    }
+
+    /// Tokenizes the first `len` bytes of the text.
+    fn tokenize_text(&mut self, len: usize) -> std::io::Result<()> {
+        let tokens = self
+            .vocab
+            .tokenize(&self.text[..len], self.bos)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?;
+        self.tokens.extend(tokens.into_iter().map(|(_, id)| id));
+        self.text.drain(..len);
+        self.bos = false;
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Iterator for ReaderTokenizer<'_, R> {
+    type Item = std::io::Result<TokenId>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(token) = self.tokens.pop_front() {
+                return Some(Ok(token));
+            }
+            if self.done {
+                return None;
+            }
+            if let Err(err) = self.read_chunk() {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+/// An iterator over the text generated by a session. Obtained with
+/// [InferenceSession::stream_text], which borrows the session, or with
+/// [pipeline::Pipeline::stream], which owns it.
+pub struct TextStream<
+    'a,
+    M: LanguageModel,
+    S: BorrowMut<InferenceSession> = &'a mut InferenceSession,
+> {
+    session: S,
+    model: &'a M,
+    vocab: &'a Vocabulary,
+    params: &'a InferenceParameters,
+    detokenizer: Detokenizer<'a>,
+    /// Text that could be the beginning of a stop sequence.
+    pending: String,
+    /// An error that is returned after the pending text.
+    error: Option<InferenceError>,
+    stop_reason: Option<StopReason>,
+    done: bool,
+}
+impl<'a, M: LanguageModel, S: BorrowMut<InferenceSession>> TextStream<'a, M, S> {
+    fn new(
+        session: S,
+        model: &'a M,
+        vocab: &'a Vocabulary,
+        params: &'a InferenceParameters,
+    ) -> Self {
+        Self {
+            session,
+            model,
+            vocab,
+            params,
+            detokenizer: Detokenizer::new(vocab),
+            pending: String::new(),
+            error: None,
+            stop_reason: None,
+            done: false,
+        }
+    }
+
+    /// Returns the session the text is generated by, e.g. to get the ids of
+    /// the generated tokens with [InferenceSession::tokens].
+    pub fn session(&self) -> &InferenceSession {
+        self.session.borrow()
+    }
+
+    /// Why generation stopped, once the iterator has ended without an error.
+    pub fn stop_reason(&self) -> Option<&StopReason> {
+        self.stop_reason.as_ref()
+    }
+
+    /// Ends the stream, returning the text that was held back, if any.
+    fn finish(
+        &mut self,
+        stop_reason: Option<StopReason>,
+    ) -> Option<Result<String, InferenceError>> {
+        self.done = true;
+        self.stop_reason = stop_reason;
+        self.pending.extend(self.detokenizer.flush());
+        let text = std::mem::take(&mut self.pending);
+        match text.is_empty() {
+            true => self.error.take().map(Err),
+            false => Some(Ok(text)),
+        }
+    }
+}
+impl<M: LanguageModel, S: BorrowMut<InferenceSession>> Iterator for TextStream<'_, M, S> {
+    type Item = Result<String, InferenceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return self.error.take().map(Err);
+        }
+
+        loop {
+            let session = self.session.borrow_mut();
+            if session.abort.swap(false, Ordering::Relaxed) {
+                return self.finish(Some(StopReason::Aborted));
+            }
+
+            let token = session.infer_next_token(self.model, self.vocab, self.params);
+            match token {
+                Ok(OutputToken::Token(_)) => {
+                    let id = *session.tokens.last().expect("a token was just generated");
+                    let Some(text) = self.detokenizer.push(id) else {
+                        continue;
+                    };
+                    self.pending.push_str(&text);
+
+                    let stop_sequences = &self.params.stop_sequences;
+                    if let Some((stop_at, stop_sequence)) =
+                        find_stop_sequence(&self.pending, stop_sequences)
+                    {
+                        // Discard the text after the stop sequence.
+                        self.pending.truncate(stop_at);
+                        self.detokenizer.flush();
+                        let stop_reason = StopReason::StopSequence(stop_sequence.to_owned());
+                        return self.finish(Some(stop_reason));
+                    }
+
+                    let held_back = partial_stop_sequence_len(&self.pending, stop_sequences);
+                    let text: String = self
+                        .pending
+                        .drain(..self.pending.len() - held_back)
+                        .collect();
+                    if !text.is_empty() {
+                        return Some(Ok(text));
+                    }
+                }
+                Ok(OutputToken::EndOfText) => return self.finish(Some(StopReason::EndOfSequence)),
+                Err(InferenceError::ContextFull) => {
+                    return self.finish(Some(StopReason::ContextFull))
+                }
+                Err(err) => {
+                    self.error = Some(err);
+                    return self.finish(None);
+                }
+            }
+        }
+    }
+}
+
+/// Converts generated tokens to text as they are generated.
+///
+/// Some tokens are single bytes, so a multi-byte UTF-8 character can be
+/// split across tokens. The bytes of incomplete characters are held back
+/// until the rest of the character is pushed.
+#[derive(Clone, Debug)]
+pub struct Detokenizer<'v> {
+    vocab: &'v Vocabulary,
+    buffer: Vec<u8>,
+}
+impl<'v> Detokenizer<'v> {
+    pub fn new(vocab: &'v Vocabulary) -> Self {
+        Self {
+            vocab,
+            buffer: vec![],
+        }
+    }
+
+    /// Adds the token, and returns the text that is complete so far, if
+    /// any. Invalid byte sequences are replaced by U+FFFD.
+    pub fn push(&mut self, id: TokenId) -> Option<String> {
+        self.buffer
+            .extend_from_slice(self.vocab.token_bytes(id).unwrap_or_default());
+
+        let mut text = String::new();
+        loop {
+            match std::str::from_utf8(&self.buffer) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    self.buffer.clear();
+                    break;
+                }
+                Err(err) => {
+                    let valid_len = err.valid_up_to();
+                    text.push_str(std::str::from_utf8(&self.buffer[..valid_len]).unwrap());
+                    match err.error_len() {
+                        // The end of the buffer could be the start of a
+                        // character, so wait for more bytes.
+                        None => {
+                            self.buffer.drain(..valid_len);
+                            break;
+                        }
+                        Some(invalid_len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            self.buffer.drain(..valid_len + invalid_len);
+                        }
+                    }
+                }
+            }
+        }
+
+        (!text.is_empty()).then_some(text)
+    }
+
+    /// Returns the bytes that are still held back, e.g. because generation
+    /// ended in the middle of a character, with invalid sequences replaced by
+    /// U+FFFD.
+    pub fn flush(&mut self) -> Option<String> {
+        let text = String::from_utf8_lossy(&self.buffer).into_owned();
+        self.buffer.clear();
+        (!text.is_empty()).then_some(text)
+    }
 }
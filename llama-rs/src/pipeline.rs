@@ -0,0 +1,175 @@
+//! A high-level entry point that bundles a model with its vocabulary and the
+//! parameters to generate text with it. The lower-level APIs it wraps remain
+//! available for everything it doesn't cover.
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # // Only runs when a model is provided, e.g. by the tests.
+//! # let Ok(model_path) = std::env::var("LLAMA_RS_TEST_MODEL") else { return Ok(()) };
+//! use llama_rs::pipeline::LlamaBuilder;
+//!
+//! let pipeline = LlamaBuilder::new(model_path)
+//!     .n_ctx(512)
+//!     .threads(4)
+//!     .seed(42)
+//!     .build()?;
+//! for chunk in pipeline.stream("The capital of France is")?.take(16) {
+//!     print!("{}", chunk?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::path::PathBuf;
+
+use crate::{
+    InferenceError, InferenceParameters, InferenceSession, InferenceSessionParameters, Llama,
+    LoadError, LoadParameters, LoadStrategy, StopReason, TextStream, Vocabulary,
+};
+
+/// Configures and loads a [Pipeline].
+#[derive(Clone, Debug)]
+pub struct LlamaBuilder {
+    model_path: PathBuf,
+    n_ctx: i32,
+    n_threads: i32,
+    seed: Option<u64>,
+    mmap: bool,
+}
+
+impl LlamaBuilder {
+    /// Starts configuring a pipeline for the model at `model_path`, with a
+    /// context window of 512 tokens and the default inference parameters.
+    pub fn new(model_path: impl Into<PathBuf>) -> Self {
+        Self {
+            model_path: model_path.into(),
+            n_ctx: 512,
+            n_threads: InferenceParameters::default().n_threads,
+            seed: None,
+            mmap: true,
+        }
+    }
+
+    /// Sets the path of the model to load.
+    pub fn model_path(mut self, model_path: impl Into<PathBuf>) -> Self {
+        self.model_path = model_path.into();
+        self
+    }
+
+    /// Sets the size of the context window, in tokens.
+    pub fn n_ctx(mut self, n_ctx: i32) -> Self {
+        self.n_ctx = n_ctx;
+        self
+    }
+
+    /// Sets the number of threads used to evaluate the model.
+    pub fn threads(mut self, n_threads: i32) -> Self {
+        self.n_threads = n_threads;
+        self
+    }
+
+    /// Sets the seed of every generation, which makes them reproducible.
+    /// Without a seed, each generation is seeded from system entropy.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Sets whether the model file is memory-mapped, see [LoadStrategy].
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Loads the model and returns the pipeline.
+    pub fn build(self) -> Result<Pipeline, LoadError> {
+        let load_params = LoadParameters {
+            strategy: match self.mmap {
+                true => LoadStrategy::Mmap,
+                false => LoadStrategy::Read,
+            },
+            ..Default::default()
+        };
+        let (model, vocab) =
+            Llama::load_with_parameters(&self.model_path, self.n_ctx, load_params, |_| {})?;
+
+        Ok(Pipeline {
+            model,
+            vocab,
+            params: InferenceParameters {
+                n_threads: self.n_threads,
+                ..Default::default()
+            },
+            session_params: InferenceSessionParameters {
+                seed: self.seed,
+                ..Default::default()
+            },
+        })
+    }
+}
+
+/// A model with its vocabulary and the parameters to generate text with it.
+/// Built with [LlamaBuilder].
+///
+/// Each generation runs in a new session, so generations are independent of
+/// each other.
+pub struct Pipeline {
+    model: Llama,
+    vocab: Vocabulary,
+    params: InferenceParameters,
+    session_params: InferenceSessionParameters,
+}
+
+impl Pipeline {
+    pub fn model(&self) -> &Llama {
+        &self.model
+    }
+
+    pub fn vocab(&self) -> &Vocabulary {
+        &self.vocab
+    }
+
+    /// The parameters used by every generation, which can be changed to
+    /// e.g. set the temperature or stop sequences.
+    pub fn params_mut(&mut self) -> &mut InferenceParameters {
+        &mut self.params
+    }
+
+    /// Generates the completion of `prompt`, until the end of text token, a
+    /// stop sequence, or until the context window is full, and returns it
+    /// with the reason generation stopped.
+    pub fn complete(&self, prompt: &str) -> Result<(String, StopReason), InferenceError> {
+        let mut stream = self.stream(prompt)?;
+        let mut completion = String::new();
+        for chunk in stream.by_ref() {
+            completion.push_str(&chunk?);
+        }
+        let stop_reason = stream
+            .stop_reason()
+            .cloned()
+            .expect("the stream ended without an error");
+        Ok((completion, stop_reason))
+    }
+
+    /// Feeds `prompt` to a new session, and returns an iterator over the text
+    /// generated after it. See [InferenceSession::stream_text].
+    pub fn stream(
+        &self,
+        prompt: &str,
+    ) -> Result<TextStream<'_, Llama, InferenceSession>, InferenceError> {
+        let mut session = self.model.start_session(self.session_params);
+        session.feed_prompt::<std::convert::Infallible>(
+            &self.model,
+            &self.vocab,
+            &self.params,
+            prompt,
+            |_| Ok(()),
+        )?;
+        Ok(TextStream::new(
+            session,
+            &self.model,
+            &self.vocab,
+            &self.params,
+        ))
+    }
+}
@@ -0,0 +1,146 @@
+//! Caches the state of a session after evaluating a prompt on disk, so that
+//! starting a session with the same prompt again skips its evaluation.
+
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use rand::SeedableRng;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::{
+    InferenceError, InferenceParameters, InferenceRng, InferenceSession,
+    InferenceSessionParameters, InferenceSnapshot, Llama, SnapshotError, TokenId, Vocabulary,
+};
+
+/// A directory holding the state of sessions after evaluating prompts, one
+/// file per prompt.
+///
+/// Entries are keyed by the prompt's tokens, the hyperparameters of the model
+/// and the parameters of the session, so that a model with different
+/// hyperparameters never uses them. Models that only differ by their weights,
+/// such as fine-tunes or models with a LoRA adapter, must be told apart with
+/// `model_id`, e.g. the path of the model file and of its adapters.
+#[derive(Clone, Debug)]
+pub struct PromptCache {
+    dir: PathBuf,
+    model_id: String,
+}
+
+impl PromptCache {
+    /// Uses the cache in `dir`, which is created when the first entry is
+    /// added, for the model identified by `model_id`.
+    pub fn new(dir: impl Into<PathBuf>, model_id: impl Into<String>) -> Self {
+        Self {
+            dir: dir.into(),
+            model_id: model_id.into(),
+        }
+    }
+
+    /// Returns the path of the entry for `tokens` evaluated by `model` in a
+    /// session with `session_params`.
+    fn entry_path(
+        &self,
+        model: &Llama,
+        session_params: &InferenceSessionParameters,
+        tokens: &[TokenId],
+    ) -> PathBuf {
+        let key = format!(
+            "{:?} {:?} {:?} {} {} {:?} {:?}",
+            self.model_id,
+            model.file_format,
+            model.hparams,
+            model.rope_freq_base,
+            model.rope_freq_scale,
+            session_params.memory_k_type,
+            session_params.memory_v_type,
+        );
+        let mut hash = Xxh3::new();
+        hash.update(key.as_bytes());
+        for token in tokens {
+            hash.update(&token.to_le_bytes());
+        }
+        self.dir.join(format!("{:016x}.bin", hash.digest()))
+    }
+}
+
+impl Llama {
+    /// Starts a session like [Llama::start_session], and feeds it `prompt`
+    /// like [InferenceSession::feed_prompt]. If the same prompt was evaluated
+    /// by the same model before, the state saved in `cache` is restored
+    /// instead; otherwise, the state after evaluating it is added to the
+    /// cache. Returns the session, and whether it was restored from the cache.
+    ///
+    /// A restored session is reseeded from `session_params.seed`, so that
+    /// runs with different seeds still differ. The cache is only an
+    /// optimization: an entry that can't be read is evaluated again, and
+    /// errors writing an entry are logged.
+    pub fn start_session_cached(
+        &self,
+        session_params: InferenceSessionParameters,
+        cache: &PromptCache,
+        vocab: &Vocabulary,
+        params: &InferenceParameters,
+        prompt: &str,
+    ) -> Result<(InferenceSession, bool), InferenceError> {
+        let tokens: Vec<TokenId> = vocab
+            .tokenize(prompt, params.adds_bos(vocab))?
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect();
+        let path = cache.entry_path(self, &session_params, &tokens);
+
+        if path.exists() {
+            match read_entry(&path).and_then(|snapshot| self.session_from_snapshot(snapshot)) {
+                Ok(mut session) => {
+                    log::debug!("Restored the evaluated prompt from {path:?}");
+                    session.rng = match session_params.seed {
+                        Some(seed) => InferenceRng::seed_from_u64(seed),
+                        None => InferenceRng::from_entropy(),
+                    };
+                    return Ok((session, true));
+                }
+                Err(err) => log::warn!("Ignoring the prompt cache entry at {path:?}: {err}"),
+            }
+        }
+
+        let mut session = self.start_session(session_params);
+        session.feed_prompt::<std::convert::Infallible>(self, vocab, params, prompt, |_| Ok(()))?;
+
+        match write_entry(&mut session, &cache.dir, &path) {
+            Ok(()) => log::debug!("Cached the evaluated prompt at {path:?}"),
+            Err(err) => log::warn!("Could not cache the evaluated prompt at {path:?}: {err}"),
+        }
+        Ok((session, false))
+    }
+}
+
+fn read_entry(path: &Path) -> Result<InferenceSnapshot, SnapshotError> {
+    InferenceSnapshot::read(&mut BufReader::new(File::open(path)?))
+}
+
+/// Writes the state of `session` to `path`, under another name until it is
+/// complete, so that an interrupted write is never read.
+fn write_entry(
+    session: &mut InferenceSession,
+    dir: &Path,
+    path: &Path,
+) -> Result<(), SnapshotError> {
+    fs::create_dir_all(dir)?;
+    let part_path = path.with_extension("part");
+    let result = File::create(&part_path)
+        .map_err(SnapshotError::from)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            // SAFETY: The session is not used while the snapshot is alive.
+            unsafe { session.get_snapshot() }.write(&mut writer)?;
+            Ok(writer.flush()?)
+        })
+        .and_then(|()| Ok(fs::rename(&part_path, path)?));
+    if result.is_err() {
+        fs::remove_file(&part_path).ok();
+    }
+    result
+}
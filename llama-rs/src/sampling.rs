@@ -0,0 +1,427 @@
+//! Strategies for picking the next token from the logits produced by the model.
+//!
+//! All the functions in this module expect logits that have already been
+//! adjusted by the token biases, repetition penalty and temperature.
+
+use partial_sort::PartialSort;
+use rand::{distributions::WeightedIndex, prelude::Distribution};
+
+use crate::TokenId;
+
+/// Returns the id of the token with the highest logit.
+pub fn sample_greedy(logits: &[f32]) -> TokenId {
+    logits
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(id, _)| id as TokenId)
+        .expect("logits should not be empty")
+}
+
+/// Samples a token among the `top_k` most likely ones (all of them if
+/// `top_k` is 0), keeping only the most likely of those whose cumulative
+/// probability reaches `top_p`.
+///
+/// If nothing is truncated, the logits are not sorted. The kept tokens are
+/// sampled from in the order of their ids either way, so that the same random
+/// state results in the same token whether they were sorted or not.
+pub fn sample_top_p_top_k(
+    logits: &[f32],
+    top_k: usize,
+    top_p: f32,
+    rng: &mut impl rand::Rng,
+) -> TokenId {
+    let top_k = match top_k {
+        0 => logits.len(),
+        top_k => top_k.min(logits.len()),
+    };
+    let candidates = if top_k == logits.len() && top_p >= 1.0 {
+        (0..logits.len() as TokenId).collect()
+    } else {
+        top_p_top_k_candidates(logits, top_k, top_p)
+    };
+    sample_by_id(logits, &candidates, rng)
+}
+
+/// Returns the ids of the tokens kept by [sample_top_p_top_k], in increasing
+/// order, by sorting the logits.
+fn top_p_top_k_candidates(logits: &[f32], top_k: usize, top_p: f32) -> Vec<TokenId> {
+    let mut logits_id: Vec<(f32, TokenId)> = logits.iter().copied().zip(0..).collect();
+
+    // find the top K tokens
+    logits_id.partial_sort(top_k, |a, b| {
+        // Sort descending
+        b.0.total_cmp(&a.0)
+    });
+    logits_id.truncate(top_k);
+
+    // Top p sampling
+    if top_p < 1.0 {
+        let maxl = logits_id[0].0;
+        let probs: Vec<f32> = logits_id.iter().map(|(l, _)| (l - maxl).exp()).collect();
+        let sum: f32 = probs.iter().sum();
+
+        let mut cumsum = 0.0;
+        if let Some(i) = probs.iter().position(|p| {
+            cumsum += p / sum;
+            cumsum >= top_p
+        }) {
+            logits_id.truncate(i + 1);
+        }
+    }
+
+    let mut ids: Vec<TokenId> = logits_id.into_iter().map(|(_, id)| id).collect();
+    ids.sort_unstable();
+    ids
+}
+
+/// Samples one of the `candidates` token ids, weighted by the softmax of
+/// their logits. If none of them can be sampled, e.g. because they were all
+/// banned, the first of the most likely ones is returned, as with greedy
+/// sampling.
+fn sample_by_id(logits: &[f32], candidates: &[TokenId], rng: &mut impl rand::Rng) -> TokenId {
+    let logit = |id: TokenId| logits[id as usize];
+    let (maxl, best) = candidates
+        .iter()
+        .map(|&id| (logit(id), id))
+        .max_by(|a, b| a.0.total_cmp(&b.0).then(b.1.cmp(&a.1)))
+        .expect("there should be candidates");
+    match WeightedIndex::new(candidates.iter().map(|&id| (logit(id) - maxl).exp())) {
+        Ok(dist) => candidates[dist.sample(rng)],
+        Err(_) => best,
+    }
+}
+
+/// Samples a token using Mirostat v2 (<https://arxiv.org/abs/2007.14966>).
+///
+/// `tau` is the target surprise (in bits) and `eta` the learning rate used to
+/// update `mu`, the running estimate of the maximum surprise. `mu` should be
+/// initialized to `2.0 * tau` and kept between calls.
+///
+/// The provided `logits` are converted to probabilities in place.
+pub fn sample_mirostat_v2(
+    logits: &mut [f32],
+    tau: f32,
+    eta: f32,
+    mu: &mut f32,
+    rng: &mut impl rand::Rng,
+) -> TokenId {
+    let mut candidates = sorted_probabilities(logits);
+
+    // Discard the tokens whose surprise exceeds mu, but always keep the most
+    // likely one.
+    let n_keep = candidates
+        .iter()
+        .position(|(p, _)| -p.log2() > *mu)
+        .unwrap_or(candidates.len())
+        .max(1);
+    candidates.truncate(n_keep);
+
+    let (p, id) = sample_candidate(&candidates, rng);
+
+    // Move mu towards the target surprise, based on the surprise of the
+    // sampled token within the truncated distribution.
+    // Nothing is learned if all the tokens were banned.
+    let sum: f32 = candidates.iter().map(|(p, _)| p).sum();
+    let observed_surprise = -(p / sum).log2();
+    if observed_surprise.is_finite() {
+        *mu -= eta * (observed_surprise - tau);
+    }
+
+    id
+}
+
+/// Samples a token using tail-free sampling
+/// (<https://www.trentonbricken.com/Tail-Free-Sampling/>).
+///
+/// The tokens are sorted by probability, and the tail of the distribution is
+/// found from the second derivative of the sorted probabilities: the tokens
+/// are kept until the cumulative sum of the normalized absolute second
+/// derivatives exceeds `z`. A `z` of 1.0 disables the truncation.
+///
+/// The provided `logits` are converted to probabilities in place.
+pub fn sample_tail_free(logits: &mut [f32], z: f32, rng: &mut impl rand::Rng) -> TokenId {
+    let mut candidates = sorted_probabilities(logits);
+    truncate_tail_free(&mut candidates, z);
+    sample_candidate(&candidates, rng).1
+}
+
+/// Samples a token using locally typical sampling
+/// (<https://arxiv.org/abs/2202.00666>).
+///
+/// The tokens whose surprise is closest to the entropy of the distribution
+/// are kept, until their cumulative probability exceeds `p`. A `p` of 1.0
+/// disables the truncation.
+///
+/// The provided `logits` are converted to probabilities in place.
+pub fn sample_typical(logits: &mut [f32], p: f32, rng: &mut impl rand::Rng) -> TokenId {
+    let mut candidates = sorted_probabilities(logits);
+    truncate_typical(&mut candidates, p);
+    sample_candidate(&candidates, rng).1
+}
+
+/// Truncates `candidates`, sorted by descending probability, for tail-free
+/// sampling. At least one candidate is kept.
+fn truncate_tail_free(candidates: &mut Vec<(f32, TokenId)>, z: f32) {
+    if z >= 1.0 || candidates.len() <= 2 {
+        return;
+    }
+
+    let first_derivatives: Vec<f32> = candidates.windows(2).map(|w| w[0].0 - w[1].0).collect();
+    let mut second_derivatives: Vec<f32> = first_derivatives
+        .windows(2)
+        .map(|w| (w[0] - w[1]).abs())
+        .collect();
+
+    let sum: f32 = second_derivatives.iter().sum();
+    if sum > 0.0 {
+        for d in second_derivatives.iter_mut() {
+            *d /= sum;
+        }
+    }
+
+    let mut cumulative = 0.0;
+    let n_keep = second_derivatives
+        .iter()
+        .position(|d| {
+            cumulative += d;
+            cumulative > z
+        })
+        .unwrap_or(candidates.len())
+        .max(1);
+    candidates.truncate(n_keep);
+}
+
+/// Truncates `candidates` for locally typical sampling, and reorders them by
+/// how typical they are. At least one candidate is kept.
+fn truncate_typical(candidates: &mut Vec<(f32, TokenId)>, p: f32) {
+    if p >= 1.0 {
+        return;
+    }
+
+    let entropy: f32 = candidates
+        .iter()
+        .filter(|(prob, _)| *prob > 0.0)
+        .map(|(prob, _)| -prob * prob.ln())
+        .sum();
+
+    // Sort by how far the surprise of each token is from the entropy.
+    candidates.sort_by(|a, b| {
+        let a = (-a.0.ln() - entropy).abs();
+        let b = (-b.0.ln() - entropy).abs();
+        a.total_cmp(&b)
+    });
+
+    let mut cumulative = 0.0;
+    let n_keep = candidates
+        .iter()
+        .position(|(prob, _)| {
+            cumulative += prob;
+            cumulative > p
+        })
+        .map_or(candidates.len(), |i| i + 1);
+    candidates.truncate(n_keep);
+}
+
+/// Converts the logits to probabilities, and returns them with their token
+/// ids, sorted by descending probability.
+fn sorted_probabilities(logits: &mut [f32]) -> Vec<(f32, TokenId)> {
+    softmax(logits);
+
+    let mut candidates: Vec<(f32, TokenId)> = logits.iter().copied().zip(0..).collect();
+    candidates.sort_unstable_by(|a, b| b.0.total_cmp(&a.0));
+    candidates
+}
+
+/// Samples one of the candidates, weighted by their (not necessarily
+/// normalized) probabilities. If none of them can be sampled, e.g. because
+/// all the tokens were banned, the first candidate is returned.
+fn sample_candidate(candidates: &[(f32, TokenId)], rng: &mut impl rand::Rng) -> (f32, TokenId) {
+    match WeightedIndex::new(candidates.iter().map(|(p, _)| *p)) {
+        Ok(dist) => candidates[dist.sample(rng)],
+        Err(_) => candidates[0],
+    }
+}
+
+/// Converts the logits to probabilities in place.
+fn softmax(logits: &mut [f32]) {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut sum = 0.0;
+    for l in logits.iter_mut() {
+        *l = (*l - max).exp();
+        sum += *l;
+    }
+    for l in logits.iter_mut() {
+        *l /= sum;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+
+    fn random_logits(n: usize, rng: &mut impl Rng) -> Vec<f32> {
+        (0..n).map(|_| rng.gen_range(-10.0..10.0)).collect()
+    }
+
+    #[test]
+    fn top_p_top_k_without_truncation_samples_like_the_sorting_path() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let logits = random_logits(1000, &mut rng);
+
+        // The sorting path, which the shortcut skips, keeps every token.
+        let all: Vec<TokenId> = (0..1000).collect();
+        assert_eq!(top_p_top_k_candidates(&logits, 1000, 1.0), all);
+
+        let mut fast_rng = ChaCha8Rng::seed_from_u64(1);
+        let mut sorting_rng = ChaCha8Rng::seed_from_u64(1);
+        for _ in 0..100 {
+            let fast = sample_top_p_top_k(&logits, 0, 1.0, &mut fast_rng);
+            let sorted = top_p_top_k_candidates(&logits, logits.len(), 1.0);
+            assert_eq!(fast, sample_by_id(&logits, &sorted, &mut sorting_rng));
+        }
+        assert_eq!(fast_rng, sorting_rng);
+    }
+
+    #[test]
+    fn top_p_top_k_keeps_the_most_likely_tokens() {
+        let logits = [1.0, 4.0, 3.0, f32::NEG_INFINITY, 2.0];
+        assert_eq!(top_p_top_k_candidates(&logits, 2, 1.0), [1, 2]);
+        assert_eq!(top_p_top_k_candidates(&logits, 5, 0.5), [1]);
+        assert_eq!(top_p_top_k_candidates(&logits, 5, 0.9), [1, 2, 4]);
+
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for _ in 0..100 {
+            assert!([1, 2].contains(&sample_top_p_top_k(&logits, 2, 1.0, &mut rng)));
+            assert_ne!(sample_top_p_top_k(&logits, 0, 1.0, &mut rng), 3);
+        }
+    }
+
+    #[test]
+    fn mirostat_moves_mu_towards_the_target_surprise() {
+        // The probabilities decrease slowly enough for the distribution to
+        // hold more than the target surprise.
+        let logits: Vec<f32> = (0..64).map(|i| -0.25 * i as f32).collect();
+        let mut probs = logits.clone();
+        softmax(&mut probs);
+        let (tau, eta) = (3.0, 0.1);
+
+        let mut final_mus = Vec::new();
+        for initial_mu in [0.5, 30.0] {
+            let mut rng = ChaCha8Rng::seed_from_u64(0);
+            let mut mu = initial_mu;
+            let mut surprises = Vec::new();
+            for step in 0..2000 {
+                let previous_mu = mu;
+                let id = sample_mirostat_v2(&mut logits.clone(), tau, eta, &mut mu, &mut rng);
+
+                // The surprise of the token within the kept ones, which are
+                // the most likely tokens.
+                let kept: f32 = probs
+                    .iter()
+                    .filter(|p| -p.log2() <= previous_mu)
+                    .sum::<f32>()
+                    .max(probs[0]);
+                surprises.push(-(probs[id as usize] / kept).log2());
+
+                // Single steps can move away from the target, but mu moves
+                // towards it overall.
+                if step == 200 {
+                    assert!((mu - initial_mu) * (tau - initial_mu) > 0.0, "{mu}");
+                }
+            }
+
+            let mean_surprise = surprises[1000..].iter().sum::<f32>() / 1000.0;
+            assert!(
+                (mean_surprise - tau).abs() < 0.2,
+                "starting from {initial_mu}: {mean_surprise}"
+            );
+            final_mus.push(mu);
+        }
+        // mu ends up in the same place wherever it starts.
+        assert!((final_mus[0] - final_mus[1]).abs() < 1.0, "{final_mus:?}");
+    }
+
+    /// The logits of a distribution of probabilities 0.4, 0.3, 0.15, 0.1,
+    /// 0.03 and 0.02, in a different order than their ids.
+    fn synthetic_logits() -> Vec<f32> {
+        [0.1f32, 0.4, 0.02, 0.3, 0.15, 0.03]
+            .iter()
+            .map(|p| p.ln())
+            .collect()
+    }
+
+    /// Returns the ids of the candidates kept by `truncate`, in increasing
+    /// order, and checks that `sample` only samples those.
+    fn kept_ids(
+        truncate: impl Fn(&mut Vec<(f32, TokenId)>),
+        sample: impl Fn(&mut [f32], &mut ChaCha8Rng) -> TokenId,
+    ) -> Vec<TokenId> {
+        let mut candidates = sorted_probabilities(&mut synthetic_logits());
+        truncate(&mut candidates);
+        let mut ids: Vec<TokenId> = candidates.into_iter().map(|(_, id)| id).collect();
+        ids.sort_unstable();
+
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for _ in 0..100 {
+            let id = sample(&mut synthetic_logits(), &mut rng);
+            assert!(ids.contains(&id), "{id} not in {ids:?}");
+        }
+        ids
+    }
+
+    #[test]
+    fn tail_free_truncates_where_the_curve_flattens() {
+        // The normalized second derivatives are 0.22, 0.43, 0.09 and 0.26.
+        let kept = |z| {
+            kept_ids(
+                |candidates| truncate_tail_free(candidates, z),
+                |logits, rng| sample_tail_free(logits, z, rng),
+            )
+        };
+        assert_eq!(kept(0.2), [1]);
+        assert_eq!(kept(0.7), [1, 3]);
+        assert_eq!(kept(0.95), [1, 3, 4]);
+        assert_eq!(kept(1.0), [0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn typical_keeps_the_tokens_whose_surprise_is_near_the_entropy() {
+        // The entropy is 1.43 nats, and the surprises of the tokens 0.92
+        // (0.4), 1.20 (0.3), 1.90 (0.15), 2.30 (0.1), and more for the others.
+        let kept = |p| {
+            kept_ids(
+                |candidates| truncate_typical(candidates, p),
+                |logits, rng| sample_typical(logits, p, rng),
+            )
+        };
+        assert_eq!(kept(0.2), [3]);
+        // The most likely token is not the most typical one.
+        assert_eq!(kept(0.4), [3, 4]);
+        assert_eq!(kept(0.5), [1, 3, 4]);
+        assert_eq!(kept(1.0), [0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn banning_every_token_does_not_panic() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let banned = vec![f32::NEG_INFINITY; 8];
+
+        assert_eq!(sample_top_p_top_k(&banned, 0, 1.0, &mut rng), 0);
+        assert_eq!(sample_top_p_top_k(&banned, 3, 0.9, &mut rng), 0);
+        let mut mu = 10.0;
+        let id = sample_mirostat_v2(&mut banned.clone(), 5.0, 0.1, &mut mu, &mut rng);
+        assert!((0..8).contains(&id));
+        assert_eq!(mu, 10.0);
+        assert!((0..8).contains(&sample_tail_free(&mut banned.clone(), 0.9, &mut rng)));
+        assert!((0..8).contains(&sample_typical(&mut banned.clone(), 0.9, &mut rng)));
+
+        // The tokens that aren't banned are sampled from, however unlikely.
+        let mut logits = banned;
+        logits[5] = -1e30;
+        assert_eq!(sample_top_p_top_k(&logits, 0, 1.0, &mut rng), 5);
+    }
+}
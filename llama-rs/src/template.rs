@@ -0,0 +1,194 @@
+//! Prompt templates, which wrap the user's input in the format that
+//! instruction-tuned models were trained on, and fill-in-the-middle prompts
+//! for code models.
+
+use std::{fmt::Display, str::FromStr};
+
+/// The format a prompt is rendered in.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum PromptTemplate {
+    /// The prompt as is, preceded by the system prompt if there is one.
+    #[default]
+    Raw,
+    /// The format of Stanford Alpaca and the models fine-tuned on its
+    /// dataset.
+    Alpaca,
+    /// The format of Vicuna v1.1.
+    Vicuna,
+    /// The ChatML format, in which each message is delimited by the special
+    /// tokens `<|im_start|>` and `<|im_end|>`.
+    ChatMl,
+    /// A custom format, in which `{system}` is replaced by the system prompt
+    /// and `{prompt}` by the user's input.
+    Custom(String),
+}
+
+impl PromptTemplate {
+    /// Returns the system prompt used when none is provided.
+    pub fn default_system_prompt(&self) -> &'static str {
+        match self {
+            PromptTemplate::Raw | PromptTemplate::ChatMl | PromptTemplate::Custom(_) => "",
+            PromptTemplate::Alpaca => {
+                "Below is an instruction that describes a task. Write a response that \
+                 appropriately completes the request."
+            }
+            PromptTemplate::Vicuna => {
+                "A chat between a curious user and an artificial intelligence assistant. The \
+                 assistant gives helpful, detailed, and polite answers to the user's questions."
+            }
+        }
+    }
+
+    /// Renders the user's input with the template. If `system` is `None`,
+    /// the template's default system prompt is used.
+    pub fn render(&self, system: Option<&str>, user: &str) -> String {
+        let system = system.unwrap_or_else(|| self.default_system_prompt());
+        match self {
+            PromptTemplate::Raw if system.is_empty() => user.to_owned(),
+            PromptTemplate::Raw => format!("{system}\n\n{user}"),
+            PromptTemplate::Alpaca => {
+                format!("{system}\n\n### Instruction:\n{user}\n\n### Response:\n")
+            }
+            PromptTemplate::Vicuna => format!("{system} USER: {user} ASSISTANT:"),
+            PromptTemplate::ChatMl if system.is_empty() => {
+                format!("<|im_start|>user\n{user}<|im_end|>\n<|im_start|>assistant\n")
+            }
+            PromptTemplate::ChatMl => format!(
+                "<|im_start|>system\n{system}<|im_end|>\n\
+                 <|im_start|>user\n{user}<|im_end|>\n<|im_start|>assistant\n"
+            ),
+            PromptTemplate::Custom(format) => {
+                format.replace("{system}", system).replace("{prompt}", user)
+            }
+        }
+    }
+
+    /// Returns the texts of the special tokens the template uses, which
+    /// should be registered with
+    /// [Vocabulary::add_special_token](crate::Vocabulary::add_special_token)
+    /// so that they are tokenized as single tokens.
+    pub fn special_tokens(&self) -> &'static [&'static str] {
+        match self {
+            PromptTemplate::ChatMl => &["<|im_start|>", "<|im_end|>"],
+            _ => &[],
+        }
+    }
+}
+
+/// The special tokens of fill-in-the-middle prompts, as named in Code Llama's
+/// vocabulary: the markers of the prefix, of the suffix and of the middle,
+/// and the end of the generated middle. They should be registered with
+/// [Vocabulary::add_special_token](crate::Vocabulary::add_special_token) so
+/// that they are tokenized as single tokens.
+pub const FIM_SPECIAL_TOKENS: [&str; 4] = ["<PRE>", "<SUF>", "<MID>", "<EOT>"];
+
+/// Renders a fill-in-the-middle prompt, after which code models such as Code
+/// Llama generate the text that goes between `prefix` and `suffix`, followed
+/// by `<EOT>`.
+pub fn render_fim(prefix: &str, suffix: &str) -> String {
+    format!("<PRE>{prefix}<SUF>{suffix}<MID>")
+}
+
+impl FromStr for PromptTemplate {
+    type Err = String;
+
+    /// Parses the name of a built-in template, or a custom format containing
+    /// `{prompt}`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "raw" => Ok(PromptTemplate::Raw),
+            "alpaca" => Ok(PromptTemplate::Alpaca),
+            "vicuna" => Ok(PromptTemplate::Vicuna),
+            "chatml" => Ok(PromptTemplate::ChatMl),
+            _ if s.contains("{prompt}") => Ok(PromptTemplate::Custom(s.to_owned())),
+            _ => Err(format!(
+                "unknown template {s:?}, expected raw, alpaca, vicuna, chatml or a format containing \
+                 {{prompt}}"
+            )),
+        }
+    }
+}
+
+impl Display for PromptTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PromptTemplate::Raw => write!(f, "raw"),
+            PromptTemplate::Alpaca => write!(f, "alpaca"),
+            PromptTemplate::Vicuna => write!(f, "vicuna"),
+            PromptTemplate::ChatMl => write!(f, "chatml"),
+            PromptTemplate::Custom(format) => write!(f, "{format}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_in_templates() {
+        assert_eq!(PromptTemplate::Raw.render(None, "Hi"), "Hi");
+        assert_eq!(
+            PromptTemplate::Raw.render(Some("Be brief."), "Hi"),
+            "Be brief.\n\nHi"
+        );
+        assert_eq!(
+            PromptTemplate::Alpaca.render(Some("Be brief."), "Hi"),
+            "Be brief.\n\n### Instruction:\nHi\n\n### Response:\n"
+        );
+        assert_eq!(
+            PromptTemplate::Vicuna.render(Some("Be brief."), "Hi"),
+            "Be brief. USER: Hi ASSISTANT:"
+        );
+        assert_eq!(
+            PromptTemplate::Vicuna.render(None, "Hi"),
+            format!(
+                "{} USER: Hi ASSISTANT:",
+                PromptTemplate::Vicuna.default_system_prompt()
+            )
+        );
+    }
+
+    #[test]
+    fn chatml_template() {
+        assert_eq!(
+            PromptTemplate::ChatMl.render(None, "Hi"),
+            "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(
+            PromptTemplate::ChatMl.render(Some("Be brief."), "Hi"),
+            "<|im_start|>system\nBe brief.<|im_end|>\n\
+             <|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(
+            PromptTemplate::ChatMl.special_tokens(),
+            ["<|im_start|>", "<|im_end|>"]
+        );
+        assert!(PromptTemplate::Alpaca.special_tokens().is_empty());
+    }
+
+    #[test]
+    fn custom_templates() {
+        let template = PromptTemplate::Custom("[{system}] {prompt} =>".to_owned());
+        assert_eq!(
+            template.render(Some("Be brief."), "Hi"),
+            "[Be brief.] Hi =>"
+        );
+        assert_eq!(template.render(None, "Hi"), "[] Hi =>");
+    }
+
+    #[test]
+    fn templates_are_parsed_from_their_names() {
+        for template in [
+            PromptTemplate::Raw,
+            PromptTemplate::Alpaca,
+            PromptTemplate::Vicuna,
+            PromptTemplate::ChatMl,
+            PromptTemplate::Custom("Q: {prompt}\nA:".to_owned()),
+        ] {
+            assert_eq!(template.to_string().parse(), Ok(template));
+        }
+        assert_eq!("Alpaca".parse(), Ok(PromptTemplate::Alpaca));
+        assert!("llama".parse::<PromptTemplate>().is_err());
+    }
+}
@@ -0,0 +1,269 @@
+//! Writes tiny models with random weights, so that the loader and the
+//! evaluation can be tested without a real model.
+
+use std::{
+    fs::File,
+    io::Write,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use rand::{Rng, SeedableRng};
+
+use crate::{ggml, FileFormat, InferenceParameters, Llama, Vocabulary};
+
+/// The description of a model file, written with [TestModel::write].
+#[derive(Clone, Debug)]
+pub(crate) struct TestModel {
+    pub format: FileFormat,
+    pub n_parts: usize,
+    pub n_vocab: Option<usize>,
+    pub n_embd: i32,
+    pub n_mult: i32,
+    pub n_head: i32,
+    pub n_layer: i32,
+    pub seed: u64,
+    /// Whether the matrices are stored as `f16`, instead of `f32`. The
+    /// vectors are `f32` either way, as in converted models.
+    pub f16: bool,
+    /// The first tokens of the vocabulary.
+    pub special_tokens: [&'static str; 3],
+    /// Tensors written after those of the model, as (name, dimensions).
+    pub extra_tensors: Vec<(String, Vec<i32>)>,
+    /// Tokens added to the vocabulary after the default ones.
+    pub extra_tokens: Vec<Vec<u8>>,
+}
+
+impl Default for TestModel {
+    fn default() -> Self {
+        Self {
+            format: FileFormat::Ggjt,
+            n_parts: 1,
+            n_vocab: None,
+            n_embd: 64,
+            n_mult: 32,
+            n_head: 2,
+            n_layer: 2,
+            seed: 0,
+            f16: false,
+            special_tokens: ["<unk>", "<s>", "</s>"],
+            extra_tensors: Vec::new(),
+            extra_tokens: Vec::new(),
+        }
+    }
+}
+
+/// The tokens of every test model: the special tokens, the byte tokens, and
+/// a few words.
+pub(crate) const WORDS: &[&str] = &[
+    " ", "a", "b", "c", "d", "e", "h", "l", "o", " he", "llo", " hello", "\n", " world", "wor",
+    "ld", ",", "!",
+];
+
+/// Returns a path in the temporary directory that no other test uses.
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("llama-rs-test-{}-{n}-{name}", std::process::id()))
+}
+
+/// Parameters evaluating on a single thread, so that the results are the
+/// same on every machine.
+pub(crate) fn inference_parameters() -> InferenceParameters {
+    InferenceParameters {
+        n_threads: 1,
+        ..Default::default()
+    }
+}
+
+impl TestModel {
+    pub fn vocabulary(&self) -> Vec<Vec<u8>> {
+        let mut vocab: Vec<Vec<u8>> = self
+            .special_tokens
+            .iter()
+            .map(|token| token.as_bytes().to_vec())
+            .collect();
+        vocab.extend((0..=255u8).map(|byte| format!("<0x{byte:02X}>").into_bytes()));
+        vocab.extend(WORDS.iter().map(|word| word.as_bytes().to_vec()));
+        vocab.extend(self.extra_tokens.iter().cloned());
+        if let Some(n_vocab) = self.n_vocab {
+            let n = vocab.len();
+            vocab.extend((n..n_vocab).map(|i| format!("tok{i}").into_bytes()));
+            vocab.truncate(n_vocab);
+        }
+        vocab
+    }
+
+    pub fn n_ff(&self) -> i32 {
+        ((2 * (4 * self.n_embd) / 3 + self.n_mult - 1) / self.n_mult) * self.n_mult
+    }
+
+    /// Returns the name, dimensions and split type of every tensor, in file
+    /// order. Tensors with a split type of `None` are not split.
+    pub fn tensors(&self) -> Vec<(String, Vec<i32>, Option<usize>)> {
+        let n_vocab = self.vocabulary().len() as i32;
+        let (n_embd, n_ff) = (self.n_embd, self.n_ff());
+        let mut tensors = vec![
+            (
+                "tok_embeddings.weight".to_owned(),
+                vec![n_embd, n_vocab],
+                Some(0),
+            ),
+            ("norm.weight".to_owned(), vec![n_embd], None),
+            ("output.weight".to_owned(), vec![n_embd, n_vocab], Some(1)),
+        ];
+        for i in 0..self.n_layer {
+            let layer = [
+                ("attention_norm.weight", vec![n_embd], None),
+                ("attention.wq.weight", vec![n_embd, n_embd], Some(1)),
+                ("attention.wk.weight", vec![n_embd, n_embd], Some(1)),
+                ("attention.wv.weight", vec![n_embd, n_embd], Some(1)),
+                ("attention.wo.weight", vec![n_embd, n_embd], Some(0)),
+                ("ffn_norm.weight", vec![n_embd], None),
+                ("feed_forward.w1.weight", vec![n_embd, n_ff], Some(1)),
+                ("feed_forward.w2.weight", vec![n_ff, n_embd], Some(0)),
+                ("feed_forward.w3.weight", vec![n_embd, n_ff], Some(1)),
+            ];
+            tensors.extend(
+                layer
+                    .into_iter()
+                    .map(|(name, dims, split)| (format!("layers.{i}.{name}"), dims, split)),
+            );
+        }
+        tensors.extend(
+            self.extra_tensors
+                .iter()
+                .map(|(name, dims)| (name.clone(), dims.clone(), None)),
+        );
+        tensors
+    }
+
+    /// Writes the model to a new temporary file, and its other parts next to
+    /// it, and returns the path of the first part.
+    pub fn write(&self) -> PathBuf {
+        let path = temp_path("model.bin");
+        let vocab = self.vocabulary();
+        let tensors = self.tensors();
+
+        // The weights are generated once, then split between the parts.
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(self.seed);
+        let data: Vec<Vec<f32>> = tensors
+            .iter()
+            .map(|(_, dims, _)| {
+                let n: i32 = dims.iter().product();
+                if dims.len() == 1 {
+                    vec![1.0; n as usize]
+                } else {
+                    (0..n).map(|_| rng.gen_range(-0.17..0.17)).collect()
+                }
+            })
+            .collect();
+
+        for part in 0..self.n_parts {
+            let part_path = if part == 0 {
+                path.clone()
+            } else {
+                let mut name = path.file_name().unwrap().to_owned();
+                name.push(format!(".{part}"));
+                path.with_file_name(name)
+            };
+            let mut file = File::create(part_path).unwrap();
+            let mut bytes = Vec::new();
+            match self.format {
+                FileFormat::Ggml => bytes.extend(0x67676d6cu32.to_le_bytes()),
+                FileFormat::Ggmf => {
+                    bytes.extend(0x67676d66u32.to_le_bytes());
+                    bytes.extend(1u32.to_le_bytes());
+                }
+                FileFormat::Ggjt => {
+                    bytes.extend(0x67676a74u32.to_le_bytes());
+                    bytes.extend(1u32.to_le_bytes());
+                }
+            }
+            let n_rot = self.n_embd / self.n_head;
+            for value in [
+                vocab.len() as i32,
+                self.n_embd,
+                self.n_mult,
+                self.n_head,
+                self.n_layer,
+                n_rot,
+                i32::from(self.f16),
+            ] {
+                bytes.extend(value.to_le_bytes());
+            }
+            for (i, token) in vocab.iter().enumerate() {
+                bytes.extend((token.len() as i32).to_le_bytes());
+                bytes.extend(token);
+                if self.format != FileFormat::Ggml {
+                    bytes.extend((-(i as f32)).to_le_bytes());
+                }
+            }
+
+            for ((name, dims, split), data) in tensors.iter().zip(&data) {
+                let (dims, data) = self.part_of(part, dims, *split, data);
+                let f16 = self.f16 && dims.len() == 2;
+                bytes.extend((dims.len() as i32).to_le_bytes());
+                bytes.extend((name.len() as i32).to_le_bytes());
+                bytes.extend(i32::from(f16).to_le_bytes());
+                for dim in &dims {
+                    bytes.extend(dim.to_le_bytes());
+                }
+                bytes.extend(name.as_bytes());
+                if self.format == FileFormat::Ggjt {
+                    bytes.resize((bytes.len() + 31) / 32 * 32, 0);
+                }
+                for value in data {
+                    match f16 {
+                        true => bytes.extend(ggml::fp32_to_fp16(value).to_le_bytes()),
+                        false => bytes.extend(value.to_le_bytes()),
+                    }
+                }
+            }
+            file.write_all(&bytes).unwrap();
+        }
+        path
+    }
+
+    /// Returns the dimensions and the data of the slice of a tensor stored
+    /// in the given part.
+    fn part_of(
+        &self,
+        part: usize,
+        dims: &[i32],
+        split: Option<usize>,
+        data: &[f32],
+    ) -> (Vec<i32>, Vec<f32>) {
+        let n_parts = self.n_parts as i32;
+        match split {
+            Some(0) if self.n_parts > 1 => {
+                let (ne0, ne1) = (dims[0], dims[1]);
+                let n = ne0 / n_parts;
+                let start = part as i32 * n;
+                let data = (0..ne1)
+                    .flat_map(|row| {
+                        let row = (row * ne0 + start) as usize;
+                        data[row..row + n as usize].iter().copied()
+                    })
+                    .collect();
+                (vec![n, ne1], data)
+            }
+            Some(1) if self.n_parts > 1 => {
+                let (ne0, ne1) = (dims[0], dims[1]);
+                let n = ne1 / n_parts;
+                let start = (part as i32 * n * ne0) as usize;
+                (
+                    vec![ne0, n],
+                    data[start..start + (n * ne0) as usize].to_vec(),
+                )
+            }
+            _ => (dims.to_vec(), data.to_vec()),
+        }
+    }
+
+    /// Writes the model and loads it with a context window of `n_ctx`
+    /// tokens.
+    pub fn load(&self, n_ctx: i32) -> (Llama, Vocabulary) {
+        Llama::load(self.write(), n_ctx, |_| {}).unwrap()
+    }
+}
@@ -0,0 +1,2806 @@
+use std::{cell::Cell, path::PathBuf};
+
+use crate::test_model::{self, inference_parameters, TestModel};
+use crate::*;
+
+const TOKENS: &[TokenId] = &[1, 270, 271, 272, 273, 274, 275, 276];
+
+/// Feeds `tokens` to a new session of `model` with a context window of
+/// `n_ctx` tokens, and returns it.
+fn session_with(model: &Llama, n_ctx: usize, tokens: &[TokenId]) -> InferenceSession {
+    let mut session = model.start_session(Default::default());
+    if n_ctx != session.n_ctx() {
+        model.resize_context(&mut session, n_ctx).unwrap();
+    }
+    session
+        .feed_tokens(model, &inference_parameters(), tokens)
+        .unwrap();
+    session
+}
+
+#[test]
+fn resize_context_grows_a_session() {
+    let (model, _) = TestModel::default().load(8);
+    let mut session = session_with(&model, 8, &TOKENS[..6]);
+    model.resize_context(&mut session, 16).unwrap();
+    assert_eq!(session.n_ctx(), 16);
+    assert_eq!(model.n_ctx(), 8);
+
+    // The tokens that didn't fit before can now be evaluated, with the same
+    // results as in a session that was that large from the start.
+    let params = inference_parameters();
+    session.feed_tokens(&model, &params, &TOKENS[6..]).unwrap();
+    let expected = session_with(&model, 16, TOKENS);
+    assert_eq!(session.last_logits(), expected.last_logits());
+}
+
+#[test]
+fn resize_context_shrinks_a_session() {
+    let (model, _) = TestModel::default().load(16);
+    let mut session = session_with(&model, 16, &TOKENS[..4]);
+    model.resize_context(&mut session, 4).unwrap();
+    assert_eq!(session.n_ctx(), 4);
+
+    let expected = session_with(&model, 9, TOKENS);
+    model.resize_context(&mut session, 9).unwrap();
+    session
+        .feed_tokens(&model, &inference_parameters(), &TOKENS[4..])
+        .unwrap();
+    assert_eq!(session.last_logits(), expected.last_logits());
+}
+
+#[test]
+fn resize_context_rejects_a_window_too_small_for_the_session() {
+    let (model, _) = TestModel::default().load(16);
+    let mut session = session_with(&model, 16, &TOKENS[..6]);
+    let logits = session.last_logits().to_vec();
+
+    for n_ctx in [0, 5] {
+        assert!(matches!(
+            model.resize_context(&mut session, n_ctx),
+            Err(InferenceError::ContextTooSmall { n_past: 6, .. })
+        ));
+    }
+    assert_eq!(session.n_ctx(), 16);
+    assert_eq!(session.last_logits(), logits);
+    session
+        .feed_tokens(&model, &inference_parameters(), &TOKENS[6..])
+        .unwrap();
+}
+
+#[test]
+fn resize_context_leaves_other_sessions_unchanged() {
+    let (model, _) = TestModel::default().load(8);
+    let mut resized = session_with(&model, 8, &TOKENS[..2]);
+    let mut other = session_with(&model, 8, &TOKENS[..2]);
+    model.resize_context(&mut resized, 32).unwrap();
+
+    let params = inference_parameters();
+    other.feed_tokens(&model, &params, &TOKENS[2..6]).unwrap();
+    resized.feed_tokens(&model, &params, &TOKENS[2..6]).unwrap();
+    assert_eq!(other.n_ctx(), 8);
+    assert_eq!(other.last_logits(), resized.last_logits());
+}
+
+/// Writes a LoRA adapter with the given rank and alpha, and the given
+/// `A` and `B` matrices for each weight, and returns its path.
+pub(crate) fn write_lora(rank: i32, alpha: i32, tensors: &[(&str, [i32; 2], Vec<f32>)]) -> PathBuf {
+    let mut bytes = Vec::new();
+    for value in [ggml::FILE_MAGIC_GGLA, ggml::FORMAT_VERSION_GGLA] {
+        bytes.extend(value.to_le_bytes());
+    }
+    for value in [rank, alpha] {
+        bytes.extend(value.to_le_bytes());
+    }
+    for (name, ne, data) in tensors {
+        for value in [2, name.len() as i32, 0, ne[0], ne[1]] {
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes.extend(name.as_bytes());
+        bytes.resize((bytes.len() + 31) / 32 * 32, 0);
+        for value in data {
+            bytes.extend(value.to_le_bytes());
+        }
+    }
+    let path = test_model::temp_path("lora.bin");
+    std::fs::write(&path, bytes).unwrap();
+    path
+}
+
+fn load_for_lora() -> Llama {
+    let path = TestModel::default().write();
+    Llama::load_with_strategy(path, 8, LoadStrategy::Read, |_| {})
+        .unwrap()
+        .0
+}
+
+#[test]
+fn apply_lora_adds_the_product_of_the_matrices() {
+    let mut model = load_for_lora();
+    let name = "layers.0.attention.wq.weight";
+    let before = model.tensor_to_f32(name).unwrap();
+
+    // With a rank of 1, B A is the outer product of the columns of B and A.
+    let n_embd = 64;
+    let a: Vec<f32> = (0..n_embd).map(|i| i as f32 / 64.0).collect();
+    let b: Vec<f32> = (0..n_embd).map(|i| 1.0 - i as f32 / 32.0).collect();
+    let lora = write_lora(
+        1,
+        2,
+        &[
+            (&format!("{name}.loraA"), [1, n_embd], a.clone()),
+            (&format!("{name}.loraB"), [1, n_embd], b.clone()),
+        ],
+    );
+    model.apply_lora(lora, 0.5).unwrap();
+
+    let after = model.tensor_to_f32(name).unwrap();
+    for (i, (&before, &after)) in before.iter().zip(&after).enumerate() {
+        let (row, column) = (i / n_embd as usize, i % n_embd as usize);
+        let expected = before + b[row] * a[column];
+        assert!((after - expected).abs() < 1e-5, "{after} != {expected}");
+    }
+}
+
+#[test]
+fn apply_lora_leaves_the_weights_unchanged_if_an_adapter_is_invalid() {
+    let mut model = load_for_lora();
+    let names = [
+        "layers.0.attention.wq.weight",
+        "layers.1.attention.wq.weight",
+    ];
+    let before: Vec<_> = names.iter().map(|name| model.tensor_to_f32(name)).collect();
+
+    let valid = |name: &str| {
+        [
+            (format!("{name}.loraA"), [1, 64], vec![1.0; 64]),
+            (format!("{name}.loraB"), [1, 64], vec![1.0; 64]),
+        ]
+    };
+    let invalid_adapters = [
+        // The second weight has a matrix of the wrong shape.
+        vec![
+            (format!("{}.loraA", names[1]), [1, 32], vec![1.0; 32]),
+            (format!("{}.loraB", names[1]), [1, 64], vec![1.0; 64]),
+        ],
+        // The second weight doesn't exist.
+        valid("layers.9.attention.wq.weight").to_vec(),
+        // The second weight only has one of its matrices.
+        valid(names[1])[..1].to_vec(),
+    ];
+    for invalid in invalid_adapters {
+        let tensors: Vec<_> = valid(names[0]).into_iter().chain(invalid).collect();
+        let tensors: Vec<_> = tensors
+            .iter()
+            .map(|(name, ne, data)| (name.as_str(), *ne, data.clone()))
+            .collect();
+        let lora = write_lora(1, 1, &tensors);
+        assert!(model.apply_lora(lora, 1.0).is_err());
+
+        let after: Vec<_> = names.iter().map(|name| model.tensor_to_f32(name)).collect();
+        assert_eq!(before, after);
+    }
+}
+
+#[test]
+fn load_tensors_into_leaves_the_skipped_tensors_untouched() {
+    use std::io::{Seek, SeekFrom};
+
+    let mut model = load_for_lora();
+    let other_path = TestModel {
+        seed: 1,
+        ..Default::default()
+    }
+    .write();
+    let (other, _) = Llama::load_with_strategy(&other_path, 8, LoadStrategy::Read, |_| {}).unwrap();
+    let header = Llama::load_header_only(&other_path, 8).unwrap();
+
+    // The first tensor, one in the middle, and the last one.
+    let skip: HashSet<String> = [
+        "tok_embeddings.weight",
+        "layers.0.attention.wq.weight",
+        "layers.1.feed_forward.w3.weight",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect();
+    let before: HashMap<_, _> = skip
+        .iter()
+        .map(|name| (name, model.tensor_to_f32(name).unwrap()))
+        .collect();
+    let mut file = std::fs::File::open(&other_path).unwrap();
+    file.seek(SeekFrom::Start(header.tensors_offset)).unwrap();
+    model.load_tensors_into(file, &skip).unwrap();
+
+    for (name, _, _) in TestModel::default().tensors() {
+        let data = model.tensor_to_f32(&name).unwrap();
+        match before.get(&name) {
+            Some(before) => assert_eq!(&data, before, "{name}"),
+            None => assert_eq!(data, other.tensor_to_f32(&name).unwrap(), "{name}"),
+        }
+        assert_ne!(
+            skip.contains(&name),
+            data == other.tensor_to_f32(&name).unwrap(),
+            "{name}"
+        );
+    }
+}
+
+/// Evaluates `tokens` after `n_past` tokens in each of the `sessions`, and
+/// returns the memory the evaluation used, and the estimate of it.
+fn eval_ctx_usage(
+    model: &Llama,
+    sessions: &mut [InferenceSession],
+    n_past: usize,
+    tokens: &[TokenId],
+    params: &InferenceParameters,
+) -> (usize, usize) {
+    let history: Vec<TokenId> = (0..n_past).map(|i| TOKENS[i % TOKENS.len()]).collect();
+    for session in sessions.iter_mut() {
+        let params = InferenceParameters {
+            n_batch: n_past.max(1),
+            ..inference_parameters()
+        };
+        session.feed_tokens(model, &params, &history).unwrap();
+    }
+
+    let mut sequences: Vec<_> = sessions.iter_mut().map(|s| (s, tokens)).collect();
+    model.evaluate_sequences(&mut sequences, params, &mut Default::default());
+    let used = sequences[0].0.scratch.as_ref().unwrap().used_mem();
+    let n = tokens.len() * sequences.len();
+    let estimate = estimate_eval_ctx_size(&model.hparams, n, sequences.len(), params);
+    (used, estimate)
+}
+
+#[test]
+fn estimate_eval_ctx_size_bounds_the_memory_used() {
+    let n_ctx = 64;
+    let (model, _) = TestModel::default().load(n_ctx as i32);
+    for n in [1, 4, 17] {
+        for n_past in [0, n_ctx - n - 1] {
+            for (n_threads, increased_determinism) in [(1, false), (2, false), (1, true)] {
+                for (memory_type, n_seq) in [
+                    (ModelKVMemoryType::Float32, 1),
+                    (ModelKVMemoryType::Float16, 1),
+                    (ModelKVMemoryType::Float16, 3),
+                ] {
+                    let params = InferenceParameters {
+                        n_threads,
+                        n_batch: 1,
+                        increased_determinism,
+                        ..Default::default()
+                    };
+                    let mut sessions: Vec<_> = (0..n_seq)
+                        .map(|_| {
+                            model.start_session(InferenceSessionParameters {
+                                memory_k_type: memory_type,
+                                memory_v_type: memory_type,
+                                seed: None,
+                            })
+                        })
+                        .collect();
+                    let tokens = &vec![270; n / n_seq + 1][..n / n_seq + 1];
+                    let (used, estimate) =
+                        eval_ctx_usage(&model, &mut sessions, n_past, tokens, &params);
+                    let case = format!(
+                        "{n} tokens after {n_past}, {n_threads} threads, \
+                         {increased_determinism}, {memory_type:?}, {n_seq} sequences"
+                    );
+                    assert!(used <= estimate, "{case}: {used} > {estimate}");
+                    // The estimate is not much larger than needed when the
+                    // tokens fill the end of the context window.
+                    if n_past + tokens.len() == n_ctx {
+                        assert!(estimate < used + used / 8, "{case}: {used} << {estimate}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Evaluates `tokens` with a straightforward implementation of the forward
+/// pass of LLaMA, in f64, and returns the logits of each token.
+fn reference_logits(model: &Llama, tokens: &[TokenId]) -> Vec<Vec<f64>> {
+    let Hyperparameters {
+        n_embd,
+        n_head,
+        n_vocab,
+        ..
+    } = model.hparams;
+    let (n_embd, n_head, n_vocab) = (n_embd as usize, n_head as usize, n_vocab as usize);
+    let n_ff = model.hparams.n_ff() as usize;
+    let head_dim = n_embd / n_head;
+    let weight = |name: &str| -> Vec<f64> {
+        let data = model.tensor_to_f32(name).unwrap();
+        data.into_iter().map(f64::from).collect()
+    };
+    // The tensors have a row of `x.len()` elements per output.
+    let mul_mat = |w: &[f64], x: &[f64]| -> Vec<f64> {
+        w.chunks(x.len())
+            .map(|row| row.iter().zip(x).map(|(w, x)| w * x).sum())
+            .collect()
+    };
+    let norm = |x: &[f64], w: &[f64]| -> Vec<f64> {
+        let mean = x.iter().sum::<f64>() / x.len() as f64;
+        let var = x.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / x.len() as f64;
+        let scale = 1.0 / (var + 1e-5).sqrt();
+        x.iter()
+            .zip(w)
+            .map(|(x, w)| (x - mean) * scale * w)
+            .collect()
+    };
+    let rope = |x: &mut [f64], position: usize| {
+        for head in x.chunks_mut(head_dim) {
+            for i in (0..head_dim).step_by(2) {
+                let theta = position as f64 * 10000f64.powf(-(i as f64) / head_dim as f64);
+                let (x0, x1) = (head[i], head[i + 1]);
+                head[i] = x0 * theta.cos() - x1 * theta.sin();
+                head[i + 1] = x0 * theta.sin() + x1 * theta.cos();
+            }
+        }
+    };
+
+    let embeddings = weight("tok_embeddings.weight");
+    let mut x: Vec<Vec<f64>> = tokens
+        .iter()
+        .map(|&t| embeddings[t as usize * n_embd..][..n_embd].to_vec())
+        .collect();
+
+    for il in 0..model.layers.len() {
+        let w = |name: &str| weight(&format!("layers.{il}.{name}.weight"));
+        let (wq, wk, wv, wo) = (
+            w("attention.wq"),
+            w("attention.wk"),
+            w("attention.wv"),
+            w("attention.wo"),
+        );
+        let (w1, w2, w3) = (
+            w("feed_forward.w1"),
+            w("feed_forward.w2"),
+            w("feed_forward.w3"),
+        );
+        let (attention_norm, ffn_norm) = (w("attention_norm"), w("ffn_norm"));
+
+        let normed: Vec<_> = x.iter().map(|x| norm(x, &attention_norm)).collect();
+        let mut q: Vec<_> = normed.iter().map(|x| mul_mat(&wq, x)).collect();
+        let mut k: Vec<_> = normed.iter().map(|x| mul_mat(&wk, x)).collect();
+        let v: Vec<_> = normed.iter().map(|x| mul_mat(&wv, x)).collect();
+        for (position, (q, k)) in q.iter_mut().zip(&mut k).enumerate() {
+            rope(q, position);
+            rope(k, position);
+        }
+
+        for t in 0..tokens.len() {
+            let mut attention = vec![0.0; n_embd];
+            for h in 0..n_head {
+                let range = h * head_dim..(h + 1) * head_dim;
+                let scores: Vec<f64> = (0..=t)
+                    .map(|s| {
+                        let dot: f64 = q[t][range.clone()]
+                            .iter()
+                            .zip(&k[s][range.clone()])
+                            .map(|(q, k)| q * k)
+                            .sum();
+                        dot / (head_dim as f64).sqrt()
+                    })
+                    .collect();
+                let max = scores.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                let exps: Vec<f64> = scores.iter().map(|s| (s - max).exp()).collect();
+                let sum: f64 = exps.iter().sum();
+                for (s, p) in exps.iter().enumerate() {
+                    for i in range.clone() {
+                        attention[i] += p / sum * v[s][i];
+                    }
+                }
+            }
+            let residual: Vec<f64> = mul_mat(&wo, &attention)
+                .iter()
+                .zip(&x[t])
+                .map(|(a, x)| a + x)
+                .collect();
+
+            let normed = norm(&residual, &ffn_norm);
+            let gate = mul_mat(&w1, &normed);
+            let up = mul_mat(&w3, &normed);
+            let hidden: Vec<f64> = gate
+                .iter()
+                .zip(&up)
+                .map(|(g, u)| g / (1.0 + (-g).exp()) * u)
+                .collect();
+            debug_assert_eq!(hidden.len(), n_ff);
+            let output = mul_mat(&w2, &hidden);
+            // Attention of the next tokens still needs the inputs of this
+            // one, but they were already used to compute `q`, `k` and `v`.
+            x[t] = output.iter().zip(&residual).map(|(o, r)| o + r).collect();
+        }
+    }
+
+    let (norm_weight, output) = (weight("norm.weight"), weight("output.weight"));
+    x.iter()
+        .map(|x| {
+            let logits = mul_mat(&output, &norm(x, &norm_weight));
+            debug_assert_eq!(logits.len(), n_vocab);
+            logits
+        })
+        .collect()
+}
+
+/// Asserts that logits computed by ggml are those of [reference_logits], up
+/// to the precision of the f16 tables ggml uses for `exp` and `silu`.
+fn assert_logits_eq(actual: &[f32], expected: &[f64]) {
+    assert_eq!(actual.len(), expected.len());
+    for (i, (&actual, &expected)) in actual.iter().zip(expected).enumerate() {
+        assert!(
+            (f64::from(actual) - expected).abs() < 2e-3,
+            "logit {i}: {actual} != {expected}"
+        );
+    }
+}
+
+#[test]
+fn evaluation_matches_the_reference_forward_pass() {
+    let (model, _) = TestModel::default().load(32);
+    let expected = reference_logits(&model, TOKENS);
+
+    // The tokens are evaluated in batches of different sizes, after the
+    // tokens already in the memory.
+    let mut session = model.start_session(Default::default());
+    let params = inference_parameters();
+    let mut n_evaluated = 0;
+    for batch in [&TOKENS[..1], &TOKENS[1..4], &TOKENS[4..]] {
+        let mut output = EvaluateOutputRequest {
+            all_logits: Some(Vec::new()),
+            ..Default::default()
+        };
+        model.evaluate(&mut session, &params, batch, &mut output);
+        let all_logits = output.all_logits.unwrap();
+        for (i, logits) in all_logits.chunks(model.n_vocab()).enumerate() {
+            assert_logits_eq(logits, &expected[n_evaluated + i]);
+        }
+        n_evaluated += batch.len();
+    }
+}
+
+#[test]
+fn batched_sequences_match_the_reference_forward_pass() {
+    let (model, _) = TestModel::default().load(32);
+    let prompts: [&[TokenId]; 3] = [&TOKENS[..3], &[1, 275, 271, 272, 276], &[1]];
+    let params = inference_parameters();
+    let mut sessions: Vec<_> = (0..prompts.len())
+        .map(|_| model.start_session(Default::default()))
+        .collect();
+
+    // The prompts are evaluated together, then one more token for each.
+    for step in [0, 1] {
+        let mut sequences: Vec<_> = sessions
+            .iter_mut()
+            .zip(prompts)
+            .map(|(session, prompt)| {
+                let tokens = if step == 0 { prompt } else { &[270][..] };
+                (session, tokens)
+            })
+            .collect();
+        model.evaluate_sequences(&mut sequences, &params, &mut Default::default());
+    }
+
+    for (session, prompt) in sessions.iter().zip(prompts) {
+        let tokens = [prompt, &[270]].concat();
+        let expected = reference_logits(&model, &tokens);
+        assert_logits_eq(session.last_logits(), expected.last().unwrap());
+    }
+}
+
+/// The hyperparameters of the released LLaMA models.
+fn llama_hyperparameters(n_embd: i32, n_head: i32, n_layer: i32) -> Hyperparameters {
+    Hyperparameters {
+        n_vocab: 32000,
+        n_ctx: 512,
+        n_embd,
+        n_mult: 256,
+        n_head,
+        n_layer,
+        n_rot: n_embd / n_head,
+        f16_: 1,
+    }
+}
+
+// The bookkeeping of ggml depends on the size of pointers.
+#[cfg(target_pointer_width = "64")]
+#[test]
+fn estimate_ctx_size_of_the_llama_models() {
+    let models = [
+        ((4096, 32, 32), 11008, 13_477_428_896, 4_212_473_504),
+        ((5120, 40, 40), 13824, 26_032_639_392, 8_136_396_192),
+        ((6656, 52, 60), 17920, 65_059_619_616, 20_333_429_536),
+        ((8192, 64, 80), 22016, 130_574_121_120, 40_808_151_200),
+    ];
+    for ((n_embd, n_head, n_layer), n_ff, f16_size, q4_0_size) in models {
+        let hparams = llama_hyperparameters(n_embd, n_head, n_layer);
+        assert_eq!(hparams.n_ff(), n_ff);
+        assert_eq!(
+            estimate_ctx_size(&hparams, GgmlType::F16, n_ff, 1),
+            f16_size
+        );
+        assert_eq!(
+            estimate_ctx_size(&hparams, GgmlType::Q4_0, n_ff, 1),
+            q4_0_size
+        );
+        // The parts are loaded into the same tensors.
+        assert_eq!(
+            estimate_ctx_size(&hparams, GgmlType::F16, n_ff, 4),
+            f16_size
+        );
+        assert_eq!(hparams.estimated_ctx_size().unwrap(), f16_size);
+    }
+}
+
+/// Returns the token sampled from `logits` by a session of `model`.
+fn sample_from(model: &Llama, logits: Vec<f32>, params: &InferenceParameters) -> TokenId {
+    let mut session = model.start_session(Default::default());
+    session.last_logits = logits;
+    session.sample(params)
+}
+
+#[test]
+fn a_temperature_of_zero_is_greedy_whatever_the_seed() {
+    let (model, vocab) = TestModel::default().load(8);
+    let modes = [
+        SamplingMode::Greedy,
+        SamplingMode::TopPTopK,
+        SamplingMode::MirostatV2 { tau: 5.0, eta: 0.1 },
+        SamplingMode::TailFree { z: 0.9 },
+        SamplingMode::Typical { p: 0.9 },
+    ];
+    for mode in modes {
+        let params = InferenceParameters {
+            temp: 0.0,
+            sampling_mode: mode,
+            penalties: Penalties {
+                repeat: 1.0,
+                ..Default::default()
+            },
+            ..inference_parameters()
+        };
+        let mut tokens = Vec::new();
+        for seed in 0..4 {
+            let mut session = model.start_session(InferenceSessionParameters {
+                seed: Some(seed),
+                ..Default::default()
+            });
+            session
+                .feed_prompt::<Infallible>(&model, &vocab, &params, " hello", |_| Ok(()))
+                .unwrap();
+            let logits = session.adjusted_logits(&params);
+            assert!(logits.iter().all(|logit| !logit.is_nan()), "{mode:?}");
+            let argmax = (0..logits.len())
+                .max_by(|&a, &b| logits[a].total_cmp(&logits[b]).then(b.cmp(&a)))
+                .unwrap() as TokenId;
+
+            // The random number generator isn't used either.
+            let rng = session.rng.clone();
+            assert_eq!(session.sample(&params), argmax, "{mode:?}");
+            assert!(session.rng == rng, "{mode:?}");
+            tokens.push(
+                session
+                    .infer_next_token(&model, &vocab, &params)
+                    .unwrap()
+                    .to_string(),
+            );
+        }
+        assert!(
+            tokens.windows(2).all(|w| w[0] == w[1]),
+            "{mode:?}: {tokens:?}"
+        );
+    }
+
+    // Negative and infinite logits don't produce NaNs either.
+    let mut logits = vec![-1.0; model.n_vocab()];
+    logits[5] = -0.5;
+    logits[6] = f32::NEG_INFINITY;
+    let params = InferenceParameters {
+        temp: 0.0,
+        ..inference_parameters()
+    };
+    assert_eq!(sample_from(&model, logits, &params), 5);
+}
+
+/// Returns the logits `[2, -2, 2, 2, 2]` adjusted by `penalties`, after the
+/// tokens `[3, 0, 1, 2, 2, 2]`. Only the last five tokens are penalized, so
+/// token 3 isn't.
+fn penalized_logits(model: &Llama, temp: f32, penalties: Penalties) -> Vec<f32> {
+    let mut session = model.start_session(Default::default());
+    session.tokens = vec![3, 0, 1, 2, 2, 2];
+    session.last_logits = vec![2.0, -2.0, 2.0, 2.0, 2.0];
+    let params = InferenceParameters {
+        temp,
+        penalties: Penalties {
+            repeat_last_n: 5,
+            ..penalties
+        },
+        ..inference_parameters()
+    };
+    session.adjusted_logits(&params)
+}
+
+#[test]
+fn penalties_only_change_the_logits_of_recent_tokens() {
+    let (model, _) = TestModel::default().load(8);
+    let none = Penalties {
+        repeat: 1.0,
+        frequency: 0.0,
+        presence: 0.0,
+        ..Default::default()
+    };
+
+    assert_eq!(
+        penalized_logits(&model, 1.0, none),
+        [2.0, -2.0, 2.0, 2.0, 2.0]
+    );
+    // The repetition penalty divides positive logits and multiplies negative
+    // ones, whatever the number of occurrences.
+    assert_eq!(
+        penalized_logits(
+            &model,
+            1.0,
+            Penalties {
+                repeat: 2.0,
+                ..none
+            }
+        ),
+        [1.0, -4.0, 1.0, 2.0, 2.0]
+    );
+    // The frequency penalty is subtracted once per occurrence.
+    assert_eq!(
+        penalized_logits(
+            &model,
+            1.0,
+            Penalties {
+                frequency: 0.5,
+                ..none
+            }
+        ),
+        [1.5, -2.5, 0.5, 2.0, 2.0]
+    );
+    // The presence penalty is subtracted once.
+    assert_eq!(
+        penalized_logits(
+            &model,
+            1.0,
+            Penalties {
+                presence: 0.25,
+                ..none
+            }
+        ),
+        [1.75, -2.25, 1.75, 2.0, 2.0]
+    );
+    // The penalties are scaled by the temperature, like the logits.
+    assert_eq!(
+        penalized_logits(
+            &model,
+            0.5,
+            Penalties {
+                presence: 0.25,
+                ..none
+            }
+        ),
+        [3.5, -4.5, 3.5, 4.0, 4.0]
+    );
+}
+
+#[test]
+fn special_tokens_default_to_those_of_llama() {
+    let (model, vocab) = TestModel::default().load(8);
+    assert_eq!(
+        (vocab.unk_token(), vocab.bos_token(), vocab.eos_token()),
+        (UNK_TOKEN_ID, BOS_TOKEN_ID, EOD_TOKEN_ID)
+    );
+    assert_eq!(vocab.len(), model.n_vocab());
+}
+
+#[test]
+fn special_tokens_are_read_from_the_vocabulary() {
+    let (model, vocab) = TestModel {
+        special_tokens: ["</s>", "<unk>", "<s>"],
+        ..Default::default()
+    }
+    .load(8);
+    assert_eq!(
+        (vocab.eos_token(), vocab.unk_token(), vocab.bos_token()),
+        (0, 1, 2)
+    );
+    assert_eq!(model.bos_token, 2);
+    model.warmup(1).unwrap();
+
+    let tokens = model.tokenize(&vocab, " hello", true).unwrap();
+    assert_eq!(tokens.first(), Some(&2));
+
+    // The end of sentence is the most likely token, unless it is ignored.
+    let mut logits = vec![0.0; model.n_vocab()];
+    logits[0] = 2.0;
+    logits[EOD_TOKEN_ID as usize] = 1.0;
+    let mut params = InferenceParameters {
+        temp: 0.0,
+        ..inference_parameters()
+    };
+    assert_eq!(sample_from(&model, logits.clone(), &params), 0);
+    params.ignore_eos = true;
+    assert_eq!(sample_from(&model, logits, &params), EOD_TOKEN_ID);
+}
+
+#[test]
+fn logit_bias_changes_the_sampled_token() {
+    let (model, _) = TestModel::default().load(8);
+    let mut logits = vec![0.0; model.n_vocab()];
+    logits[270] = 3.0;
+    logits[272] = 2.0;
+    let mut params = InferenceParameters {
+        temp: 0.0,
+        ..inference_parameters()
+    };
+    assert_eq!(sample_from(&model, logits.clone(), &params), 270);
+
+    params.logit_bias.insert(270, -1.5);
+    assert_eq!(sample_from(&model, logits.clone(), &params), 272);
+    params.logit_bias.insert(260, 5.0);
+    assert_eq!(sample_from(&model, logits.clone(), &params), 260);
+
+    // Banned tokens are never sampled, even if they are by far the most
+    // likely.
+    let mut params = InferenceParameters {
+        temp: 1.0,
+        ..inference_parameters()
+    };
+    logits[270] = 100.0;
+    params.ban_token(270);
+    let mut session = model.start_session(Default::default());
+    for _ in 0..32 {
+        session.last_logits = logits.clone();
+        assert_ne!(session.sample(&params), 270);
+    }
+}
+
+#[test]
+fn prompt_cache_restores_an_evaluated_prompt() {
+    let (model, vocab) = TestModel::default().load(16);
+    let dir = test_model::temp_path("prompt-cache");
+    let cache = prompt_cache::PromptCache::new(&dir, "model");
+    let params = inference_parameters();
+    let session_params = InferenceSessionParameters {
+        seed: Some(1),
+        ..Default::default()
+    };
+    let start = |model: &Llama, cache, prompt| {
+        model
+            .start_session_cached(session_params, cache, &vocab, &params, prompt)
+            .unwrap()
+    };
+
+    let (evaluated, restored) = start(&model, &cache, " hello world");
+    assert!(!restored);
+    let (mut cached, restored) = start(&model, &cache, " hello world");
+    assert!(restored);
+    assert_eq!(cached.tokens(), evaluated.tokens());
+    assert_eq!(cached.n_past(), evaluated.n_past());
+    assert_eq!(cached.last_logits(), evaluated.last_logits());
+
+    // The restored session generates like the evaluated one.
+    let mut evaluated = evaluated;
+    assert_eq!(cached.sample(&params), evaluated.sample(&params));
+
+    // Other prompts, models and hyperparameters are not in the cache.
+    assert!(!start(&model, &cache, " hello").1);
+    let other_cache = prompt_cache::PromptCache::new(&dir, "other model");
+    assert!(!start(&model, &other_cache, " hello world").1);
+    let (other_model, _) = TestModel::default().load(32);
+    assert!(!start(&other_model, &cache, " hello world").1);
+}
+
+#[test]
+fn prompt_cache_evaluates_a_corrupt_entry_again() {
+    let (model, vocab) = TestModel::default().load(16);
+    let dir = test_model::temp_path("prompt-cache");
+    let cache = prompt_cache::PromptCache::new(&dir, "model");
+    let params = inference_parameters();
+    let start = || {
+        model
+            .start_session_cached(Default::default(), &cache, &vocab, &params, " hello")
+            .unwrap()
+    };
+
+    let (evaluated, _) = start();
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        std::fs::write(entry.unwrap().path(), b"not a snapshot").unwrap();
+    }
+    let (session, restored) = start();
+    assert!(!restored);
+    assert_eq!(session.last_logits(), evaluated.last_logits());
+    assert!(start().1);
+}
+
+fn load_parts(path: &std::path::Path, parallel_load: bool) -> Result<Llama, LoadError> {
+    let params = LoadParameters {
+        parallel_load,
+        strategy: LoadStrategy::Read,
+        ..Default::default()
+    };
+    Llama::load_with_parameters(path, 8, params, |_| {}).map(|(model, _)| model)
+}
+
+#[test]
+fn parts_loaded_in_parallel_are_those_loaded_sequentially() {
+    let test_model = TestModel {
+        n_parts: 2,
+        n_vocab: Some(278),
+        ..Default::default()
+    };
+    let path = test_model.write();
+    let sequential = load_parts(&path, false).unwrap();
+    let parallel = load_parts(&path, true).unwrap();
+    let whole = TestModel {
+        n_parts: 1,
+        ..test_model.clone()
+    }
+    .load(8)
+    .0;
+
+    for (name, _, _) in test_model.tensors() {
+        let expected = whole.tensor_to_f32(&name).unwrap();
+        assert_eq!(sequential.tensor_to_f32(&name).unwrap(), expected, "{name}");
+        assert_eq!(parallel.tensor_to_f32(&name).unwrap(), expected, "{name}");
+    }
+}
+
+#[test]
+fn parts_that_do_not_split_evenly_are_rejected() {
+    // The 277 rows of the output can't be split in 2.
+    let path = TestModel {
+        n_parts: 2,
+        ..Default::default()
+    }
+    .write();
+    for parallel_load in [false, true] {
+        let err = load_parts(&path, parallel_load).err().unwrap();
+        assert!(
+            matches!(&err, LoadError::UnevenSplit { tensor_name, n_parts: 2, .. }
+                if tensor_name == "output.weight"),
+            "{err:?}"
+        );
+    }
+}
+
+#[test]
+fn tensor_names_of_other_converters_are_normalized() {
+    for (raw, expected) in [
+        ("model.embed_tokens.weight", Some("tok_embeddings.weight")),
+        ("model.norm.weight", Some("norm.weight")),
+        ("lm_head.weight", Some("output.weight")),
+        (
+            "model.layers.0.input_layernorm.weight",
+            Some("layers.0.attention_norm.weight"),
+        ),
+        (
+            "model.layers.0.self_attn.q_proj.weight",
+            Some("layers.0.attention.wq.weight"),
+        ),
+        (
+            "model.layers.1.self_attn.k_proj.weight",
+            Some("layers.1.attention.wk.weight"),
+        ),
+        (
+            "model.layers.2.self_attn.v_proj.weight",
+            Some("layers.2.attention.wv.weight"),
+        ),
+        (
+            "model.layers.3.self_attn.o_proj.weight",
+            Some("layers.3.attention.wo.weight"),
+        ),
+        (
+            "model.layers.10.post_attention_layernorm.weight",
+            Some("layers.10.ffn_norm.weight"),
+        ),
+        (
+            "model.layers.31.mlp.gate_proj.weight",
+            Some("layers.31.feed_forward.w1.weight"),
+        ),
+        (
+            "model.layers.31.mlp.down_proj.weight",
+            Some("layers.31.feed_forward.w2.weight"),
+        ),
+        (
+            "model.layers.31.mlp.up_proj.weight",
+            Some("layers.31.feed_forward.w3.weight"),
+        ),
+        // The names of the loader, and unknown names, have no alias.
+        ("tok_embeddings.weight", None),
+        ("layers.0.attention.wq.weight", None),
+        ("model.layers.0.self_attn.rotary_emb.inv_freq", None),
+        ("model.layers.x.self_attn.q_proj.weight", None),
+        ("model.layers.0", None),
+        ("", None),
+    ] {
+        assert_eq!(normalize_tensor_name(raw).as_deref(), expected, "{raw}");
+    }
+}
+
+/// Replaces the `i32` that is `before` bytes before the name of the tensor
+/// `name` in the model file at `path` by `value`.
+fn patch_tensor_header(path: &std::path::Path, name: &str, before: usize, value: i32) {
+    let mut bytes = std::fs::read(path).unwrap();
+    let name_start = bytes
+        .windows(name.len())
+        .position(|w| w == name.as_bytes())
+        .unwrap();
+    let offset = name_start - before;
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    std::fs::write(path, bytes).unwrap();
+}
+
+/// Replaces the dimension `i` of the tensor `name` in the model file at
+/// `path` by `dim`.
+fn corrupt_dim(path: &std::path::Path, name: &str, n_dims: usize, i: usize, dim: i32) {
+    patch_tensor_header(path, name, 4 * (n_dims - i), dim);
+}
+
+/// Replaces the ftype of the tensor `name` in the model file at `path`.
+fn corrupt_ftype(path: &std::path::Path, name: &str, n_dims: usize, ftype: i32) {
+    patch_tensor_header(path, name, 4 * n_dims + 4, ftype);
+}
+
+/// Writes a ggjt file with only the hyperparameters `[n_vocab, n_embd,
+/// n_mult, n_head, n_layer, n_rot, f16]`, and returns its path.
+fn write_header(hparams: [i32; 7]) -> PathBuf {
+    let mut bytes = Vec::new();
+    bytes.extend(0x67676a74u32.to_le_bytes());
+    bytes.extend(1u32.to_le_bytes());
+    for value in hparams {
+        bytes.extend(value.to_le_bytes());
+    }
+    let path = test_model::temp_path("header.bin");
+    std::fs::write(&path, bytes).unwrap();
+    path
+}
+
+#[test]
+fn hyperparameters_that_are_not_positive_are_rejected() {
+    let valid = [277, 64, 32, 2, 2, 32, 0];
+    let assert_invalid = |path: &std::path::Path, n_ctx, field: &str, invalid: i32| {
+        let errors = [
+            Llama::load(path, n_ctx, |_| {}).err().unwrap(),
+            Llama::load_header_only(path, n_ctx).err().unwrap(),
+        ];
+        for err in errors {
+            assert!(
+                matches!(&err, LoadError::InvalidHyperparameters { name, value, .. }
+                    if *name == field && *value == invalid),
+                "{field}: {err:?}"
+            );
+        }
+    };
+
+    for (i, field) in ["n_vocab", "n_embd", "n_mult", "n_head", "n_layer"]
+        .into_iter()
+        .enumerate()
+    {
+        for invalid in [0, -1] {
+            let mut hparams = valid;
+            hparams[i] = invalid;
+            assert_invalid(&write_header(hparams), 8, field, invalid);
+        }
+    }
+    assert_invalid(&write_header(valid), 0, "n_ctx", 0);
+}
+
+#[test]
+fn paths_that_are_not_readable_files_are_rejected() {
+    let missing = test_model::temp_path("missing.bin");
+    let err = Llama::load(&missing, 8, |_| {}).err().unwrap();
+    assert!(
+        matches!(&err, LoadError::FileNotFound { path } if *path == missing),
+        "{err:?}"
+    );
+
+    let directory = std::env::temp_dir();
+    for err in [
+        Llama::load(&directory, 8, |_| {}).err().unwrap(),
+        Llama::load_header_only(&directory, 8).err().unwrap(),
+        Llama::verify(&directory).err().unwrap(),
+    ] {
+        assert!(
+            matches!(&err, LoadError::IsADirectory { path } if *path == directory),
+            "{err:?}"
+        );
+    }
+}
+
+#[test]
+fn quantized_tensors_with_a_partial_block_are_rejected() {
+    for ftype in [2, 3] {
+        let path = TestModel::default().write();
+        corrupt_ftype(&path, "layers.0.ffn_norm.weight", 1, ftype);
+        corrupt_dim(&path, "layers.0.ffn_norm.weight", 1, 0, 63);
+        let err = Llama::load(&path, 8, |_| {}).err().unwrap();
+        assert!(
+            matches!(
+                &err,
+                LoadError::BlockSizeMismatch { tensor_name, nelements: 63, block_size: 32, .. }
+                    if tensor_name == "layers.0.ffn_norm.weight"
+            ),
+            "ftype {ftype}: {err:?}"
+        );
+    }
+}
+
+#[test]
+fn f16_models_keep_their_vectors_in_f32() {
+    let test_model = TestModel {
+        f16: true,
+        ..Default::default()
+    };
+    let (model, _) = test_model.load(8);
+    let (f32_model, _) = TestModel::default().load(8);
+    for (name, dims, _) in test_model.tensors() {
+        let expected_type = match dims.len() {
+            1 => ggml::TYPE_F32,
+            _ => ggml::TYPE_F16,
+        };
+        assert_eq!(model.tensors[&name].get_type(), expected_type, "{name}");
+        let values = model.tensor_to_f32(&name).unwrap();
+        let f32_values = f32_model.tensor_to_f32(&name).unwrap();
+        for (value, f32_value) in values.iter().zip(&f32_values) {
+            assert!((value - f32_value).abs() < 1e-3, "{name}");
+        }
+    }
+}
+
+#[test]
+fn tensors_of_another_type_than_the_model_are_rejected() {
+    // An f32 matrix in an f16 model, and an f16 matrix in an f32 model.
+    for (f16, ftype, expected_ftype) in [(true, 0, 1), (false, 1, 0)] {
+        let path = TestModel {
+            f16,
+            ..Default::default()
+        }
+        .write();
+        corrupt_ftype(&path, "layers.1.attention.wk.weight", 2, ftype);
+        let err = Llama::load(&path, 8, |_| {}).err().unwrap();
+        assert!(
+            matches!(
+                &err,
+                LoadError::TensorWrongType { tensor_name, ftype: actual, expected_ftype: expected, .. }
+                    if tensor_name == "layers.1.attention.wk.weight"
+                        && *actual == ftype
+                        && *expected == expected_ftype
+            ),
+            "{err:?}"
+        );
+    }
+}
+
+#[test]
+fn corrupted_dimensions_of_split_tensors_are_rejected() {
+    let test_model = TestModel {
+        n_parts: 2,
+        n_vocab: Some(278),
+        ..Default::default()
+    };
+    // wq is split into rows, and wo into columns.
+    for (name, i) in [
+        ("layers.0.attention.wq.weight", 1),
+        ("layers.0.attention.wo.weight", 0),
+    ] {
+        for dim in [40, 0, -1, i32::MAX] {
+            let path = test_model.write();
+            let mut part_name = path.file_name().unwrap().to_owned();
+            part_name.push(".1");
+            corrupt_dim(&path.with_file_name(part_name), name, 2, i, dim);
+
+            for parallel_load in [false, true] {
+                let err = load_parts(&path, parallel_load).err().unwrap();
+                assert!(
+                    matches!(&err, LoadError::TensorWrongSize { tensor_name, .. }
+                        if tensor_name == name),
+                    "{name}, ne[{i}] = {dim}: {err:?}"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn parts_are_the_numbered_siblings_of_the_main_file() {
+    let path = TestModel {
+        n_parts: 2,
+        n_vocab: Some(278),
+        ..Default::default()
+    }
+    .write();
+    let sibling = |suffix: &str| {
+        let mut name = path.file_name().unwrap().to_owned();
+        name.push(suffix);
+        path.with_file_name(name)
+    };
+    // Neither a file with another suffix nor one after a missing part is a
+    // part of the model.
+    std::fs::write(sibling(".bak"), b"").unwrap();
+    std::fs::write(sibling(".3"), b"").unwrap();
+
+    let header = Llama::load_header_only(&path, 8).unwrap();
+    assert_eq!(header.part_paths, [path.clone(), sibling(".1")]);
+    let (model, _) = Llama::load(&path, 8, |_| {}).unwrap();
+    assert_eq!(model.hparams.n_vocab, 278);
+}
+
+#[test]
+fn verify_accepts_a_valid_model() {
+    let test_model = TestModel::default();
+    let report = Llama::verify(test_model.write()).unwrap();
+    assert_eq!(report.tensor_count, test_model.tensors().len());
+    assert!(report.mismatches.is_empty(), "{:?}", report.mismatches);
+}
+
+#[test]
+fn verify_reports_the_tensors_that_do_not_match_the_hyperparameters() {
+    let path = TestModel {
+        extra_tensors: vec![("rope.freqs".to_owned(), vec![32])],
+        ..Default::default()
+    }
+    .write();
+    // Swapping the dimensions of w1 keeps the size of its data, so the rest
+    // of the file can still be read.
+    let mut bytes = std::fs::read(&path).unwrap();
+    let name = b"layers.0.feed_forward.w1.weight";
+    let dims = bytes.windows(name.len()).position(|w| w == name).unwrap() - 8;
+    bytes[dims..dims + 8].rotate_left(4);
+    std::fs::write(&path, bytes).unwrap();
+
+    let report = Llama::verify(&path).unwrap();
+    assert_eq!(
+        report.mismatches,
+        [
+            TensorMismatch::WrongShape {
+                tensor_name: "layers.0.feed_forward.w1.weight".to_owned(),
+                path: path.clone(),
+                expected: vec![64, 192],
+                actual: vec![192, 64],
+            },
+            TensorMismatch::Unknown {
+                tensor_name: "rope.freqs".to_owned(),
+                path: path.clone(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn verify_reports_a_truncated_file() {
+    let path = TestModel::default().write();
+    truncate(&path, 100);
+
+    let report = Llama::verify(&path).unwrap();
+    assert!(
+        matches!(report.mismatches[..], [TensorMismatch::Truncated { .. }]),
+        "{:?}",
+        report.mismatches
+    );
+}
+
+#[test]
+fn a_vocabulary_smaller_than_the_embeddings_is_rejected() {
+    let path = TestModel {
+        n_vocab: Some(278),
+        ..Default::default()
+    }
+    .write();
+    // Remove the last token, its length and its score, and claim a
+    // vocabulary of 277 tokens, so that the embeddings have one row too many.
+    let mut bytes = std::fs::read(&path).unwrap();
+    let token = bytes.windows(6).position(|w| w == b"tok277").unwrap();
+    bytes.drain(token - 4..token + 6 + 4);
+    bytes[8..12].copy_from_slice(&277i32.to_le_bytes());
+    std::fs::write(&path, bytes).unwrap();
+
+    let err = Llama::load(&path, 8, |_| {}).err().unwrap();
+    assert!(
+        matches!(
+            err,
+            LoadError::VocabMismatch {
+                n_vocab: 277,
+                actual: 278,
+                ..
+            }
+        ),
+        "{err:?}"
+    );
+}
+
+/// Removes the last `n` bytes of the file at `path`.
+fn truncate(path: &std::path::Path, n: u64) {
+    let file = std::fs::File::options().write(true).open(path).unwrap();
+    let len = file.metadata().unwrap().len();
+    file.set_len(len - n).unwrap();
+}
+
+#[test]
+fn files_that_end_within_a_tensor_are_truncated() {
+    let path = TestModel::default().write();
+    truncate(&path, 100);
+    for strategy in [LoadStrategy::Mmap, LoadStrategy::Read] {
+        let params = LoadParameters {
+            strategy,
+            ..Default::default()
+        };
+        let err = Llama::load_with_parameters(&path, 8, params, |_| {})
+            .err()
+            .unwrap();
+        assert!(
+            matches!(&err, LoadError::TruncatedFile { tensor_name, expected: 49152, .. }
+                if tensor_name == "layers.1.feed_forward.w3.weight"),
+            "{strategy:?}: {err:?}"
+        );
+    }
+
+    // The tensors split between parts are only half as long.
+    let path = TestModel {
+        n_parts: 2,
+        n_vocab: Some(278),
+        ..Default::default()
+    }
+    .write();
+    let mut name = path.file_name().unwrap().to_owned();
+    name.push(".1");
+    truncate(&path.with_file_name(name), 100);
+    for parallel_load in [false, true] {
+        let err = load_parts(&path, parallel_load).err().unwrap();
+        assert!(
+            matches!(&err, LoadError::TruncatedFile { tensor_name, expected: 24576, .. }
+                if tensor_name == "layers.1.feed_forward.w3.weight"),
+            "{err:?}"
+        );
+    }
+}
+
+#[test]
+fn cpu_features_agree_with_the_build() {
+    let features = cpu_features();
+
+    // ggml is built with at least the target features of the crate, and
+    // with the features of the host when it is the target.
+    let enabled = [
+        (cfg!(target_feature = "avx"), features.avx, "avx"),
+        (cfg!(target_feature = "avx2"), features.avx2, "avx2"),
+        (cfg!(target_feature = "fma"), features.fma, "fma"),
+        (cfg!(target_feature = "f16c"), features.f16c, "f16c"),
+        (cfg!(target_feature = "sse3"), features.sse3, "sse3"),
+        (cfg!(target_feature = "neon"), features.neon, "neon"),
+        (
+            cfg!(target_feature = "simd128"),
+            features.wasm_simd,
+            "simd128",
+        ),
+    ];
+    for (target_feature, reported, name) in enabled {
+        assert!(!target_feature || reported, "{name}");
+    }
+    #[cfg(target_arch = "x86_64")]
+    {
+        let detected = [
+            (std::is_x86_feature_detected!("avx"), features.avx, "avx"),
+            (std::is_x86_feature_detected!("avx2"), features.avx2, "avx2"),
+            (std::is_x86_feature_detected!("fma"), features.fma, "fma"),
+            (std::is_x86_feature_detected!("f16c"), features.f16c, "f16c"),
+            (std::is_x86_feature_detected!("sse3"), features.sse3, "sse3"),
+        ];
+        for (detected, reported, name) in detected {
+            // The kernels would crash on a CPU without their instructions.
+            assert!(detected || !reported, "{name}");
+        }
+    }
+
+    // The instructions of other architectures are never used.
+    if cfg!(not(target_arch = "aarch64")) && cfg!(not(target_arch = "arm")) {
+        assert!(!features.neon && !features.arm_fma && !features.fp16_va);
+    }
+    if cfg!(not(any(target_arch = "x86", target_arch = "x86_64"))) {
+        assert!(!features.avx && !features.avx2 && !features.avx512 && !features.sse3);
+    }
+    if cfg!(not(target_arch = "powerpc64")) {
+        assert!(!features.vsx);
+    }
+
+    let info = features.to_string();
+    assert!(
+        info.starts_with(&format!("AVX = {} | ", features.avx as u8)),
+        "{info}"
+    );
+    assert_eq!(info.split(" | ").count(), 12, "{info}");
+}
+
+#[test]
+fn tensor_info_describes_the_tensors_of_the_file() {
+    for f16 in [false, true] {
+        let test_model = TestModel {
+            f16,
+            ..Default::default()
+        };
+        let (model, _) = test_model.load(8);
+        let mut expected: Vec<_> = test_model
+            .tensors()
+            .into_iter()
+            .map(|(name, dims, _)| {
+                let dtype = match f16 && dims.len() == 2 {
+                    true => GgmlType::F16,
+                    false => GgmlType::F32,
+                };
+                let n_elements = dims.iter().product::<i32>() as usize;
+                TensorInfo {
+                    name,
+                    shape: dims.into_iter().map(i64::from).collect(),
+                    dtype,
+                    nbytes: n_elements * dtype.size(),
+                }
+            })
+            .collect();
+        expected.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let info = model.tensor_info();
+        assert_eq!(info.len(), 21);
+        assert_eq!(info, expected, "f16: {f16}");
+        let wq = info
+            .iter()
+            .find(|info| info.name == "layers.0.attention.wq.weight")
+            .unwrap();
+        assert_eq!(wq.nbytes, 64 * 64 * if f16 { 2 } else { 4 });
+    }
+}
+
+#[test]
+fn checksums_only_depend_on_the_tensor_data() {
+    let checksum = |path: &std::path::Path, strategy| {
+        let params = LoadParameters {
+            strategy,
+            checksum: true,
+            ..Default::default()
+        };
+        let (model, _) = Llama::load_with_parameters(path, 8, params, |_| {}).unwrap();
+        model.checksum().unwrap()
+    };
+    let path = TestModel::default().write();
+    let expected = checksum(&path, LoadStrategy::Mmap);
+    for strategy in [LoadStrategy::Mmap, LoadStrategy::Read] {
+        assert_eq!(checksum(&path, strategy), expected, "{strategy:?}");
+    }
+    let (model, _) = Llama::load(&path, 8, |_| {}).unwrap();
+    assert_eq!(model.checksum(), None);
+
+    // Flip a bit of the last tensor.
+    let mut bytes = std::fs::read(&path).unwrap();
+    *bytes.last_mut().unwrap() ^= 1;
+    std::fs::write(&path, bytes).unwrap();
+    for strategy in [LoadStrategy::Mmap, LoadStrategy::Read] {
+        assert_ne!(checksum(&path, strategy), expected, "{strategy:?}");
+    }
+}
+
+#[test]
+fn only_ggjt_tensor_data_is_aligned() {
+    for format in [FileFormat::Ggml, FileFormat::Ggmf, FileFormat::Ggjt] {
+        let test_model = TestModel {
+            format,
+            ..Default::default()
+        };
+        let path = test_model.write();
+        let bytes = std::fs::read(&path).unwrap();
+        let mut padded = 0;
+        for strategy in [LoadStrategy::Mmap, LoadStrategy::Read] {
+            let (model, _) = Llama::load_with_strategy(&path, 8, strategy, |_| {}).unwrap();
+            for (name, dims, _) in test_model.tensors() {
+                // The name is the end of the header, and follows its length.
+                let name_start = bytes
+                    .windows(name.len())
+                    .enumerate()
+                    .position(|(i, w)| {
+                        let len = (name.len() as i32).to_le_bytes();
+                        w == name.as_bytes() && bytes[i - 4 * dims.len() - 8..][..4] == len
+                    })
+                    .unwrap();
+                let name_end = name_start + name.len();
+                let data_offset = match format {
+                    FileFormat::Ggjt => (name_end + 31) / 32 * 32,
+                    _ => name_end,
+                };
+                padded += usize::from(data_offset != name_end);
+                let n_elements = dims.iter().product::<i32>() as usize;
+                let expected: Vec<f32> = bytes[data_offset..data_offset + 4 * n_elements]
+                    .chunks(4)
+                    .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                    .collect();
+                assert_eq!(
+                    model.tensor_to_f32(&name).unwrap(),
+                    expected,
+                    "{format:?}, {strategy:?}: {name}"
+                );
+            }
+        }
+        assert_eq!(padded > 0, format == FileFormat::Ggjt, "{format:?}");
+    }
+}
+
+#[test]
+fn read_errors_say_what_was_read_and_where() {
+    let name = "layers.1.feed_forward.w3.weight";
+    let path = TestModel::default().write();
+    let len = std::fs::metadata(&path).unwrap().len();
+    let data_offset = len - 49152;
+    truncate(&path, 100);
+    let err = Llama::load(&path, 8, |_| {}).err().unwrap();
+    assert!(
+        matches!(&err, LoadError::TruncatedFile { tensor_name, offset, .. }
+            if tensor_name == name && *offset == data_offset),
+        "{err:?}"
+    );
+    let message = err.to_string();
+    assert!(message.contains(name), "{message}");
+    assert!(message.contains(&format!("{data_offset:#x}")), "{message}");
+
+    // A file that ends within the header of a tensor fails before its name
+    // is known, so the error says where the header starts instead.
+    let bytes = std::fs::read(&path).unwrap();
+    let name_start = bytes
+        .windows(name.len())
+        .position(|w| w == name.as_bytes())
+        .unwrap();
+    let header_offset = (name_start - 20) as u64;
+    std::fs::write(&path, &bytes[..name_start + 5]).unwrap();
+    let err = Llama::load(&path, 8, |_| {}).err().unwrap();
+    assert!(
+        matches!(&err, LoadError::ReadFailed { what, offset, path: err_path, .. }
+            if what == "a tensor header" && *offset == header_offset && *err_path == path),
+        "{err:?}"
+    );
+    assert!(
+        err.to_string().contains(&format!("{header_offset:#x}")),
+        "{err}"
+    );
+}
+
+#[test]
+fn unknown_tensors_are_only_skipped_if_asked() {
+    let test_model = TestModel {
+        extra_tensors: vec![
+            ("rope.freqs".to_owned(), vec![32]),
+            ("extra.weight".to_owned(), vec![64, 4, 2]),
+        ],
+        ..Default::default()
+    };
+    let path = test_model.write();
+    let load = |strategy, skip_unknown_tensors| {
+        let params = LoadParameters {
+            strategy,
+            skip_unknown_tensors,
+            ..Default::default()
+        };
+        Llama::load_with_parameters(&path, 8, params, |_| {}).map(|(model, _)| model)
+    };
+    let without_extra_tensors = TestModel {
+        extra_tensors: Vec::new(),
+        ..test_model.clone()
+    };
+    let (expected, _) = without_extra_tensors.load(8);
+
+    for strategy in [LoadStrategy::Mmap, LoadStrategy::Read] {
+        let err = load(strategy, false).err().unwrap();
+        assert!(
+            matches!(&err, LoadError::UnknownTensor { tensor_name, .. } if tensor_name == "rope.freqs"),
+            "{strategy:?}: {err:?}"
+        );
+
+        let model = load(strategy, true).unwrap();
+        for (name, _, _) in without_extra_tensors.tensors() {
+            assert_eq!(
+                model.tensor_to_f32(&name).unwrap(),
+                expected.tensor_to_f32(&name).unwrap(),
+                "{strategy:?}: {name}"
+            );
+        }
+    }
+}
+
+#[test]
+fn context_shift_near_the_end_of_the_window() {
+    use ContextPolicy::*;
+    let shift = |policy, n_past, n_tokens| context_shift(policy, 16, n_past, n_tokens).ok();
+
+    // One slot is always left free, so a full window holds 15 tokens.
+    assert_eq!(shift(SlidingWindow, 15, 1), Some((1, 13)));
+    assert_eq!(shift(SlidingWindow, 14, 1), Some((1, 13)));
+    assert_eq!(shift(SlidingWindow, 15, 4), Some((1, 10)));
+    assert_eq!(shift(SlidingWindow, 15, 14), Some((1, 0)));
+    assert_eq!(shift(SlidingWindow, 15, 15), None);
+    assert_eq!(shift(SlidingWindow, 0, 16), None);
+    assert_eq!(shift(SlidingWindow, 0, 15), Some((0, 0)));
+
+    assert_eq!(shift(DiscardOldest { n_keep: 4 }, 15, 1), Some((4, 5)));
+    assert_eq!(shift(DiscardOldest { n_keep: 0 }, 15, 1), Some((1, 7)));
+    assert_eq!(shift(DiscardOldest { n_keep: 4 }, 15, 6), Some((4, 5)));
+    assert_eq!(shift(DiscardOldest { n_keep: 4 }, 15, 7), None);
+    assert_eq!(shift(DiscardOldest { n_keep: 20 }, 15, 1), None);
+    assert_eq!(shift(DiscardOldest { n_keep: 20 }, 2, 1), Some((2, 0)));
+
+    assert_eq!(shift(Error, 15, 1), None);
+}
+
+/// Feeds `tokens` one at a time to a session with a context window of 8
+/// tokens and `policy`, and returns it.
+fn session_with_policy(
+    model: &Llama,
+    session_params: InferenceSessionParameters,
+    policy: ContextPolicy,
+    tokens: &[TokenId],
+) -> InferenceSession {
+    let params = InferenceParameters {
+        context_policy: policy,
+        n_batch: 1,
+        ..inference_parameters()
+    };
+    let mut session = model.start_session(session_params);
+    session.feed_tokens(model, &params, tokens).unwrap();
+    session
+}
+
+#[test]
+fn sliding_window_shifts_the_memory_by_one_token() {
+    // With a single layer, the keys and values of a token only depend on
+    // the token and its position, so shifting them is exact.
+    let (model, _) = TestModel {
+        n_layer: 1,
+        ..Default::default()
+    }
+    .load(8);
+    let memory_types = [
+        (ModelKVMemoryType::Float32, 1e-5),
+        (ModelKVMemoryType::Float16, 1e-2),
+    ];
+    for (memory_type, tolerance) in memory_types {
+        let session_params = InferenceSessionParameters {
+            memory_k_type: memory_type,
+            memory_v_type: memory_type,
+            ..Default::default()
+        };
+        let mut tokens = TOKENS.to_vec();
+        tokens.extend([273, 3]);
+        let session = session_with_policy(
+            &model,
+            session_params,
+            ContextPolicy::SlidingWindow,
+            &tokens,
+        );
+
+        // Every new token past the 7th discarded the oldest one but the
+        // first.
+        let retained = [&tokens[..1], &tokens[4..]].concat();
+        assert_eq!(session.tokens(), retained);
+        assert_eq!(session.n_past(), 7);
+
+        let expected = session_with(&model, 8, &retained);
+        for (actual, expected) in session.last_logits().iter().zip(expected.last_logits()) {
+            assert!(
+                (actual - expected).abs() < tolerance,
+                "{actual} != {expected}"
+            );
+        }
+    }
+}
+
+#[test]
+fn discard_oldest_evaluates_the_retained_tokens_again() {
+    let (model, _) = TestModel::default().load(8);
+    let policy = ContextPolicy::DiscardOldest { n_keep: 2 };
+    let session = session_with_policy(&model, Default::default(), policy, TOKENS);
+
+    // When the 8th token came, 2 tokens were kept and 2 of the 5 others
+    // retained.
+    let retained = [&TOKENS[..2], &TOKENS[5..]].concat();
+    assert_eq!(session.tokens(), retained);
+    assert_eq!(session.n_past(), 5);
+
+    let expected = session_with(&model, 8, &retained);
+    for (actual, expected) in session.last_logits().iter().zip(expected.last_logits()) {
+        assert!((actual - expected).abs() < 1e-5, "{actual} != {expected}");
+    }
+}
+
+#[test]
+#[allow(deprecated)]
+fn n_keep_is_discard_oldest() {
+    let params = InferenceParameters {
+        n_keep: Some(3),
+        ..Default::default()
+    };
+    assert_eq!(
+        params.resolved_context_policy(),
+        ContextPolicy::DiscardOldest { n_keep: 3 }
+    );
+    let params = InferenceParameters {
+        n_keep: Some(3),
+        context_policy: ContextPolicy::SlidingWindow,
+        ..Default::default()
+    };
+    assert_eq!(
+        params.resolved_context_policy(),
+        ContextPolicy::SlidingWindow
+    );
+}
+
+#[test]
+fn warmup_reports_the_first_layer_with_a_non_finite_output() {
+    let model = load_for_lora();
+    model.warmup(1).unwrap();
+
+    // ggml asserts that the input of silu is not NaN, so the weights applied
+    // after it overflow instead.
+    let weight = &model.layers[1].w2;
+    let n = weight.nelements() as usize;
+    let data = match weight.nbytes() / n {
+        4 => f32::INFINITY.to_le_bytes().repeat(n),
+        _ => ggml::fp32_to_fp16(f32::INFINITY).to_le_bytes().repeat(n),
+    };
+    // SAFETY: The model was read into memory, and nothing else uses it.
+    unsafe { weight.write_data(&data) };
+    assert!(matches!(
+        model.warmup(1),
+        Err(InferenceError::NonFiniteLayerOutput { layer: 1 })
+    ));
+}
+
+#[test]
+fn shared_model_is_usable_from_other_threads() {
+    let (model, _) = TestModel::default().load(8);
+    let expected = session_with(&model, 8, &TOKENS[..4]).last_logits().to_vec();
+    let model = SharedModel::new(model);
+
+    // A thread panicking while it holds the lock doesn't make the model
+    // unusable.
+    let poisoner = model.clone();
+    std::thread::spawn(move || {
+        let _model = poisoner.lock();
+        panic!("poisoning the lock");
+    })
+    .join()
+    .unwrap_err();
+
+    let threads: Vec<_> = (0..2)
+        .map(|_| {
+            let model = model.clone();
+            std::thread::spawn(move || {
+                session_with(&model.lock(), 8, &TOKENS[..4])
+                    .last_logits()
+                    .to_vec()
+            })
+        })
+        .collect();
+    for thread in threads {
+        assert_eq!(thread.join().unwrap(), expected);
+    }
+}
+
+#[test]
+fn model_loaded_in_a_buffer_evaluates_like_the_model() {
+    let path = TestModel::default().write();
+    let params = LoadParameters {
+        strategy: LoadStrategy::Read,
+        ..Default::default()
+    };
+    let ctx_size = Cell::new(0);
+    let (model, _) = Llama::load_with_parameters(&path, 8, params, |progress| {
+        if let LoadProgress::ContextSize { bytes } = progress {
+            ctx_size.set(bytes);
+        }
+    })
+    .unwrap();
+    let expected = session_with(&model, 8, &TOKENS[..4]).last_logits().to_vec();
+    drop(model);
+
+    let buffer = vec![0u8; ctx_size.get() + ggml::MEM_ALIGN];
+    let (model, _) = Llama::load_with_buffer(&path, 8, params, buffer, |_| {}).unwrap();
+    assert_eq!(
+        session_with(&model, 8, &TOKENS[..4]).last_logits(),
+        expected
+    );
+
+    let buffer = vec![0u8; ctx_size.get() / 2];
+    assert!(matches!(
+        Llama::load_with_buffer(&path, 8, params, buffer, |_| {}),
+        Err(LoadError::ContextBufferTooSmall { .. })
+    ));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn hyperparameters_round_trip_through_json() {
+    let hparams = llama_hyperparameters(4096, 32, 32);
+    let json = hparams.to_json().unwrap();
+    assert_eq!(
+        json,
+        r#"{"n_vocab":32000,"n_ctx":512,"n_embd":4096,"n_mult":256,"n_head":32,"n_layer":32,"n_rot":128,"f16_":1}"#
+    );
+    assert_eq!(Hyperparameters::from_json(&json).unwrap(), hparams);
+    assert!(Hyperparameters::from_json(r#"{"n_vocab":32000}"#).is_err());
+}
+
+#[test]
+fn stop_sequences_are_found_in_the_text() {
+    let stop_sequences = ["lo".to_owned(), "hel".to_owned(), String::new()];
+    assert_eq!(
+        find_stop_sequence("oh, hello", &stop_sequences),
+        Some((4, "hel"))
+    );
+    assert_eq!(find_stop_sequence("oh, he", &stop_sequences), None);
+
+    assert_eq!(partial_stop_sequence_len("oh, he", &stop_sequences), 2);
+    assert_eq!(partial_stop_sequence_len("oh, l", &stop_sequences), 1);
+    assert_eq!(partial_stop_sequence_len("oh, hi", &stop_sequences), 0);
+    // Only whole characters of the stop sequence are held back.
+    assert_eq!(
+        partial_stop_sequence_len("a\u{e9}", &["\u{e9}t\u{e9}".to_owned()]),
+        2
+    );
+    assert_eq!(
+        partial_stop_sequence_len("a\u{c3}", &["\u{e9}".to_owned()]),
+        0
+    );
+}
+
+/// A model that generates the tokens of `script` after a prompt of
+/// `prompt_len` tokens, then the end of sentence.
+struct Script {
+    hparams: Hyperparameters,
+    prompt_len: usize,
+    script: Vec<TokenId>,
+}
+
+impl LanguageModel for Script {
+    fn hparams(&self) -> &Hyperparameters {
+        &self.hparams
+    }
+
+    fn evaluate(
+        &self,
+        session: &mut InferenceSession,
+        _params: &InferenceParameters,
+        input_tokens: &[TokenId],
+        _output_request: &mut EvaluateOutputRequest,
+    ) {
+        let n_evaluated = session.n_past() + input_tokens.len();
+        let next = self
+            .script
+            .get(n_evaluated - self.prompt_len)
+            .copied()
+            .unwrap_or(EOD_TOKEN_ID);
+        let mut logits = vec![0.0; self.n_vocab()];
+        logits[next as usize] = 1.0;
+        session.advance(input_tokens.len(), &logits);
+    }
+}
+
+#[test]
+fn streamed_text_is_split_between_characters() {
+    // The emoji U+1F600 is F0 9F 98 80 in UTF-8, and U+E9 is C3 A9.
+    let (model, vocab) = TestModel {
+        extra_tokens: vec![
+            vec![0xf0, 0x9f],
+            vec![0x98],
+            vec![0x80],
+            vec![0xc3],
+            vec![0xa9],
+        ],
+        ..Default::default()
+    }
+    .load(32);
+    let script = Script {
+        hparams: model.hparams().clone(),
+        prompt_len: 2,
+        script: vec![270, 277, 278, 279, 272, 280, 281, 276],
+    };
+    let params = InferenceParameters {
+        temp: 0.0,
+        ..inference_parameters()
+    };
+    let mut session = InferenceSession::new(script.hparams(), Default::default());
+    session.feed_tokens(&script, &params, &TOKENS[..2]).unwrap();
+
+    let mut stream = session.stream_text(&script, &vocab, &params);
+    let chunks: Vec<String> = stream.by_ref().collect::<Result<_, _>>().unwrap();
+    assert_eq!(chunks, [" hello", "\u{1f600}", " world", "\u{e9}", "!"]);
+    assert_eq!(stream.stop_reason(), Some(&StopReason::EndOfSequence));
+}
+
+/// Generates " world, hello!" after " hello" with `stop_sequences`, and
+/// returns the chunks of text passed to the callback and why generation
+/// stopped.
+fn generate_with_stop_sequences(stop_sequences: &[&str]) -> (Vec<String>, StopReason) {
+    let (model, vocab) = TestModel::default().load(32);
+    let token = |text: &str| model.tokenize(&vocab, text, false).unwrap()[0];
+    let script = Script {
+        hparams: model.hparams().clone(),
+        prompt_len: 2,
+        script: [" world", ",", " he", "llo", "!"]
+            .into_iter()
+            .map(token)
+            .collect(),
+    };
+
+    let params = InferenceParameters {
+        temp: 0.0,
+        echo_prompt: false,
+        stop_sequences: stop_sequences.iter().map(|s| s.to_string()).collect(),
+        ..inference_parameters()
+    };
+    let chunks = RefCell::new(Vec::new());
+    let mut session = InferenceSession::new(script.hparams(), Default::default());
+    let stats = session
+        .inference_with_prompt::<Infallible>(&script, &vocab, &params, " hello", None, |t, _| {
+            if let OutputToken::Token(text) = t {
+                chunks.borrow_mut().push(text.to_owned());
+            }
+            Ok(())
+        })
+        .unwrap();
+    (chunks.into_inner(), stats.stop_reason)
+}
+
+#[test]
+fn generation_stops_at_a_single_token_stop_sequence() {
+    let (chunks, stop_reason) = generate_with_stop_sequences(&[","]);
+    assert_eq!(chunks, [" world"]);
+    assert_eq!(stop_reason, StopReason::StopSequence(",".to_owned()));
+}
+
+#[test]
+fn generation_stops_at_a_stop_sequence_spanning_tokens() {
+    let (chunks, stop_reason) = generate_with_stop_sequences(&["hello", "xyz"]);
+    // The beginning of the stop sequence in " he" is held back.
+    assert_eq!(chunks, [" world", ",", " "]);
+    assert_eq!(stop_reason, StopReason::StopSequence("hello".to_owned()));
+}
+
+#[test]
+fn generation_emits_the_text_held_back_once_the_stop_sequence_is_not_completed() {
+    let (chunks, stop_reason) = generate_with_stop_sequences(&["hello?"]);
+    assert_eq!(chunks, [" world", ",", " ", "hello!"]);
+    assert_eq!(stop_reason, StopReason::EndOfSequence);
+}
+
+#[test]
+fn dump_graph_dot_writes_the_graph_of_the_model() {
+    let (model, _) = TestModel::default().load(8);
+    let path = test_model::temp_path("graph.dot");
+    model.dump_graph_dot(&TOKENS[..3], &path).unwrap();
+    let dot = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(dot.starts_with("digraph G {"), "{dot}");
+    // Operations are labelled with their index, name, shape and operation.
+    assert!(dot.contains(" Qcur-0 [64, 3] | "), "{dot}");
+    assert!(dot.contains(" logits [277, 3] | <x>X*Y"), "{dot}");
+    for il in 0..2 {
+        assert!(
+            dot.contains(&format!(" layer_out-{il} [64, 3] | ")),
+            "{dot}"
+        );
+    }
+
+    let too_many = vec![TOKENS[0]; 9];
+    let err = model.dump_graph_dot(&too_many, &path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn log_softmax_is_the_log_of_the_probability() {
+    // ln(e^3 / (e + e^2 + e^3)), and ln(3 / 4).
+    assert!((log_softmax(&[1.0, 2.0, 3.0], 2) - -0.407_605_96).abs() < 1e-6);
+    assert!((log_softmax(&[1.0, 2.0, 3.0], 0) - -2.407_605_96).abs() < 1e-6);
+    let logits = [0.0, 3f32.ln(), f32::NEG_INFINITY];
+    assert!((log_softmax(&logits, 1) - 0.75f64.ln()).abs() < 1e-6);
+    assert_eq!(log_softmax(&logits, 2), f64::NEG_INFINITY);
+    // Large logits don't overflow.
+    assert!((log_softmax(&[1000.0, 1000.0], 0) - 0.5f64.ln()).abs() < 1e-6);
+}
+
+#[test]
+fn score_sums_the_log_probabilities_of_the_continuation() {
+    let (model, _) = TestModel::default().load(32);
+    let prefix = &TOKENS[..2];
+    // Longer than a batch, so that the logits come from several evaluations.
+    let continuation: Vec<TokenId> = TOKENS[2..].iter().chain(&TOKENS[1..]).copied().collect();
+    assert!(prefix.len() + continuation.len() > InferenceParameters::default().n_batch);
+
+    let mut session = model.start_session(InferenceSessionParameters {
+        memory_k_type: ModelKVMemoryType::Float16,
+        memory_v_type: ModelKVMemoryType::Float16,
+        ..Default::default()
+    });
+    let tokens: Vec<TokenId> = prefix.iter().chain(&continuation).copied().collect();
+    let mut output_request = EvaluateOutputRequest {
+        all_logits: Some(Vec::new()),
+        ..Default::default()
+    };
+    model.evaluate(
+        &mut session,
+        &inference_parameters(),
+        &tokens,
+        &mut output_request,
+    );
+    let all_logits = output_request.all_logits.unwrap();
+    let n_vocab = model.n_vocab();
+    let expected: f64 = continuation
+        .iter()
+        .enumerate()
+        .map(|(i, &token)| {
+            let position = prefix.len() - 1 + i;
+            log_softmax(&all_logits[position * n_vocab..][..n_vocab], token)
+        })
+        .sum();
+
+    let score = model.score(prefix, &continuation, 1).unwrap();
+    assert!(score < 0.0);
+    assert!(
+        (f64::from(score) - expected).abs() < 1e-3,
+        "{score} != {expected}"
+    );
+    assert_eq!(model.score(prefix, &[], 1).unwrap(), 0.0);
+    let first = model.score(prefix, &continuation[..1], 1).unwrap();
+    let first_expected = log_softmax(&all_logits[n_vocab..][..n_vocab], continuation[0]);
+    assert!((f64::from(first) - first_expected).abs() < 1e-3);
+}
+
+#[test]
+fn hidden_states_hold_n_embd_values_per_token() {
+    let (model, _) = TestModel::default().load(32);
+    let (n_embd, n_vocab) = (64, model.n_vocab());
+    let tokens = &TOKENS[..3];
+    let evaluate = |layer| {
+        let mut session = model.start_session(Default::default());
+        model.evaluate_with_hidden_states(&mut session, &inference_parameters(), tokens, layer)
+    };
+
+    let (logits, all_layers) = evaluate(None);
+    assert_eq!(logits.len(), n_vocab * tokens.len());
+    assert_eq!(all_layers.len(), 2);
+    for hidden_state in &all_layers {
+        assert_eq!(hidden_state.len(), n_embd * tokens.len());
+    }
+    for (layer, hidden_state) in all_layers.iter().enumerate() {
+        let (layer_logits, hidden_states) = evaluate(Some(layer));
+        assert_eq!(layer_logits, logits);
+        assert_eq!(hidden_states, std::slice::from_ref(hidden_state));
+    }
+
+    // The last layer is the final one: the logits are its output, normalized
+    // and projected on the vocabulary.
+    let norm = model.tensor_to_f32("norm.weight").unwrap();
+    let output = model.tensor_to_f32("output.weight").unwrap();
+    for (hidden, logits) in all_layers[1].chunks(n_embd).zip(logits.chunks(n_vocab)) {
+        let mean = hidden.iter().sum::<f32>() / n_embd as f32;
+        let variance = hidden.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / n_embd as f32;
+        let normalized: Vec<f32> = hidden
+            .iter()
+            .zip(&norm)
+            .map(|(x, w)| (x - mean) / (variance + 1e-5).sqrt() * w)
+            .collect();
+        for (row, &logit) in output.chunks(n_embd).zip(logits) {
+            let expected: f32 = row.iter().zip(&normalized).map(|(a, b)| a * b).sum();
+            assert!((logit - expected).abs() < 1e-3, "{logit} != {expected}");
+        }
+    }
+}
+
+#[test]
+fn sessions_with_the_same_seed_generate_the_same_tokens() {
+    let (model, vocab) = TestModel::default().load(32);
+    let generate = |seed| {
+        let mut session = model.start_session(InferenceSessionParameters {
+            seed: Some(seed),
+            ..Default::default()
+        });
+        let params = InferenceParameters {
+            top_k: 0,
+            temp: 1.0,
+            ..inference_parameters()
+        };
+        session.feed_tokens(&model, &params, &TOKENS[..2]).unwrap();
+        for _ in 0..16 {
+            session.infer_next_token(&model, &vocab, &params).unwrap();
+        }
+        session.tokens().to_vec()
+    };
+
+    assert_eq!(generate(7), generate(7));
+    assert_ne!(generate(7), generate(8));
+}
+
+#[test]
+fn restored_snapshots_continue_like_the_original_session() {
+    let (model, vocab) = TestModel::default().load(32);
+    let params = InferenceParameters {
+        top_k: 0,
+        temp: 1.0,
+        ignore_eos: true,
+        ..inference_parameters()
+    };
+    let start = || {
+        let mut session = model.start_session(InferenceSessionParameters {
+            seed: Some(7),
+            ..Default::default()
+        });
+        session.feed_tokens(&model, &params, &TOKENS[..2]).unwrap();
+        session
+    };
+    let generate = |session: &mut InferenceSession, n| {
+        for _ in 0..n {
+            session.infer_next_token(&model, &vocab, &params).unwrap();
+        }
+    };
+
+    let mut uninterrupted = start();
+    generate(&mut uninterrupted, 16);
+
+    let mut session = start();
+    generate(&mut session, 6);
+    let mut bytes = Vec::new();
+    unsafe { session.get_snapshot() }.write(&mut bytes).unwrap();
+    drop(session);
+    let snapshot = InferenceSnapshot::read(&mut bytes.as_slice()).unwrap();
+    let mut restored = model.session_from_snapshot(snapshot).unwrap();
+    generate(&mut restored, 10);
+
+    assert_eq!(restored.tokens(), uninterrupted.tokens());
+    let bits = |session: &InferenceSession| {
+        session
+            .last_logits
+            .iter()
+            .map(|logit| logit.to_bits())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(bits(&restored), bits(&uninterrupted));
+}
+
+/// Greedy parameters with classifier-free guidance, and no repetition
+/// penalty.
+fn guidance_parameters(cfg_negative_prompt: Option<&str>, cfg_scale: f32) -> InferenceParameters {
+    InferenceParameters {
+        temp: 0.0,
+        penalties: Penalties {
+            repeat: 1.0,
+            ..Default::default()
+        },
+        cfg_negative_prompt: cfg_negative_prompt.map(str::to_owned),
+        cfg_scale,
+        ..inference_parameters()
+    }
+}
+
+/// Generates 8 tokens after the prompt `" hello"` with `params`, and returns
+/// the session.
+fn generate_with_guidance(
+    model: &Llama,
+    vocab: &Vocabulary,
+    params: &InferenceParameters,
+) -> InferenceSession {
+    let mut session = model.start_session(Default::default());
+    session
+        .feed_prompt::<Infallible>(model, vocab, params, " hello", |_| Ok(()))
+        .unwrap();
+    for _ in 0..8 {
+        session.infer_next_token(model, vocab, params).unwrap();
+    }
+    session
+}
+
+#[test]
+fn guidance_with_a_scale_of_one_is_normal_decoding() {
+    let (model, vocab) = TestModel::default().load(32);
+    let unguided = generate_with_guidance(&model, &vocab, &guidance_parameters(None, 1.0));
+    let guided = generate_with_guidance(&model, &vocab, &guidance_parameters(Some(" world"), 1.0));
+    assert!(guided.guidance.is_none());
+    assert_eq!(guided.tokens(), unguided.tokens());
+}
+
+#[test]
+fn guidance_samples_from_the_logits_combined_with_the_negative_prompt() {
+    let (model, vocab) = TestModel::default().load(32);
+    let params = guidance_parameters(Some(" world"), 3.0);
+    let mut session = generate_with_guidance(&model, &vocab, &params);
+
+    // The guidance session follows the negative prompt with the generated
+    // tokens.
+    let guidance = session.guidance.as_ref().unwrap();
+    let generated = &session.tokens()[2..];
+    assert_eq!(guidance.tokens()[..2], [1, 272]);
+    assert_eq!(&guidance.tokens()[2..], generated);
+    let negative = session_with(&model, 32, guidance.tokens());
+    for (&actual, &expected) in guidance.last_logits.iter().zip(&negative.last_logits) {
+        assert!((actual - expected).abs() < 1e-4, "{actual} != {expected}");
+    }
+
+    // The next token is the most likely under the guided logits.
+    let guided: Vec<f32> = session
+        .last_logits
+        .iter()
+        .zip(&guidance.last_logits)
+        .map(|(&pos, &neg)| 3.0 * (pos - neg) + neg)
+        .collect();
+    assert_eq!(session.sample(&params), sampling::sample_greedy(&guided));
+}
+
+#[test]
+fn generated_tokens_counts_the_tokens_of_the_call() {
+    let (model, vocab) = TestModel::default().load(32);
+    let params = InferenceParameters {
+        ignore_eos: true,
+        ..inference_parameters()
+    };
+    let mut session = model.start_session(Default::default());
+    for n in [3, 5] {
+        let stats = session
+            .inference_with_prompt::<Infallible>(
+                &model,
+                &vocab,
+                &params,
+                " hello",
+                Some(n),
+                |_, _| Ok(()),
+            )
+            .unwrap();
+        assert_eq!(stats.generated_tokens, n);
+    }
+    // The second call continued after the 2 + 3 tokens of the first.
+    assert_eq!(session.tokens().len(), 2 + 3 + 1 + 5);
+}
+
+#[test]
+fn ignoring_the_end_of_sentence_generates_the_maximum_token_count() {
+    let (model, vocab) = TestModel::default().load(32);
+    // The end of sentence is always sampled, unless it is ignored.
+    let mut params = InferenceParameters {
+        temp: 1.0,
+        top_k: 0,
+        echo_prompt: false,
+        ..inference_parameters()
+    };
+    params.logit_bias.insert(EOD_TOKEN_ID, 1000.0);
+    let generate = |params: &InferenceParameters, seed| {
+        let mut session = model.start_session(InferenceSessionParameters {
+            seed: Some(seed),
+            ..Default::default()
+        });
+        let tokens = RefCell::new(Vec::new());
+        let stats = session
+            .inference_with_prompt::<Infallible>(
+                &model,
+                &vocab,
+                params,
+                " hello",
+                Some(12),
+                |token, _| {
+                    tokens.borrow_mut().push(token.to_string());
+                    Ok(())
+                },
+            )
+            .unwrap();
+        (stats, tokens.into_inner(), session)
+    };
+
+    let (stats, _, _) = generate(&params, 0);
+    assert_eq!(stats.stop_reason, StopReason::EndOfSequence);
+    assert_eq!(stats.generated_tokens, 1);
+
+    params.ignore_eos = true;
+    for seed in 0..4 {
+        let (stats, tokens, session) = generate(&params, seed);
+        assert_eq!(stats.stop_reason, StopReason::MaxTokens, "{seed}");
+        assert_eq!(stats.generated_tokens, 12, "{seed}");
+        assert_eq!(tokens.len(), 12, "{seed}");
+        assert!(!session.tokens()[2..].contains(&EOD_TOKEN_ID), "{seed}");
+    }
+}
+
+#[test]
+fn unbounded_generation_stops_at_the_end_of_sentence_or_a_full_context() {
+    let (model, vocab) = TestModel::default().load(8);
+    let generate = |params: &InferenceParameters| {
+        let mut session = model.start_session(InferenceSessionParameters {
+            seed: Some(1),
+            ..Default::default()
+        });
+        let stats = session
+            .inference_with_prompt::<Infallible>(&model, &vocab, params, " hello", None, |_, _| {
+                Ok(())
+            })
+            .unwrap();
+        (stats, session.tokens().to_vec())
+    };
+
+    // Without the end of sentence, generation stops when the context window
+    // of 8 tokens is full, which always leaves one slot free.
+    let mut params = InferenceParameters {
+        ignore_eos: true,
+        ..inference_parameters()
+    };
+    let (stats, tokens) = generate(&params);
+    assert_eq!(stats.stop_reason, StopReason::ContextFull);
+    assert_eq!(tokens.len(), 7);
+    assert_eq!(stats.generated_tokens, 5);
+
+    params.ignore_eos = false;
+    params.logit_bias.insert(EOD_TOKEN_ID, 1000.0);
+    let (stats, tokens) = generate(&params);
+    assert_eq!(stats.stop_reason, StopReason::EndOfSequence);
+    assert_eq!(tokens, [1, 270, EOD_TOKEN_ID]);
+}
+
+#[test]
+fn aborted_generation_stops_between_tokens() {
+    let (model, vocab) = TestModel::default().load(32);
+    let params = InferenceParameters {
+        ignore_eos: true,
+        echo_prompt: false,
+        ..inference_parameters()
+    };
+    let mut session = model.start_session(Default::default());
+    let handle = session.abort_handle();
+    let n_tokens = Cell::new(0);
+    let stats = session
+        .inference_with_prompt::<Infallible>(&model, &vocab, &params, " hello", Some(16), |_, _| {
+            n_tokens.set(n_tokens.get() + 1);
+            if n_tokens.get() == 3 {
+                handle.abort();
+            }
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(stats.stop_reason, StopReason::Aborted);
+    assert_eq!(stats.generated_tokens, 3);
+    assert_eq!(n_tokens.get(), 3);
+
+    // The request is cleared once generation stopped, and generation that is
+    // aborted before it starts generates nothing.
+    let run = |session: &mut InferenceSession| {
+        session
+            .inference_with_prompt::<Infallible>(
+                &model,
+                &vocab,
+                &params,
+                "",
+                Some(2),
+                |_, _| Ok(()),
+            )
+            .unwrap()
+    };
+    assert_eq!(run(&mut session).stop_reason, StopReason::MaxTokens);
+    handle.abort();
+    let stats = run(&mut session);
+    assert_eq!(stats.stop_reason, StopReason::Aborted);
+    assert_eq!(stats.generated_tokens, 0);
+}
+
+#[test]
+fn inference_to_writer_writes_the_generated_text() {
+    let (model, vocab) = TestModel::default().load(32);
+    let params = InferenceParameters {
+        echo_prompt: false,
+        ..inference_parameters()
+    };
+    let new_session = || {
+        model.start_session(InferenceSessionParameters {
+            seed: Some(3),
+            ..Default::default()
+        })
+    };
+
+    let expected = RefCell::new(String::new());
+    new_session()
+        .inference_with_prompt(&model, &vocab, &params, " hello", Some(8), |token, _| {
+            expected.borrow_mut().push_str(&token.to_string());
+            Ok::<(), Infallible>(())
+        })
+        .unwrap();
+    let mut out = Vec::new();
+    let stats = new_session()
+        .inference_to_writer(&model, &vocab, &params, " hello", Some(8), &mut out)
+        .unwrap();
+    let expected = expected.into_inner();
+    assert!(!expected.is_empty());
+    assert_eq!(String::from_utf8(out).unwrap(), expected);
+    assert_eq!(stats.prompt_tokens, 2);
+}
+
+/// A writer whose writes all fail.
+struct ClosedWriter;
+
+impl std::io::Write for ClosedWriter {
+    fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn inference_to_writer_stops_at_the_first_write_error() {
+    let (model, vocab) = TestModel::default().load(32);
+    let mut session = model.start_session(Default::default());
+    let err = session
+        .inference_to_writer(
+            &model,
+            &vocab,
+            &inference_parameters(),
+            " hello",
+            Some(8),
+            &mut ClosedWriter,
+        )
+        .err()
+        .unwrap();
+    let InferenceError::UserCallback(err) = err else {
+        panic!("{err:?}");
+    };
+    let err = err.downcast_ref::<std::io::Error>().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    // The prompt and the token that couldn't be written.
+    assert_eq!(session.tokens().len(), 3);
+}
+
+#[test]
+fn token_healing_extends_the_last_token_of_the_prompt() {
+    let (model, vocab) = TestModel::default().load(32);
+    let token = |text: &str| model.tokenize(&vocab, text, false).unwrap()[0];
+    let (he, hello, world) = (token(" he"), token(" hello"), token(" world"));
+    assert_eq!(
+        model.tokenize(&vocab, " hello he", false).unwrap(),
+        [hello, he]
+    );
+
+    // Without healing, " world" is generated.
+    let params = InferenceParameters {
+        logit_bias: HashMap::from([(world, 100.0)]),
+        token_healing: true,
+        ..inference_parameters()
+    };
+    let mut session = model.start_session(Default::default());
+    session
+        .feed_prompt::<Infallible>(&model, &vocab, &params, " hello he", |_| Ok(()))
+        .unwrap();
+    assert_eq!(session.tokens(), [vocab.bos_token(), hello]);
+
+    // The first token starts with the removed " he", and the next ones are
+    // not constrained.
+    session.infer_next_token(&model, &vocab, &params).unwrap();
+    assert!([he, hello].contains(session.tokens().last().unwrap()));
+    session.infer_next_token(&model, &vocab, &params).unwrap();
+    assert_eq!(session.tokens().last(), Some(&world));
+
+    // A last token that no other token starts with is kept.
+    let mut session = model.start_session(Default::default());
+    session
+        .feed_prompt::<Infallible>(&model, &vocab, &params, " hello!", |_| Ok(()))
+        .unwrap();
+    assert_eq!(session.tokens(), [vocab.bos_token(), hello, token("!")]);
+    session.infer_next_token(&model, &vocab, &params).unwrap();
+    assert_eq!(session.tokens().last(), Some(&world));
+}
+
+#[test]
+fn grammar_constrains_the_generated_text() {
+    let (model, vocab) = TestModel {
+        extra_tokens: vec![
+            b"yes".to_vec(),
+            b"ye".to_vec(),
+            b"s".to_vec(),
+            b"no".to_vec(),
+        ],
+        ..Default::default()
+    }
+    .load(32);
+    let world = model.tokenize(&vocab, " world", false).unwrap()[0];
+    let params = InferenceParameters {
+        logit_bias: HashMap::from([(world, 100.0)]),
+        grammar: Some(r#"root ::= "yes" | "no""#.parse().unwrap()),
+        echo_prompt: false,
+        ..inference_parameters()
+    };
+
+    for seed in 0..8 {
+        let mut session = model.start_session(InferenceSessionParameters {
+            seed: Some(seed),
+            ..Default::default()
+        });
+        let text = RefCell::new(String::new());
+        let stats = session
+            .inference_with_prompt::<Infallible>(&model, &vocab, &params, " hello", None, |t, _| {
+                if let OutputToken::Token(t) = t {
+                    text.borrow_mut().push_str(t);
+                }
+                Ok(())
+            })
+            .unwrap();
+        let text = text.into_inner();
+        assert!(["yes", "no"].contains(&text.as_str()), "{text:?}");
+        assert_eq!(stats.stop_reason, StopReason::EndOfSequence);
+    }
+}
+
+/// Returns the vocabulary of the default test model.
+fn test_vocabulary() -> Vocabulary {
+    TestModel::default().load(8).1
+}
+
+#[test]
+fn architectures_are_detected_from_the_vocabulary() {
+    // The vocabulary of the test model, padded to `len` tokens, the last of
+    // which is `last`.
+    let vocabulary = |len: usize, last: &str| {
+        let mut vocab = test_vocabulary();
+        while vocab.id_to_token.len() < len - 1 {
+            let token = format!("tok{}", vocab.id_to_token.len());
+            vocab.id_to_token.push(token);
+        }
+        vocab.id_to_token.push(last.to_owned());
+        vocab
+    };
+    let cases = [
+        (278, "[PAD]", ModelArchitecture::Llama),
+        (32000, "tok31999", ModelArchitecture::Llama),
+        (32001, "[PAD]", ModelArchitecture::Alpaca),
+        (32001, "<pad>", ModelArchitecture::Llama),
+        (32002, "[PAD]", ModelArchitecture::Llama),
+    ];
+    for (len, last, expected) in cases {
+        let vocab = vocabulary(len, last);
+        assert_eq!(ModelArchitecture::detect(&vocab), expected, "{len} {last}");
+    }
+}
+
+#[test]
+fn each_architecture_has_its_defaults() {
+    let cases = [
+        (
+            ModelArchitecture::Llama,
+            "llama",
+            template::PromptTemplate::Raw,
+        ),
+        (
+            ModelArchitecture::Alpaca,
+            "alpaca",
+            template::PromptTemplate::Alpaca,
+        ),
+        (
+            ModelArchitecture::Vicuna,
+            "vicuna",
+            template::PromptTemplate::Vicuna,
+        ),
+    ];
+    for (architecture, name, template) in cases {
+        assert_eq!(architecture.to_string(), name);
+        assert_eq!(name.parse(), Ok(architecture));
+        assert_eq!(name.to_uppercase().parse(), Ok(architecture));
+        assert_eq!(architecture.default_template(), template, "{name}");
+        assert!(architecture.add_bos(), "{name}");
+    }
+    assert_eq!(ModelArchitecture::default(), ModelArchitecture::Llama);
+    assert!("falcon".parse::<ModelArchitecture>().is_err());
+
+    // The architecture is detected when loading, unless it is given.
+    let path = TestModel::default().write();
+    let (model, _) = Llama::load(&path, 8, |_| {}).unwrap();
+    assert_eq!(model.architecture(), ModelArchitecture::Llama);
+    let params = LoadParameters {
+        architecture: Some(ModelArchitecture::Vicuna),
+        ..Default::default()
+    };
+    let (model, _) = Llama::load_with_parameters(&path, 8, params, |_| {}).unwrap();
+    assert_eq!(model.architecture(), ModelArchitecture::Vicuna);
+}
+
+#[test]
+fn prompts_start_with_the_beginning_of_sentence_as_configured() {
+    let path = TestModel::default().write();
+    // (architecture, vocabulary override, parameters override, expected)
+    let cases = [
+        (ModelArchitecture::Llama, None, None, true),
+        (ModelArchitecture::Alpaca, None, None, true),
+        (ModelArchitecture::Vicuna, None, None, true),
+        (ModelArchitecture::Llama, Some(false), None, false),
+        (ModelArchitecture::Llama, None, Some(false), false),
+        (ModelArchitecture::Llama, Some(false), Some(true), true),
+        (ModelArchitecture::Llama, Some(true), Some(false), false),
+    ];
+    for (architecture, vocab_add_bos, add_bos, expected) in cases {
+        let load_params = LoadParameters {
+            architecture: Some(architecture),
+            ..Default::default()
+        };
+        let (model, mut vocab) =
+            Llama::load_with_parameters(&path, 8, load_params, |_| {}).unwrap();
+        assert_eq!(vocab.should_add_bos(), architecture.add_bos());
+        if let Some(vocab_add_bos) = vocab_add_bos {
+            vocab.set_add_bos(vocab_add_bos);
+        }
+        let params = InferenceParameters {
+            add_bos,
+            ..inference_parameters()
+        };
+        assert_eq!(params.adds_bos(&vocab), expected);
+
+        let mut session = model.start_session(Default::default());
+        session
+            .feed_prompt::<Infallible>(&model, &vocab, &params, " hello", |_| Ok(()))
+            .unwrap();
+        let prompt: &[TokenId] = if expected { &[1, 270] } else { &[270] };
+        assert_eq!(
+            session.tokens(),
+            prompt,
+            "{architecture} {vocab_add_bos:?} {add_bos:?}"
+        );
+        // Only the first prompt of a session starts a sentence.
+        session
+            .feed_prompt::<Infallible>(&model, &vocab, &params, " hello", |_| Ok(()))
+            .unwrap();
+        assert_eq!(session.tokens()[prompt.len()..], [270]);
+    }
+}
+
+/// Returns the ids of the tokens of `text`, without the beginning of
+/// sentence.
+fn token_ids(vocab: &Vocabulary, text: &str) -> Vec<TokenId> {
+    vocab
+        .tokenize(text, false)
+        .unwrap()
+        .into_iter()
+        .map(|(_, id)| id)
+        .collect()
+}
+
+#[test]
+fn search_finds_the_tokens_containing_the_text_by_id() {
+    let vocab = test_vocabulary();
+    assert_eq!(vocab.search("llo"), [(269, "llo"), (270, " hello")]);
+    assert_eq!(vocab.search("wor"), [(272, " world"), (273, "wor")]);
+    assert_eq!(vocab.search(" hello"), [(270, " hello")]);
+    assert!(vocab.search("Hello").is_empty());
+    assert!(vocab.search("zzz").is_empty());
+
+    let bytes = vocab.search("<0x4");
+    assert_eq!(bytes.len(), 16);
+    assert_eq!(bytes[0], (3 + 0x40, "<0x40>"));
+    assert!(bytes.windows(2).all(|w| w[0].0 < w[1].0));
+    assert_eq!(vocab.search("").len(), vocab.len());
+}
+
+#[test]
+fn special_tokens_are_tokenized_as_single_tokens() {
+    let (_, mut vocab) = TestModel {
+        extra_tokens: vec![b"<|im_start|>".to_vec(), b"<|im_end|>".to_vec()],
+        ..Default::default()
+    }
+    .load(8);
+    let template = template::PromptTemplate::ChatMl;
+    for &text in template.special_tokens() {
+        let id = vocab.token_id(text).unwrap();
+        vocab.add_special_token(text, id);
+    }
+    let (im_start, im_end) = (277, 278);
+    assert_eq!(token_ids(&vocab, "<|im_start|>"), [im_start]);
+    assert_eq!(token_ids(&vocab, "<|im_end|>"), [im_end]);
+
+    // The text around the special tokens is tokenized on its own.
+    let prompt = template.render(None, "hello world");
+    assert_eq!(
+        prompt,
+        "<|im_start|>user\nhello world<|im_end|>\n<|im_start|>assistant\n"
+    );
+    let expected: Vec<TokenId> = [
+        vec![im_start],
+        token_ids(&vocab, "user\nhello world"),
+        vec![im_end],
+        token_ids(&vocab, "\n"),
+        vec![im_start],
+        token_ids(&vocab, "assistant\n"),
+    ]
+    .concat();
+    assert_eq!(token_ids(&vocab, &prompt), expected);
+    assert_eq!(token_ids(&vocab, "\n"), [271]);
+    assert_eq!(token_ids(&vocab, " hello<|im_end|>"), [270, im_end]);
+
+    // A special token is only matched whole.
+    assert!(!token_ids(&vocab, "<|im_end").contains(&im_end));
+}
+
+#[test]
+fn fill_in_the_middle_prompts_are_in_prefix_suffix_middle_order() {
+    // Code Llama's vocabulary stores these tokens with a leading space.
+    let (_, mut vocab) = TestModel {
+        extra_tokens: [" <PRE>", " <SUF>", " <MID>", " <EOT>"]
+            .map(|token| token.as_bytes().to_vec())
+            .to_vec(),
+        ..Default::default()
+    }
+    .load(8);
+    for text in template::FIM_SPECIAL_TOKENS {
+        let id = vocab.token_id(&format!(" {text}")).unwrap();
+        vocab.add_special_token(text, id);
+    }
+    let (pre, suf, mid) = (277, 278, 279);
+
+    let prompt = template::render_fim(" hello", "!\n");
+    assert_eq!(prompt, "<PRE> hello<SUF>!\n<MID>");
+    assert_eq!(
+        vocab.tokenize(&prompt, true).unwrap(),
+        [
+            ("", 1),
+            ("<PRE>", pre),
+            (" hello", 270),
+            ("<SUF>", suf),
+            ("!", 276),
+            ("\n", 271),
+            ("<MID>", mid),
+        ]
+    );
+}
+
+#[test]
+fn tokenize_append_returns_the_tokens_after_the_join() {
+    let vocab = test_vocabulary();
+    let long = " hello".repeat(20);
+    for (existing, new) in [
+        (" hello\n", " world"),
+        (" hello", " world!"),
+        (" hello", "\n"),
+        ("", " hello"),
+        (long.as_str(), " world"),
+    ] {
+        let whole = token_ids(&vocab, &format!("{existing}{new}"));
+        let existing_tokens = token_ids(&vocab, existing);
+        assert_eq!(whole[..existing_tokens.len()], existing_tokens);
+        assert_eq!(
+            vocab.tokenize_append(existing, new).unwrap(),
+            whole[existing_tokens.len()..],
+            "{existing:?} + {new:?}"
+        );
+    }
+    assert!(vocab.tokenize_append(" hello", "").unwrap().is_empty());
+}
+
+#[test]
+fn tokenize_append_tokenizes_the_new_text_alone_if_a_token_spans_the_join() {
+    let vocab = test_vocabulary();
+    // " he" and "llo" are tokenized together as " hello".
+    assert_eq!(token_ids(&vocab, " hello").len(), 1);
+    assert_eq!(
+        vocab.tokenize_append(" he", "llo").unwrap(),
+        token_ids(&vocab, "llo")
+    );
+}
+
+#[test]
+fn count_tokens_is_the_number_of_tokens() {
+    let vocab = test_vocabulary();
+    for text in [
+        "",
+        " hello world",
+        "hello,\n world!",
+        "\u{e9}t\u{e9} \u{1f600}",
+    ] {
+        for bos in [false, true] {
+            assert_eq!(
+                vocab.count_tokens(text, bos).unwrap(),
+                vocab.tokenize(text, bos).unwrap().len(),
+                "{text:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn prompts_too_long_for_the_context_window_are_rejected_before_evaluating() {
+    let (model, vocab) = TestModel::default().load(8);
+    let prompt = " hello world".repeat(4);
+    assert!(vocab.count_tokens(&prompt, true).unwrap() >= 8);
+
+    let params = InferenceParameters {
+        context_policy: ContextPolicy::Error,
+        ..inference_parameters()
+    };
+    let mut session = model.start_session(Default::default());
+    let result = session.feed_prompt::<Infallible>(&model, &vocab, &params, &prompt, |_| Ok(()));
+    assert!(matches!(result, Err(InferenceError::ContextFull)));
+    assert_eq!(session.n_past(), 0);
+}
+
+#[test]
+fn detokenizer_holds_back_characters_split_across_tokens() {
+    // The emoji U+1F600 is F0 9F 98 80 in UTF-8.
+    let (_, vocab) = TestModel {
+        extra_tokens: vec![vec![0xf0, 0x9f], vec![0x98, 0x80], vec![0x80]],
+        ..Default::default()
+    }
+    .load(8);
+    let n = vocab.len() as TokenId;
+    let (first_half, second_half, continuation) = (n - 3, n - 2, n - 1);
+    let hello = token_ids(&vocab, " hello")[0];
+
+    let mut detokenizer = Detokenizer::new(&vocab);
+    assert_eq!(detokenizer.push(hello).as_deref(), Some(" hello"));
+    assert_eq!(detokenizer.push(first_half), None);
+    assert_eq!(detokenizer.push(second_half).as_deref(), Some("\u{1f600}"));
+
+    // A character that is never completed is flushed as U+FFFD.
+    assert_eq!(detokenizer.push(first_half), None);
+    assert_eq!(detokenizer.flush().as_deref(), Some("\u{fffd}"));
+    assert_eq!(detokenizer.flush(), None);
+
+    // Bytes that can't start a character are replaced right away.
+    let mut detokenizer = Detokenizer::new(&vocab);
+    assert_eq!(detokenizer.push(continuation).as_deref(), Some("\u{fffd}"));
+}
+
+#[test]
+fn tokenize_reader_yields_the_tokens_of_the_whole_text() {
+    let vocab = test_vocabulary();
+    let text = " hello world,\n hello\u{e9}\u{1f600} world!".repeat(10);
+    for bos in [false, true] {
+        let expected = vocab.tokenize(&text, bos).unwrap();
+        let expected: Vec<TokenId> = expected.into_iter().map(|(_, id)| id).collect();
+        // Small buffers split the tokens and the characters between reads.
+        for capacity in [1, 3, 7, 1024] {
+            let reader = std::io::BufReader::with_capacity(capacity, text.as_bytes());
+            let tokens: Vec<TokenId> = vocab
+                .tokenize_reader(reader, bos)
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(tokens, expected, "capacity {capacity}");
+        }
+    }
+
+    let invalid: &[u8] = b" hello \xff world";
+    let error = vocab
+        .tokenize_reader(invalid, false)
+        .find_map(Result::err)
+        .unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn characters_without_a_token_are_tokenized_as_their_bytes() {
+    let vocab = test_vocabulary();
+    // The byte tokens `<0x00>` to `<0xFF>` follow the three special tokens.
+    let byte = |byte: u8| 3 + TokenId::from(byte);
+
+    assert_eq!(token_ids(&vocab, "\u{e9}"), [byte(0xC3), byte(0xA9)]);
+    assert_eq!(
+        token_ids(&vocab, " hello\u{1f600}!"),
+        [270, byte(0xF0), byte(0x9F), byte(0x98), byte(0x80), 276]
+    );
+    for (text, id) in vocab.tokenize("\u{e9}", false).unwrap() {
+        assert_eq!(text, vocab.id_to_token[id as usize]);
+    }
+    assert_eq!(vocab.id_to_token[byte(0xC3) as usize], "<0xC3>");
+}
+
+#[test]
+fn describe_tokens_shows_the_id_and_text_of_each_token() {
+    let (_, vocab) = TestModel {
+        extra_tokens: vec![b"\xe2\x82".to_vec()],
+        ..Default::default()
+    }
+    .load(8);
+    assert_eq!(
+        vocab.describe_tokens(&[1, 270, 271, 3 + 0x41, 277, 1000]),
+        "1 -> \"<s>\"\n\
+         270 -> \" hello\"\n\
+         271 -> \"\\n\"\n\
+         68 -> \"<0x41>\"\n\
+         277 -> [226, 130]\n\
+         1000 -> <unknown>"
+    );
+}
@@ -0,0 +1,44 @@
+//! Runs [Llama::complete] with a real model, whose path is given by the
+//! `LLAMA_RS_TEST_MODEL` environment variable. Run with
+//! `LLAMA_RS_TEST_MODEL=path/to/ggml-model.bin cargo test -- --ignored`.
+
+use llama_rs::{InferenceParameters, InferenceSessionParameters, Llama, StopReason};
+
+#[test]
+#[ignore = "needs a model, whose path is set in LLAMA_RS_TEST_MODEL"]
+fn complete_stops_at_the_end_of_text_or_the_maximum_token_count() {
+    let model_path =
+        std::env::var("LLAMA_RS_TEST_MODEL").expect("LLAMA_RS_TEST_MODEL should be set");
+    let (model, vocab) = Llama::load(model_path, 512, |_| {}).unwrap();
+    let params = InferenceParameters {
+        n_threads: num_threads(),
+        ..Default::default()
+    };
+    let prompt = "The capital of France is";
+
+    let (completion, stop_reason) = model
+        .complete_with_session_parameters(
+            &vocab,
+            &params,
+            InferenceSessionParameters {
+                seed: Some(42),
+                ..Default::default()
+            },
+            prompt,
+            Some(16),
+        )
+        .unwrap();
+    assert!(!completion.is_empty());
+    assert!(!completion.starts_with(prompt), "{completion:?}");
+    assert!(
+        matches!(
+            stop_reason,
+            StopReason::EndOfSequence | StopReason::MaxTokens
+        ),
+        "{stop_reason:?}"
+    );
+}
+
+fn num_threads() -> i32 {
+    std::thread::available_parallelism().map_or(1, |n| n.get() as i32)
+}
@@ -0,0 +1,91 @@
+//! Implements [LanguageModel] outside of the crate, to check that the
+//! generation loop of [InferenceSession] only needs its public API.
+
+use llama_rs::{
+    ContextPolicy, EvaluateOutputRequest, Hyperparameters, InferenceParameters, InferenceSession,
+    LanguageModel, TokenId,
+};
+
+/// A model that always predicts the token after the last one it was fed.
+struct Counter {
+    hparams: Hyperparameters,
+}
+
+impl Counter {
+    fn new(n_ctx: i32) -> Self {
+        Self {
+            hparams: Hyperparameters {
+                n_vocab: 8,
+                n_ctx,
+                n_embd: 4,
+                n_mult: 4,
+                n_head: 1,
+                n_layer: 1,
+                n_rot: 4,
+                f16_: 0,
+            },
+        }
+    }
+
+    fn start_session(&self) -> InferenceSession {
+        InferenceSession::new(&self.hparams, Default::default())
+    }
+}
+
+impl LanguageModel for Counter {
+    fn hparams(&self) -> &Hyperparameters {
+        &self.hparams
+    }
+
+    fn evaluate(
+        &self,
+        session: &mut InferenceSession,
+        _params: &InferenceParameters,
+        input_tokens: &[TokenId],
+        _output_request: &mut EvaluateOutputRequest,
+    ) {
+        assert!(session.n_past() + input_tokens.len() <= session.n_ctx());
+        let mut logits = vec![0.0; self.n_vocab()];
+        let next = (input_tokens.last().unwrap() + 1) as usize % self.n_vocab();
+        logits[next] = 1.0;
+        session.advance(input_tokens.len(), &logits);
+    }
+}
+
+fn greedy() -> InferenceParameters {
+    InferenceParameters {
+        temp: 0.0,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn the_session_generates_with_a_model_from_another_crate() {
+    let model = Counter::new(16);
+    let params = greedy();
+    let mut session = model.start_session();
+    session.feed_tokens(&model, &params, &[3]).unwrap();
+    for _ in 0..6 {
+        let token = session.sample(&params);
+        session.feed_tokens(&model, &params, &[token]).unwrap();
+    }
+    assert_eq!(session.tokens(), [3, 4, 5, 6, 7, 0, 1]);
+    assert_eq!(session.n_past(), 7);
+}
+
+#[test]
+fn the_session_makes_room_with_a_model_from_another_crate() {
+    let model = Counter::new(4);
+    let params = InferenceParameters {
+        context_policy: ContextPolicy::SlidingWindow,
+        n_batch: 1,
+        ..greedy()
+    };
+    let mut session = model.start_session();
+    session
+        .feed_tokens(&model, &params, &[0, 1, 2, 3, 4])
+        .unwrap();
+    assert!(session.n_past() < 4);
+    assert_eq!(session.tokens().last(), Some(&4));
+    assert_eq!(session.sample(&params), 5);
+}